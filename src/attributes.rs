@@ -0,0 +1,161 @@
+//! Path attributes from `.gitattributes` and the filters they select when adding content.
+
+use crate::{pathspec::glob_matches, Repository};
+use std::fs;
+
+/// The state of one attribute for a path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttrValue {
+    Set,
+    Unset,
+    Value(String),
+}
+
+#[derive(Debug, Clone)]
+struct AttrLine {
+    pattern: String,
+    attrs: Vec<(String, Option<AttrValue>)>,
+}
+
+/// Attributes of the top-level `.gitattributes` and `info/attributes`, which wins.
+#[derive(Debug, Clone, Default)]
+pub struct Attributes {
+    lines: Vec<AttrLine>,
+}
+
+impl Attributes {
+    pub fn parse(text: &str) -> Attributes {
+        let mut lines = Vec::new();
+        for line in text.lines() {
+            let mut words = line.split_whitespace();
+            let Some(pattern) = words.next().filter(|p| !p.starts_with('#')) else {
+                continue;
+            };
+            let mut attrs = Vec::new();
+            for word in words {
+                // `!attr` returns the attribute to unspecified
+                let (name, value) = if let Some(name) = word.strip_prefix('-') {
+                    (name, Some(AttrValue::Unset))
+                } else if let Some(name) = word.strip_prefix('!') {
+                    (name, None)
+                } else if let Some((name, value)) = word.split_once('=') {
+                    (name, Some(AttrValue::Value(value.to_owned())))
+                } else {
+                    (word, Some(AttrValue::Set))
+                };
+                if name == "binary" && value == Some(AttrValue::Set) {
+                    for macro_attr in ["diff", "merge", "text"] {
+                        attrs.push((macro_attr.to_owned(), Some(AttrValue::Unset)));
+                    }
+                }
+                attrs.push((name.to_owned(), value));
+            }
+            lines.push(AttrLine {
+                pattern: pattern.to_owned(),
+                attrs,
+            });
+        }
+        Attributes { lines }
+    }
+
+    /// Returns the value of `name` for a path relative to the top of the working tree.
+    pub fn get(&self, path: &str, name: &str) -> Option<AttrValue> {
+        for line in self.lines.iter().rev() {
+            // Patterns without a slash match the file name in any directory
+            let pattern = line.pattern.trim_start_matches('/');
+            let matched = if line.pattern.contains('/') {
+                glob_matches(pattern.as_bytes(), path.as_bytes())
+            } else {
+                let file_name = path.rsplit('/').next().unwrap_or(path);
+                glob_matches(pattern.as_bytes(), file_name.as_bytes())
+            };
+            if !matched {
+                continue;
+            }
+            if let Some((_, value)) = line.attrs.iter().rev().find(|(attr, _)| attr == name) {
+                return value.clone();
+            }
+        }
+        None
+    }
+}
+
+/// Whether content looks like text, using git's heuristics for `text=auto`.
+fn is_text(data: &[u8]) -> bool {
+    let mut printable = 0;
+    let mut non_printable = 0;
+    for (i, &byte) in data.iter().enumerate() {
+        match byte {
+            0 => return false,
+            // A carriage return not followed by a newline means binary
+            b'\r' if data.get(i + 1) != Some(&b'\n') => return false,
+            0x7f => non_printable += 1,
+            b'\r' | b'\n' | b'\t' | 0x08 | 0x1b | 0x0c | 0x20.. => printable += 1,
+            _ => non_printable += 1,
+        }
+    }
+    (printable >> 7) >= non_printable
+}
+
+impl Repository {
+    pub fn attributes(&self) -> anyhow::Result<Attributes> {
+        let mut text = String::new();
+        // Bare repositories only have the attributes in the git directory
+        let work_tree_file = self.work_dir().ok().map(|dir| dir.join(".gitattributes"));
+        let git_dir_file = self.common_dir.join("info").join("attributes");
+        for path in work_tree_file.into_iter().chain([git_dir_file]) {
+            match fs::read_to_string(&path) {
+                Ok(contents) => {
+                    text.push_str(&contents);
+                    text.push('\n');
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(Attributes::parse(&text))
+    }
+
+    fn auto_crlf(&self) -> anyhow::Result<bool> {
+        let config = self.config()?;
+        match config.get("core.autocrlf") {
+            Some(value) if value.eq_ignore_ascii_case("input") => Ok(true),
+            _ => Ok(config.get_bool("core.autocrlf")?.unwrap_or(false)),
+        }
+    }
+
+    /// Whether `clean_filter` may change content at `path`, otherwise it can be streamed.
+    pub fn may_filter(&self, path: &str) -> anyhow::Result<bool> {
+        let attributes = self.attributes()?;
+        Ok(match attributes.get(path, "text") {
+            Some(AttrValue::Unset) => false,
+            Some(AttrValue::Value(value)) if value != "auto" => false,
+            Some(_) => true,
+            None => attributes.get(path, "eol").is_some() || self.auto_crlf()?,
+        })
+    }
+
+    /// Converts working tree content at `path` to what is stored in the repository,
+    /// normalizing line endings of text files to LF.
+    pub fn clean_filter(&self, path: &str, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let attributes = self.attributes()?;
+        let convert = match attributes.get(path, "text") {
+            Some(AttrValue::Set) => true,
+            Some(AttrValue::Unset) => false,
+            Some(AttrValue::Value(value)) if value == "auto" => is_text(&data),
+            Some(AttrValue::Value(_)) => false,
+            None if attributes.get(path, "eol").is_some() => true,
+            None => self.auto_crlf()? && is_text(&data),
+        };
+        if !convert || !data.windows(2).any(|pair| pair == b"\r\n") {
+            return Ok(data);
+        }
+        let mut converted = Vec::with_capacity(data.len());
+        for (i, &byte) in data.iter().enumerate() {
+            if byte != b'\r' || data.get(i + 1) != Some(&b'\n') {
+                converted.push(byte);
+            }
+        }
+        Ok(converted)
+    }
+}
@@ -0,0 +1,205 @@
+//! Streaming object access for `cat-file --batch` and friends.
+
+use crate::{
+    odb::PackDatabase,
+    pack::Pack,
+    read_loose_header,
+    stream::{self, ObjectStream},
+    ObjectRef, Repository,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+pub const DEFAULT_FORMAT: &str = "%(objectname) %(objecttype) %(objectsize)";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Atom {
+    Literal(String),
+    Name,
+    Type,
+    Size,
+    DiskSize,
+    DeltaBase,
+    Rest,
+}
+
+/// A parsed `--batch-check=<format>` string.
+#[derive(Debug, Clone)]
+pub struct BatchFormat {
+    atoms: Vec<Atom>,
+}
+
+impl BatchFormat {
+    pub fn parse(format: &str) -> anyhow::Result<BatchFormat> {
+        let mut atoms = Vec::new();
+        let mut rest = format;
+        while let Some(start) = rest.find("%(") {
+            if start > 0 {
+                atoms.push(Atom::Literal(rest[..start].to_owned()));
+            }
+            let end = rest[start..]
+                .find(')')
+                .map(|end| start + end)
+                .ok_or_else(|| anyhow::Error::msg("format: %(unterminated atom"))?;
+            atoms.push(match &rest[start + 2..end] {
+                "objectname" => Atom::Name,
+                "objecttype" => Atom::Type,
+                "objectsize" => Atom::Size,
+                "objectsize:disk" => Atom::DiskSize,
+                "deltabase" => Atom::DeltaBase,
+                "rest" => Atom::Rest,
+                _ => anyhow::bail!("unknown format element: {}", &rest[start..=end]),
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            atoms.push(Atom::Literal(rest.to_owned()));
+        }
+        Ok(BatchFormat { atoms })
+    }
+
+    /// Whether input lines are split into the object name and `%(rest)`.
+    pub fn splits_input(&self) -> bool {
+        self.atoms.contains(&Atom::Rest)
+    }
+
+    fn needs_pack_info(&self) -> bool {
+        self.atoms
+            .iter()
+            .any(|atom| matches!(atom, Atom::DiskSize | Atom::DeltaBase))
+    }
+
+    /// Expands the format for one object, `rest` being the remainder of the input line.
+    pub fn expand(&self, info: &ObjectInfo, rest: &str) -> String {
+        let mut line = String::new();
+        for atom in &self.atoms {
+            match atom {
+                Atom::Literal(text) => line.push_str(text),
+                Atom::Name => line.push_str(&info.object_ref.0),
+                Atom::Type => line.push_str(&info.kind),
+                Atom::Size => line.push_str(&info.size.to_string()),
+                Atom::DiskSize => line.push_str(&info.disk_size.to_string()),
+                Atom::DeltaBase => line.push_str(&info.delta_base.0),
+                Atom::Rest => line.push_str(rest),
+            }
+        }
+        line
+    }
+}
+
+/// An object as reported by the batch modes.
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub object_ref: ObjectRef,
+    pub kind: String,
+    pub size: usize,
+    pub disk_size: u64,
+    /// The null hash unless the object is stored as a delta
+    pub delta_base: ObjectRef,
+}
+
+/// Looks up many objects in a row, opening the packs only once.
+pub struct ObjectReader {
+    /// Object directories with their packs, the repository's own and then its alternates
+    stores: Vec<(PathBuf, Rc<[Pack]>)>,
+    pack_info: bool,
+}
+
+impl ObjectReader {
+    pub fn new(repo: &Repository, format: &BatchFormat) -> anyhow::Result<ObjectReader> {
+        let mut stores = vec![(repo.common_dir.join("objects"), repo.packs()?)];
+        for dir in repo.alternate_object_dirs()? {
+            let packs = PackDatabase::new(dir.join("pack"), repo.object_format()?).packs()?;
+            stores.push((dir, packs));
+        }
+        Ok(ObjectReader {
+            stores,
+            pack_info: format.needs_pack_info(),
+        })
+    }
+
+    /// Looks up the type, size and storage details of an object without reading its contents.
+    pub fn read(&self, object_ref: &ObjectRef) -> anyhow::Result<Option<ObjectInfo>> {
+        for (objects_dir, packs) in &self.stores {
+            if let Some(info) = self.read_from(object_ref, objects_dir, packs)? {
+                return Ok(Some(info));
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_from(
+        &self,
+        object_ref: &ObjectRef,
+        objects_dir: &Path,
+        packs: &[Pack],
+    ) -> anyhow::Result<Option<ObjectInfo>> {
+        let null = ObjectRef::null(object_ref.format());
+        let (prefix, remainder) = object_ref.0.split_at(2);
+        let path = objects_dir.join(prefix).join(remainder);
+        if path.is_file() {
+            let (kind, size) = read_loose_header(&path)?;
+            return Ok(Some(ObjectInfo {
+                object_ref: object_ref.clone(),
+                kind,
+                size,
+                disk_size: fs::metadata(&path)?.len(),
+                delta_base: null,
+            }));
+        }
+        let hash = object_ref.to_bytes();
+        for pack in packs {
+            let Some(position) = pack.index.position(&hash) else {
+                continue;
+            };
+            let offset = pack.index.offset(position)?;
+            let (kind, size) = pack.read_header_at(offset)?;
+            let (disk_size, delta_base) = if self.pack_info {
+                let (disk_size, base) = pack.entry_info(position)?;
+                let base = match base {
+                    Some(base) => ObjectRef::from_hash(&base),
+                    None => null,
+                };
+                (disk_size, base)
+            } else {
+                (0, null)
+            };
+            return Ok(Some(ObjectInfo {
+                object_ref: object_ref.clone(),
+                kind: kind.to_owned(),
+                size,
+                disk_size,
+                delta_base,
+            }));
+        }
+        Ok(None)
+    }
+
+    /// Opens an object to read its contents in chunks.
+    pub fn open(&self, object_ref: &ObjectRef) -> anyhow::Result<Option<ObjectStream>> {
+        let (prefix, remainder) = object_ref.0.split_at(2);
+        let hash = object_ref.to_bytes();
+        for (objects_dir, packs) in &self.stores {
+            let path = objects_dir.join(prefix).join(remainder);
+            if path.is_file() {
+                return stream::open_loose(&path).map(Some);
+            }
+            for pack in packs.iter() {
+                if let Some(position) = pack.index.position(&hash) {
+                    return pack.open_at(pack.index.offset(position)?).map(Some);
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Repository {
+    /// Lists every object in the repository, loose or packed, sorted and without duplicates.
+    pub fn all_objects(&self) -> anyhow::Result<Vec<ObjectRef>> {
+        Ok(self.object_database()?.iterate()?)
+    }
+}
@@ -0,0 +1,1005 @@
+//! Attributing every line of a file to the commit that introduced it.
+
+use crate::{
+    diff::{self, DiffOptions, DiffStatus},
+    hash::RawHash,
+    myers::{self, Edit},
+    ObjectRef, PersonLine, Repository,
+};
+use anyhow::Context;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    io::Write,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Minimal number of alphanumeric characters in a block of lines for it to be
+/// recognized as moved, like git's default for `-M`
+const MOVE_SCORE: usize = 20;
+
+#[derive(Debug, Clone, Default)]
+pub struct BlameOptions {
+    /// Lines to blame as 0-based half-open ranges of the final file, all lines if empty
+    pub ranges: Vec<(usize, usize)>,
+    /// Commits which are looked through, their changes are attributed to earlier commits
+    pub ignore_revs: HashSet<ObjectRef>,
+    /// Detect lines moved or copied within the file
+    pub detect_moves: bool,
+}
+
+/// A version of the blamed file, `commit` is `None` for the working tree
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Suspect {
+    pub commit: Option<ObjectRef>,
+    pub path: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlameEntry {
+    pub suspect: Suspect,
+    /// Line number in the suspect's version of the file, 0-based
+    pub orig_line: usize,
+    /// Line number in the blamed file, 0-based
+    pub final_line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Blame {
+    /// Contents of the blamed file, split into lines
+    pub lines: Vec<Vec<u8>>,
+    /// Requested lines by final line number
+    pub entries: Vec<BlameEntry>,
+    /// For each blamed commit, the first parent and path the file came from
+    pub previous: HashMap<ObjectRef, (ObjectRef, String)>,
+}
+
+/// How blame output is written by [`write_blame`]
+#[derive(Debug, Clone, Default)]
+pub struct BlameFormat {
+    /// Machine readable output, with commit details once per commit
+    pub porcelain: bool,
+    /// Like `porcelain`, but with commit details for every line
+    pub line_porcelain: bool,
+    /// Do not mark root commits as boundaries
+    pub show_root: bool,
+    /// Show full commit hashes
+    pub long: bool,
+    /// Leave out author names and dates
+    pub suppress: bool,
+    /// Show author emails instead of names
+    pub show_email: bool,
+    /// Show the file name of the original commit even if the file was never renamed
+    pub show_name: bool,
+}
+
+/// Author, committer and summary of a blamed commit, as shown by blame
+#[derive(Debug)]
+pub struct CommitInfo {
+    pub author: PersonLine<'static>,
+    pub committer: PersonLine<'static>,
+    pub summary: String,
+    /// A root commit, the lines it introduced might be older
+    pub boundary: bool,
+}
+
+/// What a single position of a [`LinePattern`] matches
+#[derive(Debug, Clone)]
+enum Atom {
+    Any,
+    Byte(u8),
+    /// Inclusive byte ranges of a bracket expression
+    Class {
+        negated: bool,
+        ranges: Vec<(u8, u8)>,
+    },
+}
+
+impl Atom {
+    fn matches(&self, byte: u8) -> bool {
+        match self {
+            Atom::Any => true,
+            Atom::Byte(expected) => byte == *expected,
+            Atom::Class { negated, ranges } => {
+                ranges
+                    .iter()
+                    .any(|(low, high)| (*low..=*high).contains(&byte))
+                    != *negated
+            }
+        }
+    }
+}
+
+/// A POSIX basic regular expression, which git uses for `-L /regex/`. Supports literals,
+/// `.`, bracket expressions, `*`, the `\+` and `\?` extensions and the `^` and `$`
+/// anchors, but no groups, alternation or intervals.
+#[derive(Debug)]
+struct LinePattern {
+    anchored_start: bool,
+    anchored_end: bool,
+    /// Atoms with the minimal and maximal number of repetitions
+    pieces: Vec<(Atom, usize, usize)>,
+}
+
+impl LinePattern {
+    fn parse(pattern: &str) -> anyhow::Result<LinePattern> {
+        let unsupported =
+            || anyhow::Error::msg(format!("unsupported regular expression '{pattern}'"));
+        let bytes = pattern.as_bytes();
+        let anchored_start = bytes.first() == Some(&b'^');
+        let mut anchored_end = false;
+        let mut pieces: Vec<(Atom, usize, usize)> = Vec::new();
+        let mut i = usize::from(anchored_start);
+        while i < bytes.len() {
+            let atom = match bytes[i] {
+                b'$' if i + 1 == bytes.len() => {
+                    anchored_end = true;
+                    break;
+                }
+                // A star at the start of the expression is an ordinary character
+                b'*' if !pieces.is_empty() => {
+                    let last = pieces.len() - 1;
+                    pieces[last].1 = 0;
+                    pieces[last].2 = usize::MAX;
+                    i += 1;
+                    continue;
+                }
+                b'\\' => {
+                    i += 1;
+                    match bytes.get(i).copied() {
+                        Some(quantifier @ (b'+' | b'?')) if !pieces.is_empty() => {
+                            let last = pieces.len() - 1;
+                            match quantifier {
+                                b'+' => pieces[last].2 = usize::MAX,
+                                _ => pieces[last].1 = 0,
+                            }
+                            i += 1;
+                            continue;
+                        }
+                        Some(byte)
+                            if !byte.is_ascii_alphanumeric() && !b"(){}|<>`'".contains(&byte) =>
+                        {
+                            Atom::Byte(byte)
+                        }
+                        _ => return Err(unsupported()),
+                    }
+                }
+                b'.' => Atom::Any,
+                b'[' => {
+                    let (atom, end) = Self::parse_class(bytes, i + 1).ok_or_else(unsupported)?;
+                    i = end;
+                    atom
+                }
+                byte => Atom::Byte(byte),
+            };
+            pieces.push((atom, 1, 1));
+            i += 1;
+        }
+        // `x\+` is `xx*`, so that every piece either matches once or loops on itself
+        let pieces = pieces
+            .into_iter()
+            .flat_map(|(atom, min, max)| match (min, max) {
+                (1, usize::MAX) => vec![(atom.clone(), 1, 1), (atom, 0, usize::MAX)],
+                (min, max) => vec![(atom, min, max)],
+            })
+            .collect();
+        Ok(LinePattern {
+            anchored_start,
+            anchored_end,
+            pieces,
+        })
+    }
+
+    /// Parses a bracket expression starting after its `[`, returning the position of its `]`.
+    fn parse_class(bytes: &[u8], mut i: usize) -> Option<(Atom, usize)> {
+        let negated = bytes.get(i) == Some(&b'^');
+        if negated {
+            i += 1;
+        }
+        let mut ranges = Vec::new();
+        let start = i;
+        loop {
+            let byte = *bytes.get(i)?;
+            // A `]` right at the start is part of the set
+            if byte == b']' && i > start {
+                return Some((Atom::Class { negated, ranges }, i));
+            }
+            if bytes[i..].starts_with(b"[:") {
+                let end = i + bytes[i..].windows(2).position(|w| w == b":]")?;
+                let class: &[(u8, u8)] = match &bytes[i + 2..end] {
+                    b"alpha" => &[(b'a', b'z'), (b'A', b'Z')],
+                    b"digit" => &[(b'0', b'9')],
+                    b"alnum" => &[(b'a', b'z'), (b'A', b'Z'), (b'0', b'9')],
+                    b"upper" => &[(b'A', b'Z')],
+                    b"lower" => &[(b'a', b'z')],
+                    b"space" => &[(b' ', b' '), (b'\t', b'\r')],
+                    b"blank" => &[(b' ', b' '), (b'\t', b'\t')],
+                    b"xdigit" => &[(b'0', b'9'), (b'a', b'f'), (b'A', b'F')],
+                    _ => return None,
+                };
+                ranges.extend_from_slice(class);
+                i = end + 2;
+            } else if bytes.get(i + 1) == Some(&b'-')
+                && bytes.get(i + 2).is_some_and(|b| *b != b']')
+            {
+                ranges.push((byte, bytes[i + 2]));
+                i += 3;
+            } else {
+                ranges.push((byte, byte));
+                i += 1;
+            }
+        }
+    }
+
+    /// Runs the pieces as a nondeterministic automaton, keeping track of all pieces a match
+    /// could continue with at once. This takes linear time in the length of the line, where
+    /// backtracking would take exponential time for patterns like `a*a*a*b`.
+    fn is_match(&self, line: &[u8]) -> bool {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let accept = self.pieces.len();
+        // Whether a match can continue with the piece, or is complete at `accept`
+        let mut states = vec![false; accept + 1];
+        let mut next = states.clone();
+        for pos in 0..=line.len() {
+            if pos == 0 || !self.anchored_start {
+                states[0] = true;
+            }
+            // Optional pieces can be skipped
+            for piece in 0..accept {
+                if states[piece] && self.pieces[piece].1 == 0 {
+                    states[piece + 1] = true;
+                }
+            }
+            if states[accept] && (!self.anchored_end || pos == line.len()) {
+                return true;
+            }
+            let Some(&byte) = line.get(pos) else {
+                break;
+            };
+            next.fill(false);
+            for (piece, (atom, _, max)) in self.pieces.iter().enumerate() {
+                if states[piece] && atom.matches(byte) {
+                    next[if *max == 1 { piece + 1 } else { piece }] = true;
+                }
+            }
+            std::mem::swap(&mut states, &mut next);
+        }
+        false
+    }
+}
+
+/// Splits `/regex/rest` into the regex and what follows its closing slash.
+fn split_regex(spec: &str) -> Option<(&str, &str)> {
+    let rest = spec.strip_prefix('/')?;
+    let mut escaped = false;
+    for (i, c) in rest.char_indices() {
+        match c {
+            '/' if !escaped => return Some((&rest[..i], &rest[i + 1..])),
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+/// Resolves an `-L` argument to a 0-based half-open range. The `/regex/` forms take basic
+/// regular expressions like git.
+pub fn parse_line_range(spec: &str, lines: &[Vec<u8>]) -> anyhow::Result<(usize, usize)> {
+    let find = |pattern: &str, from: usize| {
+        let regex = LinePattern::parse(pattern)?;
+        (from..lines.len())
+            .find(|i| regex.is_match(&lines[*i]))
+            .ok_or_else(|| anyhow::Error::msg(format!("-L parameter '{pattern}': no match")))
+    };
+    let invalid = || anyhow::Error::msg(format!("invalid -L argument '{spec}'"));
+    // A leading `/regex/` may contain commas
+    let (start, end_spec) = match split_regex(spec) {
+        Some((pattern, rest)) => {
+            let end_spec = match rest {
+                "" => None,
+                rest => Some(rest.strip_prefix(',').ok_or_else(invalid)?),
+            };
+            (find(pattern, 0)?, end_spec)
+        }
+        None if spec.starts_with('/') => return Err(invalid()),
+        None => {
+            let (start_spec, end_spec) = match spec.split_once(',') {
+                Some((start, end)) => (start, Some(end)),
+                None => (spec, None),
+            };
+            let start = match start_spec {
+                "" => 0,
+                s => s
+                    .parse::<usize>()
+                    .map_err(|_| invalid())?
+                    .checked_sub(1)
+                    .ok_or_else(invalid)?,
+            };
+            (start, end_spec)
+        }
+    };
+    anyhow::ensure!(
+        start < lines.len().max(1),
+        "file has only {} lines",
+        lines.len()
+    );
+    let (start, end) = match end_spec {
+        None | Some("") => (start, lines.len()),
+        Some(e) if e.starts_with('+') => {
+            let count: usize = e[1..].parse().map_err(|_| invalid())?;
+            (start, start + count.max(1))
+        }
+        Some(e) if e.starts_with('-') => {
+            let count: usize = e[1..].parse().map_err(|_| invalid())?;
+            ((start + 1).saturating_sub(count.max(1)), start + 1)
+        }
+        Some(e) if e.starts_with('/') => match split_regex(e) {
+            Some((pattern, "")) => (start, find(pattern, start + 1)? + 1),
+            _ => return Err(invalid()),
+        },
+        Some(e) => {
+            let end: usize = e.parse().map_err(|_| invalid())?;
+            // Ranges may be given backwards
+            (start.min(end.saturating_sub(1)), (start + 1).max(end))
+        }
+    };
+    Ok((start, end.min(lines.len())))
+}
+
+/// Matches lines of `child` to lines of `parent` that were moved, for a line run
+/// of `child` not otherwise accounted for.
+fn find_moved_lines(
+    parent: &[&[u8]],
+    child: &[&[u8]],
+    unmatched: &[usize],
+) -> HashMap<usize, usize> {
+    let mut positions: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for (i, line) in parent.iter().enumerate() {
+        positions.entry(line).or_default().push(i);
+    }
+    let unmatched: HashSet<usize> = unmatched.iter().copied().collect();
+    let mut moved = HashMap::new();
+    let mut sorted: Vec<usize> = unmatched.iter().copied().collect();
+    sorted.sort_unstable();
+
+    let mut i = 0;
+    while i < sorted.len() {
+        let start = sorted[i];
+        // Longest block of consecutive unmatched lines found somewhere in the parent
+        let mut best = (0, 0);
+        for &p in positions.get(child[start]).into_iter().flatten() {
+            let length = (0..)
+                .take_while(|k| {
+                    unmatched.contains(&(start + k))
+                        && p + k < parent.len()
+                        && parent[p + k] == child[start + k]
+                })
+                .count();
+            if length > best.1 {
+                best = (p, length);
+            }
+        }
+        let (p, length) = best;
+        let score: usize = child[start..start + length]
+            .iter()
+            .map(|line| line.iter().filter(|c| c.is_ascii_alphanumeric()).count())
+            .sum();
+        if length > 0 && score >= MOVE_SCORE {
+            for k in 0..length {
+                moved.insert(start + k, p + k);
+            }
+            i += length;
+        } else {
+            i += 1;
+        }
+    }
+    moved
+}
+
+/// Maps changed lines of `child` to lines removed from `parent`, used to look through
+/// ignored commits. Identical removed lines are preferred, otherwise lines are paired
+/// by their position within the changed hunk.
+fn map_changed_lines(parent: &[&[u8]], child: &[&[u8]], edits: &[Edit]) -> HashMap<usize, usize> {
+    let mut removed: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for edit in edits.iter().rev() {
+        if let Edit::Delete { old } = *edit {
+            removed.entry(parent[old]).or_default().push(old);
+        }
+    }
+    let mut used = HashSet::new();
+    let mut mapped = HashMap::new();
+    for edit in edits {
+        if let Edit::Insert { new } = *edit {
+            if let Some(old) = removed.get_mut(child[new]).and_then(Vec::pop) {
+                used.insert(old);
+                mapped.insert(new, old);
+            }
+        }
+    }
+
+    let (mut deleted, mut inserted) = (Vec::new(), Vec::new());
+    for edit in edits
+        .iter()
+        .chain(std::iter::once(&Edit::Equal { old: 0, new: 0 }))
+    {
+        match *edit {
+            Edit::Delete { old } if !used.contains(&old) => deleted.push(old),
+            Edit::Insert { new } if !mapped.contains_key(&new) => inserted.push(new),
+            Edit::Equal { .. } => {
+                mapped.extend(inserted.iter().copied().zip(deleted.iter().copied()));
+                deleted.clear();
+                inserted.clear();
+            }
+            _ => {}
+        }
+    }
+    mapped
+}
+
+/// Lines still to be attributed, as (final line, line in the suspect's version)
+/// Writes `blame` of the file `path` like `git blame` does, in its default format or as
+/// porcelain.
+pub fn write_blame(
+    out: &mut impl Write,
+    repo: &Repository,
+    path: &str,
+    blame: &Blame,
+    format: &BlameFormat,
+) -> anyhow::Result<()> {
+    let mut infos: HashMap<&Suspect, CommitInfo> = HashMap::new();
+    for entry in blame.entries.iter() {
+        if !infos.contains_key(&entry.suspect) {
+            let info = repo.blame_commit_info(&entry.suspect, format.show_root)?;
+            infos.insert(&entry.suspect, info);
+        }
+    }
+    if format.porcelain || format.line_porcelain {
+        write_porcelain(out, repo, blame, &infos, format.line_porcelain)
+    } else {
+        write_annotated(out, repo, path, blame, &infos, format)
+    }
+}
+
+fn suspect_hash(repo: &Repository, suspect: &Suspect) -> anyhow::Result<String> {
+    Ok(match &suspect.commit {
+        Some(commit) => commit.as_str().to_owned(),
+        None => "0".repeat(repo.object_format()?.hex_len()),
+    })
+}
+
+/// Writes a line of the blamed file, adding the newline the last line may lack.
+fn write_line(out: &mut impl Write, line: &[u8]) -> anyhow::Result<()> {
+    out.write_all(line)?;
+    if !line.ends_with(b"\n") {
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+fn write_porcelain(
+    out: &mut impl Write,
+    repo: &Repository,
+    blame: &Blame,
+    infos: &HashMap<&Suspect, CommitInfo>,
+    every_line: bool,
+) -> anyhow::Result<()> {
+    let mut seen = HashSet::new();
+    for (i, entry) in blame.entries.iter().enumerate() {
+        let hash = suspect_hash(repo, &entry.suspect)?;
+        let group_start = i == 0 || {
+            let prev = &blame.entries[i - 1];
+            prev.suspect != entry.suspect
+                || prev.orig_line + 1 != entry.orig_line
+                || prev.final_line + 1 != entry.final_line
+        };
+        write!(
+            out,
+            "{hash} {} {}",
+            entry.orig_line + 1,
+            entry.final_line + 1
+        )?;
+        if group_start {
+            let length = blame.entries[i..]
+                .iter()
+                .zip(0..)
+                .take_while(|(e, k)| {
+                    e.suspect == entry.suspect
+                        && e.orig_line == entry.orig_line + k
+                        && e.final_line == entry.final_line + k
+                })
+                .count();
+            write!(out, " {length}")?;
+        }
+        writeln!(out)?;
+        if every_line || (group_start && seen.insert(&entry.suspect)) {
+            let info = &infos[&entry.suspect];
+            for (role, person) in [("author", &info.author), ("committer", &info.committer)] {
+                writeln!(out, "{role} {}", person.name)?;
+                writeln!(out, "{role}-mail <{}>", person.email)?;
+                writeln!(out, "{role}-time {}", person.timestamp)?;
+                writeln!(out, "{role}-tz {:+05}", person.timezone)?;
+            }
+            writeln!(out, "summary {}", info.summary)?;
+            if info.boundary {
+                writeln!(out, "boundary")?;
+            }
+            let previous = match &entry.suspect.commit {
+                Some(commit) => blame.previous.get(commit).cloned(),
+                None => repo
+                    .head_commit()?
+                    .map(|head| (head, entry.suspect.path.clone())),
+            };
+            if let Some((commit, path)) = previous {
+                writeln!(out, "previous {} {}", commit.as_str(), path)?;
+            }
+            writeln!(out, "filename {}", entry.suspect.path)?;
+        }
+        out.write_all(b"\t")?;
+        write_line(out, &blame.lines[entry.final_line])?;
+    }
+    Ok(())
+}
+
+fn write_annotated(
+    out: &mut impl Write,
+    repo: &Repository,
+    path: &str,
+    blame: &Blame,
+    infos: &HashMap<&Suspect, CommitInfo>,
+    format: &BlameFormat,
+) -> anyhow::Result<()> {
+    let hash_length = if format.long {
+        repo.object_format()?.hex_len()
+    } else {
+        8
+    };
+    let show_name = format.show_name || blame.entries.iter().any(|e| e.suspect.path != path);
+    let person = |suspect: &Suspect| {
+        let author = &infos[suspect].author;
+        if format.show_email {
+            format!("<{}>", author.email)
+        } else {
+            author.name.to_string()
+        }
+    };
+    let name_width = blame
+        .entries
+        .iter()
+        .map(|e| person(&e.suspect).chars().count())
+        .max()
+        .unwrap_or(0);
+    let path_width = blame
+        .entries
+        .iter()
+        .map(|e| e.suspect.path.len())
+        .max()
+        .unwrap_or(0);
+    let number_width = blame
+        .entries
+        .last()
+        .map(|e| (e.final_line + 1).to_string().len())
+        .unwrap_or(1);
+    for entry in blame.entries.iter() {
+        let info = &infos[&entry.suspect];
+        let hash = suspect_hash(repo, &entry.suspect)?;
+        if info.boundary {
+            write!(out, "^{}", &hash[..hash_length - 1])?;
+        } else {
+            write!(out, "{}", &hash[..hash_length])?;
+        }
+        if show_name {
+            write!(out, " {:path_width$}", entry.suspect.path)?;
+        }
+        if !format.suppress {
+            let name = person(&entry.suspect);
+            let padding = name_width - name.chars().count();
+            write!(out, " ({name}{:padding$} {}", "", info.author.date_string())?;
+        }
+        write!(out, " {:>number_width$}) ", entry.final_line + 1)?;
+        write_line(out, &blame.lines[entry.final_line])?;
+    }
+    Ok(())
+}
+
+type Tracked = Vec<(usize, usize)>;
+
+impl Repository {
+    fn commit_time(&self, commit: &ObjectRef) -> anyhow::Result<u64> {
+        Ok(self.read_commit(commit)?.data().committer.timestamp)
+    }
+
+    /// Finds the path `path` of `commit` had in `parent`, following renames.
+    fn path_in_parent(
+        &self,
+        commit: Option<&ObjectRef>,
+        parent: &ObjectRef,
+        path: &str,
+    ) -> anyhow::Result<Option<(String, RawHash)>> {
+        let parent_tree = self.peel_to_tree(parent)?;
+        if let Some((mode, hash)) = self.find_tree_entry(&parent_tree, path)? {
+            return Ok((!diff::is_tree_mode(mode)).then(|| (path.to_owned(), hash)));
+        }
+        let Some(commit) = commit else {
+            return Ok(None);
+        };
+        // Like git, only the followed path is considered as a rename destination, so it
+        // can take a source that a full diff would pair with another file
+        let options = DiffOptions {
+            recursive: true,
+            ..DiffOptions::default()
+        };
+        let entries: Vec<_> = diff::diff_trees(
+            self,
+            Some(&parent_tree),
+            Some(&self.peel_to_tree(commit)?),
+            &options,
+        )?
+        .into_iter()
+        .filter(|e| match e.status {
+            DiffStatus::Deleted => true,
+            DiffStatus::Added => e.path() == path,
+            _ => false,
+        })
+        .collect();
+        let options = DiffOptions {
+            detection: diff::Detection::Renames,
+            ..options
+        };
+        Ok(diff::detect_renames(self, entries, &options)?
+            .into_iter()
+            .filter(|e| e.status == DiffStatus::Renamed && e.path() == path)
+            .find_map(|e| e.old.map(|old| (old.path, old.hash))))
+    }
+
+    /// Commits to look through when blaming: `revs`, those listed in `files` and in the
+    /// file `blame.ignoreRevsFile` names. The files list one revision per line, `#` starts
+    /// a comment.
+    pub fn blame_ignore_revs(
+        &self,
+        revs: &[String],
+        files: &[PathBuf],
+    ) -> anyhow::Result<HashSet<ObjectRef>> {
+        let mut names = revs.to_vec();
+        let mut files = files.to_vec();
+        if let Some(file) = self.config()?.get("blame.ignoreRevsFile") {
+            files.push(self.work_dir()?.join(file));
+        }
+        for file in files {
+            let contents = fs::read_to_string(&file)
+                .with_context(|| format!("could not open object name list: {}", file.display()))?;
+            names.extend(
+                contents
+                    .lines()
+                    .map(|line| line.split('#').next().unwrap_or_default().trim())
+                    .filter(|line| !line.is_empty())
+                    .map(str::to_owned),
+            );
+        }
+        let mut ignored = HashSet::new();
+        for name in names {
+            ignored.insert(self.peel(&self.resolve_revision(&name)?, "commit")?);
+        }
+        Ok(ignored)
+    }
+
+    /// Resolves `-L` arguments against the file to blame, see [`parse_line_range`].
+    pub fn blame_line_ranges(
+        &self,
+        path: &str,
+        start: Option<&ObjectRef>,
+        specs: &[String],
+    ) -> anyhow::Result<Vec<(usize, usize)>> {
+        if specs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let contents = self.blamed_contents(path, start)?;
+        let lines: Vec<Vec<u8>> = myers::split_lines(&contents)
+            .into_iter()
+            .map(<[u8]>::to_vec)
+            .collect();
+        specs
+            .iter()
+            .map(|spec| parse_line_range(spec, &lines))
+            .collect()
+    }
+
+    /// Details of the commit lines are blamed on. Root commits are boundaries unless
+    /// `show_root` is set, changes in the working tree are not committed yet.
+    pub fn blame_commit_info(
+        &self,
+        suspect: &Suspect,
+        show_root: bool,
+    ) -> anyhow::Result<CommitInfo> {
+        let owned = |person: PersonLine| PersonLine {
+            name: person.name.into_owned().into(),
+            email: person.email.into_owned().into(),
+            timestamp: person.timestamp,
+            timezone: person.timezone,
+        };
+        Ok(match &suspect.commit {
+            Some(commit) => {
+                let commit = self.read_commit(commit)?;
+                let data = commit.data();
+                CommitInfo {
+                    boundary: data.parent_hashes.is_empty() && !show_root,
+                    author: owned(data.author),
+                    committer: owned(data.committer),
+                    summary: commit.subject().to_owned(),
+                }
+            }
+            None => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .with_context(|| "Calculating current time")?
+                    .as_secs();
+                let person = || PersonLine {
+                    name: "Not Committed Yet".into(),
+                    email: "not.committed.yet".into(),
+                    timestamp: now,
+                    timezone: 0,
+                };
+                CommitInfo {
+                    author: person(),
+                    committer: person(),
+                    summary: format!("Version of {} from {}", suspect.path, suspect.path),
+                    boundary: false,
+                }
+            }
+        })
+    }
+
+    /// Contents of the file to blame, from `start` or the working tree.
+    pub fn blamed_contents(
+        &self,
+        path: &str,
+        start: Option<&ObjectRef>,
+    ) -> anyhow::Result<Vec<u8>> {
+        match start {
+            Some(commit) => {
+                let tree = self.peel_to_tree(commit)?;
+                let (_, hash) = self.find_tree_entry(&tree, path)?.ok_or_else(|| {
+                    anyhow::Error::msg(format!("no such path {path} in {}", commit.0))
+                })?;
+                Ok(self.read_blob(&ObjectRef::from_hash(&hash))?)
+            }
+            None => std::fs::read(self.work_dir()?.join(path))
+                .map_err(|_| anyhow::Error::msg(format!("no such path '{path}' in HEAD"))),
+        }
+    }
+
+    /// Blames `path` as of `start`, or as in the working tree if `start` is `None`.
+    pub fn blame(
+        &self,
+        path: &str,
+        start: Option<&ObjectRef>,
+        options: &BlameOptions,
+    ) -> anyhow::Result<Blame> {
+        let data = self.blamed_contents(path, start)?;
+        let lines: Vec<Vec<u8>> = myers::split_lines(&data)
+            .into_iter()
+            .map(<[u8]>::to_vec)
+            .collect();
+
+        let mut requested: Vec<usize> = if options.ranges.is_empty() {
+            (0..lines.len()).collect()
+        } else {
+            options
+                .ranges
+                .iter()
+                .flat_map(|(start, end)| *start..*end)
+                .collect()
+        };
+        requested.sort_unstable();
+        requested.dedup();
+
+        // Suspects are processed newest first, keyed by commit time
+        type Key = (u64, String, String);
+        let key = |time: u64, suspect: &Suspect| -> Key {
+            let hash = suspect
+                .commit
+                .as_ref()
+                .map(|c| c.0.clone())
+                .unwrap_or_default();
+            (time, hash, suspect.path.clone())
+        };
+        let mut queue: BTreeMap<Key, (Suspect, Vec<u8>, Tracked)> = BTreeMap::new();
+        let first = Suspect {
+            commit: start.cloned(),
+            path: path.to_owned(),
+        };
+        let time = match start {
+            Some(commit) => self.commit_time(commit)?,
+            None => u64::MAX,
+        };
+        queue.insert(
+            key(time, &first),
+            (first, data, requested.iter().map(|i| (*i, *i)).collect()),
+        );
+
+        let mut entries = Vec::new();
+        let mut previous = HashMap::new();
+        while let Some((_, (suspect, data, mut tracked))) = queue.pop_last() {
+            let parents = match &suspect.commit {
+                Some(commit) => self.commit_parents(commit)?,
+                None => self.head_commit()?.into_iter().collect(),
+            };
+            let ignored = suspect
+                .commit
+                .as_ref()
+                .is_some_and(|c| options.ignore_revs.contains(c));
+            let child_lines = myers::split_lines(&data);
+
+            for parent in parents.iter() {
+                if tracked.is_empty() {
+                    break;
+                }
+                let Some((parent_path, hash)) =
+                    self.path_in_parent(suspect.commit.as_ref(), parent, &suspect.path)?
+                else {
+                    continue;
+                };
+                if let Some(commit) = &suspect.commit {
+                    previous
+                        .entry(commit.clone())
+                        .or_insert_with(|| (parent.clone(), parent_path.clone()));
+                }
+                let parent_data = self.read_blob(&ObjectRef::from_hash(&hash))?;
+                let parent_lines = myers::split_lines(&parent_data);
+                let edits = myers::diff(&parent_lines, &child_lines);
+
+                let mut mapping: HashMap<usize, usize> = edits
+                    .iter()
+                    .filter_map(|e| match *e {
+                        Edit::Equal { old, new } => Some((new, old)),
+                        _ => None,
+                    })
+                    .collect();
+                if ignored {
+                    mapping.extend(map_changed_lines(&parent_lines, &child_lines, &edits));
+                }
+                if options.detect_moves {
+                    let unmatched: Vec<usize> = tracked
+                        .iter()
+                        .map(|(_, line)| *line)
+                        .filter(|line| !mapping.contains_key(line))
+                        .collect();
+                    mapping.extend(find_moved_lines(&parent_lines, &child_lines, &unmatched));
+                }
+
+                let (passed, remaining): (Tracked, Tracked) = tracked
+                    .into_iter()
+                    .partition(|(_, line)| mapping.contains_key(line));
+                tracked = remaining;
+                if passed.is_empty() {
+                    continue;
+                }
+                let parent_suspect = Suspect {
+                    commit: Some(parent.clone()),
+                    path: parent_path,
+                };
+                let parent_key = key(self.commit_time(parent)?, &parent_suspect);
+                let queued = queue
+                    .entry(parent_key)
+                    .or_insert_with(|| (parent_suspect, parent_data, Vec::new()));
+                queued.2.extend(
+                    passed
+                        .into_iter()
+                        .map(|(final_line, line)| (final_line, mapping[&line])),
+                );
+            }
+
+            entries.extend(
+                tracked
+                    .into_iter()
+                    .map(|(final_line, orig_line)| BlameEntry {
+                        suspect: suspect.clone(),
+                        orig_line,
+                        final_line,
+                    }),
+            );
+        }
+        entries.sort_by_key(|e| e.final_line);
+        Ok(Blame {
+            lines,
+            entries,
+            previous,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_match(pattern: &str, line: &str) -> bool {
+        LinePattern::parse(pattern)
+            .unwrap()
+            .is_match(line.as_bytes())
+    }
+
+    #[test]
+    fn literals_and_anchors() {
+        assert!(is_match("main", "fn main() {\n"));
+        assert!(!is_match("mian", "fn main() {\n"));
+        assert!(is_match("^fn", "fn main() {\n"));
+        assert!(!is_match("^main", "fn main() {\n"));
+        // The newline ending the line is not matched by `$`
+        assert!(is_match("{$", "fn main() {\n"));
+        assert!(!is_match("main$", "fn main() {\n"));
+        assert!(is_match("^$", "\n"));
+        assert!(is_match("", "anything"));
+        // Anchors elsewhere are ordinary characters
+        assert!(is_match("a^b", "a^b"));
+        assert!(is_match("a$b", "a$b"));
+    }
+
+    #[test]
+    fn any_and_repetitions() {
+        assert!(is_match("f.o", "fxo"));
+        assert!(!is_match("f.o", "fo"));
+        assert!(is_match("^ab*c$", "ac"));
+        assert!(is_match("^ab*c$", "abbbc"));
+        assert!(!is_match("^ab\\+c$", "ac"));
+        assert!(is_match("^ab\\+c$", "abbc"));
+        assert!(is_match("^ab\\?c$", "ac"));
+        assert!(is_match("^ab\\?c$", "abc"));
+        assert!(!is_match("^ab\\?c$", "abbc"));
+        assert!(is_match("^a.*z$", "abcz"));
+        // A leading star is a literal
+        assert!(is_match("^*a", "*a"));
+        assert!(!is_match("^*a", "a"));
+    }
+
+    #[test]
+    fn bracket_expressions() {
+        assert!(is_match("^[abc]*$", "abcabc"));
+        assert!(!is_match("^[abc]*$", "abcd"));
+        assert!(is_match("^[a-c]x", "bx"));
+        assert!(is_match("^[^a-c]x", "dx"));
+        assert!(!is_match("^[^a-c]x", "bx"));
+        assert!(is_match("^[]a]", "]"));
+        assert!(is_match("^[a-]", "-"));
+        assert!(is_match("^[[:digit:]]\\+$", "2024"));
+        assert!(!is_match("^[[:digit:]]\\+$", "20x4"));
+        assert!(is_match("[[:space:]]", "a\tb"));
+        assert!(LinePattern::parse("[abc").is_err());
+        assert!(LinePattern::parse("[[:nothing:]]").is_err());
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(is_match("a\\.b", "a.b"));
+        assert!(!is_match("a\\.b", "axb"));
+        assert!(is_match("\\*", "*"));
+        assert!(is_match("\\[x]", "[x]"));
+        assert!(is_match("\\\\", "\\"));
+        // Groups, alternation, intervals and word boundaries are not supported
+        for pattern in ["\\(a\\)", "a\\|b", "a\\{2\\}", "\\<a", "\\w", "a\\"] {
+            assert!(LinePattern::parse(pattern).is_err(), "{pattern}");
+        }
+    }
+
+    #[test]
+    fn matching_takes_linear_time() {
+        let line = "a".repeat(10000);
+        assert!(!is_match("a*a*a*a*a*a*a*a*b", &line));
+        assert!(is_match("^a*a*a*a*a*a*a*a*$", &line));
+    }
+
+    #[test]
+    fn line_ranges() {
+        let lines: Vec<Vec<u8>> = ["fn a() {\n", "}\n", "fn b() {\n", "    x\n", "}\n"]
+            .iter()
+            .map(|line| line.as_bytes().to_vec())
+            .collect();
+        assert_eq!(parse_line_range("2,4", &lines).unwrap(), (1, 4));
+        assert_eq!(parse_line_range("4,2", &lines).unwrap(), (1, 4));
+        assert_eq!(parse_line_range("3,+2", &lines).unwrap(), (2, 4));
+        assert_eq!(parse_line_range("3,-2", &lines).unwrap(), (1, 3));
+        assert_eq!(parse_line_range("/^fn b/,/^}/", &lines).unwrap(), (2, 5));
+        assert_eq!(parse_line_range("/^fn/", &lines).unwrap(), (0, 5));
+        assert!(parse_line_range("/^fn c/", &lines).is_err());
+        assert!(parse_line_range("9", &lines).is_err());
+    }
+}
@@ -0,0 +1,229 @@
+//! Branch management on top of references.
+
+use crate::{refs, ObjectRef, Repository};
+
+/// Where a branch integrates from, as stored in `branch.<name>.remote` and `.merge`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upstream {
+    /// Remote name, `.` for local branches
+    pub remote: String,
+    /// Full name of the branch on the remote, like `refs/heads/main`
+    pub merge: String,
+}
+
+impl Upstream {
+    /// The local reference tracking this upstream
+    pub fn tracking_ref(&self) -> String {
+        match (self.remote.as_str(), self.merge.strip_prefix("refs/heads/")) {
+            (".", _) => self.merge.clone(),
+            (remote, Some(branch)) => format!("refs/remotes/{remote}/{branch}"),
+            (remote, None) => format!("refs/remotes/{remote}/{}", self.merge),
+        }
+    }
+
+    /// Short name as shown to users, like `origin/main`
+    pub fn short_name(&self) -> String {
+        let tracking = self.tracking_ref();
+        tracking
+            .strip_prefix("refs/heads/")
+            .or_else(|| tracking.strip_prefix("refs/remotes/"))
+            .unwrap_or(&tracking)
+            .to_owned()
+    }
+}
+
+impl Repository {
+    pub fn branch_ref(name: &str) -> String {
+        format!("refs/heads/{name}")
+    }
+
+    /// Name of the branch HEAD is on, `None` when detached.
+    pub fn current_branch(&self) -> anyhow::Result<Option<String>> {
+        Ok(self.head()?.branch_name().map(str::to_owned))
+    }
+
+    pub fn upstream(&self, branch: &str) -> anyhow::Result<Option<Upstream>> {
+        let config = self.config()?;
+        Ok(
+            match (
+                config.get(&format!("branch.{branch}.remote")),
+                config.get(&format!("branch.{branch}.merge")),
+            ) {
+                (Some(remote), Some(merge)) => Some(Upstream {
+                    remote: remote.to_owned(),
+                    merge: merge.to_owned(),
+                }),
+                _ => None,
+            },
+        )
+    }
+
+    /// Figures out the upstream configuration for a name like `origin/main` or `main`.
+    pub fn resolve_upstream(&self, name: &str) -> anyhow::Result<Upstream> {
+        if self.ref_exists(&format!("refs/remotes/{name}"))? {
+            // Remote names may contain slashes, prefer configured remotes
+            let config = self.config()?;
+            let remote = name
+                .match_indices('/')
+                .map(|(i, _)| &name[..i])
+                .find(|remote| config.get(&format!("remote.{remote}.url")).is_some())
+                .or_else(|| name.split_once('/').map(|(remote, _)| remote))
+                .ok_or_else(|| anyhow::Error::msg(format!("Invalid upstream '{name}'")))?;
+            return Ok(Upstream {
+                remote: remote.to_owned(),
+                merge: format!("refs/heads/{}", &name[remote.len() + 1..]),
+            });
+        }
+        let local = name.strip_prefix("refs/heads/").unwrap_or(name);
+        if self.ref_exists(&Self::branch_ref(local))? {
+            return Ok(Upstream {
+                remote: ".".to_owned(),
+                merge: Self::branch_ref(local),
+            });
+        }
+        anyhow::bail!("the requested upstream branch '{name}' does not exist")
+    }
+
+    pub fn set_upstream(&self, branch: &str, upstream: &Upstream) -> anyhow::Result<()> {
+        self.set_config(&format!("branch.{branch}.remote"), &upstream.remote)?;
+        self.set_config(&format!("branch.{branch}.merge"), &upstream.merge)
+    }
+
+    pub fn unset_upstream(&self, branch: &str) -> anyhow::Result<bool> {
+        let remote = self.unset_config(&format!("branch.{branch}.remote"))?;
+        let merge = self.unset_config(&format!("branch.{branch}.merge"))?;
+        Ok(remote || merge)
+    }
+
+    /// Creates a branch at `start`, replacing an existing one only if `force` is set.
+    /// `start_name` is how the start was given, for the reflog.
+    pub fn create_branch(
+        &self,
+        name: &str,
+        start: &ObjectRef,
+        start_name: &str,
+        force: bool,
+    ) -> anyhow::Result<()> {
+        refs::check_branch_name(name)?;
+        let ref_name = Self::branch_ref(name);
+        let exists = self.ref_exists(&ref_name)?;
+        if exists {
+            anyhow::ensure!(force, "a branch named '{}' already exists", name);
+            anyhow::ensure!(
+                self.current_branch()?.as_deref() != Some(name),
+                "cannot force update the current branch"
+            );
+            if let Some(worktree) = self.worktree_with_branch(&ref_name)? {
+                anyhow::bail!(
+                    "cannot force update the branch '{}' used by worktree at '{}'",
+                    name,
+                    worktree.path.display()
+                );
+            }
+        }
+        let commit = self.peel(start, "commit")?;
+        let message = match exists {
+            true => format!("branch: Reset to {start_name}"),
+            false => format!("branch: Created from {start_name}"),
+        };
+        self.update_ref(&ref_name, &commit, &message)
+    }
+
+    /// Renames a branch with its reflog and configuration, following the HEAD of every
+    /// worktree that is on it.
+    pub fn rename_branch(&self, old: &str, new: &str, force: bool) -> anyhow::Result<()> {
+        refs::check_branch_name(new)?;
+        let old_ref = Self::branch_ref(old);
+        let new_ref = Self::branch_ref(new);
+        let is_current = self.current_branch()?.as_deref() == Some(old);
+        let old_head = refs::Head::Branch(old_ref.clone());
+        let others: Vec<_> = self
+            .worktrees()?
+            .into_iter()
+            .filter(|worktree| {
+                worktree.git_dir != self.path && worktree.head.as_ref() == Some(&old_head)
+            })
+            .collect();
+        // Renaming an unborn current branch only needs to move HEAD
+        if !self.ref_exists(&old_ref)? && !is_current {
+            anyhow::bail!("no branch named '{old}'");
+        }
+        if old != new && self.ref_exists(&new_ref)? {
+            anyhow::ensure!(force, "a branch named '{}' already exists", new);
+            // The branch may be checked out here, in a bare repository, or in another worktree
+            let checked_out_at = match self.worktree_with_branch(&new_ref)? {
+                Some(worktree) => Some(worktree.path),
+                None if self.current_branch()?.as_deref() == Some(new) => {
+                    Some(self.work_dir().unwrap_or(self.git_dir()).to_owned())
+                }
+                None => None,
+            };
+            if let Some(path) = checked_out_at {
+                anyhow::bail!(
+                    "cannot force update the branch '{}' checked out at '{}'",
+                    new,
+                    path.display()
+                );
+            }
+            self.delete_ref(&new_ref)?;
+        }
+        let message = format!("Branch: renamed {old_ref} to {new_ref}");
+        if self.ref_exists(&old_ref)? {
+            self.rename_ref(&old_ref, &new_ref, &message)?;
+        }
+        if is_current {
+            self.set_head(&refs::Head::Branch(new_ref.clone()), &message)?;
+        }
+        for worktree in others {
+            worktree
+                .repository()
+                .set_head(&refs::Head::Branch(new_ref.clone()), &message)?;
+        }
+        if old != new {
+            self.rename_config_section(&format!("branch.{new}"), None)?;
+            self.rename_config_section(&format!("branch.{old}"), Some(&format!("branch.{new}")))?;
+        }
+        Ok(())
+    }
+
+    /// Deletes a branch. Unless `force` is set, the branch has to be merged into its
+    /// upstream or, if it has none, into HEAD. Returns the commit the branch pointed to.
+    pub fn delete_branch(&self, name: &str, force: bool) -> anyhow::Result<ObjectRef> {
+        let ref_name = Self::branch_ref(name);
+        let tip = self
+            .read_ref(&ref_name)?
+            .ok_or_else(|| anyhow::Error::msg(format!("branch '{name}' not found.")))?;
+        anyhow::ensure!(
+            self.current_branch()?.as_deref() != Some(name),
+            "Cannot delete branch '{}' checked out at '{}'",
+            name,
+            self.work_dir().unwrap_or(self.git_dir()).display()
+        );
+        if let Some(worktree) = self.worktree_with_branch(&ref_name)? {
+            anyhow::bail!(
+                "Cannot delete branch '{}' checked out at '{}'",
+                name,
+                worktree.path.display()
+            );
+        }
+        if !force {
+            let upstream_tip = match self.upstream(name)? {
+                Some(upstream) => self.read_ref(&upstream.tracking_ref())?,
+                None => None,
+            };
+            let merged = match upstream_tip.or(self.head_commit()?) {
+                Some(target) => self.is_ancestor(&tip, &target)?,
+                None => false,
+            };
+            anyhow::ensure!(
+                merged,
+                "The branch '{}' is not fully merged.\nIf you are sure you want to delete it, run 'git branch -D {}'.",
+                name,
+                name
+            );
+        }
+        self.delete_ref(&ref_name)?;
+        self.rename_config_section(&format!("branch.{name}"), None)?;
+        Ok(tip)
+    }
+}
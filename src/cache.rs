@@ -0,0 +1,63 @@
+//! In-process caches of the object database, so repeated lookups skip inflating and
+//! re-reading pack indexes.
+
+use crate::{pack::Pack, ObjectRef};
+use std::{
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+    time::SystemTime,
+};
+
+/// Objects larger than this are not worth keeping around
+const MAX_CACHED_OBJECT: usize = 1024 * 1024;
+const CACHE_CAPACITY: usize = 16 * 1024 * 1024;
+
+/// Least recently used cache of inflated objects, bounded by their total size.
+#[derive(Debug, Default)]
+pub struct ObjectCache {
+    entries: HashMap<ObjectRef, (u64, String, Vec<u8>)>,
+    /// Entries by the time they were last used, oldest first
+    order: BTreeMap<u64, ObjectRef>,
+    clock: u64,
+    size: usize,
+}
+
+impl ObjectCache {
+    pub fn get(&mut self, object_ref: &ObjectRef) -> Option<(String, Vec<u8>)> {
+        self.clock += 1;
+        let (used, kind, data) = self.entries.get_mut(object_ref)?;
+        self.order.remove(used);
+        self.order.insert(self.clock, object_ref.clone());
+        *used = self.clock;
+        Some((kind.clone(), data.clone()))
+    }
+
+    pub fn insert(&mut self, object_ref: &ObjectRef, kind: &str, data: &[u8]) {
+        if data.len() > MAX_CACHED_OBJECT || self.entries.contains_key(object_ref) {
+            return;
+        }
+        while self.size + data.len() > CACHE_CAPACITY {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((_, _, evicted)) = self.entries.remove(&oldest) {
+                self.size -= evicted.len();
+            }
+        }
+        self.clock += 1;
+        self.size += data.len();
+        self.order.insert(self.clock, object_ref.clone());
+        self.entries.insert(
+            object_ref.clone(),
+            (self.clock, kind.to_owned(), data.to_vec()),
+        );
+    }
+}
+
+/// The opened packs with their parsed indexes, valid as long as the pack directory is
+/// unchanged.
+#[derive(Debug, Default)]
+pub struct PackCache {
+    pub modified: Option<SystemTime>,
+    pub packs: Option<Rc<[Pack]>>,
+}
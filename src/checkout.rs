@@ -0,0 +1,417 @@
+//! Materializing trees into the working directory and the index.
+
+use crate::{
+    diff::{self, is_gitlink_mode},
+    hash::RawHash,
+    index::{Index, IndexEntry},
+    pathspec::Pathspec,
+    refs::Head,
+    Error, ObjectRef, Repository, TreeData,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsStr,
+    fs,
+    io::Write,
+    os::unix::{ffi::OsStrExt, fs::OpenOptionsExt},
+    path::Path,
+};
+
+/// All non-tree entries of a tree by their full path
+pub type Snapshot = BTreeMap<String, (u32, RawHash)>;
+
+pub fn tree_snapshot(repo: &Repository, tree: Option<&TreeData>) -> anyhow::Result<Snapshot> {
+    let mut sides = Vec::new();
+    if let Some(tree) = tree {
+        diff::flatten_tree(repo, tree, "", &mut sides)?;
+    }
+    Ok(sides
+        .into_iter()
+        .map(|side| (side.path, (side.mode, side.hash)))
+        .collect())
+}
+
+/// Builds an index matching a tree, without any stat information.
+pub fn index_from_snapshot(snapshot: &Snapshot) -> Index {
+    let mut index = Index::default();
+    for (path, (mode, hash)) in snapshot.iter() {
+        index.add(IndexEntry::new(path, *mode, *hash, 0));
+    }
+    index
+}
+
+/// Writes a blob into the working tree at `path` (relative to the working directory),
+/// replacing whatever was there, and returns an index entry describing it.
+pub fn write_worktree_entry(
+    repo: &Repository,
+    path: &str,
+    mode: u32,
+    hash: &RawHash,
+) -> anyhow::Result<IndexEntry> {
+    let full_path = repo.work_dir()?.join(path);
+    if let Some(parent) = full_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Remove the old file first, so that we never write through a symbolic link and the
+    // new file gets created with the right permissions
+    match fs::symlink_metadata(&full_path) {
+        Ok(metadata) if metadata.is_dir() => {
+            if is_gitlink_mode(mode) {
+                return Ok(IndexEntry::new(path, mode, *hash, 0));
+            }
+            fs::remove_dir(&full_path).map_err(|_| {
+                anyhow::Error::msg(format!(
+                    "Updating '{path}' would lose untracked files in it"
+                ))
+            })?;
+        }
+        Ok(_) => fs::remove_file(&full_path)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    match mode {
+        0o160000 => {
+            // Submodules are not checked out, git leaves an empty directory in their place
+            fs::create_dir_all(&full_path)?;
+            return Ok(IndexEntry::new(path, mode, *hash, 0));
+        }
+        0o120000 => {
+            let target = repo.read_blob(&ObjectRef::from_hash(hash))?;
+            std::os::unix::fs::symlink(OsStr::from_bytes(&target), &full_path)?;
+        }
+        _ => {
+            let data = repo.read_blob(&ObjectRef::from_hash(hash))?;
+            let permissions = if mode & 0o111 != 0 { 0o777 } else { 0o666 };
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(permissions)
+                .open(&full_path)?;
+            file.write_all(&data)?;
+        }
+    }
+    let metadata = fs::symlink_metadata(&full_path)?;
+    Ok(IndexEntry::from_metadata(path, mode, *hash, &metadata))
+}
+
+/// Removes a file from the working tree along with any directories left empty.
+pub fn remove_worktree_entry(repo: &Repository, path: &str) -> anyhow::Result<()> {
+    let work_dir = repo.work_dir()?;
+    let full_path = work_dir.join(path);
+    match fs::symlink_metadata(&full_path) {
+        // Submodule directories are only removed when empty
+        Ok(metadata) if metadata.is_dir() => {
+            let _ = fs::remove_dir(&full_path);
+        }
+        Ok(_) => fs::remove_file(&full_path)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let mut dir = full_path.parent();
+    while let Some(current) = dir {
+        if current == work_dir || fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+    Ok(())
+}
+
+/// Checks whether the working tree has a file or symlink at `path` or at any of its parents.
+fn worktree_blocker(work_dir: &Path, path: &str) -> Option<String> {
+    let mut prefix = String::new();
+    for component in path.split('/') {
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        prefix.push_str(component);
+        match fs::symlink_metadata(work_dir.join(&prefix)) {
+            Ok(metadata) if !metadata.is_dir() => return Some(prefix),
+            Ok(_) => continue,
+            Err(_) => return None,
+        }
+    }
+    None
+}
+
+#[derive(Debug, Default)]
+struct Conflicts {
+    modified: Vec<String>,
+    untracked: Vec<String>,
+}
+
+impl Conflicts {
+    fn into_result(self, operation: &str) -> anyhow::Result<()> {
+        let action = match operation {
+            "merge" => "merge",
+            _ => "switch branches",
+        };
+        let mut message = String::new();
+        if !self.modified.is_empty() {
+            message.push_str(&format!(
+                "Your local changes to the following files would be overwritten by {operation}:\n"
+            ));
+            for path in self.modified.iter() {
+                message.push_str(&format!("\t{path}\n"));
+            }
+            message.push_str(&format!(
+                "Please commit your changes or stash them before you {action}.\n"
+            ));
+        }
+        if !self.untracked.is_empty() {
+            message.push_str(&format!(
+                "The following untracked working tree files would be overwritten by {operation}:\n"
+            ));
+            for path in self.untracked.iter() {
+                message.push_str(&format!("\t{path}\n"));
+            }
+            message.push_str(&format!(
+                "Please move or remove them before you {action}.\n"
+            ));
+        }
+        if message.is_empty() {
+            Ok(())
+        } else {
+            message.push_str("Aborting");
+            Err(Error::Refused(message).into())
+        }
+    }
+}
+
+impl Repository {
+    /// Switches the working tree and index from the `old` tree to the `new` one.
+    ///
+    /// Files that are the same in both trees are left alone, keeping any local changes to
+    /// them. Unless `force` is set, the operation is refused when it would overwrite
+    /// local modifications or untracked files, in which case nothing is touched.
+    pub fn checkout_tree(
+        &self,
+        old: Option<&TreeData>,
+        new: &TreeData,
+        force: bool,
+    ) -> anyhow::Result<()> {
+        self.update_worktree(old, new, force, "checkout")
+    }
+
+    /// Like [`Repository::checkout_tree`], `operation` names the command in error messages.
+    pub fn update_worktree(
+        &self,
+        old: Option<&TreeData>,
+        new: &TreeData,
+        force: bool,
+        operation: &str,
+    ) -> anyhow::Result<()> {
+        let old = tree_snapshot(self, old)?;
+        let new = tree_snapshot(self, Some(new))?;
+        // Without an index the working tree is assumed to match the old tree
+        let mut index = match self.read_index()? {
+            Some(index) => index,
+            None => index_from_snapshot(&old),
+        };
+        let work_dir = self.work_dir()?;
+
+        let indexed = |index: &Index, path: &str| index.get(path, 0).map(|e| (e.mode, e.hash));
+        let mut paths: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+        if force {
+            paths.extend(index.entries().iter().map(|e| &e.path));
+        }
+
+        let mut to_remove = Vec::new();
+        let mut to_write = Vec::new();
+        let mut conflicts = Conflicts::default();
+        for path in paths {
+            let old_entry = old.get(path).copied();
+            let new_entry = new.get(path).copied();
+            let index_entry = indexed(&index, path);
+
+            if force {
+                match new_entry {
+                    None => to_remove.push(path.clone()),
+                    Some(entry) => {
+                        let clean = match index.get(path, 0) {
+                            Some(e) => {
+                                index_entry == Some(entry) && !self.is_worktree_modified(e)?
+                            }
+                            None => false,
+                        };
+                        if !clean || index.is_conflicted(path) {
+                            to_write.push((path.clone(), entry));
+                        }
+                    }
+                }
+                continue;
+            }
+            if old_entry == new_entry {
+                continue;
+            }
+
+            match index.get(path, 0) {
+                _ if index.is_conflicted(path) => conflicts.modified.push(path.clone()),
+                Some(entry) => {
+                    let staged_ok = index_entry == old_entry || index_entry == new_entry;
+                    if !staged_ok || self.is_worktree_modified(entry)? {
+                        conflicts.modified.push(path.clone());
+                        continue;
+                    }
+                }
+                None if old_entry.is_some() => {
+                    // Deleted from the index, but still present in the old tree
+                    if new_entry.is_some() {
+                        conflicts.modified.push(path.clone());
+                        continue;
+                    }
+                }
+                None => {
+                    if let (Some((mode, hash)), Some(blocker)) =
+                        (new_entry, worktree_blocker(work_dir, path))
+                    {
+                        let same = blocker == *path
+                            && self.hash_worktree_file(&work_dir.join(path), mode).ok()
+                                == Some(hash);
+                        let tracked = old.contains_key(&blocker) && !new.contains_key(&blocker);
+                        if !same && !tracked {
+                            conflicts.untracked.push(path.clone());
+                            continue;
+                        }
+                    }
+                }
+            }
+            match new_entry {
+                Some(entry) => to_write.push((path.clone(), entry)),
+                None => to_remove.push(path.clone()),
+            }
+        }
+        conflicts.into_result(operation)?;
+
+        // Deepest paths first, so that directories get emptied before we prune them
+        for path in to_remove.iter().rev() {
+            remove_worktree_entry(self, path)?;
+            index.remove_path(path);
+        }
+        for (path, (mode, hash)) in to_write {
+            let entry = write_worktree_entry(self, &path, mode, &hash)?;
+            index.add(entry);
+        }
+        self.write_index(&index)
+    }
+
+    /// Checks out `commit` and points `HEAD` at `new_head`, with `message` in the reflog.
+    pub fn checkout_commit(
+        &self,
+        commit: &ObjectRef,
+        new_head: &Head,
+        force: bool,
+        message: &str,
+    ) -> anyhow::Result<()> {
+        let new_tree = self.peel_to_tree(commit)?;
+        let old_tree = match self.head_commit()? {
+            Some(head) => Some(self.peel_to_tree(&head)?),
+            None => None,
+        };
+        self.checkout_tree(old_tree.as_ref(), &new_tree, force)?;
+        self.set_head(new_head, message)
+    }
+
+    /// Restores files matching `pathspec` in the index and/or working tree.
+    ///
+    /// Files are taken from `source` if given, otherwise the working tree is restored from
+    /// the index (optionally from stage 2 or 3 of conflicted files). Tracked files missing
+    /// in the source are removed. HEAD is never touched.
+    pub fn restore(
+        &self,
+        pathspec: &Pathspec,
+        source: Option<&TreeData>,
+        options: &RestoreOptions,
+    ) -> anyhow::Result<()> {
+        let head_tree = match self.head_commit()? {
+            Some(head) => Some(self.peel_to_tree(&head)?),
+            None => None,
+        };
+        let mut index = match self.read_index()? {
+            Some(index) => index,
+            None => index_from_snapshot(&tree_snapshot(self, head_tree.as_ref())?),
+        };
+        let source = match source {
+            Some(tree) => Some(tree_snapshot(self, Some(tree))?),
+            None => None,
+        };
+
+        let mut candidates: BTreeSet<String> = index
+            .entries()
+            .iter()
+            .map(|e| e.path.clone())
+            .filter(|path| pathspec.matches(path))
+            .collect();
+        if let Some(source) = &source {
+            candidates.extend(source.keys().filter(|p| pathspec.matches(p)).cloned());
+        }
+        let candidate_refs: Vec<&str> = candidates.iter().map(String::as_str).collect();
+        if let Some(unmatched) = pathspec.unmatched(&candidate_refs).first() {
+            anyhow::bail!("pathspec '{unmatched}' did not match any file(s) known to git");
+        }
+
+        let mut targets = Vec::with_capacity(candidates.len());
+        for path in candidates.iter() {
+            let target = match &source {
+                Some(source) => source.get(path).copied(),
+                None if index.is_conflicted(path) => {
+                    let stage = match options.conflict_side {
+                        Some(stage) => stage,
+                        None => anyhow::bail!("path '{path}' is unmerged"),
+                    };
+                    let entry = index.get(path, stage).ok_or_else(|| {
+                        anyhow::Error::msg(format!("path '{path}' does not have that version"))
+                    })?;
+                    Some((entry.mode, entry.hash))
+                }
+                None => index.get(path, 0).map(|e| (e.mode, e.hash)),
+            };
+            targets.push((path, target));
+        }
+
+        if options.staged {
+            for (path, target) in targets.iter() {
+                match *target {
+                    Some((mode, hash)) => {
+                        let unchanged = index
+                            .get(path, 0)
+                            .is_some_and(|e| e.mode == mode && e.hash == hash);
+                        if !unchanged {
+                            index.add(IndexEntry::new(path, mode, hash, 0));
+                        }
+                    }
+                    None => index.remove_path(path),
+                }
+            }
+        }
+        if options.worktree {
+            // Removals go first (deepest paths first), a file may be replaced by a directory
+            for (path, _) in targets.iter().rev().filter(|(_, t)| t.is_none()) {
+                remove_worktree_entry(self, path)?;
+            }
+            for (path, target) in targets.iter() {
+                let Some((mode, hash)) = *target else {
+                    continue;
+                };
+                let entry = write_worktree_entry(self, path, mode, &hash)?;
+                // Refresh cached stat information if the index now matches the file
+                let index_matches = index
+                    .get(path, 0)
+                    .is_some_and(|e| e.mode == mode && e.hash == hash);
+                if index_matches {
+                    index.add(entry);
+                }
+            }
+        }
+        self.write_index(&index)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RestoreOptions {
+    pub staged: bool,
+    pub worktree: bool,
+    /// Stage to restore conflicted paths from, 2 for ours and 3 for theirs
+    pub conflict_side: Option<u8>,
+}
@@ -0,0 +1,165 @@
+//! Cloning repositories on the local file system.
+
+use crate::{branch::Upstream, init::InitOptions, refs::Head, Repository};
+use anyhow::Context;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct CloneOptions {
+    /// Borrow the objects of the source through an alternate instead of copying them
+    pub shared: bool,
+    /// Repositories whose objects are borrowed, so only objects missing in them are copied
+    pub reference: Vec<PathBuf>,
+}
+
+/// Opens a repository to clone from or to borrow objects from.
+fn open_source(path: &Path) -> anyhow::Result<Repository> {
+    Repository::open(path)
+        .map_err(|_| anyhow::Error::msg(format!("repository '{}' does not exist", path.display())))
+}
+
+/// Hard links `from` to `to`, copying it if linking is not possible.
+fn link_or_copy(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to).with_context(|| format!("failed to copy file to '{}'", to.display()))?;
+    }
+    Ok(())
+}
+
+/// Copies the files of the objects directory `from` that `into` does not have yet, either
+/// itself or in one of its `alternates`. The alternates of `from` are left out, those of the
+/// clone are written separately.
+fn copy_objects(from: &Path, into: &Repository, alternates: &[PathBuf]) -> anyhow::Result<()> {
+    let database = into.object_database()?;
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        for entry in fs::read_dir(from.join(&relative))? {
+            let entry = entry?;
+            let relative = relative.join(entry.file_name());
+            let target = into.git_dir().join("objects").join(&relative);
+            if entry.file_type()?.is_dir() {
+                fs::create_dir_all(&target)?;
+                pending.push(relative);
+                continue;
+            }
+            if relative == Path::new("info").join("alternates") || target.exists() {
+                continue;
+            }
+            // Packs are named after their contents, so one with the same name is the same
+            if relative.starts_with("pack")
+                && alternates.iter().any(|dir| dir.join(&relative).exists())
+            {
+                continue;
+            }
+            // Loose objects found through a reference are not copied
+            let name = relative.to_str().map(|name| name.replace('/', ""));
+            if let Some(object_ref) = name.and_then(|name| crate::ObjectRef::from_hex(&name).ok()) {
+                if database.exists(&object_ref)? {
+                    continue;
+                }
+            }
+            link_or_copy(&entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Clones the repository at `source` into the new directory `dest`, checking out the branch
+/// `HEAD` of the source is on. Branches of the source become remote-tracking branches of
+/// `origin`, and tags are copied.
+pub fn clone_local(
+    source: &Path,
+    dest: &Path,
+    options: &CloneOptions,
+) -> anyhow::Result<Repository> {
+    let source_repo = open_source(source)?;
+    let source_dir = source_repo.common_dir().to_path_buf();
+    if dest.exists() {
+        anyhow::ensure!(
+            fs::read_dir(dest)?.next().is_none(),
+            "destination path '{}' already exists and is not an empty directory.",
+            dest.display()
+        );
+    }
+    fs::create_dir_all(dest)?;
+    let repo = Repository::new(fs::canonicalize(dest)?.join(".git"));
+    repo.init(&InitOptions {
+        format: Some(source_repo.object_format()?),
+        ..InitOptions::default()
+    })?;
+
+    let mut alternates = Vec::new();
+    for reference in &options.reference {
+        alternates.push(open_source(reference)?.common_dir().join("objects"));
+    }
+    if options.shared {
+        alternates.push(source_dir.join("objects"));
+    }
+    // Objects the source borrows have to be found by the clone as well
+    alternates.extend(source_repo.alternate_object_dirs()?);
+    if !alternates.is_empty() {
+        let mut contents = String::new();
+        for dir in &alternates {
+            let dir = dir
+                .to_str()
+                .context("Alternate object directory is not UTF-8")?;
+            contents.push_str(dir);
+            contents.push('\n');
+        }
+        let info_dir = repo.git_dir().join("objects").join("info");
+        fs::create_dir_all(&info_dir)?;
+        fs::write(info_dir.join("alternates"), contents)?;
+    }
+    if !options.shared {
+        copy_objects(&source_dir.join("objects"), &repo, &alternates)?;
+    }
+    // Opened again, so the copied packs are seen
+    let repo = Repository::new(repo.git_dir().to_path_buf());
+
+    let url = fs::canonicalize(source)?;
+    let url = url.to_str().context("Repository path is not UTF-8")?;
+    let message = format!("clone: from {url}");
+    repo.set_config("remote.origin.url", url)?;
+    repo.set_config("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*")?;
+    for (name, target) in source_repo.list_refs("refs/heads/")? {
+        let branch = &name["refs/heads/".len()..];
+        repo.update_ref(&format!("refs/remotes/origin/{branch}"), &target, &message)?;
+    }
+    for (name, target) in source_repo.list_refs("refs/tags/")? {
+        repo.update_ref(&name, &target, &message)?;
+    }
+
+    match source_repo.head()? {
+        Head::Branch(name) => {
+            let branch = name.strip_prefix("refs/heads/").unwrap_or(&name);
+            match source_repo.read_ref(&name)? {
+                Some(commit) => {
+                    let remote_head = format!("ref: refs/remotes/origin/{branch}\n");
+                    let path = repo.git_dir().join("refs/remotes/origin/HEAD");
+                    repo.write_file_atomically(&path, remote_head.as_bytes())?;
+                    repo.set_upstream(
+                        branch,
+                        &Upstream {
+                            remote: "origin".to_owned(),
+                            merge: name.clone(),
+                        },
+                    )?;
+                    // HEAD is still unborn, so the whole tree is checked out
+                    repo.checkout_commit(&commit, &Head::Branch(name.clone()), true, &message)?;
+                    repo.update_ref(&name, &commit, &message)?;
+                }
+                None => {
+                    repo.warn("warning: You appear to have cloned an empty repository.".to_owned());
+                    repo.set_head(&Head::Branch(name), &message)?;
+                }
+            }
+        }
+        Head::Detached(commit) => {
+            repo.checkout_commit(&commit, &Head::Detached(commit.clone()), true, &message)?
+        }
+    }
+    Ok(repo)
+}
@@ -0,0 +1,448 @@
+//! Reading of git configuration files.
+
+use crate::Repository;
+use anyhow::Context;
+use std::{fs, path::Path};
+
+#[derive(Debug, Clone)]
+struct ConfigEntry {
+    /// Section name, always lowercase
+    section: String,
+    /// Subsection name, case sensitive
+    subsection: Option<String>,
+    /// Variable name, always lowercase
+    key: String,
+    /// `None` for variables without `=`, which means boolean true
+    value: Option<String>,
+}
+
+impl ConfigEntry {
+    fn matches(&self, section: &str, subsection: Option<&str>, key: &str) -> bool {
+        self.section.eq_ignore_ascii_case(section)
+            && self.subsection.as_deref() == subsection
+            && self.key.eq_ignore_ascii_case(key)
+    }
+}
+
+/// Parses a boolean the way git does for config values and environment variables.
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" | "" => Some(false),
+        _ => None,
+    }
+}
+
+/// Splits `section.subsection.key` into its parts. The subsection may contain dots.
+fn split_name(name: &str) -> anyhow::Result<(&str, Option<&str>, &str)> {
+    let (section, rest) = name
+        .split_once('.')
+        .ok_or_else(|| anyhow::Error::msg(format!("Key does not contain a section: {name}")))?;
+    Ok(match rest.rsplit_once('.') {
+        Some((subsection, key)) => (section, Some(subsection), key),
+        None => (section, None, rest),
+    })
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    entries: Vec<ConfigEntry>,
+}
+
+impl Config {
+    /// Loads the global configuration followed by the one in the repository at `git_dir`,
+    /// so that repository settings take precedence.
+    pub fn load(git_dir: &Path) -> anyhow::Result<Config> {
+        let mut config = Config::default();
+        if let Some(home) = std::env::var_os("HOME") {
+            config.merge_file(&Path::new(&home).join(".gitconfig"))?;
+        }
+        config.merge_file(&git_dir.join("config"))?;
+        Ok(config)
+    }
+
+    /// Appends entries from given file, missing files are silently ignored.
+    fn merge_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        match fs::read_to_string(path) {
+            Ok(text) => {
+                let parsed =
+                    Config::parse(&text).with_context(|| format!("Trying to parse {path:?}"))?;
+                self.entries.extend(parsed.entries);
+                Ok(())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Config> {
+        let mut entries = Vec::new();
+        let mut section: Option<(String, Option<String>)> = None;
+
+        for (line_no, line) in text.lines().enumerate() {
+            match parse_line(line, line_no)? {
+                Line::Blank => {}
+                Line::Section(name, subsection) => section = Some((name, subsection)),
+                Line::Variable(key, value) => {
+                    let (section, subsection) = section.clone().ok_or_else(|| {
+                        anyhow::Error::msg(format!(
+                            "Variable outside of section at line {}",
+                            line_no + 1
+                        ))
+                    })?;
+                    entries.push(ConfigEntry {
+                        section,
+                        subsection,
+                        key,
+                        value,
+                    });
+                }
+            }
+        }
+        Ok(Config { entries })
+    }
+
+    /// Returns the last value set for `section[.subsection].key`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        let (section, subsection, key) = split_name(name).ok()?;
+        self.entries
+            .iter()
+            .rev()
+            .find(|e| e.matches(section, subsection, key))
+            .map(|e| e.value.as_deref().unwrap_or("true"))
+    }
+
+    pub fn get_bool(&self, name: &str) -> anyhow::Result<Option<bool>> {
+        self.get(name)
+            .map(|value| {
+                parse_bool(value).ok_or_else(|| {
+                    anyhow::Error::msg(format!("Bad boolean config value '{value}' for '{name}'"))
+                })
+            })
+            .transpose()
+    }
+
+    pub fn get_int(&self, name: &str) -> anyhow::Result<Option<i64>> {
+        self.get(name)
+            .map(|value| {
+                let (digits, multiplier) =
+                    match value.chars().last().map(|c| c.to_ascii_lowercase()) {
+                        Some('k') => (&value[..value.len() - 1], 1024),
+                        Some('m') => (&value[..value.len() - 1], 1024 * 1024),
+                        Some('g') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+                        _ => (value, 1),
+                    };
+                digits
+                    .trim()
+                    .parse::<i64>()
+                    .map(|v| v * multiplier)
+                    .with_context(|| format!("Bad numeric config value '{value}' for '{name}'"))
+            })
+            .transpose()
+    }
+}
+
+/// Quotes a value if needed, so that it is read back unchanged.
+fn quote_value(value: &str) -> String {
+    let needs_quotes = value.starts_with(char::is_whitespace)
+        || value.ends_with(char::is_whitespace)
+        || value.contains(['#', ';']);
+    let mut escaped = String::with_capacity(value.len() + 2);
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    if needs_quotes {
+        format!("\"{escaped}\"")
+    } else {
+        escaped
+    }
+}
+
+fn section_header(section: &str, subsection: Option<&str>) -> String {
+    match subsection {
+        Some(subsection) => format!(
+            "[{section} \"{}\"]",
+            subsection.replace('\\', "\\\\").replace('"', "\\\"")
+        ),
+        None => format!("[{section}]"),
+    }
+}
+
+/// Splits `section[.subsection]` as used for whole sections.
+fn split_section_name(name: &str) -> (&str, Option<&str>) {
+    match name.split_once('.') {
+        Some((section, subsection)) => (section, Some(subsection)),
+        None => (name, None),
+    }
+}
+
+/// Section name and subsection
+type SectionName = (String, Option<String>);
+
+/// Line based editing of a configuration file, keeping comments and formatting intact.
+struct ConfigFile {
+    lines: Vec<String>,
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> anyhow::Result<ConfigFile> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(ConfigFile {
+            lines: text.lines().map(str::to_owned).collect(),
+        })
+    }
+
+    fn contents(&self) -> String {
+        let mut text = self.lines.join("\n");
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Parses every line, also returning the section in effect at that line.
+    fn parsed(&self) -> anyhow::Result<Vec<(Option<SectionName>, Line)>> {
+        let mut section = None;
+        let mut result = Vec::with_capacity(self.lines.len());
+        for (line_no, line) in self.lines.iter().enumerate() {
+            let line = parse_line(line, line_no)?;
+            if let Line::Section(name, subsection) = &line {
+                section = Some((name.clone(), subsection.clone()));
+            }
+            result.push((section.clone(), line));
+        }
+        Ok(result)
+    }
+
+    fn in_section(current: &Option<SectionName>, section: &str, subsection: Option<&str>) -> bool {
+        current.as_ref().is_some_and(|(name, sub)| {
+            name.eq_ignore_ascii_case(section) && sub.as_deref() == subsection
+        })
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        let (section, subsection, key) = split_name(name)?;
+        let line = format!("\t{key} = {}", quote_value(value));
+        let parsed = self.parsed()?;
+
+        let existing = parsed.iter().rposition(|(current, line)| {
+            matches!(line, Line::Variable(k, _) if k.eq_ignore_ascii_case(key))
+                && Self::in_section(current, section, subsection)
+        });
+        if let Some(i) = existing {
+            self.lines[i] = line;
+            return Ok(());
+        }
+        let last_in_section = parsed
+            .iter()
+            .rposition(|(current, _)| Self::in_section(current, section, subsection));
+        match last_in_section {
+            Some(i) => self.lines.insert(i + 1, line),
+            None => {
+                self.lines.push(section_header(section, subsection));
+                self.lines.push(line);
+            }
+        }
+        Ok(())
+    }
+
+    fn unset(&mut self, name: &str) -> anyhow::Result<bool> {
+        let (section, subsection, key) = split_name(name)?;
+        let parsed = self.parsed()?;
+        let before = self.lines.len();
+        let mut keep = parsed.iter().map(|(current, line)| {
+            !(matches!(line, Line::Variable(k, _) if k.eq_ignore_ascii_case(key))
+                && Self::in_section(current, section, subsection))
+        });
+        self.lines.retain(|_| keep.next().unwrap_or(true));
+        Ok(self.lines.len() != before)
+    }
+
+    /// Renames `old` section to `new`, or removes it with all its variables if `new` is `None`.
+    fn rename_section(&mut self, old: &str, new: Option<&str>) -> anyhow::Result<bool> {
+        let (section, subsection) = split_section_name(old);
+        let parsed = self.parsed()?;
+        let mut found = false;
+        let mut lines = Vec::with_capacity(self.lines.len());
+        for (line, (current, parsed_line)) in self.lines.drain(..).zip(parsed) {
+            if !Self::in_section(&current, section, subsection) {
+                lines.push(line);
+                continue;
+            }
+            found = true;
+            match (new, parsed_line) {
+                (Some(new), Line::Section(..)) => {
+                    let (new_section, new_subsection) = split_section_name(new);
+                    lines.push(section_header(new_section, new_subsection));
+                }
+                (Some(_), _) => lines.push(line),
+                (None, _) => {}
+            }
+        }
+        self.lines = lines;
+        Ok(found)
+    }
+}
+
+impl Repository {
+    fn edit_config(
+        &self,
+        edit: impl FnOnce(&mut ConfigFile) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<bool> {
+        let path = self.common_dir.join("config");
+        let mut file = ConfigFile::read(&path)?;
+        let changed = edit(&mut file)?;
+        if changed {
+            self.write_file_atomically(&path, file.contents().as_bytes())?;
+        }
+        Ok(changed)
+    }
+
+    /// Sets `section[.subsection].key` in the repository configuration.
+    pub fn set_config(&self, name: &str, value: &str) -> anyhow::Result<()> {
+        self.edit_config(|file| file.set(name, value).map(|_| true))?;
+        Ok(())
+    }
+
+    /// Removes all values of a variable, returns whether there were any.
+    pub fn unset_config(&self, name: &str) -> anyhow::Result<bool> {
+        self.edit_config(|file| file.unset(name))
+    }
+
+    /// Renames a whole section like `branch.old` to `branch.new`, or removes it when `new`
+    /// is `None`. Returns whether the section existed.
+    pub fn rename_config_section(&self, old: &str, new: Option<&str>) -> anyhow::Result<bool> {
+        self.edit_config(|file| file.rename_section(old, new))
+    }
+}
+
+enum Line {
+    Blank,
+    /// Lowercase section name and subsection
+    Section(String, Option<String>),
+    /// Lowercase variable name and value
+    Variable(String, Option<String>),
+}
+
+fn parse_line(line: &str, line_no: usize) -> anyhow::Result<Line> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+        return Ok(Line::Blank);
+    }
+
+    if let Some(header) = line.strip_prefix('[') {
+        let (header, rest) = header.split_once(']').ok_or_else(|| {
+            anyhow::Error::msg(format!(
+                "Unterminated section header at line {}",
+                line_no + 1
+            ))
+        })?;
+        let rest = rest.trim();
+        anyhow::ensure!(
+            rest.is_empty() || rest.starts_with('#') || rest.starts_with(';'),
+            "Unexpected data after section header at line {}",
+            line_no + 1
+        );
+        return Ok(match header.split_once(' ') {
+            Some((name, subsection)) => {
+                let subsection = subsection
+                    .trim()
+                    .strip_prefix('"')
+                    .and_then(|s| s.strip_suffix('"'))
+                    .ok_or_else(|| {
+                        anyhow::Error::msg(format!(
+                            "Subsection must be quoted at line {}",
+                            line_no + 1
+                        ))
+                    })?;
+                Line::Section(name.to_ascii_lowercase(), Some(unescape(subsection)))
+            }
+            // Deprecated `[section.subsection]` syntax
+            None => match header.split_once('.') {
+                Some((name, subsection)) => {
+                    Line::Section(name.to_ascii_lowercase(), Some(subsection.to_owned()))
+                }
+                None => Line::Section(header.to_ascii_lowercase(), None),
+            },
+        });
+    }
+
+    let (key, value) = match line.split_once('=') {
+        Some((key, value)) => (key.trim(), Some(parse_value(value))),
+        None => (line, None),
+    };
+    anyhow::ensure!(
+        !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'),
+        "Invalid variable name '{}' at line {}",
+        key,
+        line_no + 1
+    );
+    Ok(Line::Variable(key.to_ascii_lowercase(), value))
+}
+
+fn unescape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some('b') => {
+                    result.pop();
+                }
+                Some(c) => result.push(c),
+                None => {}
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Parses the right hand side of a variable, handling quotes, escapes and trailing comments.
+fn parse_value(raw: &str) -> String {
+    let mut result = String::with_capacity(raw.len());
+    let mut in_quotes = false;
+    // Whitespace is only kept if it is followed by something else than a comment
+    let mut pending_space = String::new();
+    let mut chars = raw.trim_start().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                result.push_str(&std::mem::take(&mut pending_space));
+            }
+            '#' | ';' if !in_quotes => break,
+            '\\' => {
+                result.push_str(&std::mem::take(&mut pending_space));
+                match chars.next() {
+                    Some('n') => result.push('\n'),
+                    Some('t') => result.push('\t'),
+                    Some('b') => {
+                        result.pop();
+                    }
+                    Some(c) => result.push(c),
+                    None => {}
+                }
+            }
+            c if c.is_whitespace() && !in_quotes => pending_space.push(c),
+            c => {
+                result.push_str(&std::mem::take(&mut pending_space));
+                result.push(c);
+            }
+        }
+    }
+    result
+}
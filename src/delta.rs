@@ -0,0 +1,268 @@
+//! Git's binary delta format: creating deltas against a base and applying them.
+
+use std::collections::HashMap;
+
+/// Matches are searched for at blocks of this many bytes
+const BLOCK_SIZE: usize = 16;
+/// Candidate positions remembered per block, repetitive data would otherwise be slow
+const MAX_CANDIDATES: usize = 64;
+/// Largest copy a single instruction does, like git for compatibility with old readers
+const MAX_COPY: usize = 0x10000;
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_insert(out: &mut Vec<u8>, data: &[u8]) {
+    for chunk in data.chunks(0x7f) {
+        out.push(chunk.len() as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+fn write_copy(out: &mut Vec<u8>, mut offset: usize, mut size: usize) {
+    while size > 0 {
+        let chunk = size.min(MAX_COPY);
+        let op_pos = out.len();
+        let mut op = 0x80u8;
+        out.push(0);
+        // Only non-zero bytes of offset and size are stored, flagged in the opcode
+        for i in 0..4 {
+            let byte = (offset >> (8 * i)) as u8;
+            if byte != 0 {
+                op |= 1 << i;
+                out.push(byte);
+            }
+        }
+        for i in 0..3 {
+            let byte = (chunk >> (8 * i)) as u8;
+            if byte != 0 {
+                op |= 1 << (4 + i);
+                out.push(byte);
+            }
+        }
+        out[op_pos] = op;
+        offset += chunk;
+        size -= chunk;
+    }
+}
+
+/// Positions of the blocks of a delta base, built once and reused for several targets.
+/// The index owns the base, so that it can be kept while other objects are read.
+pub struct DeltaIndex {
+    base: Vec<u8>,
+    blocks: HashMap<[u8; BLOCK_SIZE], Vec<usize>>,
+}
+
+impl DeltaIndex {
+    pub fn new(base: Vec<u8>) -> DeltaIndex {
+        let mut blocks: HashMap<[u8; BLOCK_SIZE], Vec<usize>> = HashMap::new();
+        for (i, block) in base.chunks_exact(BLOCK_SIZE).enumerate() {
+            let block = block.try_into().expect("Chunks have the block size");
+            let positions = blocks.entry(block).or_default();
+            if positions.len() < MAX_CANDIDATES {
+                positions.push(i * BLOCK_SIZE);
+            }
+        }
+        DeltaIndex { base, blocks }
+    }
+
+    pub fn base(&self) -> &[u8] {
+        &self.base
+    }
+
+    /// Longest match of `target[pos..]` starting at one of the indexed blocks.
+    fn longest_match(&self, target: &[u8], pos: usize) -> Option<(usize, usize)> {
+        let block: [u8; BLOCK_SIZE] = target.get(pos..pos + BLOCK_SIZE)?.try_into().ok()?;
+        let candidates = self.blocks.get(&block)?;
+        candidates
+            .iter()
+            .map(|&start| {
+                let length = self.base[start..]
+                    .iter()
+                    .zip(&target[pos..])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                (start, length)
+            })
+            .max_by_key(|&(start, length)| (length, std::cmp::Reverse(start)))
+    }
+
+    /// Creates a delta turning the base into `target`, or `None` if it would be larger
+    /// than `max_size`.
+    pub fn create_delta(&self, target: &[u8], max_size: usize) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        write_varint(&mut out, self.base.len());
+        write_varint(&mut out, target.len());
+
+        let mut literal_start = 0;
+        let mut pos = 0;
+        while pos < target.len() {
+            let Some((mut start, mut length)) = self.longest_match(target, pos) else {
+                pos += 1;
+                continue;
+            };
+            // Matches may also extend backwards into the pending literal bytes
+            let mut match_pos = pos;
+            while match_pos > literal_start
+                && start > 0
+                && self.base[start - 1] == target[match_pos - 1]
+            {
+                start -= 1;
+                match_pos -= 1;
+                length += 1;
+            }
+            write_insert(&mut out, &target[literal_start..match_pos]);
+            write_copy(&mut out, start, length);
+            pos = match_pos + length;
+            literal_start = pos;
+            if out.len() > max_size {
+                return None;
+            }
+        }
+        write_insert(&mut out, &target[literal_start..]);
+        (out.len() <= max_size).then_some(out)
+    }
+}
+
+/// Reads one of the variable length sizes at the start of a delta.
+fn read_size(delta: &mut impl std::io::Read) -> anyhow::Result<usize> {
+    let mut value = 0;
+    let mut shift = 0;
+    let mut byte = [0u8];
+    loop {
+        delta.read_exact(&mut byte).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => anyhow::Error::msg("Truncated delta header"),
+            _ => e.into(),
+        })?;
+        value |= ((byte[0] & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+/// Reads the size of the delta result from the start of a delta stream, skipping the base size.
+pub fn read_result_size(delta: &mut impl std::io::Read) -> anyhow::Result<usize> {
+    read_size(delta)?;
+    read_size(delta)
+}
+
+/// Applies a git delta to `base`.
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut header = delta;
+    let base_size = read_size(&mut header)?;
+    let result_size = read_size(&mut header)?;
+    let mut pos = delta.len() - header.len();
+    anyhow::ensure!(base_size == base.len(), "Delta base has wrong size");
+
+    let mut result = Vec::with_capacity(result_size);
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        if op & 0x80 != 0 {
+            // Copy from base, offset and size bytes are present when their bit is set
+            let mut fields = [0usize; 2];
+            let mut bit = 0;
+            for (field, bytes) in fields.iter_mut().zip([4, 3]) {
+                for i in 0..bytes {
+                    if op & (1 << bit) != 0 {
+                        let byte = *delta
+                            .get(pos)
+                            .ok_or_else(|| anyhow::Error::msg("Truncated delta"))?;
+                        *field |= (byte as usize) << (8 * i);
+                        pos += 1;
+                    }
+                    bit += 1;
+                }
+            }
+            let [offset, mut size] = fields;
+            if size == 0 {
+                size = 0x10000;
+            }
+            let chunk = base
+                .get(offset..offset + size)
+                .ok_or_else(|| anyhow::Error::msg("Delta copies outside of base"))?;
+            result.extend_from_slice(chunk);
+        } else if op != 0 {
+            let chunk = delta
+                .get(pos..pos + op as usize)
+                .ok_or_else(|| anyhow::Error::msg("Truncated delta"))?;
+            result.extend_from_slice(chunk);
+            pos += op as usize;
+        } else {
+            anyhow::bail!("Invalid delta opcode 0");
+        }
+    }
+    anyhow::ensure!(result.len() == result_size, "Delta result has wrong size");
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes that do not repeat within short distances, like compressed data
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let base = noise(200_000, 1);
+        // Copies longer than a single instruction can do, inserts and reordered parts
+        let mut target = base[1000..150_000].to_vec();
+        target.extend_from_slice(b"something new in between");
+        target.extend_from_slice(&base[..5000]);
+        target.extend(noise(300, 2));
+        let index = DeltaIndex::new(base.clone());
+        let delta = index.create_delta(&target, usize::MAX).unwrap();
+        assert!(delta.len() < 1000);
+        assert_eq!(apply_delta(&base, &delta).unwrap(), target);
+        assert_eq!(read_result_size(&mut &delta[..]).unwrap(), target.len());
+    }
+
+    #[test]
+    fn index_is_reused() {
+        let index = DeltaIndex::new(noise(4096, 3));
+        for seed in 4..8 {
+            let mut target = index.base()[seed * 100..].to_vec();
+            target.extend(noise(50, seed as u32));
+            let delta = index.create_delta(&target, usize::MAX).unwrap();
+            assert_eq!(apply_delta(index.base(), &delta).unwrap(), target);
+        }
+    }
+
+    #[test]
+    fn too_large_delta() {
+        let index = DeltaIndex::new(noise(1000, 9));
+        let target = noise(1000, 10);
+        assert!(index.create_delta(&target, 500).is_none());
+        // Unrelated data still makes a valid, if useless, delta
+        let delta = index.create_delta(&target, usize::MAX).unwrap();
+        assert_eq!(apply_delta(index.base(), &delta).unwrap(), target);
+    }
+
+    #[test]
+    fn invalid_deltas() {
+        let base = noise(100, 11);
+        let delta = DeltaIndex::new(base.clone())
+            .create_delta(&base[10..], usize::MAX)
+            .unwrap();
+        assert!(apply_delta(&base[1..], &delta).is_err());
+        assert!(apply_delta(&base, &delta[..delta.len() - 1]).is_err());
+        assert!(apply_delta(&base, &delta[..1]).is_err());
+        assert!(apply_delta(&base, &[]).is_err());
+    }
+}
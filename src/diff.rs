@@ -0,0 +1,1123 @@
+//! Tree diffing with similarity based rename and copy detection.
+
+use crate::{
+    hash::RawHash,
+    myers::{self, Edit},
+    ObjectRef, Repository, TreeData,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+};
+
+/// Similarity scores are expressed as a fraction of this value, like git does.
+pub const MAX_SCORE: u32 = 60000;
+/// Default minimal similarity for renames and copies, 50%
+pub const DEFAULT_MIN_SCORE: u32 = MAX_SCORE / 2;
+/// Default value of `diff.renameLimit`
+pub const DEFAULT_RENAME_LIMIT: usize = 1000;
+
+pub fn is_tree_mode(mode: u32) -> bool {
+    mode & 0o170000 == 0o040000
+}
+
+pub fn is_gitlink_mode(mode: u32) -> bool {
+    mode & 0o170000 == 0o160000
+}
+
+/// Parses a similarity score like git: `50` and `5` both mean 50%, `50%` means 50% and
+/// `0.5` means 50%.
+pub fn parse_score(value: &str) -> anyhow::Result<u32> {
+    let mut num: u64 = 0;
+    let mut scale: u64 = 1;
+    let mut dot = false;
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '.' if !dot => {
+                scale = 1;
+                dot = true;
+            }
+            '%' => {
+                anyhow::ensure!(chars.peek().is_none(), "Invalid similarity score: {value}");
+                scale = if dot { scale * 100 } else { 100 };
+            }
+            '0'..='9' => {
+                if scale < 100000 {
+                    scale *= 10;
+                    num = num * 10 + c.to_digit(10).unwrap() as u64;
+                }
+            }
+            _ => anyhow::bail!("Invalid similarity score: {value}"),
+        }
+    }
+    Ok(if num >= scale {
+        MAX_SCORE
+    } else {
+        (MAX_SCORE as u64 * num / scale) as u32
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffSide {
+    pub path: String,
+    pub mode: u32,
+    pub hash: RawHash,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffStatus {
+    Added,
+    Deleted,
+    Modified,
+    TypeChanged,
+    Renamed,
+    Copied,
+}
+
+impl DiffStatus {
+    pub fn letter(&self) -> char {
+        match self {
+            DiffStatus::Added => 'A',
+            DiffStatus::Deleted => 'D',
+            DiffStatus::Modified => 'M',
+            DiffStatus::TypeChanged => 'T',
+            DiffStatus::Renamed => 'R',
+            DiffStatus::Copied => 'C',
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiffEntry {
+    pub status: DiffStatus,
+    pub old: Option<DiffSide>,
+    pub new: Option<DiffSide>,
+    /// Similarity of renames and copies, out of `MAX_SCORE`
+    pub score: u32,
+}
+
+impl DiffEntry {
+    /// The path this entry is sorted and reported by
+    pub fn path(&self) -> &str {
+        self.new
+            .as_ref()
+            .or(self.old.as_ref())
+            .map(|s| s.path.as_str())
+            .unwrap_or_default()
+    }
+
+    /// Similarity as a percentage, as shown in `R087`
+    pub fn similarity(&self) -> u32 {
+        self.score * 100 / MAX_SCORE
+    }
+
+    fn status_string(&self) -> String {
+        match self.status {
+            DiffStatus::Renamed | DiffStatus::Copied => {
+                format!("{}{:03}", self.status.letter(), self.similarity())
+            }
+            status => status.letter().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Detection {
+    None,
+    Renames,
+    Copies,
+    /// Consider all files of the old tree as copy sources, not only changed ones
+    CopiesHarder,
+}
+
+#[derive(Debug, Clone)]
+pub struct DiffOptions {
+    pub recursive: bool,
+    pub detection: Detection,
+    /// Minimal similarity for inexact renames and copies, out of `MAX_SCORE`
+    pub min_score: u32,
+    /// Inexact detection is skipped if sources times destinations exceeds the square of this
+    pub rename_limit: usize,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions {
+            recursive: false,
+            detection: Detection::None,
+            min_score: DEFAULT_MIN_SCORE,
+            rename_limit: DEFAULT_RENAME_LIMIT,
+        }
+    }
+}
+
+type TreeEntries = BTreeMap<String, (u32, RawHash)>;
+
+fn tree_entries(tree: Option<&TreeData>) -> TreeEntries {
+    tree.map(|tree| {
+        tree.iter()
+            .map(|item| (item.name.into_owned(), (item.mode, item.hash)))
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+fn join_path(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{prefix}/{name}")
+    }
+}
+
+/// Lists all non-tree entries of a tree, recursively.
+pub fn flatten_tree(
+    repo: &Repository,
+    tree: &TreeData,
+    prefix: &str,
+    out: &mut Vec<DiffSide>,
+) -> anyhow::Result<()> {
+    for item in tree.iter() {
+        let path = join_path(prefix, &item.name);
+        if is_tree_mode(item.mode) {
+            let subtree = repo.read_tree(&ObjectRef::from_hash(&item.hash))?;
+            flatten_tree(repo, &subtree, &path, out)?;
+        } else {
+            out.push(DiffSide {
+                path,
+                mode: item.mode,
+                hash: item.hash,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Reports the whole `side` as added or deleted, expanding trees when diffing recursively.
+fn push_one_sided(
+    repo: &Repository,
+    side: DiffSide,
+    added: bool,
+    opts: &DiffOptions,
+    out: &mut Vec<DiffEntry>,
+) -> anyhow::Result<()> {
+    let sides = if opts.recursive && is_tree_mode(side.mode) {
+        let mut sides = Vec::new();
+        let tree = repo.read_tree(&ObjectRef::from_hash(&side.hash))?;
+        flatten_tree(repo, &tree, &side.path, &mut sides)?;
+        sides
+    } else {
+        vec![side]
+    };
+    for side in sides {
+        out.push(if added {
+            DiffEntry {
+                status: DiffStatus::Added,
+                old: None,
+                new: Some(side),
+                score: 0,
+            }
+        } else {
+            DiffEntry {
+                status: DiffStatus::Deleted,
+                old: Some(side),
+                new: None,
+                score: 0,
+            }
+        });
+    }
+    Ok(())
+}
+
+fn diff_tree_entries(
+    repo: &Repository,
+    prefix: &str,
+    old: TreeEntries,
+    new: TreeEntries,
+    opts: &DiffOptions,
+    out: &mut Vec<DiffEntry>,
+) -> anyhow::Result<()> {
+    let mut names: Vec<&String> = old.keys().chain(new.keys()).collect();
+    names.sort();
+    names.dedup();
+
+    for name in names {
+        let path = join_path(prefix, name);
+        let side = |(mode, hash): (u32, RawHash)| DiffSide {
+            path: path.clone(),
+            mode,
+            hash,
+        };
+        match (old.get(name).copied(), new.get(name).copied()) {
+            (Some(o), Some(n)) if o == n => {}
+            (Some(o), Some(n)) if is_tree_mode(o.0) && is_tree_mode(n.0) && opts.recursive => {
+                let old_tree = repo.read_tree(&ObjectRef::from_hash(&o.1))?;
+                let new_tree = repo.read_tree(&ObjectRef::from_hash(&n.1))?;
+                diff_tree_entries(
+                    repo,
+                    &path,
+                    tree_entries(Some(&old_tree)),
+                    tree_entries(Some(&new_tree)),
+                    opts,
+                    out,
+                )?;
+            }
+            (Some(o), Some(n)) if is_tree_mode(o.0) != is_tree_mode(n.0) => {
+                push_one_sided(repo, side(o), false, opts, out)?;
+                push_one_sided(repo, side(n), true, opts, out)?;
+            }
+            (Some(o), Some(n)) => out.push(DiffEntry {
+                status: if o.0 & 0o170000 == n.0 & 0o170000 {
+                    DiffStatus::Modified
+                } else {
+                    DiffStatus::TypeChanged
+                },
+                old: Some(side(o)),
+                new: Some(side(n)),
+                score: 0,
+            }),
+            (Some(o), None) => push_one_sided(repo, side(o), false, opts, out)?,
+            (None, Some(n)) => push_one_sided(repo, side(n), true, opts, out)?,
+            (None, None) => unreachable!("Name comes from one of the trees"),
+        }
+    }
+    Ok(())
+}
+
+/// Compares two trees, a missing tree is treated as empty.
+pub fn diff_trees(
+    repo: &Repository,
+    old: Option<&TreeData>,
+    new: Option<&TreeData>,
+    opts: &DiffOptions,
+) -> anyhow::Result<Vec<DiffEntry>> {
+    let mut entries = Vec::new();
+    diff_tree_entries(
+        repo,
+        "",
+        tree_entries(old),
+        tree_entries(new),
+        opts,
+        &mut entries,
+    )?;
+
+    if opts.detection == Detection::None {
+        return Ok(entries);
+    }
+    let mut unchanged = Vec::new();
+    if opts.detection == Detection::CopiesHarder {
+        if let Some(old) = old {
+            flatten_tree(repo, old, "", &mut unchanged)?;
+            unchanged.retain(|side| {
+                !entries
+                    .iter()
+                    .any(|e| e.old.as_ref().is_some_and(|o| o.path == side.path))
+            });
+        }
+    }
+    RenameDetector::new(repo, opts).run(entries, unchanged)
+}
+
+/// Pairs up added and deleted files of an already computed diff as renames or copies.
+pub fn detect_renames(
+    repo: &Repository,
+    entries: Vec<DiffEntry>,
+    opts: &DiffOptions,
+) -> anyhow::Result<Vec<DiffEntry>> {
+    RenameDetector::new(repo, opts).run(entries, Vec::new())
+}
+
+/// Fingerprint of a blob used for similarity estimation: number of bytes per hashed chunk.
+///
+/// Chunks end at a newline or after 64 bytes, so similar text files share most of their
+/// chunks even when lines were inserted or removed.
+struct Fingerprint {
+    chunks: HashMap<u64, u64>,
+    size: u64,
+}
+
+impl Fingerprint {
+    fn new(data: &[u8]) -> Fingerprint {
+        let mut chunks = HashMap::new();
+        let mut start = 0;
+        while start < data.len() {
+            let limit = data.len().min(start + 64);
+            let end = data[start..limit]
+                .iter()
+                .position(|b| *b == b'\n')
+                .map(|p| start + p + 1)
+                .unwrap_or(limit);
+            // FNV-1a
+            let hash = data[start..end].iter().fold(0xcbf29ce484222325u64, |h, b| {
+                (h ^ *b as u64).wrapping_mul(0x100000001b3)
+            });
+            *chunks.entry(hash).or_insert(0) += (end - start) as u64;
+            start = end;
+        }
+        Fingerprint {
+            chunks,
+            size: data.len() as u64,
+        }
+    }
+
+    /// Number of bytes of `self` that can be found in `other`.
+    fn common_bytes(&self, other: &Fingerprint) -> u64 {
+        self.chunks
+            .iter()
+            .map(|(hash, count)| (*count).min(other.chunks.get(hash).copied().unwrap_or(0)))
+            .sum()
+    }
+}
+
+struct Source {
+    side: DiffSide,
+    /// Index of the deletion entry, only deleted files can be renamed
+    deleted: Option<usize>,
+    /// Number of destinations that took this source
+    uses: usize,
+}
+
+struct RenameDetector<'r> {
+    repo: &'r Repository,
+    opts: &'r DiffOptions,
+    fingerprints: HashMap<RawHash, Fingerprint>,
+}
+
+impl<'r> RenameDetector<'r> {
+    fn new(repo: &'r Repository, opts: &'r DiffOptions) -> Self {
+        RenameDetector {
+            repo,
+            opts,
+            fingerprints: HashMap::new(),
+        }
+    }
+
+    fn copies(&self) -> bool {
+        matches!(
+            self.opts.detection,
+            Detection::Copies | Detection::CopiesHarder
+        )
+    }
+
+    fn fingerprint(&mut self, hash: &RawHash) -> anyhow::Result<&Fingerprint> {
+        if !self.fingerprints.contains_key(hash) {
+            let data = self.repo.read_blob(&ObjectRef::from_hash(hash))?;
+            self.fingerprints.insert(*hash, Fingerprint::new(&data));
+        }
+        Ok(&self.fingerprints[hash])
+    }
+
+    fn similarity(&mut self, src: &RawHash, dst: &RawHash) -> anyhow::Result<u32> {
+        let src_size = self.fingerprint(src)?.size;
+        let dst_size = self.fingerprint(dst)?.size;
+        let max_size = src_size.max(dst_size);
+        let min_size = src_size.min(dst_size);
+        if min_size == 0 {
+            return Ok(0);
+        }
+        // Even if all of the smaller file was found in the bigger one, the score would
+        // not reach the threshold
+        let max_delta = max_size * (MAX_SCORE - self.opts.min_score) as u64;
+        if (max_size - min_size) * (MAX_SCORE as u64) > max_delta {
+            return Ok(0);
+        }
+        let common = self.fingerprints[src].common_bytes(&self.fingerprints[dst]);
+        Ok((common * MAX_SCORE as u64 / max_size) as u32)
+    }
+
+    fn run(
+        mut self,
+        entries: Vec<DiffEntry>,
+        unchanged: Vec<DiffSide>,
+    ) -> anyhow::Result<Vec<DiffEntry>> {
+        // Trees only show up here when not diffing recursively, these can only be
+        // matched exactly
+        let renameable = |mode: u32| !is_gitlink_mode(mode);
+
+        let mut sources = Vec::new();
+        let mut destinations = Vec::new();
+        for (idx, entry) in entries.iter().enumerate() {
+            match (entry.status, &entry.old, &entry.new) {
+                (DiffStatus::Deleted, Some(old), _) if renameable(old.mode) => {
+                    sources.push(Source {
+                        side: old.clone(),
+                        deleted: Some(idx),
+                        uses: 0,
+                    })
+                }
+                (DiffStatus::Added, _, Some(new)) if renameable(new.mode) => destinations.push(idx),
+                (DiffStatus::Modified, Some(old), _) if self.copies() => sources.push(Source {
+                    side: old.clone(),
+                    deleted: None,
+                    uses: 0,
+                }),
+                _ => {}
+            }
+        }
+        sources.extend(unchanged.into_iter().map(|side| Source {
+            side,
+            deleted: None,
+            uses: 0,
+        }));
+        if sources.is_empty() || destinations.is_empty() {
+            return Ok(entries);
+        }
+
+        // Destination entry index -> (source index, score)
+        let mut matches: HashMap<usize, (usize, u32)> = HashMap::new();
+        let same_kind = |a: u32, b: u32| a & 0o170000 == b & 0o170000;
+
+        // Exact matches first, these are cheap and unambiguous
+        let mut by_hash: HashMap<RawHash, Vec<usize>> = HashMap::new();
+        for (idx, source) in sources.iter().enumerate() {
+            by_hash.entry(source.side.hash).or_default().push(idx);
+        }
+        for &dst in destinations.iter() {
+            let new = entries[dst]
+                .new
+                .as_ref()
+                .expect("Destinations are additions");
+            let Some(candidates) = by_hash.get(&new.hash) else {
+                continue;
+            };
+            let candidates: Vec<usize> = candidates
+                .iter()
+                .copied()
+                .filter(|&src| same_kind(sources[src].side.mode, new.mode))
+                .collect();
+            // Prefer a deleted file that was not renamed yet, then a deleted file
+            // with the same name in another directory
+            let basename = |path: &str| path.rsplit('/').next().unwrap_or_default().to_owned();
+            let best = candidates
+                .iter()
+                .copied()
+                .filter(|&src| sources[src].deleted.is_some() && sources[src].uses == 0)
+                .min_by_key(|&src| basename(&sources[src].side.path) != basename(&new.path))
+                .or_else(|| {
+                    candidates
+                        .iter()
+                        .copied()
+                        .find(|&src| self.copies() || sources[src].deleted.is_some())
+                });
+            if let Some(src) = best {
+                sources[src].uses += 1;
+                matches.insert(dst, (src, MAX_SCORE));
+            }
+        }
+
+        let remaining_destinations: Vec<usize> = destinations
+            .iter()
+            .copied()
+            .filter(|dst| !matches.contains_key(dst))
+            .collect();
+        let candidate_sources: Vec<usize> = (0..sources.len())
+            .filter(|&src| {
+                self.copies() || (sources[src].deleted.is_some() && sources[src].uses == 0)
+            })
+            .collect();
+        // Like in git, zero means the largest supported limit
+        let limit = match self.opts.rename_limit {
+            0 => 32767,
+            limit => limit,
+        };
+        if remaining_destinations.len() * candidate_sources.len() > limit * limit {
+            let needed = remaining_destinations.len().max(candidate_sources.len());
+            self.repo.warn(
+                "warning: exhaustive rename detection was skipped due to too many files."
+                    .to_owned(),
+            );
+            self.repo.warn(format!(
+                "warning: you may want to set your diff.renameLimit variable to at least {needed} and retry the command."
+            ));
+        } else {
+            let mut candidates = Vec::new();
+            for &dst in remaining_destinations.iter() {
+                let new = entries[dst]
+                    .new
+                    .as_ref()
+                    .expect("Destinations are additions");
+                for &src in candidate_sources.iter() {
+                    if !same_kind(sources[src].side.mode, new.mode) || is_tree_mode(new.mode) {
+                        continue;
+                    }
+                    let score = self.similarity(&sources[src].side.hash, &new.hash)?;
+                    if score >= self.opts.min_score {
+                        candidates.push((score, dst, src));
+                    }
+                }
+            }
+            // Best scores first, ties resolved by the order of destinations and sources
+            candidates.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+            for (score, dst, src) in candidates {
+                if matches.contains_key(&dst) {
+                    continue;
+                }
+                if !self.copies() && sources[src].uses > 0 {
+                    continue;
+                }
+                sources[src].uses += 1;
+                matches.insert(dst, (src, score));
+            }
+        }
+
+        // A deleted source used by several destinations is renamed to the last one and
+        // copied to the others, a source that still exists is only ever copied.
+        let mut result = Vec::with_capacity(entries.len());
+        let consumed: Vec<usize> = sources
+            .iter()
+            .filter(|s| s.uses > 0)
+            .filter_map(|s| s.deleted)
+            .collect();
+        for (idx, entry) in entries.into_iter().enumerate() {
+            if consumed.contains(&idx) {
+                continue;
+            }
+            match matches.get(&idx) {
+                Some(&(src, score)) => {
+                    let source = &mut sources[src];
+                    source.uses -= 1;
+                    let status = if source.deleted.is_some() && source.uses == 0 {
+                        DiffStatus::Renamed
+                    } else {
+                        DiffStatus::Copied
+                    };
+                    result.push(DiffEntry {
+                        status,
+                        old: Some(source.side.clone()),
+                        new: entry.new,
+                        score,
+                    });
+                }
+                None => result.push(entry),
+            }
+        }
+        Ok(result)
+    }
+}
+
+fn hex(hash: &RawHash) -> String {
+    hex::encode(hash)
+}
+
+/// Hashes of both sides, the null hash of the same format for a missing side.
+fn side_hashes(old: Option<&DiffSide>, new: Option<&DiffSide>) -> (RawHash, RawHash) {
+    let null = old
+        .or(new)
+        .map(|s| s.hash.format())
+        .unwrap_or_default()
+        .null();
+    (old.map_or(null, |s| s.hash), new.map_or(null, |s| s.hash))
+}
+
+fn abbrev(hash: &RawHash) -> String {
+    hex(hash)[..7].to_owned()
+}
+
+pub fn write_raw(out: &mut impl Write, entry: &DiffEntry) -> anyhow::Result<()> {
+    let old_mode = entry.old.as_ref().map(|s| s.mode).unwrap_or(0);
+    let new_mode = entry.new.as_ref().map(|s| s.mode).unwrap_or(0);
+    let (old_hash, new_hash) = side_hashes(entry.old.as_ref(), entry.new.as_ref());
+    write!(
+        out,
+        ":{old_mode:06o} {new_mode:06o} {} {} ",
+        hex(&old_hash),
+        hex(&new_hash)
+    )?;
+    write_name_status(out, entry)
+}
+
+pub fn write_name_status(out: &mut impl Write, entry: &DiffEntry) -> anyhow::Result<()> {
+    match (entry.status, &entry.old, &entry.new) {
+        (DiffStatus::Renamed | DiffStatus::Copied, Some(old), Some(new)) => {
+            writeln!(out, "{}\t{}\t{}", entry.status_string(), old.path, new.path)?
+        }
+        _ => writeln!(out, "{}\t{}", entry.status_string(), entry.path())?,
+    }
+    Ok(())
+}
+
+/// Contents of a tree entry as it is diffed, gitlinks are shown as the commit they point to.
+fn side_contents(repo: &Repository, side: Option<&DiffSide>) -> anyhow::Result<Vec<u8>> {
+    match side {
+        None => Ok(Vec::new()),
+        Some(side) if is_gitlink_mode(side.mode) => {
+            Ok(format!("Subproject commit {}\n", hex(&side.hash)).into_bytes())
+        }
+        Some(side) => Ok(repo.read_blob(&ObjectRef::from_hash(&side.hash))?),
+    }
+}
+
+pub fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(8000)].contains(&0)
+}
+
+/// Git's default "function name" for hunk headers: the closest preceding line that starts
+/// with a letter, `_` or `$`.
+fn hunk_function_name(lines: &[&[u8]], before: usize) -> String {
+    lines[..before]
+        .iter()
+        .rev()
+        .find(|line| {
+            line.first()
+                .is_some_and(|c| c.is_ascii_alphabetic() || *c == b'_' || *c == b'$')
+        })
+        .map(|line| {
+            let line = String::from_utf8_lossy(line);
+            let line = line.trim_end();
+            let mut end = line.len().min(80);
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line[..end].to_owned()
+        })
+        .unwrap_or_default()
+}
+
+fn hunk_range(start: usize, len: usize) -> String {
+    let start = if len == 0 { start } else { start + 1 };
+    if len == 1 {
+        format!("{start}")
+    } else {
+        format!("{start},{len}")
+    }
+}
+
+/// Writes hunks of a unified diff with `context` lines around every change.
+pub fn write_hunks(
+    out: &mut impl Write,
+    old: &[u8],
+    new: &[u8],
+    context: usize,
+) -> anyhow::Result<()> {
+    let old_lines = myers::split_lines(old);
+    let new_lines = myers::split_lines(new);
+    let edits = myers::diff(&old_lines, &new_lines);
+
+    let changes: Vec<usize> = edits
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| !matches!(e, Edit::Equal { .. }))
+        .map(|(i, _)| i)
+        .collect();
+    let mut groups: Vec<(usize, usize)> = Vec::new();
+    for &change in changes.iter() {
+        match groups.last_mut() {
+            Some((_, end)) if change <= *end + 2 * context + 1 => *end = change,
+            _ => groups.push((change, change)),
+        }
+    }
+
+    for (first, last) in groups {
+        let start = first.saturating_sub(context);
+        let end = (last + context + 1).min(edits.len());
+        let hunk = &edits[start..end];
+
+        let old_start = match edits[start] {
+            Edit::Equal { old, .. } | Edit::Delete { old } => old,
+            Edit::Insert { .. } => edits[..start]
+                .iter()
+                .filter(|e| !matches!(e, Edit::Insert { .. }))
+                .count(),
+        };
+        let new_start = match edits[start] {
+            Edit::Equal { new, .. } | Edit::Insert { new } => new,
+            Edit::Delete { .. } => edits[..start]
+                .iter()
+                .filter(|e| !matches!(e, Edit::Delete { .. }))
+                .count(),
+        };
+        let old_len = hunk
+            .iter()
+            .filter(|e| !matches!(e, Edit::Insert { .. }))
+            .count();
+        let new_len = hunk
+            .iter()
+            .filter(|e| !matches!(e, Edit::Delete { .. }))
+            .count();
+
+        let function = hunk_function_name(&old_lines, old_start);
+        write!(
+            out,
+            "@@ -{} +{} @@",
+            hunk_range(old_start, old_len),
+            hunk_range(new_start, new_len)
+        )?;
+        if function.is_empty() {
+            writeln!(out)?;
+        } else {
+            writeln!(out, " {function}")?;
+        }
+
+        for edit in hunk {
+            let (prefix, line) = match *edit {
+                Edit::Equal { old, .. } => (b' ', old_lines[old]),
+                Edit::Delete { old } => (b'-', old_lines[old]),
+                Edit::Insert { new } => (b'+', new_lines[new]),
+            };
+            out.write_all(&[prefix])?;
+            out.write_all(line)?;
+            if !line.ends_with(b"\n") {
+                out.write_all(b"\n\\ No newline at end of file\n")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn write_patch(
+    out: &mut impl Write,
+    repo: &Repository,
+    entry: &DiffEntry,
+) -> anyhow::Result<()> {
+    let old = entry.old.as_ref();
+    let new = entry.new.as_ref();
+    let a_path = old.or(new).map(|s| s.path.as_str()).unwrap_or_default();
+    let b_path = new.or(old).map(|s| s.path.as_str()).unwrap_or_default();
+    writeln!(out, "diff --git a/{a_path} b/{b_path}")?;
+
+    match (old, new) {
+        (None, Some(new)) => writeln!(out, "new file mode {:06o}", new.mode)?,
+        (Some(old), None) => writeln!(out, "deleted file mode {:06o}", old.mode)?,
+        (Some(old), Some(new)) if old.mode != new.mode => {
+            writeln!(out, "old mode {:06o}", old.mode)?;
+            writeln!(out, "new mode {:06o}", new.mode)?;
+        }
+        _ => {}
+    }
+    match entry.status {
+        DiffStatus::Renamed => {
+            writeln!(out, "similarity index {}%", entry.similarity())?;
+            writeln!(out, "rename from {a_path}")?;
+            writeln!(out, "rename to {b_path}")?;
+        }
+        DiffStatus::Copied => {
+            writeln!(out, "similarity index {}%", entry.similarity())?;
+            writeln!(out, "copy from {a_path}")?;
+            writeln!(out, "copy to {b_path}")?;
+        }
+        _ => {}
+    }
+
+    let (old_hash, new_hash) = side_hashes(old, new);
+    if old_hash == new_hash {
+        return Ok(());
+    }
+    write!(out, "index {}..{}", abbrev(&old_hash), abbrev(&new_hash))?;
+    match (old, new) {
+        (Some(old), Some(new)) if old.mode == new.mode => writeln!(out, " {:06o}", old.mode)?,
+        _ => writeln!(out)?,
+    }
+
+    let old_data = side_contents(repo, old)?;
+    let new_data = side_contents(repo, new)?;
+    let a_name = old
+        .map(|_| format!("a/{a_path}"))
+        .unwrap_or_else(|| "/dev/null".to_owned());
+    let b_name = new
+        .map(|_| format!("b/{b_path}"))
+        .unwrap_or_else(|| "/dev/null".to_owned());
+    if is_binary(&old_data) || is_binary(&new_data) {
+        writeln!(out, "Binary files {a_name} and {b_name} differ")?;
+        return Ok(());
+    }
+    if old_data.is_empty() && new_data.is_empty() {
+        return Ok(());
+    }
+    writeln!(out, "--- {a_name}")?;
+    writeln!(out, "+++ {b_name}")?;
+    write_hunks(out, &old_data, &new_data, 3)
+}
+
+/// Writes a `--stat` like summary of changed files, with a graph of changed lines.
+pub fn write_stat(
+    out: &mut impl Write,
+    repo: &Repository,
+    entries: &[DiffEntry],
+) -> anyhow::Result<()> {
+    enum Change {
+        Lines(usize, usize),
+        Binary(usize, usize),
+    }
+    let mut rows = Vec::new();
+    for entry in entries {
+        let name = match (entry.status, &entry.old, &entry.new) {
+            (DiffStatus::Renamed | DiffStatus::Copied, Some(old), Some(new)) => {
+                format!("{} => {}", old.path, new.path)
+            }
+            _ => entry.path().to_owned(),
+        };
+        let old = side_contents(repo, entry.old.as_ref())?;
+        let new = side_contents(repo, entry.new.as_ref())?;
+        let change = if is_binary(&old) || is_binary(&new) {
+            Change::Binary(old.len(), new.len())
+        } else {
+            let old_lines = myers::split_lines(&old);
+            let new_lines = myers::split_lines(&new);
+            let edits = myers::diff(&old_lines, &new_lines);
+            let added = edits
+                .iter()
+                .filter(|e| matches!(e, Edit::Insert { .. }))
+                .count();
+            let deleted = edits
+                .iter()
+                .filter(|e| matches!(e, Edit::Delete { .. }))
+                .count();
+            Change::Lines(added, deleted)
+        };
+        rows.push((name, change));
+    }
+
+    let name_width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    let max_change = rows
+        .iter()
+        .map(|(_, change)| match change {
+            Change::Lines(added, deleted) => added + deleted,
+            Change::Binary(..) => 0,
+        })
+        .max()
+        .unwrap_or(0);
+    let count_width = max_change.to_string().len();
+    let graph_width = 80usize.saturating_sub(name_width + count_width + 5).max(10);
+    let scale = |n: usize| {
+        if max_change <= graph_width || n == 0 {
+            n
+        } else {
+            (n * graph_width / max_change).max(1)
+        }
+    };
+
+    let (mut insertions, mut deletions) = (0, 0);
+    for (name, change) in rows.iter() {
+        match change {
+            Change::Lines(added, deleted) => {
+                insertions += added;
+                deletions += deleted;
+                writeln!(
+                    out,
+                    " {name:name_width$} | {:>width$} {}{}",
+                    added + deleted,
+                    "+".repeat(scale(*added)),
+                    "-".repeat(scale(*deleted)),
+                    width = count_width
+                )?;
+            }
+            Change::Binary(old, new) => {
+                writeln!(out, " {name:name_width$} | Bin {old} -> {new} bytes")?
+            }
+        }
+    }
+
+    let plural = |n: usize, one: &str, many: &str| {
+        if n == 1 {
+            one.to_owned()
+        } else {
+            many.to_owned()
+        }
+    };
+    write!(
+        out,
+        " {} {} changed",
+        rows.len(),
+        plural(rows.len(), "file", "files")
+    )?;
+    if insertions > 0 || deletions == 0 {
+        write!(
+            out,
+            ", {insertions} {}(+)",
+            plural(insertions, "insertion", "insertions")
+        )?;
+    }
+    if deletions > 0 || insertions == 0 {
+        write!(
+            out,
+            ", {deletions} {}(-)",
+            plural(deletions, "deletion", "deletions")
+        )?;
+    }
+    writeln!(out)?;
+
+    for entry in entries {
+        match (entry.status, &entry.old, &entry.new) {
+            (DiffStatus::Added, _, Some(new)) => {
+                writeln!(out, " create mode {:06o} {}", new.mode, new.path)?
+            }
+            (DiffStatus::Deleted, Some(old), _) => {
+                writeln!(out, " delete mode {:06o} {}", old.mode, old.path)?
+            }
+            (_, Some(old), Some(new)) if old.mode != new.mode && old.path == new.path => writeln!(
+                out,
+                " mode change {:06o} => {:06o} {}",
+                old.mode, new.mode, new.path
+            )?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{odb::MemoryDatabase, Object, TreeItem};
+    use std::borrow::Cow;
+
+    fn memory_repo() -> Repository {
+        Repository::with_object_database(".git".into(), Box::new(MemoryDatabase::default()))
+    }
+
+    fn tree(repo: &Repository, files: &[(&str, &[u8])]) -> TreeData {
+        let format = repo.object_format().unwrap();
+        let mut tree = TreeData::empty(format);
+        for (name, contents) in files {
+            let blob = Object::Blob(contents.to_vec());
+            repo.save_object(&blob).unwrap();
+            tree.add_item(&TreeItem {
+                mode: 0o100644,
+                name: Cow::Borrowed(name),
+                hash: blob.hash(format).unwrap(),
+            });
+        }
+        tree.sort();
+        tree
+    }
+
+    /// Diffs two flat trees and renders the entries like `--name-status`.
+    fn name_status(
+        old: &[(&str, &[u8])],
+        new: &[(&str, &[u8])],
+        detection: Detection,
+    ) -> Vec<String> {
+        let repo = memory_repo();
+        let old = tree(&repo, old);
+        let new = tree(&repo, new);
+        let opts = DiffOptions {
+            recursive: true,
+            detection,
+            ..DiffOptions::default()
+        };
+        diff_trees(&repo, Some(&old), Some(&new), &opts)
+            .unwrap()
+            .iter()
+            .map(|entry| match (&entry.old, &entry.new) {
+                (Some(old), Some(new)) if old.path != new.path => {
+                    format!("{} {} {}", entry.status_string(), old.path, new.path)
+                }
+                _ => format!("{} {}", entry.status_string(), entry.path()),
+            })
+            .collect()
+    }
+
+    fn lines(count: usize) -> Vec<u8> {
+        (0..count)
+            .flat_map(|n| format!("line {n}\n").into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn scores() {
+        assert_eq!(parse_score("50").unwrap(), 30000);
+        assert_eq!(parse_score("5").unwrap(), 30000);
+        assert_eq!(parse_score("50%").unwrap(), 30000);
+        assert_eq!(parse_score("0.5").unwrap(), 30000);
+        assert_eq!(parse_score("75%").unwrap(), 45000);
+        assert_eq!(parse_score("100%").unwrap(), MAX_SCORE);
+        assert_eq!(parse_score("150%").unwrap(), MAX_SCORE);
+        assert!(parse_score("5%0").is_err());
+        assert!(parse_score("1.2.3").is_err());
+        assert!(parse_score("half").is_err());
+    }
+
+    #[test]
+    fn fingerprints_count_common_chunks() {
+        let old = Fingerprint::new(b"a\nb\nc\n");
+        let new = Fingerprint::new(b"a\nc\nd\n");
+        assert_eq!(old.common_bytes(&new), 4);
+        assert_eq!(old.common_bytes(&old), 6);
+        // Long lines are split into chunks of 64 bytes
+        let long = Fingerprint::new(&[b'x'; 200]);
+        assert_eq!(long.chunks.len(), 2);
+        assert_eq!(long.common_bytes(&Fingerprint::new(&[b'x'; 64])), 64);
+    }
+
+    #[test]
+    fn exact_renames() {
+        let contents = lines(10);
+        assert_eq!(
+            name_status(&[("a", &contents)], &[("b", &contents)], Detection::Renames),
+            ["R100 a b"]
+        );
+        // Without detection, the rename is a deletion and an addition
+        assert_eq!(
+            name_status(&[("a", &contents)], &[("b", &contents)], Detection::None),
+            ["D a", "A b"]
+        );
+    }
+
+    #[test]
+    fn exact_renames_pair_the_first_candidate() {
+        // Like git, the first of several identical deleted files is renamed, the entries
+        // stay sorted by path
+        assert_eq!(
+            name_status(
+                &[("s1", b"same\n"), ("s2", b"same\n")],
+                &[("s3", b"same\n")],
+                Detection::Renames
+            ),
+            ["D s2", "R100 s1 s3"]
+        );
+    }
+
+    #[test]
+    fn exact_renames_prefer_the_same_basename() {
+        assert_eq!(
+            name_status(
+                &[("a", b"same\n"), ("f", b"same\n")],
+                &[("g/f", b"same\n")],
+                Detection::Renames
+            ),
+            ["D a", "R100 f g/f"]
+        );
+    }
+
+    #[test]
+    fn inexact_renames() {
+        let old = lines(10);
+        let mut new = old.clone();
+        new[35..41].copy_from_slice(b"LINE 5");
+        assert_eq!(
+            name_status(&[("a", &old)], &[("b", &new)], Detection::Renames),
+            ["R090 a b"]
+        );
+        // A rewrite is no rename
+        assert_eq!(
+            name_status(&[("a", &old)], &[("b", b"other\n")], Detection::Renames),
+            ["D a", "A b"]
+        );
+    }
+
+    #[test]
+    fn copies() {
+        let old = lines(10);
+        let modified = lines(11);
+        let files = [("a", &modified[..]), ("b", &old[..])];
+        assert_eq!(
+            name_status(&[("a", &old)], &files, Detection::Renames),
+            ["M a", "A b"]
+        );
+        assert_eq!(
+            name_status(&[("a", &old)], &files, Detection::Copies),
+            ["M a", "C100 a b"]
+        );
+        // Unchanged files are only considered with --find-copies-harder
+        let files = [("a", &old[..]), ("b", &old[..])];
+        assert_eq!(
+            name_status(&[("a", &old)], &files, Detection::Copies),
+            ["A b"]
+        );
+        assert_eq!(
+            name_status(&[("a", &old)], &files, Detection::CopiesHarder),
+            ["C100 a b"]
+        );
+    }
+}
@@ -0,0 +1,72 @@
+//! Errors callers may want to handle rather than just report.
+//!
+//! The core API on [`Repository`](crate::Repository) returns these directly: opening a
+//! repository, reading and writing objects, walking trees, writing a tree from a directory,
+//! the configuration, identities and revision parsing, and so do the object databases.
+//!
+//! Parsers of raw object data, references, the index and the operations built on top, like
+//! merging, blame, checkout, gc and fsck, return an `anyhow::Error` with context for the
+//! user instead. It still carries an `Error` that caused the failure, so that can be found
+//! with `downcast_ref::<Error>()`, and converting it back with `Error::from` recovers it.
+
+use crate::ObjectRef;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// No `.git` directory or file in the current directory or any of its parents
+    #[error("not a git repository (or any of the parent directories): .git")]
+    NotARepository,
+    /// A path given as the git directory, through `GIT_DIR` or a `.git` file, is not one
+    #[error("not a git repository: '{0}'")]
+    InvalidGitDir(String),
+    /// The repository is bare, but the operation needs a working tree
+    #[error("this operation must be run in a work tree")]
+    NoWorkTree,
+    /// The object does not exist, loose or packed
+    #[error("could not find object {0}")]
+    NotFound(ObjectRef),
+    /// An abbreviated hash that matches more than one object
+    #[error("short object ID {0} is ambiguous")]
+    Ambiguous(String),
+    /// A revision or hash that can not be parsed or does not name anything
+    #[error("Not a valid object name {0}")]
+    InvalidRef(String),
+    /// The object exists, but is not of the type that was asked for
+    #[error("object {object} is a {kind}, not a {expected}")]
+    WrongType {
+        object: ObjectRef,
+        kind: String,
+        expected: String,
+    },
+    /// Hashed data contains a block crafted to collide with another one, like the
+    /// SHAttered attack. The hash is not the real SHA-1 of the data.
+    #[error("SHA-1 appears to be part of a collision attack: {0}")]
+    Collision(ObjectRef),
+    /// The object can not be inflated or parsed, or does not match its hash
+    #[error("object {object} is corrupt: {reason}")]
+    Corrupt { object: ObjectRef, reason: String },
+    /// The operation stopped before changing anything to keep work from being lost, like
+    /// local changes a checkout would overwrite. Git reports these as errors, not fatal ones.
+    #[error("{0}")]
+    Refused(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// Any other failure, which can only be reported
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Error {
+        // Keep errors typed when they were passed through an `anyhow::Result`
+        match error.downcast::<Error>() {
+            Ok(error) => error,
+            Err(error) => match error.downcast::<std::io::Error>() {
+                Ok(error) => Error::Io(error),
+                Err(error) => Error::Other(error),
+            },
+        }
+    }
+}
@@ -0,0 +1,879 @@
+//! Verifying the object database: integrity of every object and connectivity from the refs.
+
+use crate::{hash::ObjectFormat, odb::PackDatabase, pack::Pack, refs::Head, ObjectRef, Repository};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+/// Bits of the exit code, like git uses them
+pub const ERROR_OBJECT: i32 = 0o1;
+pub const ERROR_REACHABLE: i32 = 0o2;
+pub const ERROR_PACK: i32 = 0o4;
+pub const ERROR_REFS: i32 = 0o10;
+
+#[derive(Debug, Clone)]
+pub struct FsckOptions {
+    /// Only check reachability, do not rehash or validate object contents
+    pub connectivity_only: bool,
+    /// Treat objects referenced by reflogs as reachable
+    pub reflogs: bool,
+    /// Report all unreachable objects, not only dangling ones
+    pub unreachable: bool,
+    pub dangling: bool,
+    /// Report commits without parents
+    pub root: bool,
+    /// Write dangling objects to `.git/lost-found`
+    pub lost_found: bool,
+}
+
+impl Default for FsckOptions {
+    fn default() -> Self {
+        FsckOptions {
+            connectivity_only: false,
+            reflogs: true,
+            unreachable: false,
+            dangling: true,
+            root: false,
+            lost_found: false,
+        }
+    }
+}
+
+/// A line of output of [`Repository::fsck`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckLine {
+    /// An error, warning or notice, git prints these to stderr
+    Diagnostic(String),
+    /// A root commit or a broken, missing, unreachable or dangling object
+    Object(String),
+}
+
+/// What [`Repository::fsck`] found, in the order it was found
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub lines: Vec<FsckLine>,
+    /// A combination of the `ERROR_*` bits, zero if the repository is fine
+    pub errors: i32,
+}
+
+/// A problem found in the contents of an object, warnings do not fail the check
+#[derive(Debug)]
+struct Problem {
+    error: bool,
+    id: &'static str,
+    message: String,
+}
+
+impl Problem {
+    fn error(id: &'static str, message: &str) -> Problem {
+        Problem {
+            error: true,
+            id,
+            message: message.to_owned(),
+        }
+    }
+
+    fn warning(id: &'static str, message: &str) -> Problem {
+        Problem {
+            error: false,
+            id,
+            message: message.to_owned(),
+        }
+    }
+}
+
+/// An object together with its (expected) type
+type Link = (&'static str, ObjectRef);
+
+/// What is remembered about a valid object: its type and the objects it refers to
+struct Checked {
+    kind: &'static str,
+    links: Vec<Link>,
+}
+
+fn parse_kind(kind: &[u8]) -> Option<&'static str> {
+    match kind {
+        b"blob" => Some("blob"),
+        b"tree" => Some("tree"),
+        b"commit" => Some("commit"),
+        b"tag" => Some("tag"),
+        _ => None,
+    }
+}
+
+/// Parses a hex object name of `format` followed by a newline at the start of `data`.
+fn parse_hash_line(data: &[u8], format: ObjectFormat) -> Option<(ObjectRef, &[u8])> {
+    let hex_len = format.hex_len();
+    let hash = std::str::from_utf8(data.get(..hex_len)?).ok()?;
+    if data.get(hex_len) != Some(&b'\n')
+        || !hash
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    {
+        return None;
+    }
+    Some((ObjectRef::from_hex(hash).ok()?, &data[hex_len + 1..]))
+}
+
+/// Headers of commits and tags must not contain NUL bytes and must end with a newline.
+fn verify_headers(data: &[u8]) -> Result<(), Problem> {
+    for (i, byte) in data.iter().enumerate() {
+        match byte {
+            b'\0' => {
+                return Err(Problem::error(
+                    "nulInHeader",
+                    &format!("unterminated header: NUL at offset {i}"),
+                ))
+            }
+            b'\n' if data.get(i + 1) == Some(&b'\n') => return Ok(()),
+            _ => {}
+        }
+    }
+    if data.last() == Some(&b'\n') {
+        Ok(())
+    } else {
+        Err(Problem::error("unterminatedHeader", "unterminated header"))
+    }
+}
+
+/// Checks an author, committer or tagger line after its keyword, returns the rest of the data.
+fn check_ident(data: &[u8]) -> Result<&[u8], Problem> {
+    let end = data.iter().position(|b| *b == b'\n').unwrap_or(data.len());
+    let (line, rest) = (&data[..end], &data[(end + 1).min(data.len())..]);
+    let bad = |id, what| {
+        Err(Problem::error(
+            id,
+            &format!("invalid author/committer line - {what}"),
+        ))
+    };
+
+    if line.first() == Some(&b'<') {
+        return bad("missingNameBeforeEmail", "missing space before email");
+    }
+    let p = line
+        .iter()
+        .position(|b| *b == b'<' || *b == b'>')
+        .unwrap_or(line.len());
+    match line.get(p) {
+        Some(b'>') => return bad("badName", "bad name"),
+        Some(b'<') => {}
+        _ => return bad("missingEmail", "missing email"),
+    }
+    if line[p - 1] != b' ' {
+        return bad("missingSpaceBeforeEmail", "missing space before email");
+    }
+    let p = p
+        + 1
+        + line[p + 1..]
+            .iter()
+            .position(|b| *b == b'<' || *b == b'>')
+            .unwrap_or(line.len() - p - 1);
+    if line.get(p) != Some(&b'>') {
+        return bad("badEmail", "bad email");
+    }
+    if line.get(p + 1) != Some(&b' ') {
+        return bad("missingSpaceBeforeDate", "missing space before date");
+    }
+    let date = &line[p + 2..];
+    if date.first() == Some(&b'0') && date.get(1) != Some(&b' ') {
+        return bad("zeroPaddedDate", "zero-padded date");
+    }
+    let digits = date.iter().take_while(|b| b.is_ascii_digit()).count();
+    if digits == 0 || date.get(digits) != Some(&b' ') {
+        return bad("badDate", "bad date");
+    }
+    if std::str::from_utf8(&date[..digits])
+        .ok()
+        .and_then(|d| d.parse::<i64>().ok())
+        .is_none()
+    {
+        return bad("badDateOverflow", "date causes integer overflow");
+    }
+    let zone = &date[digits + 1..];
+    if zone.len() != 5
+        || !matches!(zone[0], b'+' | b'-')
+        || !zone[1..].iter().all(u8::is_ascii_digit)
+    {
+        return bad("badTimezone", "bad time zone");
+    }
+    Ok(rest)
+}
+
+fn check_commit(data: &[u8], format: ObjectFormat, links: &mut Vec<Link>) -> Result<(), Problem> {
+    verify_headers(data)?;
+    let rest = data
+        .strip_prefix(b"tree ")
+        .ok_or_else(|| Problem::error("missingTree", "invalid format - expected 'tree' line"))?;
+    let (tree, mut rest) = parse_hash_line(rest, format)
+        .ok_or_else(|| Problem::error("badTreeSha1", "invalid 'tree' line format - bad sha1"))?;
+    links.push(("tree", tree));
+    while let Some(parent) = rest.strip_prefix(b"parent ") {
+        let (parent, next) = parse_hash_line(parent, format).ok_or_else(|| {
+            Problem::error("badParentSha1", "invalid 'parent' line format - bad sha1")
+        })?;
+        links.push(("commit", parent));
+        rest = next;
+    }
+    let mut authors = 0;
+    while let Some(author) = rest.strip_prefix(b"author ") {
+        authors += 1;
+        rest = check_ident(author)?;
+    }
+    if authors == 0 {
+        return Err(Problem::error(
+            "missingAuthor",
+            "invalid format - expected 'author' line",
+        ));
+    } else if authors > 1 {
+        return Err(Problem::error(
+            "multipleAuthors",
+            "invalid format - multiple 'author' lines",
+        ));
+    }
+    let committer = rest.strip_prefix(b"committer ").ok_or_else(|| {
+        Problem::error(
+            "missingCommitter",
+            "invalid format - expected 'committer' line",
+        )
+    })?;
+    check_ident(committer)?;
+    Ok(())
+}
+
+fn check_tag(data: &[u8], format: ObjectFormat, links: &mut Vec<Link>) -> Result<(), Problem> {
+    verify_headers(data)?;
+    let rest = data.strip_prefix(b"object ").ok_or_else(|| {
+        Problem::error("missingObject", "invalid format - expected 'object' line")
+    })?;
+    let (object, rest) = parse_hash_line(rest, format).ok_or_else(|| {
+        Problem::error("badObjectSha1", "invalid 'object' line format - bad sha1")
+    })?;
+    let rest = rest.strip_prefix(b"type ").ok_or_else(|| {
+        Problem::error("missingTypeEntry", "invalid format - expected 'type' line")
+    })?;
+    let end = rest.iter().position(|b| *b == b'\n').ok_or_else(|| {
+        Problem::error(
+            "missingType",
+            "invalid format - unexpected end after 'type' line",
+        )
+    })?;
+    let kind = parse_kind(&rest[..end])
+        .ok_or_else(|| Problem::error("badType", "invalid 'type' value"))?;
+    links.push((kind, object));
+    let rest = rest[end + 1..]
+        .strip_prefix(b"tag ")
+        .ok_or_else(|| Problem::error("missingTagEntry", "invalid format - expected 'tag' line"))?;
+    let end = rest.iter().position(|b| *b == b'\n').ok_or_else(|| {
+        Problem::error(
+            "missingTag",
+            "invalid format - unexpected end after 'type' line",
+        )
+    })?;
+    match rest[end + 1..].strip_prefix(b"tagger ") {
+        Some(tagger) => check_ident(tagger).map(|_| ()),
+        // Early tags do not have a tagger, this is only worth a warning
+        None => Err(Problem::warning(
+            "missingTaggerEntry",
+            "invalid format - expected 'tagger' line",
+        )),
+    }
+}
+
+/// Tree problems are collected over all entries and reported once each, in this order.
+const TREE_PROBLEMS: &[(&str, bool, &str)] = &[
+    ("nullSha1", false, "contains entries pointing to null sha1"),
+    ("fullPathname", false, "contains full pathnames"),
+    ("emptyName", false, "contains empty pathname"),
+    ("hasDot", false, "contains '.'"),
+    ("hasDotdot", false, "contains '..'"),
+    ("hasDotgit", false, "contains '.git'"),
+    (
+        "zeroPaddedFilemode",
+        false,
+        "contains zero-padded file modes",
+    ),
+    ("badFilemode", false, "contains bad file modes"),
+    ("duplicateEntries", true, "contains duplicate file entries"),
+    ("treeNotSorted", true, "not properly sorted"),
+];
+
+fn check_tree(data: &[u8], format: ObjectFormat, links: &mut Vec<Link>) -> Vec<Problem> {
+    let mut found = HashSet::new();
+    let mut names = HashSet::new();
+    let mut previous: Option<Vec<u8>> = None;
+    let mut rest = data;
+    while !rest.is_empty() {
+        let entry = rest.iter().position(|b| *b == b' ').and_then(|space| {
+            let name_end = space + 1 + rest[space + 1..].iter().position(|b| *b == 0)?;
+            let hash_end = name_end + 1 + format.raw_len();
+            let hash = rest.get(name_end + 1..hash_end)?;
+            let mode = &rest[..space];
+            if mode.is_empty() || !mode.iter().all(|b| (b'0'..=b'7').contains(b)) {
+                return None;
+            }
+            Some((mode, &rest[space + 1..name_end], hash, hash_end))
+        });
+        let Some((mode_str, name, hash, length)) = entry else {
+            return vec![Problem::error("badTree", "cannot be parsed as a tree")];
+        };
+        rest = &rest[length..];
+        let mode = u32::from_str_radix(std::str::from_utf8(mode_str).unwrap(), 8).unwrap_or(0);
+
+        if hash.iter().all(|b| *b == 0) {
+            found.insert("nullSha1");
+        }
+        if name.contains(&b'/') {
+            found.insert("fullPathname");
+        }
+        match name {
+            b"" => found.insert("emptyName"),
+            b"." => found.insert("hasDot"),
+            b".." => found.insert("hasDotdot"),
+            name if name.eq_ignore_ascii_case(b".git") => found.insert("hasDotgit"),
+            _ => false,
+        };
+        if mode_str[0] == b'0' {
+            found.insert("zeroPaddedFilemode");
+        }
+        if !matches!(
+            mode,
+            0o100755 | 0o100644 | 0o100664 | 0o120000 | 0o040000 | 0o160000
+        ) {
+            found.insert("badFilemode");
+        }
+
+        if !names.insert(name) {
+            found.insert("duplicateEntries");
+        }
+        let mut key = name.to_vec();
+        if mode == 0o040000 {
+            key.push(b'/');
+        }
+        if previous.as_ref().is_some_and(|previous| *previous > key) {
+            found.insert("treeNotSorted");
+        }
+        previous = Some(key);
+
+        match mode {
+            0o040000 => links.push(("tree", ObjectRef::from_hash(hash))),
+            // Submodule commits live in another repository
+            0o160000 => {}
+            _ => links.push(("blob", ObjectRef::from_hash(hash))),
+        }
+    }
+    TREE_PROBLEMS
+        .iter()
+        .filter(|(id, ..)| found.contains(id))
+        .map(|&(id, error, message)| Problem {
+            error,
+            id,
+            message: message.to_owned(),
+        })
+        .collect()
+}
+
+/// Validates the contents of an object and collects the objects it refers to.
+fn check_object(
+    kind: &'static str,
+    data: &[u8],
+    format: ObjectFormat,
+    validate: bool,
+) -> (Checked, Vec<Problem>) {
+    let mut links = Vec::new();
+    let problems = match kind {
+        "tree" => check_tree(data, format, &mut links),
+        "commit" => check_commit(data, format, &mut links)
+            .err()
+            .into_iter()
+            .collect(),
+        "tag" => check_tag(data, format, &mut links)
+            .err()
+            .into_iter()
+            .collect(),
+        _ => Vec::new(),
+    };
+    let problems = if validate { problems } else { Vec::new() };
+    (Checked { kind, links }, problems)
+}
+
+/// Checks the contents of an object about to be written to a repository with objects named
+/// by `format`, failing on the first error.
+pub fn validate_object(kind: &str, data: &[u8], format: ObjectFormat) -> anyhow::Result<()> {
+    let kind = parse_kind(kind.as_bytes())
+        .ok_or_else(|| anyhow::Error::msg(format!("invalid object type \"{kind}\"")))?;
+    let (_, problems) = check_object(kind, data, format, true);
+    match problems.into_iter().find(|problem| problem.error) {
+        Some(problem) => Err(anyhow::Error::msg(format!(
+            "object fails fsck: {}: {}",
+            problem.id, problem.message
+        ))
+        .context("refusing to create malformed object")),
+        None => Ok(()),
+    }
+}
+
+struct Fsck<'r> {
+    repo: &'r Repository,
+    opts: &'r FsckOptions,
+    format: ObjectFormat,
+    objects: HashMap<ObjectRef, Checked>,
+    /// Types objects were first seen with, to detect links of the wrong type
+    kinds: HashMap<ObjectRef, &'static str>,
+    root_commits: Vec<ObjectRef>,
+    report: FsckReport,
+}
+
+impl Fsck<'_> {
+    fn diagnostic(&mut self, line: String) {
+        self.report.lines.push(FsckLine::Diagnostic(line));
+    }
+
+    fn object(&mut self, line: String) {
+        self.report.lines.push(FsckLine::Object(line));
+    }
+
+    fn record(&mut self, object_ref: ObjectRef, kind: &'static str, data: &[u8]) {
+        let (checked, problems) =
+            check_object(kind, data, self.format, !self.opts.connectivity_only);
+        // The first time an object is seen, either itself or as a link, decides its type
+        if let Some(seen) = self
+            .kinds
+            .insert(object_ref.clone(), kind)
+            .filter(|seen| *seen != kind)
+        {
+            self.diagnostic(format!(
+                "error: object {} is a {seen}, not a {kind}",
+                object_ref.0
+            ));
+        }
+        let mut broken = false;
+        for (link_kind, link) in &checked.links {
+            let seen = *self.kinds.entry(link.clone()).or_insert(link_kind);
+            if seen != *link_kind {
+                self.diagnostic(format!(
+                    "error: object {} is a {seen}, not a {link_kind}",
+                    link.0
+                ));
+                broken = true;
+            }
+        }
+        if broken && !self.opts.connectivity_only {
+            self.diagnostic(format!("error in {kind} {}: broken links", object_ref.0));
+            self.report.errors |= ERROR_OBJECT;
+        }
+        for problem in problems {
+            if problem.error {
+                self.diagnostic(format!(
+                    "error in {kind} {}: {}: {}",
+                    object_ref.0, problem.id, problem.message
+                ));
+                self.report.errors |= ERROR_OBJECT;
+            } else {
+                self.diagnostic(format!(
+                    "warning in {kind} {}: {}: {}",
+                    object_ref.0, problem.id, problem.message
+                ));
+            }
+        }
+        if self.opts.root && kind == "commit" && !checked.links.iter().any(|(k, _)| *k == "commit")
+        {
+            self.root_commits.push(object_ref.clone());
+        }
+        self.objects.insert(object_ref, checked);
+    }
+
+    fn check_loose(&mut self, object_ref: ObjectRef, path: &Path) {
+        let contents = fs::read(path).and_then(|compressed| {
+            let mut contents = Vec::new();
+            flate2::read::ZlibDecoder::new(&compressed[..]).read_to_end(&mut contents)?;
+            Ok(contents)
+        });
+        let parsed = match &contents {
+            Ok(contents) => parse_loose(contents),
+            Err(e) => Err(format!("unable to unpack {}: {e}", display_path(path))),
+        };
+        match parsed {
+            Ok((kind, data)) => {
+                if !self.opts.connectivity_only {
+                    let hash = match self.format.digest(contents.as_ref().unwrap()) {
+                        Ok(hash) => hex::encode(hash),
+                        Err(e) => {
+                            self.diagnostic(format!("error: {e}"));
+                            self.report.errors |= ERROR_OBJECT;
+                            return;
+                        }
+                    };
+                    if hash != object_ref.0 {
+                        self.diagnostic(format!(
+                            "error: {hash}: hash-path mismatch, found at: {}",
+                            display_path(path)
+                        ));
+                        self.report.errors |= ERROR_OBJECT;
+                        return;
+                    }
+                }
+                self.record(object_ref, kind, data);
+            }
+            Err(message) => {
+                self.diagnostic(format!("error: {message}"));
+                self.diagnostic(format!(
+                    "error: {}: object corrupt or missing: {}",
+                    object_ref.0,
+                    display_path(path)
+                ));
+                self.report.errors |= ERROR_OBJECT;
+            }
+        }
+    }
+
+    fn check_pack(&mut self, pack: &Pack) {
+        if !self.opts.connectivity_only {
+            if let Err(e) = pack.index.verify().and_then(|_| pack.verify_checksum()) {
+                self.diagnostic(format!("error: {e} for {}", display_path(&pack.pack_path)));
+                self.report.errors |= ERROR_PACK;
+            }
+        }
+        for i in 0..pack.index.len() {
+            let object_ref = ObjectRef::from_hash(&pack.index.hash(i));
+            if self.objects.contains_key(&object_ref) {
+                continue;
+            }
+            let offset = match pack.index.offset(i) {
+                Ok(offset) => offset,
+                Err(e) => {
+                    self.diagnostic(format!(
+                        "error: cannot unpack {} from {}: {e}",
+                        object_ref.0,
+                        display_path(&pack.pack_path)
+                    ));
+                    self.report.errors |= ERROR_PACK;
+                    continue;
+                }
+            };
+            match pack.read_at(offset) {
+                Ok((kind, data)) => {
+                    if !self.opts.connectivity_only {
+                        let mut hasher = self.format.hasher();
+                        hasher.update(format!("{kind} {}\0", data.len()).as_bytes());
+                        hasher.update(&data);
+                        let hash = hasher.finalize();
+                        if let Err(e) = &hash {
+                            self.diagnostic(format!("error: {e}"));
+                        }
+                        if hash.map_or(true, |hash| hex::encode(hash) != object_ref.0) {
+                            self.diagnostic(format!(
+                                "error: packed {} from {} is corrupt",
+                                object_ref.0,
+                                display_path(&pack.pack_path)
+                            ));
+                            self.report.errors |= ERROR_PACK;
+                            continue;
+                        }
+                    }
+                    self.record(object_ref, kind, &data);
+                }
+                Err(e) => {
+                    self.diagnostic(format!(
+                        "error: cannot unpack {} from {} at offset {offset}: {e}",
+                        object_ref.0,
+                        display_path(&pack.pack_path)
+                    ));
+                    self.report.errors |= ERROR_PACK;
+                }
+            }
+        }
+    }
+
+    /// A ref or reflog entry naming an object that does not exist is an error of its own.
+    fn check_ref_target(&mut self, name: &str, target: &ObjectRef, what: &str) -> bool {
+        if self.objects.contains_key(target) {
+            return true;
+        }
+        self.diagnostic(format!("error: {name}: {what} {}", target.0));
+        self.report.errors |= ERROR_REFS;
+        false
+    }
+
+    /// Objects to start the connectivity walk from: refs, HEAD, reflogs and the index, and
+    /// the HEAD and index of other worktrees.
+    fn reachability_roots(&mut self) -> anyhow::Result<Vec<Link>> {
+        let mut roots = Vec::new();
+        let refs = self.repo.list_refs("refs/")?;
+        for (name, target) in &refs {
+            if self.check_ref_target(name, target, "invalid sha1 pointer") {
+                roots.push(("object", target.clone()));
+            }
+        }
+        match self.repo.head()? {
+            Head::Detached(target) => {
+                if self.check_ref_target("HEAD", &target, "invalid sha1 pointer") {
+                    roots.push(("object", target));
+                }
+            }
+            Head::Branch(name) => {
+                if self.repo.read_ref(&name)?.is_none() {
+                    let branch = name.strip_prefix("refs/heads/").unwrap_or(&name);
+                    self.diagnostic(format!(
+                        "notice: HEAD points to an unborn branch ({branch})"
+                    ));
+                }
+            }
+        }
+        if refs.is_empty() {
+            self.diagnostic("notice: No default references".to_owned());
+        }
+
+        if self.opts.reflogs {
+            for (name, log) in self.repo.reflog_files()? {
+                for line in log.lines() {
+                    for hash in line.split(' ').take(2) {
+                        let Ok(target) = ObjectRef::from_hex(hash) else {
+                            continue;
+                        };
+                        if !target.to_bytes().is_null()
+                            && self.check_ref_target(&name, &target, "invalid reflog entry")
+                        {
+                            roots.push(("object", target));
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut indexes: Vec<_> = self.repo.read_index()?.into_iter().collect();
+        for worktree in self.repo.other_worktrees()? {
+            if let Some(target) = worktree.head_commit()? {
+                let name = match worktree.git_dir().file_name() {
+                    _ if worktree.git_dir() == worktree.common_dir() => {
+                        "main-worktree/HEAD".to_owned()
+                    }
+                    Some(name) => format!("worktrees/{}/HEAD", name.to_string_lossy()),
+                    None => "HEAD".to_owned(),
+                };
+                if self.check_ref_target(&name, &target, "invalid sha1 pointer") {
+                    roots.push(("object", target));
+                }
+            }
+            indexes.extend(worktree.read_index()?);
+        }
+        for index in indexes {
+            for entry in index.entries() {
+                if entry.mode != 0o160000 {
+                    roots.push(("blob", ObjectRef::from_hash(&entry.hash)));
+                }
+            }
+        }
+        Ok(roots)
+    }
+
+    fn check_connectivity(&mut self) -> anyhow::Result<()> {
+        let roots = self.reachability_roots()?;
+        // Expected kind of objects that do not exist, for reporting them as missing
+        let mut missing: HashMap<ObjectRef, &'static str> = HashMap::new();
+        let mut reachable = HashSet::new();
+        // Objects to visit, with the object referring to them
+        let mut pending: Vec<(Option<Link>, Link)> =
+            roots.into_iter().map(|root| (None, root)).collect();
+        while let Some((parent, (kind, object_ref))) = pending.pop() {
+            if !reachable.insert(object_ref.clone()) {
+                continue;
+            }
+            let Some(checked) = self.objects.get(&object_ref) else {
+                if let Some((parent_kind, parent)) = parent {
+                    self.object(format!(
+                        "broken link from {parent_kind:>7} {}\n              to {kind:>7} {}",
+                        parent.0, object_ref.0
+                    ));
+                }
+                missing.insert(object_ref, kind);
+                self.report.errors |= ERROR_REACHABLE;
+                continue;
+            };
+            for link in &checked.links {
+                pending.push((Some((checked.kind, object_ref.clone())), link.clone()));
+            }
+        }
+
+        let mut missing: Vec<_> = missing.into_iter().collect();
+        missing.sort();
+        for (object_ref, kind) in missing {
+            self.object(format!("missing {kind} {}", object_ref.0));
+        }
+
+        // Unreachable objects that no other object refers to are dangling
+        let used: HashSet<&ObjectRef> = self
+            .objects
+            .values()
+            .flat_map(|checked| checked.links.iter().map(|(_, link)| link))
+            .collect();
+        let mut unreachable: Vec<_> = self
+            .objects
+            .iter()
+            .filter(|(object_ref, _)| !reachable.contains(*object_ref))
+            .collect();
+        unreachable.sort_by(|a, b| a.0.cmp(b.0));
+        for (object_ref, checked) in unreachable {
+            if self.opts.unreachable {
+                self.report.lines.push(FsckLine::Object(format!(
+                    "unreachable {} {}",
+                    checked.kind, object_ref.0
+                )));
+                continue;
+            }
+            if used.contains(object_ref) {
+                continue;
+            }
+            if self.opts.dangling {
+                self.report.lines.push(FsckLine::Object(format!(
+                    "dangling {} {}",
+                    checked.kind, object_ref.0
+                )));
+            }
+            if self.opts.lost_found {
+                self.repo.write_lost_found(object_ref, checked.kind)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Paths are shown relative to the current directory when possible, like git does.
+fn display_path(path: &Path) -> String {
+    std::env::current_dir()
+        .ok()
+        .and_then(|dir| path.strip_prefix(dir).ok())
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+
+/// Splits an inflated loose object into its type and contents, checking the size.
+fn parse_loose(contents: &[u8]) -> Result<(&'static str, &[u8]), String> {
+    let header_end = contents
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or("unable to unpack header")?;
+    let header = &contents[..header_end];
+    let space = header
+        .iter()
+        .position(|b| *b == b' ')
+        .ok_or("unable to parse header")?;
+    let kind = parse_kind(&header[..space]).ok_or_else(|| {
+        format!(
+            "invalid object type \"{}\"",
+            String::from_utf8_lossy(&header[..space])
+        )
+    })?;
+    let size: usize = std::str::from_utf8(&header[space + 1..])
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .ok_or("unable to parse header")?;
+    let data = &contents[header_end + 1..];
+    if data.len() != size {
+        return Err(format!(
+            "object has size {} but its header says {size}",
+            data.len()
+        ));
+    }
+    Ok((kind, data))
+}
+
+/// Lists the loose objects in an object directory with the path of their file.
+fn loose_objects_in(objects_dir: &Path) -> anyhow::Result<Vec<(ObjectRef, PathBuf)>> {
+    let mut objects = Vec::new();
+    for dir in fs::read_dir(objects_dir)? {
+        let dir = dir?;
+        let prefix = dir.file_name();
+        let Some(prefix) = prefix.to_str() else {
+            continue;
+        };
+        if prefix.len() != 2 || !dir.file_type()?.is_dir() {
+            continue;
+        }
+        for file in fs::read_dir(dir.path())? {
+            let file = file?;
+            let name = file.file_name();
+            if let Ok(object_ref) =
+                ObjectRef::from_hex(&format!("{prefix}{}", name.to_string_lossy()))
+            {
+                objects.push((object_ref, file.path()));
+            }
+        }
+    }
+    objects.sort();
+    Ok(objects)
+}
+
+impl Repository {
+    /// Lists loose objects with the path of their file.
+    pub fn loose_objects(&self) -> anyhow::Result<Vec<(ObjectRef, PathBuf)>> {
+        loose_objects_in(&self.common_dir.join("objects"))
+    }
+
+    /// Reads all reflogs below `logs/`, with the name of their ref.
+    pub fn reflog_files(&self) -> anyhow::Result<Vec<(String, String)>> {
+        let mut files = Vec::new();
+        for (name, path) in self.ref_files("logs", "")? {
+            files.push((name, fs::read_to_string(&path)?));
+        }
+        Ok(files)
+    }
+
+    /// Saves a dangling object below `lost-found`: commits by name, other objects by
+    /// contents for blobs and by name otherwise.
+    fn write_lost_found(&self, object_ref: &ObjectRef, kind: &str) -> anyhow::Result<()> {
+        let dir = self
+            .common_dir
+            .join("lost-found")
+            .join(if kind == "commit" { "commit" } else { "other" });
+        fs::create_dir_all(&dir)?;
+        let contents = if kind == "blob" {
+            self.read_blob(object_ref)?
+        } else {
+            format!("{}\n", object_ref.0).into_bytes()
+        };
+        fs::write(dir.join(&object_ref.0), contents)?;
+        Ok(())
+    }
+
+    /// Checks all objects and their connectivity, reporting problems in the order they are
+    /// found.
+    pub fn fsck(&self, opts: &FsckOptions) -> anyhow::Result<FsckReport> {
+        let mut fsck = Fsck {
+            repo: self,
+            opts,
+            format: self.object_format()?,
+            objects: HashMap::new(),
+            kinds: HashMap::new(),
+            root_commits: Vec::new(),
+            report: FsckReport::default(),
+        };
+        for (object_ref, path) in self.loose_objects()? {
+            fsck.check_loose(object_ref, &path);
+        }
+        for pack in self.packs()?.iter() {
+            fsck.check_pack(pack);
+        }
+        for dir in self.alternate_object_dirs()? {
+            for (object_ref, path) in loose_objects_in(&dir)? {
+                if !fsck.objects.contains_key(&object_ref) {
+                    fsck.check_loose(object_ref, &path);
+                }
+            }
+            let packs = PackDatabase::new(dir.join("pack"), fsck.format).packs()?;
+            for pack in packs.iter() {
+                fsck.check_pack(pack);
+            }
+        }
+        let mut roots = std::mem::take(&mut fsck.root_commits);
+        roots.sort();
+        for root in roots {
+            fsck.object(format!("root {}", root.0));
+        }
+        fsck.check_connectivity()?;
+        Ok(fsck.report)
+    }
+}
@@ -0,0 +1,566 @@
+//! Housekeeping: packing objects and refs, pruning unreachable objects and expiring reflogs.
+
+use crate::{
+    odb::ObjectDatabase,
+    pack::{self, PackObject, PackOptions},
+    refs, Error, Object, ObjectRef, Repository, TreeData,
+};
+use anyhow::Context;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Parses dates like `2.weeks.ago`, `yesterday`, `2024-01-31 12:00`, `now`, `never` or a
+/// timestamp into a point in time before which things expire. `None` means nothing expires.
+/// Calendar dates are taken as UTC, at the current time of day if none is given like git does.
+pub fn parse_expiry(value: &str, now: u64) -> anyhow::Result<Option<u64>> {
+    let value = value.trim();
+    match value {
+        "never" | "false" => return Ok(None),
+        "now" | "all" => return Ok(Some(now)),
+        "yesterday" => return Ok(Some(now.saturating_sub(24 * 60 * 60))),
+        _ => {}
+    }
+    if let Ok(timestamp) = value.trim_start_matches('@').parse::<u64>() {
+        return Ok(Some(timestamp));
+    }
+    if let Some(timestamp) = parse_calendar_date(value, now) {
+        return Ok(Some(timestamp));
+    }
+    let words: Vec<&str> = value
+        .split(|c: char| c == '.' || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .collect();
+    let (amount, unit) = match words.as_slice() {
+        [amount, unit] | [amount, unit, "ago"] => (*amount, *unit),
+        _ => anyhow::bail!("Invalid expiry date '{value}'"),
+    };
+    let amount: u64 = amount
+        .parse()
+        .with_context(|| format!("Invalid expiry date '{value}'"))?;
+    let seconds = match unit.trim_end_matches('s') {
+        "second" => 1,
+        "minute" => 60,
+        "hour" => 60 * 60,
+        "day" => 24 * 60 * 60,
+        "week" => 7 * 24 * 60 * 60,
+        "month" => 30 * 24 * 60 * 60,
+        "year" => 365 * 24 * 60 * 60,
+        _ => anyhow::bail!("Invalid expiry date '{value}'"),
+    };
+    Ok(Some(now.saturating_sub(amount * seconds)))
+}
+
+/// Parses `YYYY-MM-DD`, optionally followed by `HH:MM[:SS]` after a space or `T`.
+fn parse_calendar_date(value: &str, now: u64) -> Option<u64> {
+    let (date, time) = match value.split_once([' ', 'T']) {
+        Some((date, time)) => (date, Some(time.trim())),
+        None => (value, None),
+    };
+    let mut date = date.split('-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    if date.next().is_some() || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let mut seconds = (now % 86400) as i64;
+    if let Some(time) = time {
+        let parts = time
+            .split(':')
+            .map(|part| part.parse::<i64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        let (hour, minute, second) = match parts.as_slice() {
+            [hour, minute] => (*hour, *minute, 0),
+            [hour, minute, second] => (*hour, *minute, *second),
+            _ => return None,
+        };
+        seconds = hour * 3600 + minute * 60 + second;
+    }
+    // Days since the epoch from a civil date, see Howard Hinnant's date algorithms
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    u64::try_from(days * 86400 + seconds).ok()
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn mtime(path: &std::path::Path) -> anyhow::Result<u64> {
+    Ok(fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0))
+}
+
+fn static_kind(kind: &str) -> anyhow::Result<&'static str> {
+    Ok(match kind {
+        "commit" => "commit",
+        "tree" => "tree",
+        "blob" => "blob",
+        "tag" => "tag",
+        _ => anyhow::bail!("Unknown object type {kind}"),
+    })
+}
+
+/// Objects a commit or tag refers to, from the header lines naming them.
+fn header_links(data: &[u8], keys: &[&str]) -> anyhow::Result<Vec<ObjectRef>> {
+    let text = String::from_utf8_lossy(data);
+    let mut links = Vec::new();
+    for line in text.lines().take_while(|line| !line.is_empty()) {
+        if let Some((key, value)) = line.split_once(' ') {
+            if keys.contains(&key) {
+                links.push(ObjectRef::from_hex(value)?);
+            }
+        }
+    }
+    Ok(links)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RepackOptions {
+    /// Pack all reachable objects instead of only loose ones
+    pub all: bool,
+    /// Remove packs and loose objects made redundant by the new pack
+    pub delete: bool,
+    /// With `all` and `delete`, unreachable objects of removed packs which are not older
+    /// than this are kept as loose objects. Otherwise they are dropped.
+    pub unpack_unreachable: Option<u64>,
+    pub pack: PackOptions,
+}
+
+/// What a repack did
+pub struct Repacked {
+    pub name: String,
+    pub objects: usize,
+    pub deltas: usize,
+}
+
+impl Repository {
+    /// Reference tips, `HEAD`, reflog entries and index entries, also those of other
+    /// worktrees: everything that keeps objects alive. Index entries come with their path.
+    /// Reflog entries pointing to objects that no longer exist are skipped, like git does.
+    fn object_roots(&self) -> anyhow::Result<Vec<(ObjectRef, String)>> {
+        let mut roots: Vec<(ObjectRef, String)> = self
+            .list_refs("refs/")?
+            .into_iter()
+            .map(|(_, target)| (target, String::new()))
+            .collect();
+        roots.extend(self.head_commit()?.map(|head| (head, String::new())));
+        for (_, log) in self.reflog_files()? {
+            for line in log.lines() {
+                for hash in line.split(' ').take(2) {
+                    let Ok(target) = ObjectRef::from_hex(hash) else {
+                        continue;
+                    };
+                    if target.0.bytes().any(|b| b != b'0') && self.has_object(&target)? {
+                        roots.push((target, String::new()));
+                    }
+                }
+            }
+        }
+        let mut indexes: Vec<_> = self.read_index()?.into_iter().collect();
+        // What other worktrees have checked out or staged is kept as well
+        for worktree in self.other_worktrees()? {
+            roots.extend(worktree.head_commit()?.map(|head| (head, String::new())));
+            indexes.extend(worktree.read_index()?);
+        }
+        for index in indexes {
+            for entry in index.entries() {
+                if entry.mode != 0o160000 {
+                    roots.push((ObjectRef::from_hash(&entry.hash), entry.path.clone()));
+                }
+            }
+        }
+        Ok(roots)
+    }
+
+    /// Finds all reachable objects with the path trees and blobs were found at.
+    pub fn reachable_objects(&self) -> anyhow::Result<HashMap<ObjectRef, String>> {
+        let mut reachable = HashMap::new();
+        let mut pending = self.object_roots()?;
+        while let Some((object_ref, name)) = pending.pop() {
+            if reachable.contains_key(&object_ref) {
+                continue;
+            }
+            let (kind, data) = self
+                .read_raw_object(&object_ref)
+                .with_context(|| format!("Object {} is reachable", object_ref.0))?;
+            match kind.as_str() {
+                "commit" => pending.extend(
+                    header_links(&data, &["tree", "parent"])?
+                        .into_iter()
+                        .map(|link| (link, String::new())),
+                ),
+                "tag" => pending.extend(
+                    header_links(&data, &["object"])?
+                        .into_iter()
+                        .map(|link| (link, String::new())),
+                ),
+                "tree" => {
+                    let format = object_ref.format();
+                    for item in (TreeData { data, format }).iter() {
+                        // Submodule commits live in another repository
+                        if item.mode == 0o160000 {
+                            continue;
+                        }
+                        let path = if name.is_empty() {
+                            item.name.to_string()
+                        } else {
+                            format!("{name}/{}", item.name)
+                        };
+                        pending.push((ObjectRef::from_hash(&item.hash), path));
+                    }
+                }
+                _ => {}
+            }
+            reachable.insert(object_ref, name);
+        }
+        Ok(reachable)
+    }
+
+    /// Writes reachable objects into a new pack: all of them, or only loose ones. Objects
+    /// only found in alternates stay there. Returns `None` if there was nothing to pack.
+    pub fn repack(&self, opts: &RepackOptions) -> anyhow::Result<Option<Repacked>> {
+        let reachable = self.reachable_objects()?;
+        let loose: HashSet<ObjectRef> = self
+            .loose_objects()?
+            .into_iter()
+            .map(|(object_ref, _)| object_ref)
+            .collect();
+        let packed = self.pack_database()?;
+        let mut objects = Vec::new();
+        for (object_ref, name) in &reachable {
+            let local = loose.contains(object_ref) || (opts.all && packed.exists(object_ref)?);
+            if !local {
+                continue;
+            }
+            let (kind, size) = self
+                .read_object_header(object_ref)?
+                .ok_or_else(|| Error::NotFound(object_ref.clone()))?;
+            objects.push(PackObject {
+                hash: object_ref.to_bytes(),
+                kind: static_kind(&kind)?,
+                size,
+                name_hash: pack::name_hash(name),
+            });
+        }
+        if objects.is_empty() {
+            return Ok(None);
+        }
+        let old_packs = self.packs()?;
+        let count = objects.len();
+        let (name, deltas) = pack::write_pack(
+            self.object_format()?,
+            &self.pack_dir(),
+            objects,
+            &opts.pack,
+            |object| Ok(self.read_raw_object(&ObjectRef::from_hash(&object.hash))?.1),
+        )?;
+
+        if opts.delete {
+            if opts.all {
+                for old in old_packs.iter() {
+                    if old.pack_path.file_stem() == Some(format!("pack-{name}").as_ref())
+                        || old.pack_path.with_extension("keep").exists()
+                    {
+                        continue;
+                    }
+                    if let Some(expiry) = opts.unpack_unreachable {
+                        if mtime(&old.pack_path)? >= expiry {
+                            self.unpack_unreachable(old, &reachable, &loose)?;
+                        }
+                    }
+                    fs::remove_file(&old.pack_path)?;
+                    fs::remove_file(old.pack_path.with_extension("idx"))?;
+                }
+            }
+            self.prune_packed()?;
+        }
+        Ok(Some(Repacked {
+            name,
+            objects: count,
+            deltas,
+        }))
+    }
+
+    /// Writes objects of a pack that is about to be removed as loose objects, unless they
+    /// are reachable (and thus in the new pack) or already loose.
+    fn unpack_unreachable(
+        &self,
+        pack: &pack::Pack,
+        reachable: &HashMap<ObjectRef, String>,
+        loose: &HashSet<ObjectRef>,
+    ) -> anyhow::Result<()> {
+        for i in 0..pack.index.len() {
+            let object_ref = ObjectRef::from_hash(&pack.index.hash(i));
+            if reachable.contains_key(&object_ref) || loose.contains(&object_ref) {
+                continue;
+            }
+            let (kind, data) = pack.read_at(pack.index.offset(i)?)?;
+            self.save_loose_object(&Object::Unknown {
+                kind: kind.to_owned(),
+                data,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Removes loose objects which are also in a pack.
+    pub fn prune_packed(&self) -> anyhow::Result<usize> {
+        let packs = self.packs()?;
+        let mut removed = 0;
+        for (object_ref, path) in self.loose_objects()? {
+            let hash = object_ref.to_bytes();
+            if packs
+                .iter()
+                .any(|pack| pack.index.position(&hash).is_some())
+            {
+                fs::remove_file(&path)?;
+                removed += 1;
+                if let Some(dir) = path.parent() {
+                    // Fails while other objects are left in the directory
+                    let _ = fs::remove_dir(dir);
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Removes unreachable loose objects older than `expire`, returns their names and types.
+    /// Nothing is removed with `dry_run`.
+    pub fn prune(&self, expire: u64, dry_run: bool) -> anyhow::Result<Vec<(ObjectRef, String)>> {
+        let reachable = self.reachable_objects()?;
+        let mut pruned = Vec::new();
+        for (object_ref, path) in self.loose_objects()? {
+            if reachable.contains_key(&object_ref) || mtime(&path)? > expire {
+                continue;
+            }
+            let kind = match self.read_object_header(&object_ref) {
+                Ok(Some((kind, _))) => kind,
+                _ => "unknown".to_owned(),
+            };
+            if !dry_run {
+                fs::remove_file(&path)?;
+                if let Some(dir) = path.parent() {
+                    let _ = fs::remove_dir(dir);
+                }
+            }
+            pruned.push((object_ref, kind));
+        }
+        Ok(pruned)
+    }
+
+    /// Follows tags to the object they finally point to, `None` if `object` is no tag.
+    fn peel_tag(&self, object: &ObjectRef) -> anyhow::Result<Option<ObjectRef>> {
+        let mut current = object.clone();
+        loop {
+            let (kind, data) = self.read_raw_object(&current)?;
+            if kind != "tag" {
+                return Ok((current != *object).then_some(current));
+            }
+            current = header_links(&data, &["object"])?
+                .pop()
+                .ok_or_else(|| anyhow::Error::msg(format!("Invalid tag {}", current.0)))?;
+        }
+    }
+
+    /// Moves references into `packed-refs`: all of them, or only tags and references which
+    /// were already packed. Symbolic references stay loose.
+    pub fn pack_refs(&self, all: bool) -> anyhow::Result<()> {
+        let packed: HashSet<String> = self
+            .packed_refs()?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        let mut text = String::from("# pack-refs with: peeled fully-peeled sorted \n");
+        let mut loose = Vec::new();
+        for (name, target) in self.list_refs("refs/")? {
+            // References of a single worktree are never packed
+            if self.read_symbolic_ref(&name)?.is_some() || refs::is_per_worktree_ref(&name) {
+                continue;
+            }
+            if !all && !name.starts_with("refs/tags/") && !packed.contains(&name) {
+                continue;
+            }
+            text.push_str(&format!("{} {name}\n", target.0));
+            if let Some(peeled) = self.peel_tag(&target)? {
+                text.push_str(&format!("^{}\n", peeled.0));
+            }
+            loose.push((name, target));
+        }
+        self.write_file_atomically(&self.common_dir.join("packed-refs"), text.as_bytes())?;
+
+        for (name, target) in loose {
+            let path = self.common_dir.join(&name);
+            // Only remove loose refs which still have the value just packed
+            if fs::read_to_string(&path).is_ok_and(|contents| contents.trim() == target.0) {
+                fs::remove_file(&path)?;
+                let mut dir = path.parent().map(std::path::Path::to_path_buf);
+                while let Some(current) = dir {
+                    if current.parent() == Some(&self.common_dir.join("refs"))
+                        || fs::remove_dir(&current).is_err()
+                    {
+                        break;
+                    }
+                    dir = current.parent().map(std::path::Path::to_path_buf);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes reflog entries older than `expire`, and those older than `expire_unreachable`
+    /// whose commit is no longer reachable from the tip of the reference.
+    pub fn expire_reflogs(
+        &self,
+        expire: Option<u64>,
+        expire_unreachable: Option<u64>,
+    ) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        for (name, _) in self.ref_files("logs", "")? {
+            removed += self.expire_reflog(&name, expire, expire_unreachable, false)?;
+        }
+        Ok(removed)
+    }
+
+    /// Expires entries of the reflog of `name` like `expire_reflogs`, returning how many
+    /// were removed. Nothing is written on a `dry_run`.
+    pub fn expire_reflog(
+        &self,
+        name: &str,
+        expire: Option<u64>,
+        expire_unreachable: Option<u64>,
+        dry_run: bool,
+    ) -> anyhow::Result<usize> {
+        let path = self.reflog_path(name);
+        let log = match fs::read_to_string(&path) {
+            Ok(log) => log,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut removed = 0;
+        let tip = self.read_ref(name).ok().flatten();
+        let mut tip_history: Option<HashSet<ObjectRef>> = None;
+        let mut kept = String::with_capacity(log.len());
+        for line in log.lines() {
+            let timestamp = line
+                .split_once('\t')
+                .map_or(line, |(header, _)| header)
+                .rsplit(' ')
+                .nth(1)
+                .and_then(|timestamp| timestamp.parse::<u64>().ok())
+                .unwrap_or(0);
+            let mut keep = match expire {
+                Some(expire) => timestamp >= expire,
+                None => true,
+            };
+            if keep && expire_unreachable.is_some_and(|expire| timestamp < expire) {
+                let new = line.split(' ').nth(1).unwrap_or_default();
+                let history = match &mut tip_history {
+                    Some(history) => history,
+                    None => tip_history.insert(self.commit_history(tip.as_ref())?),
+                };
+                keep = ObjectRef::from_hex(new).is_ok_and(|new| history.contains(&new));
+            }
+            if keep {
+                kept.push_str(line);
+                kept.push('\n');
+            } else {
+                removed += 1;
+            }
+        }
+        if kept != log && !dry_run {
+            self.write_file_atomically(&path, kept.as_bytes())?;
+        }
+        Ok(removed)
+    }
+
+    /// All commits reachable from `tip`, missing commits end the walk quietly.
+    fn commit_history(&self, tip: Option<&ObjectRef>) -> anyhow::Result<HashSet<ObjectRef>> {
+        let mut history = HashSet::new();
+        let mut pending: Vec<ObjectRef> = tip.into_iter().cloned().collect();
+        while let Some(commit) = pending.pop() {
+            if history.contains(&commit) {
+                continue;
+            }
+            let Ok((kind, data)) = self.read_raw_object(&commit) else {
+                continue;
+            };
+            if kind == "commit" {
+                pending.extend(header_links(&data, &["parent"])?);
+            }
+            history.insert(commit);
+        }
+        Ok(history)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2023-11-14 22:13:20 UTC
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn keywords() {
+        assert_eq!(parse_expiry("never", NOW).unwrap(), None);
+        assert_eq!(parse_expiry("false", NOW).unwrap(), None);
+        assert_eq!(parse_expiry("now", NOW).unwrap(), Some(NOW));
+        assert_eq!(parse_expiry("all", NOW).unwrap(), Some(NOW));
+        assert_eq!(parse_expiry("yesterday", NOW).unwrap(), Some(NOW - 86400));
+    }
+
+    #[test]
+    fn relative_dates() {
+        assert_eq!(
+            parse_expiry("2.weeks.ago", NOW).unwrap(),
+            Some(NOW - 14 * 86400)
+        );
+        assert_eq!(
+            parse_expiry("3 days ago", NOW).unwrap(),
+            Some(NOW - 3 * 86400)
+        );
+        assert_eq!(parse_expiry("1.hour", NOW).unwrap(), Some(NOW - 3600));
+        assert_eq!(parse_expiry("90.days.ago", 0).unwrap(), Some(0));
+        assert!(parse_expiry("2.fortnights.ago", NOW).is_err());
+        assert!(parse_expiry("many.days.ago", NOW).is_err());
+        assert!(parse_expiry("soon", NOW).is_err());
+    }
+
+    #[test]
+    fn absolute_dates() {
+        assert_eq!(
+            parse_expiry("1234567890", NOW).unwrap(),
+            Some(1_234_567_890)
+        );
+        assert_eq!(
+            parse_expiry("@1234567890", NOW).unwrap(),
+            Some(1_234_567_890)
+        );
+        assert_eq!(
+            parse_expiry("2024-01-31 12:00", NOW).unwrap(),
+            Some(1_706_702_400)
+        );
+        assert_eq!(
+            parse_expiry("2024-02-29T12:30:15", NOW).unwrap(),
+            Some(1_709_209_815)
+        );
+        // Without a time, the current time of day is used
+        assert_eq!(
+            parse_expiry("2024-01-31", NOW).unwrap(),
+            Some(1_706_659_200 + NOW % 86400)
+        );
+        assert!(parse_expiry("2024-13-01", NOW).is_err());
+    }
+}
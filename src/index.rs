@@ -0,0 +1,328 @@
+//! The index (staging area) file, `.git/index`.
+
+use crate::{
+    hash::{ObjectFormat, RawHash},
+    stream, Object, Repository,
+};
+use anyhow::Context;
+use bytes::BufMut;
+use std::{
+    fs,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+};
+
+const INDEX_SIGNATURE: &[u8; 4] = b"DIRC";
+/// Size of the fixed part of an entry before the hash
+const ENTRY_STAT_SIZE: usize = 40;
+const FLAG_EXTENDED: u16 = 0x4000;
+const FLAG_STAGE_SHIFT: u16 = 12;
+const FLAG_STAGE_MASK: u16 = 0x3000;
+const FLAG_NAME_MASK: u16 = 0x0fff;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub ctime: (u32, u32),
+    pub mtime: (u32, u32),
+    pub dev: u32,
+    pub ino: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+    pub hash: RawHash,
+    /// Merge stage, 0 for normal entries and 1-3 for conflicts (base, ours, theirs)
+    pub stage: u8,
+    pub path: String,
+}
+
+impl IndexEntry {
+    /// Creates an entry for a file that was just written to (or read from) the working tree.
+    pub fn from_metadata(
+        path: &str,
+        mode: u32,
+        hash: RawHash,
+        metadata: &fs::Metadata,
+    ) -> IndexEntry {
+        IndexEntry {
+            ctime: (metadata.ctime() as u32, metadata.ctime_nsec() as u32),
+            mtime: (metadata.mtime() as u32, metadata.mtime_nsec() as u32),
+            dev: metadata.dev() as u32,
+            ino: metadata.ino() as u32,
+            mode,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size() as u32,
+            hash,
+            stage: 0,
+            path: path.to_owned(),
+        }
+    }
+
+    /// Creates an entry without any stat information, it will always be re-hashed when
+    /// compared with the working tree.
+    pub fn new(path: &str, mode: u32, hash: RawHash, stage: u8) -> IndexEntry {
+        IndexEntry {
+            ctime: (0, 0),
+            mtime: (0, 0),
+            dev: 0,
+            ino: 0,
+            mode,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            hash,
+            stage,
+            path: path.to_owned(),
+        }
+    }
+
+    /// Checks whether cached stat information matches the file, meaning it was not modified.
+    pub fn stat_matches(&self, metadata: &fs::Metadata) -> bool {
+        self.mtime == (metadata.mtime() as u32, metadata.mtime_nsec() as u32)
+            && self.ctime == (metadata.ctime() as u32, metadata.ctime_nsec() as u32)
+            && self.ino == metadata.ino() as u32
+            && self.size == metadata.size() as u32
+            && mode_from_metadata(metadata) == Some(self.mode)
+    }
+
+    fn parse(
+        data: &[u8],
+        version: u32,
+        format: ObjectFormat,
+    ) -> anyhow::Result<(IndexEntry, usize)> {
+        // Stat information, the hash and the flags
+        let hash_end = ENTRY_STAT_SIZE + format.raw_len();
+        anyhow::ensure!(data.len() >= hash_end + 2, "Truncated index entry");
+        let word = |i: usize| u32::from_be_bytes(data[i * 4..i * 4 + 4].try_into().unwrap());
+        let flags = u16::from_be_bytes(data[hash_end..hash_end + 2].try_into().unwrap());
+        let mut offset = hash_end + 2;
+        if flags & FLAG_EXTENDED != 0 {
+            anyhow::ensure!(version >= 3, "Extended flags in index version {}", version);
+            offset += 2;
+        }
+        let name_end = data[offset..]
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or_else(|| anyhow::Error::msg("Index entry path is not terminated"))?;
+        let path = std::str::from_utf8(&data[offset..offset + name_end])
+            .context("Index entry path is not valid UTF-8")?
+            .to_owned();
+        // Entries are padded with 1-8 NUL bytes to a multiple of 8 bytes
+        let length = (offset + name_end + 8) & !7;
+        anyhow::ensure!(data.len() >= length, "Truncated index entry");
+
+        Ok((
+            IndexEntry {
+                ctime: (word(0), word(1)),
+                mtime: (word(2), word(3)),
+                dev: word(4),
+                ino: word(5),
+                mode: word(6),
+                uid: word(7),
+                gid: word(8),
+                size: word(9),
+                hash: RawHash::from_bytes(&data[ENTRY_STAT_SIZE..hash_end]).unwrap(),
+                stage: ((flags & FLAG_STAGE_MASK) >> FLAG_STAGE_SHIFT) as u8,
+                path,
+            },
+            length,
+        ))
+    }
+
+    fn serialize(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        for word in [
+            self.ctime.0,
+            self.ctime.1,
+            self.mtime.0,
+            self.mtime.1,
+            self.dev,
+            self.ino,
+            self.mode,
+            self.uid,
+            self.gid,
+            self.size,
+        ] {
+            buf.put_u32(word);
+        }
+        buf.put(&self.hash[..]);
+        let name_length = self.path.len().min(FLAG_NAME_MASK as usize) as u16;
+        buf.put_u16(((self.stage as u16) << FLAG_STAGE_SHIFT) | name_length);
+        buf.put(self.path.as_bytes());
+        let length = (buf.len() - start + 8) & !7;
+        buf.resize(start + length, 0);
+    }
+}
+
+/// Mode git would record for a file with given metadata, `None` for unsupported file types.
+pub fn mode_from_metadata(metadata: &fs::Metadata) -> Option<u32> {
+    let file_type = metadata.file_type();
+    if file_type.is_symlink() {
+        Some(0o120000)
+    } else if file_type.is_file() {
+        Some(if metadata.permissions().mode() & 0o111 != 0 {
+            0o100755
+        } else {
+            0o100644
+        })
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Index {
+    /// Entries sorted by path and stage
+    entries: Vec<IndexEntry>,
+}
+
+impl Index {
+    /// Parses an index of a repository with objects named by `format`.
+    pub fn parse(data: &[u8], format: ObjectFormat) -> anyhow::Result<Index> {
+        anyhow::ensure!(
+            data.len() >= 12 + format.raw_len(),
+            "Index file is too short"
+        );
+        let (content, checksum) = data.split_at(data.len() - format.raw_len());
+        anyhow::ensure!(
+            format.digest(content)?[..] == checksum[..],
+            "Index file checksum mismatch"
+        );
+        anyhow::ensure!(&content[..4] == INDEX_SIGNATURE, "Invalid index signature");
+        let version = u32::from_be_bytes(content[4..8].try_into().unwrap());
+        anyhow::ensure!(
+            version == 2 || version == 3,
+            "Unsupported index version {}",
+            version
+        );
+        let count = u32::from_be_bytes(content[8..12].try_into().unwrap());
+
+        let mut offset = 12;
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let (entry, length) = IndexEntry::parse(&content[offset..], version, format)?;
+            entries.push(entry);
+            offset += length;
+        }
+        // Extensions (cached trees, resolve undo, ...) follow, they are all optional
+        // and are dropped when the index is written back.
+        Ok(Index { entries })
+    }
+
+    pub fn serialize(&self, format: ObjectFormat) -> crate::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        buf.put(&INDEX_SIGNATURE[..]);
+        buf.put_u32(2);
+        buf.put_u32(self.entries.len() as u32);
+        for entry in self.entries.iter() {
+            entry.serialize(&mut buf);
+        }
+        let checksum = format.digest(&buf)?;
+        buf.put(&checksum[..]);
+        Ok(buf)
+    }
+
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    fn position(&self, path: &str, stage: u8) -> Result<usize, usize> {
+        self.entries
+            .binary_search_by(|e| (e.path.as_str(), e.stage).cmp(&(path, stage)))
+    }
+
+    pub fn get(&self, path: &str, stage: u8) -> Option<&IndexEntry> {
+        self.position(path, stage).ok().map(|i| &self.entries[i])
+    }
+
+    /// Checks if there are conflict stages recorded for given path.
+    pub fn is_conflicted(&self, path: &str) -> bool {
+        (1..=3).any(|stage| self.get(path, stage).is_some())
+    }
+
+    /// Adds or replaces an entry. Adding a stage 0 entry resolves any conflict on that path.
+    pub fn add(&mut self, entry: IndexEntry) {
+        if entry.stage == 0 {
+            for stage in 1..=3 {
+                self.remove(&entry.path, stage);
+            }
+        }
+        match self.position(&entry.path, entry.stage) {
+            Ok(i) => self.entries[i] = entry,
+            Err(i) => self.entries.insert(i, entry),
+        }
+    }
+
+    pub fn remove(&mut self, path: &str, stage: u8) -> Option<IndexEntry> {
+        self.position(path, stage)
+            .ok()
+            .map(|i| self.entries.remove(i))
+    }
+
+    /// Removes all stages of given path.
+    pub fn remove_path(&mut self, path: &str) {
+        self.entries.retain(|e| e.path != path);
+    }
+}
+
+impl Repository {
+    pub fn index_path(&self) -> std::path::PathBuf {
+        self.path.join("index")
+    }
+
+    /// Reads the index, returns `Ok(None)` if there is none yet.
+    pub fn read_index(&self) -> anyhow::Result<Option<Index>> {
+        match fs::read(self.index_path()) {
+            Ok(data) => Index::parse(&data, self.object_format()?)
+                .context("Trying to read index")
+                .map(Some),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn write_index(&self, index: &Index) -> anyhow::Result<()> {
+        self.write_file_atomically(&self.index_path(), &index.serialize(self.object_format()?)?)
+    }
+
+    /// Hashes a working tree file as a blob, symbolic links are hashed by their target.
+    pub fn hash_worktree_file(&self, path: &Path, mode: u32) -> anyhow::Result<RawHash> {
+        if mode != 0o120000 {
+            let mut file = fs::File::open(path)?;
+            let size = file.metadata()?.len();
+            return stream::hash_stream(self.object_format()?, "blob", size, &mut file);
+        }
+        let target = fs::read_link(path)?
+            .into_os_string()
+            .into_string()
+            .map_err(|_| anyhow::Error::msg("Symbolic link target is not valid UTF-8"))?;
+        Ok(Object::Blob(target.into_bytes()).hash(self.object_format()?)?)
+    }
+
+    /// Checks whether the working tree file differs from its index entry, using cached stat
+    /// information when possible. Missing files are treated as modified.
+    pub fn is_worktree_modified(&self, entry: &IndexEntry) -> anyhow::Result<bool> {
+        let path = self.work_dir()?.join(&entry.path);
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e.into()),
+        };
+        let Some(mode) = mode_from_metadata(&metadata) else {
+            return Ok(true);
+        };
+        if entry.stat_matches(&metadata) && !self.is_racily_clean(entry) {
+            return Ok(false);
+        }
+        Ok(mode != entry.mode || self.hash_worktree_file(&path, mode)? != entry.hash)
+    }
+
+    /// A file modified in the same second the index was written can have unchanged stat
+    /// information, such entries need to be verified by content.
+    fn is_racily_clean(&self, entry: &IndexEntry) -> bool {
+        fs::metadata(self.index_path())
+            .map(|m| m.mtime() as u32 <= entry.mtime.0)
+            .unwrap_or(true)
+    }
+}
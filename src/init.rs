@@ -0,0 +1,264 @@
+//! Creating repositories, or adding what is missing to existing ones.
+
+use crate::{config, hash::ObjectFormat, refs, Repository};
+use anyhow::Context;
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+/// Where git looks for templates when none are configured
+const DEFAULT_TEMPLATE_DIR: &str = "/usr/share/git-core/templates";
+
+const DEFAULT_DESCRIPTION: &str =
+    "Unnamed repository; edit this file 'description' to name the repository.\n";
+
+const DEFAULT_EXCLUDE: &str = "\
+# git ls-files --others --exclude-from=.git/info/exclude
+# Lines that start with '#' are comments.
+# For a project mostly in C, the following would be a good set of
+# exclude patterns (uncomment them if you want to use them):
+# *.[oa]
+# *~
+";
+
+/// Who besides the owner may write to a repository, as in `core.sharedRepository`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SharedRepository {
+    /// Permissions follow the umask
+    Umask,
+    /// Writable by the group
+    Group,
+    /// Writable by the group and readable by everybody
+    All,
+    /// Files get exactly these permissions
+    Mode(u32),
+}
+
+impl SharedRepository {
+    pub fn parse(value: &str) -> anyhow::Result<SharedRepository> {
+        Ok(match value.to_ascii_lowercase().as_str() {
+            "umask" | "false" | "no" | "off" => SharedRepository::Umask,
+            "group" | "true" | "yes" | "on" => SharedRepository::Group,
+            "all" | "world" | "everybody" => SharedRepository::All,
+            // Single digits are the numeric forms of the names above
+            "0" => SharedRepository::Umask,
+            "1" => SharedRepository::Group,
+            "2" => SharedRepository::All,
+            mode => {
+                let mode = u32::from_str_radix(mode, 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o777)
+                    .ok_or_else(|| {
+                        anyhow::Error::msg(format!("invalid --shared value '{value}'"))
+                    })?;
+                anyhow::ensure!(
+                    mode & 0o600 == 0o600,
+                    "problem with core.sharedRepository filemode value (0{:o}).\nThe owner of files must always have read and write permissions.",
+                    mode
+                );
+                SharedRepository::Mode(mode)
+            }
+        })
+    }
+
+    /// Value for `core.sharedRepository`, `None` for the default of following the umask
+    fn config_value(self) -> Option<String> {
+        match self {
+            SharedRepository::Umask => None,
+            SharedRepository::Group => Some("1".to_owned()),
+            SharedRepository::All => Some("2".to_owned()),
+            SharedRepository::Mode(mode) => Some(format!("0{mode:o}")),
+        }
+    }
+
+    /// Permissions of a file or directory in a shared repository. Directories can be entered
+    /// by whoever may read them, and new files in them belong to their group.
+    fn adjust(self, mode: u32, is_dir: bool) -> u32 {
+        let mut mode = match self {
+            SharedRepository::Umask => return mode,
+            SharedRepository::Group => mode | 0o660,
+            SharedRepository::All => mode | 0o664,
+            SharedRepository::Mode(shared) => (mode & !0o777) | shared,
+        };
+        if is_dir {
+            mode |= (mode & 0o444) >> 2 | 0o2000;
+        }
+        mode
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct InitOptions {
+    /// Object format of a new repository, SHA-1 if not given. Existing repositories must
+    /// already use it.
+    pub format: Option<ObjectFormat>,
+    /// Create a repository without a working tree
+    pub bare: bool,
+    /// Branch `HEAD` of a new repository is on, otherwise `init.defaultBranch` or `master`
+    pub initial_branch: Option<String>,
+    /// Directory whose files are copied into the git directory, otherwise
+    /// `GIT_TEMPLATE_DIR`, `init.templateDir` or the templates installed with git. An empty
+    /// path copies no templates.
+    pub template: Option<PathBuf>,
+    /// Make the repository writable by others, sets `core.sharedRepository`
+    pub shared: Option<SharedRepository>,
+}
+
+/// Copies the files of `template` into `dest` that are not there yet.
+fn copy_template(template: &Path, dest: &Path) -> anyhow::Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(template)? {
+        let entry = entry?;
+        let target = dest.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_template(&entry.path(), &target)?;
+        } else if target.symlink_metadata().is_ok() {
+            continue;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &target)?;
+        } else {
+            fs::copy(entry.path(), &target)
+                .with_context(|| format!("cannot copy '{}'", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies the permissions of a shared repository to `path` and everything below it.
+fn adjust_shared_perm(path: &Path, shared: SharedRepository) -> anyhow::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.file_type().is_symlink() {
+        return Ok(());
+    }
+    let mode = metadata.permissions().mode();
+    let adjusted = shared.adjust(mode, metadata.is_dir());
+    if adjusted != mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(adjusted))?;
+    }
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            adjust_shared_perm(&entry?.path(), shared)?;
+        }
+    }
+    Ok(())
+}
+
+impl Repository {
+    /// Creates the repository, or reinitializes an existing one by adding missing templates
+    /// and configuration without touching `HEAD`. Returns whether the repository existed.
+    pub fn init(&self, options: &InitOptions) -> anyhow::Result<bool> {
+        let exists = crate::is_git_dir(&self.path);
+        let format = match (exists, options.format) {
+            (true, requested) => {
+                let format = self.object_format()?;
+                anyhow::ensure!(
+                    requested.is_none() || requested == Some(format),
+                    "attempt to reinitialize repository with different hash"
+                );
+                format
+            }
+            (false, requested) => requested.unwrap_or_default(),
+        };
+        let global = config::Config::load(&self.path)?;
+
+        let template = match &options.template {
+            Some(template) => Some(template.clone()),
+            None => std::env::var_os("GIT_TEMPLATE_DIR")
+                .map(PathBuf::from)
+                .or_else(|| global.get("init.templateDir").map(PathBuf::from)),
+        };
+        fs::create_dir_all(&self.path)?;
+        match template {
+            Some(template) if template.as_os_str().is_empty() => {}
+            Some(template) if !template.is_dir() => {
+                self.warn(format!(
+                    "warning: templates not found in {}",
+                    template.display()
+                ));
+            }
+            Some(template) => copy_template(&template, &self.path)?,
+            None if Path::new(DEFAULT_TEMPLATE_DIR).is_dir() => {
+                copy_template(Path::new(DEFAULT_TEMPLATE_DIR), &self.path)?
+            }
+            // Without templates installed, create what git's default ones contain
+            None => {
+                fs::create_dir_all(self.path.join("hooks"))?;
+                fs::create_dir_all(self.path.join("info"))?;
+                for (name, contents) in [
+                    ("description", DEFAULT_DESCRIPTION),
+                    ("info/exclude", DEFAULT_EXCLUDE),
+                ] {
+                    let path = self.path.join(name);
+                    if !path.exists() {
+                        fs::write(path, contents)?;
+                    }
+                }
+            }
+        }
+        for dir in ["objects/info", "objects/pack", "refs/heads", "refs/tags"] {
+            fs::create_dir_all(self.path.join(dir))?;
+        }
+
+        if exists {
+            if let Some(branch) = &options.initial_branch {
+                self.warn(format!(
+                    "warning: re-init: ignored --initial-branch={branch}"
+                ));
+            }
+        } else {
+            let branch = match &options.initial_branch {
+                Some(branch) => branch.as_str(),
+                None => global.get("init.defaultBranch").unwrap_or("master"),
+            };
+            refs::check_branch_name(branch).map_err(|_| {
+                anyhow::Error::msg(format!("invalid initial branch name: '{branch}'"))
+            })?;
+            fs::write(
+                self.path.join("HEAD"),
+                format!("ref: {}\n", Self::branch_ref(branch)),
+            )?;
+        }
+
+        // Only settings the repository has no value for yet are written on reinitialization
+        let config = match fs::read_to_string(self.path.join("config")) {
+            Ok(text) => config::Config::parse(&text)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => config::Config::default(),
+            Err(e) => return Err(e.into()),
+        };
+        // Extensions are only honored by git in repositories of format version 1
+        let version = if format == ObjectFormat::Sha1 {
+            "0"
+        } else {
+            "1"
+        };
+        let mut settings = vec![
+            ("core.repositoryformatversion", version.to_owned()),
+            ("core.filemode", "true".to_owned()),
+            ("core.bare", options.bare.to_string()),
+        ];
+        if !options.bare {
+            settings.push(("core.logallrefupdates", "true".to_owned()));
+        }
+        if format != ObjectFormat::Sha1 {
+            settings.push(("extensions.objectformat", format.name().to_owned()));
+        }
+        for (name, value) in settings {
+            if config.get(name).is_none() {
+                self.set_config(name, &value)?;
+            }
+        }
+        if let Some(shared) = options.shared {
+            if let Some(value) = shared.config_value() {
+                self.set_config("core.sharedrepository", &value)?;
+                if !exists {
+                    self.set_config("receive.denyNonFastforwards", "true")?;
+                }
+            }
+            adjust_shared_perm(&self.path, shared)?;
+        }
+        Ok(exists)
+    }
+}
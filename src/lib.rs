@@ -0,0 +1,1013 @@
+//! A git implementation as a library: open a repository, read and write objects, walk
+//! trees and build commits. The `git-starter-rust` binary is a thin command line interface
+//! on top of it.
+//!
+//! ```no_run
+//! use git_starter_rust::{Object, Repository};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let repo = Repository::find_from_current_dir()?;
+//! let head = repo.resolve_revision("HEAD")?;
+//! let commit = repo.read_commit(&head)?;
+//! println!("{}", commit.subject());
+//! for item in repo.peel_to_tree(&head)?.iter() {
+//!     println!("{} {}", item.mode, item.name);
+//! }
+//! let blob = Object::Blob(b"hello\n".to_vec());
+//! repo.save_object(&blob)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Failures worth handling, like a missing object or an ambiguous abbreviated hash, are
+//! reported as an [`Error`]:
+//!
+//! ```no_run
+//! # use git_starter_rust::{Error, Repository};
+//! # fn main() -> anyhow::Result<()> {
+//! # let repo = Repository::find_from_current_dir()?;
+//! match repo.resolve_revision("1234") {
+//!     Ok(object_ref) => println!("{object_ref}"),
+//!     Err(Error::Ambiguous(_)) => println!("more than one object starts with 1234"),
+//!     Err(error) => return Err(error.into()),
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod attributes;
+pub mod batch;
+pub mod blame;
+pub mod branch;
+mod cache;
+pub mod checkout;
+pub mod clone;
+pub mod config;
+mod delta;
+pub mod diff;
+pub mod error;
+pub mod fsck;
+pub mod gc;
+pub mod hash;
+pub mod index;
+pub mod init;
+pub mod merge;
+pub mod myers;
+pub mod odb;
+pub mod pack;
+pub mod pathspec;
+pub mod reflog;
+pub mod refs;
+pub mod revwalk;
+mod sha1dc;
+pub mod stream;
+pub mod worktree;
+
+use anyhow::Context;
+use bytes::BufMut;
+pub use error::{Error, Result};
+use hash::{ObjectFormat, RawHash};
+use odb::{ObjectDatabase, PackDatabase};
+use std::{
+    borrow::Cow,
+    cell::{OnceCell, RefCell},
+    fs,
+    io::Read,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+/// The hex name of an object, 40 hex digits for SHA-1 or 64 for SHA-256, kept in lowercase.
+pub struct ObjectRef(pub(crate) String);
+
+impl ObjectRef {
+    /// Parses a hex name in lowercase or uppercase.
+    pub fn from_hex(hash: &str) -> Result<ObjectRef> {
+        if hash.len() != 40 && hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::InvalidRef(hash.to_owned()));
+        }
+        Ok(ObjectRef(hash.to_ascii_lowercase()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Names an object by its binary hash, 20 or 32 bytes.
+    pub fn from_hash(hash: &[u8]) -> ObjectRef {
+        ObjectRef(hex::encode(hash))
+    }
+
+    /// The all-zero name of the object format, used for "no object".
+    pub fn null(format: ObjectFormat) -> ObjectRef {
+        ObjectRef::from_hash(&format.null())
+    }
+
+    /// The binary form of the hash, like it is stored in trees.
+    pub fn to_bytes(&self) -> RawHash {
+        let bytes = hex::decode(&self.0).expect("Object refs are hex digits");
+        RawHash::from_bytes(&bytes).expect("Object refs are 40 or 64 hex digits")
+    }
+
+    pub fn format(&self) -> ObjectFormat {
+        match self.0.len() {
+            40 => ObjectFormat::Sha1,
+            _ => ObjectFormat::Sha256,
+        }
+    }
+
+    fn wrong_type(&self, kind: &str, expected: &str) -> Error {
+        Error::WrongType {
+            object: self.clone(),
+            kind: kind.to_owned(),
+            expected: expected.to_owned(),
+        }
+    }
+
+    fn matches(&self, hash: &str) -> bool {
+        self.0.eq_ignore_ascii_case(hash)
+    }
+}
+
+impl std::str::FromStr for ObjectRef {
+    type Err = Error;
+
+    fn from_str(hash: &str) -> Result<ObjectRef> {
+        ObjectRef::from_hex(hash)
+    }
+}
+
+impl std::fmt::Display for ObjectRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The directory shared by all worktrees: the one named in `commondir` for linked
+/// worktrees, otherwise the git directory itself.
+fn common_dir_of(git_dir: &Path) -> PathBuf {
+    match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(dir) => {
+            let dir = git_dir.join(dir.trim_end());
+            fs::canonicalize(&dir).unwrap_or(dir)
+        }
+        Err(_) => git_dir.to_path_buf(),
+    }
+}
+
+/// Whether `path` looks like a git directory: it has `HEAD`, and `objects` and `refs` in
+/// its common directory.
+fn is_git_dir(path: &Path) -> bool {
+    let common_dir = common_dir_of(path);
+    path.join("HEAD").is_file()
+        && common_dir.join("objects").is_dir()
+        && common_dir.join("refs").is_dir()
+}
+
+/// Reads a `.git` file, which contains `gitdir: <path>` pointing at the actual git
+/// directory. Relative paths are relative to the file.
+fn read_gitfile(path: &Path) -> Result<PathBuf> {
+    let contents = fs::read_to_string(path)?;
+    let target = contents
+        .strip_prefix("gitdir: ")
+        .map(str::trim_end)
+        .filter(|target| !target.is_empty())
+        .ok_or_else(|| {
+            Error::Other(anyhow::Error::msg(format!(
+                "invalid gitfile format: {}",
+                path.display()
+            )))
+        })?;
+    let git_dir = path.parent().unwrap_or(Path::new("")).join(target);
+    if !is_git_dir(&git_dir) {
+        return Err(Error::InvalidGitDir(git_dir.display().to_string()));
+    }
+    Ok(fs::canonicalize(git_dir)?)
+}
+
+/// The git directory and working tree of a repository in `dir`: its `.git` directory or
+/// file, or `dir` itself if it is a git directory without a working tree.
+fn find_git_dir(dir: &Path) -> Result<Option<(PathBuf, Option<PathBuf>)>> {
+    let dot_git = dir.join(".git");
+    if dot_git.is_file() {
+        return Ok(Some((read_gitfile(&dot_git)?, Some(dir.to_path_buf()))));
+    }
+    if is_git_dir(&dot_git) {
+        return Ok(Some((dot_git, Some(dir.to_path_buf()))));
+    }
+    if is_git_dir(dir) {
+        return Ok(Some((dir.to_path_buf(), None)));
+    }
+    Ok(None)
+}
+
+/// Opens a found git directory. `GIT_WORK_TREE`, then `core.worktree` and `core.bare`
+/// override the working tree it was found with.
+fn open_git_dir(git_dir: PathBuf, work_tree: Option<PathBuf>) -> Result<Repository> {
+    let repo = Repository::new(git_dir);
+    let config = repo.config()?;
+    // The configuration is shared, and only describes the main worktree
+    let linked = repo.common_dir != repo.path;
+    let work_tree = if let Some(path) = std::env::var_os("GIT_WORK_TREE") {
+        Some(std::env::current_dir()?.join(path))
+    } else if linked {
+        work_tree
+    } else if let Some(path) = config.get("core.worktree") {
+        Some(repo.path.join(path))
+    } else if config.get_bool("core.bare")? == Some(true) {
+        None
+    } else {
+        work_tree
+    };
+    // Paths in the working tree are compared with the canonical current directory
+    let work_tree = work_tree.map(|path| fs::canonicalize(&path).unwrap_or(path));
+    Ok(repo.with_work_tree(work_tree))
+}
+
+/// A repository, found by its git directory. Lookups are cached, so keep it around.
+pub struct Repository {
+    pub(crate) path: PathBuf,
+    /// Where objects, most references and the configuration are, shared by all worktrees
+    pub(crate) common_dir: PathBuf,
+    /// `None` for bare repositories
+    work_tree: Option<PathBuf>,
+    objects: RefCell<cache::ObjectCache>,
+    packs: OnceCell<Rc<PackDatabase>>,
+    /// Opened on first use, as writing objects depends on the configuration
+    odb: OnceCell<Box<dyn ObjectDatabase>>,
+    format: OnceCell<ObjectFormat>,
+    /// Problems that did not stop an operation, until the caller takes them
+    warnings: RefCell<Vec<String>>,
+}
+
+impl Repository {
+    pub fn new(path: PathBuf) -> Repository {
+        let common_dir = common_dir_of(&path);
+        // Linked worktrees know their working tree from the `.git` file linking back
+        let work_tree = match fs::read_to_string(path.join("gitdir")) {
+            Ok(dot_git) if common_dir != path => Path::new(dot_git.trim_end())
+                .parent()
+                .map(Path::to_path_buf),
+            _ => path.parent().map(Path::to_path_buf),
+        };
+        Repository {
+            path,
+            common_dir,
+            work_tree,
+            packs: OnceCell::new(),
+            objects: RefCell::default(),
+            odb: OnceCell::new(),
+            format: OnceCell::new(),
+            warnings: RefCell::default(),
+        }
+    }
+
+    /// Opens a repository that keeps its objects in `database` instead of its `objects`
+    /// directory. References and configuration are still read from `path`.
+    pub fn with_object_database(path: PathBuf, database: Box<dyn ObjectDatabase>) -> Repository {
+        let repo = Repository::new(path);
+        let _ = repo.odb.set(database);
+        repo
+    }
+
+    pub fn from_current_dir() -> Result<Repository> {
+        Ok(Repository::new(std::env::current_dir()?.join(".git")))
+    }
+
+    /// Uses `work_tree` as the working tree, or none at all for a bare repository.
+    pub fn with_work_tree(mut self, work_tree: Option<PathBuf>) -> Repository {
+        self.work_tree = work_tree;
+        self
+    }
+
+    /// Takes the warnings collected so far, like alternates that could not be read or renames
+    /// that were not detected because of the rename limit. They are complete lines with a
+    /// `warning:` or `error:` prefix, which git prints to stderr.
+    pub fn take_warnings(&self) -> Vec<String> {
+        self.warnings.take()
+    }
+
+    pub(crate) fn warn(&self, warning: String) {
+        self.warnings.borrow_mut().push(warning);
+    }
+
+    /// Opens the repository at `path`: a working tree with a `.git` directory or file, or a
+    /// bare repository.
+    pub fn open(path: &Path) -> Result<Repository> {
+        let path = fs::canonicalize(path)?;
+        match find_git_dir(&path)? {
+            Some((git_dir, work_tree)) => open_git_dir(git_dir, work_tree),
+            None => Err(Error::InvalidGitDir(path.display().to_string())),
+        }
+    }
+
+    /// Finds the repository the current directory belongs to, like git does: from
+    /// `GIT_DIR` and `GIT_WORK_TREE` if set, otherwise by looking for a `.git` directory or
+    /// file, or a bare repository, in the current directory and its parents. The search stops
+    /// at `GIT_CEILING_DIRECTORIES` and, unless `GIT_DISCOVERY_ACROSS_FILESYSTEM` is set, at
+    /// file system boundaries.
+    pub fn find_from_current_dir() -> Result<Repository> {
+        let current_dir = std::env::current_dir()?;
+        if let Some(git_dir) = std::env::var_os("GIT_DIR") {
+            let git_dir = current_dir.join(git_dir);
+            let git_dir = match git_dir.is_file() {
+                true => read_gitfile(&git_dir)?,
+                false => git_dir,
+            };
+            if !is_git_dir(&git_dir) {
+                return Err(Error::InvalidGitDir(git_dir.display().to_string()));
+            }
+            // Without other configuration, the current directory is the top of the working tree
+            return open_git_dir(fs::canonicalize(git_dir)?, Some(current_dir));
+        }
+
+        let ceilings: Vec<PathBuf> = std::env::var_os("GIT_CEILING_DIRECTORIES")
+            .map(|dirs| {
+                std::env::split_paths(&dirs)
+                    .filter(|dir| dir.is_absolute())
+                    .filter_map(|dir| fs::canonicalize(dir).ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let across_filesystems = std::env::var("GIT_DISCOVERY_ACROSS_FILESYSTEM")
+            .ok()
+            .and_then(|value| config::parse_bool(&value))
+            .unwrap_or(false);
+        let device = fs::metadata(&current_dir)?.dev();
+        let mut dir = current_dir.as_path();
+        loop {
+            if let Some((git_dir, work_tree)) = find_git_dir(dir)? {
+                return open_git_dir(git_dir, work_tree);
+            }
+            let Some(parent) = dir.parent() else {
+                return Err(Error::NotARepository);
+            };
+            if ceilings.iter().any(|ceiling| ceiling == parent)
+                || (!across_filesystems && fs::metadata(parent)?.dev() != device)
+            {
+                return Err(Error::NotARepository);
+            }
+            dir = parent;
+        }
+    }
+
+    /// The `.git` directory, or `.git/worktrees/<name>` for a linked worktree
+    pub fn git_dir(&self) -> &Path {
+        &self.path
+    }
+
+    /// The git directory shared by all worktrees
+    pub fn common_dir(&self) -> &Path {
+        &self.common_dir
+    }
+
+    /// The working directory the repository belongs to, an error for bare repositories.
+    pub fn work_dir(&self) -> Result<&Path> {
+        self.work_tree.as_deref().ok_or(Error::NoWorkTree)
+    }
+
+    pub fn is_bare(&self) -> bool {
+        self.work_tree.is_none()
+    }
+
+    /// The hash function naming objects, from `extensions.objectFormat`.
+    pub fn object_format(&self) -> Result<ObjectFormat> {
+        if let Some(format) = self.format.get() {
+            return Ok(*format);
+        }
+        let format = match self.config()?.get("extensions.objectFormat") {
+            Some(name) => ObjectFormat::from_name(name).ok_or_else(|| {
+                Error::Other(anyhow::Error::msg(format!(
+                    "unknown object format '{name}'"
+                )))
+            })?,
+            None => ObjectFormat::Sha1,
+        };
+        Ok(*self.format.get_or_init(|| format))
+    }
+
+    pub fn find_object(&self, object_ref: &ObjectRef) -> Result<Object> {
+        let (kind, data) = self.read_raw_object(object_ref)?;
+        Object::from_parts(&kind, data, self.object_format()?).map_err(|e| Error::Corrupt {
+            object: object_ref.clone(),
+            reason: format!("{e:#}"),
+        })
+    }
+
+    /// Reads the type and contents of an object, loose or packed, checking its hash.
+    pub fn read_raw_object(&self, object_ref: &ObjectRef) -> Result<(String, Vec<u8>)> {
+        if let Some(cached) = self.objects.borrow_mut().get(object_ref) {
+            return Ok(cached);
+        }
+        let (kind, data) = self
+            .object_database()?
+            .read(object_ref)?
+            .ok_or_else(|| Error::NotFound(object_ref.clone()))?;
+        let object = Object::Unknown { kind, data };
+        if !object_ref.matches(&object.hash_string(object_ref.format())?) {
+            return Err(Error::Corrupt {
+                object: object_ref.clone(),
+                reason: "hash mismatch".to_owned(),
+            });
+        }
+        let Object::Unknown { kind, data } = object else {
+            unreachable!("Built as unknown object above");
+        };
+        self.objects.borrow_mut().insert(object_ref, &kind, &data);
+        Ok((kind, data))
+    }
+
+    /// Checks whether an object exists, loose or packed, without reading it.
+    pub fn has_object(&self, object_ref: &ObjectRef) -> Result<bool> {
+        self.object_database()?.exists(object_ref)
+    }
+
+    /// Reads only the type and size of an object, `None` if it does not exist.
+    pub fn read_object_header(&self, object_ref: &ObjectRef) -> Result<Option<(String, usize)>> {
+        self.object_database()?.read_header(object_ref)
+    }
+
+    pub fn config(&self) -> Result<config::Config> {
+        Ok(config::Config::load(&self.common_dir)?)
+    }
+
+    pub fn read_blob(&self, object_ref: &ObjectRef) -> Result<Vec<u8>> {
+        match self.find_object(object_ref)? {
+            Object::Blob(data) => Ok(data),
+            obj => Err(object_ref.wrong_type(obj.kind(), "blob")),
+        }
+    }
+
+    pub fn read_commit(&self, object_ref: &ObjectRef) -> Result<Commit> {
+        match self.find_object(object_ref)? {
+            Object::Commit(commit) => Ok(commit),
+            obj => Err(object_ref.wrong_type(obj.kind(), "commit")),
+        }
+    }
+
+    pub fn read_tree(&self, object_ref: &ObjectRef) -> Result<TreeData> {
+        match self.find_object(object_ref)? {
+            Object::Tree(tree) => Ok(tree),
+            obj => Err(object_ref.wrong_type(obj.kind(), "tree")),
+        }
+    }
+
+    /// Reads a tree, or the tree of a commit or of what an annotated tag points to.
+    pub fn peel_to_tree(&self, object_ref: &ObjectRef) -> Result<TreeData> {
+        match self.find_object(object_ref)? {
+            Object::Tree(tree) => Ok(tree),
+            Object::Commit(commit) => {
+                self.read_tree(&ObjectRef::from_hex(&commit.data().tree_hash)?)
+            }
+            obj if obj.kind() == "tag" => self.read_tree(&self.peel(object_ref, "tree")?),
+            obj => Err(object_ref.wrong_type(obj.kind(), "tree-ish")),
+        }
+    }
+
+    /// Looks up a slash separated path in a tree, returning its mode and hash.
+    pub fn find_tree_entry(&self, tree: &TreeData, path: &str) -> Result<Option<(u32, RawHash)>> {
+        let (name, rest) = match path.split_once('/') {
+            Some((name, rest)) => (name, Some(rest)),
+            None => (path, None),
+        };
+        let Some(item) = tree.iter().find(|item| item.name == name) else {
+            return Ok(None);
+        };
+        match rest {
+            None => Ok(Some((item.mode, item.hash))),
+            Some(rest) if item.mode & (1 << 15) == 0 => {
+                let subtree = self.read_tree(&ObjectRef::from_hash(&item.hash))?;
+                self.find_tree_entry(&subtree, rest)
+            }
+            Some(_) => Ok(None),
+        }
+    }
+
+    /// Writes a directory of the working tree as a tree object, with all files in it as
+    /// blobs. Directories starting with a dot are skipped.
+    pub fn write_tree_from_dir(&self, path: &Path) -> Result<ObjectRef> {
+        let mut trees = Vec::new();
+        let tree = Object::Tree(self.build_tree_for_directory(&mut trees, path)?);
+        let tree_ref = ObjectRef::from_hash(&tree.hash(self.object_format()?)?);
+        trees.push(tree);
+        for tree in trees.iter() {
+            self.save_object(tree)?;
+        }
+        Ok(tree_ref)
+    }
+
+    /// Builds the tree for a directory, writing blobs as they are found. The trees are
+    /// collected in `trees` to be written once complete.
+    fn build_tree_for_directory(
+        &self,
+        trees: &mut Vec<Object>,
+        path: &Path,
+    ) -> anyhow::Result<TreeData> {
+        let mut tree = TreeData::empty(self.object_format()?);
+
+        for file in fs::read_dir(path)? {
+            let file = file?;
+            let metadata = file.metadata()?;
+            let file_name = file
+                .file_name()
+                .to_str()
+                .ok_or_else(|| anyhow::Error::msg("Failed to parse filename"))?
+                .to_owned();
+            if metadata.is_dir() {
+                if file_name.starts_with('.') {
+                    continue;
+                }
+                let object = Object::Tree(self.build_tree_for_directory(trees, &file.path())?);
+                tree.add_object(&object, &file_name, 0o040000)?;
+                trees.push(object);
+            } else if metadata.is_file() {
+                let mut reader = fs::File::open(file.path())?;
+                let blob = self.write_object_stream("blob", metadata.len(), &mut reader)?;
+                let is_executable = 0 != (metadata.mode() & 0o111);
+                let mode = if is_executable { 0o100755 } else { 0o100644 };
+                tree.add_item(&TreeItem {
+                    mode,
+                    name: Cow::Borrowed(&file_name),
+                    hash: blob.to_bytes(),
+                });
+            } else {
+                anyhow::bail!(
+                    "Only directories and normal files are supported: {:?}",
+                    file.path()
+                );
+            }
+        }
+
+        tree.sort();
+        Ok(tree)
+    }
+
+    /// Identity recorded in new commits, `role` is `AUTHOR` or `COMMITTER`. Environment
+    /// variables like `GIT_AUTHOR_NAME` take precedence over `user.name` and `user.email`.
+    pub fn identity(&self, role: &str) -> Result<PersonLine<'static>> {
+        let config = self.config()?;
+        let (default_name, default_email) = match role {
+            "AUTHOR" => ("John Smith", "john.smith@example.com"),
+            _ => ("John Doe", "john.doe@example.com"),
+        };
+        let name = std::env::var(format!("GIT_{role}_NAME"))
+            .ok()
+            .or_else(|| config.get("user.name").map(str::to_owned))
+            .unwrap_or_else(|| default_name.to_owned());
+        let email = std::env::var(format!("GIT_{role}_EMAIL"))
+            .ok()
+            .or_else(|| config.get("user.email").map(str::to_owned))
+            .unwrap_or_else(|| default_email.to_owned());
+
+        let (timestamp, timezone) = match std::env::var(format!("GIT_{role}_DATE")) {
+            Ok(date) => {
+                let (timestamp, timezone) = date.split_once(' ').unwrap_or((&date, "+0000"));
+                (
+                    timestamp
+                        .trim_start_matches('@')
+                        .parse()
+                        .with_context(|| format!("Invalid date '{date}'"))?,
+                    timezone
+                        .parse()
+                        .with_context(|| format!("Invalid date '{date}'"))?,
+                )
+            }
+            Err(_) => (
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .with_context(|| "Calculating current time")?
+                    .as_secs(),
+                0,
+            ),
+        };
+        Ok(PersonLine {
+            name: name.into(),
+            email: email.into(),
+            timestamp,
+            timezone,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct TreeItem<'a> {
+    pub mode: u32,
+    pub name: Cow<'a, str>,
+    pub hash: RawHash,
+}
+
+impl TreeItem<'_> {
+    pub fn parse(data: &[u8], format: ObjectFormat) -> anyhow::Result<(&[u8], TreeItem<'_>)> {
+        let index_first_zero = data.iter().position(|b| *b == 0u8).ok_or_else(|| {
+            anyhow::Error::msg("Invalid tree item data, could not find filename terminator")
+        })?;
+        let (header, rest) = data.split_at(index_first_zero);
+        anyhow::ensure!(
+            rest.len() > format.raw_len(),
+            "Invalid tree item data, hash is truncated"
+        );
+        let (hash, rest) = rest[1..].split_at(format.raw_len());
+        let (mode, name) = std::str::from_utf8(header)?
+            .split_once(' ')
+            .ok_or_else(|| {
+                anyhow::Error::msg(
+                    "Invalid tree item data, could not find split between mode and filename.",
+                )
+            })?;
+
+        Ok((
+            rest,
+            TreeItem {
+                mode: u32::from_str_radix(mode, 8)?,
+                name: Cow::Borrowed(name),
+                hash: RawHash::from_bytes(hash).expect("Split at the hash length"),
+            },
+        ))
+    }
+
+    pub fn is_file(&self) -> bool {
+        0 != (self.mode & (1 << 15))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TreeDataIterator<'a> {
+    data: &'a [u8],
+    format: ObjectFormat,
+}
+
+impl<'a> Iterator for TreeDataIterator<'a> {
+    type Item = TreeItem<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (rest, item) = TreeItem::parse(self.data, self.format).ok()?;
+        self.data = rest;
+        Some(item)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TreeData {
+    pub(crate) data: Vec<u8>,
+    pub(crate) format: ObjectFormat,
+}
+
+impl TreeData {
+    pub fn empty(format: ObjectFormat) -> TreeData {
+        TreeData {
+            data: vec![],
+            format,
+        }
+    }
+
+    pub fn iter(&self) -> TreeDataIterator<'_> {
+        TreeDataIterator {
+            data: &self.data,
+            format: self.format,
+        }
+    }
+
+    pub fn add_object(&mut self, object: &Object, name: &str, mut mode: u32) -> Result<()> {
+        if let Object::Tree(_) = object {
+            mode &= !(1 << 15);
+        } else {
+            mode |= 1 << 15;
+        }
+
+        let mode = format!("{mode:o}");
+        self.data.put(mode.as_bytes());
+        self.data.put_u8(b' ');
+        self.data.put(name.as_bytes());
+        self.data.put_u8(0);
+        self.data.put(&object.hash(self.format)?[..]);
+        Ok(())
+    }
+
+    pub fn add_item(&mut self, item: &TreeItem) {
+        let mode = format!("{:o}", item.mode);
+        self.data.put(mode.as_bytes());
+        self.data.put_u8(b' ');
+        self.data.put(item.name.as_bytes());
+        self.data.put_u8(0);
+        self.data.put(&item.hash[..]);
+    }
+
+    pub fn sort(&mut self) {
+        let mut items: Vec<_> = self
+            .iter()
+            .map(|i| TreeItem {
+                mode: i.mode,
+                name: Cow::Owned(i.name.into_owned()),
+                hash: i.hash,
+            })
+            .collect();
+        // Git compares tree names as if they ended with a slash
+        let sort_key = |item: &TreeItem| {
+            let mut key = item.name.as_bytes().to_vec();
+            if item.mode & (1 << 15) == 0 {
+                key.push(b'/');
+            }
+            key
+        };
+        items.sort_by_key(sort_key);
+
+        self.data.clear();
+        for item in items {
+            self.add_item(&item);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PersonLine<'a> {
+    pub name: Cow<'a, str>,
+    pub email: Cow<'a, str>,
+    pub timestamp: u64,
+    pub timezone: i32,
+}
+
+impl PersonLine<'_> {
+    /// Formats the date in the person's timezone, like `2005-04-07 15:13:13 -0700`.
+    pub fn date_string(&self) -> String {
+        let offset = (self.timezone / 100 * 60 + self.timezone % 100) as i64 * 60;
+        let local = self.timestamp as i64 + offset;
+        let (days, seconds) = (local.div_euclid(86400), local.rem_euclid(86400));
+        // Civil date from days since the epoch, see Howard Hinnant's date algorithms
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let day_of_era = z.rem_euclid(146097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        format!(
+            "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} {:+05}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60,
+            self.timezone
+        )
+    }
+}
+
+impl<'a> TryFrom<&'a str> for PersonLine<'a> {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &'a str) -> Result<Self, Self::Error> {
+        let (name, rest) = value
+            .split_once('<')
+            .ok_or_else(|| anyhow::Error::msg("Could not find beginning of email"))?;
+        let (email, rest) = rest
+            .split_once('>')
+            .ok_or_else(|| anyhow::Error::msg("Could not find end of email"))?;
+        let (timestamp, timezone) = rest
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| anyhow::Error::msg("Could not find timezone"))?;
+
+        let name = name.trim();
+        let timestamp = timestamp.parse().context("Trying to parse timestamp")?;
+
+        let timezone = timezone
+            // .strip_prefix("+")
+            // .unwrap_or(timezone)
+            .parse()
+            .with_context(|| format!("Trying to parse timezone: {}", timezone))?;
+        Ok(PersonLine {
+            name: name.into(),
+            email: email.into(),
+            timestamp,
+            timezone,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct CommitData<'a> {
+    pub tree_hash: Cow<'a, str>,
+    pub parent_hashes: Vec<Cow<'a, str>>,
+    pub author: PersonLine<'a>,
+    pub committer: PersonLine<'a>,
+    pub message: Cow<'a, str>,
+}
+
+impl<'a> TryFrom<&'a str> for CommitData<'a> {
+    type Error = anyhow::Error;
+
+    fn try_from(mut value: &'a str) -> Result<Self, Self::Error> {
+        let mut tree_hash = None;
+        let mut parent_hashes = Vec::new();
+        let mut author = None;
+        let mut committer = None;
+        loop {
+            let (line, rest) = value
+                .split_once('\n')
+                .ok_or_else(|| anyhow::Error::msg("Could not find next commit line"))?;
+            value = rest;
+
+            // Header is separated from message by one empty line
+            if line.is_empty() {
+                break;
+            }
+            let (tag, value) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow::Error::msg("Failed to parse header item"))?;
+
+            match tag {
+                "tree" => {
+                    anyhow::ensure!(tree_hash == None);
+                    anyhow::ensure!(ObjectRef::from_hex(value).is_ok());
+                    tree_hash = Some(value.into());
+                }
+                "author" => {
+                    anyhow::ensure!(author == None);
+                    author = Some(PersonLine::try_from(value).context("Trying to parse author")?);
+                }
+                "committer" => {
+                    anyhow::ensure!(committer == None);
+                    committer =
+                        Some(PersonLine::try_from(value).context("Trying to parse commiter")?);
+                }
+                "parent" => {
+                    anyhow::ensure!(ObjectRef::from_hex(value).is_ok());
+                    parent_hashes.push(value.into());
+                }
+                _ => {
+                    anyhow::bail!("unexpected tag in commit '{}'", tag);
+                }
+            }
+        }
+
+        Ok(CommitData {
+            tree_hash: tree_hash.ok_or_else(|| anyhow::Error::msg("Tree hash not found"))?,
+            parent_hashes,
+            author: author.ok_or_else(|| anyhow::Error::msg("Author not proviced"))?,
+            committer: committer.ok_or_else(|| anyhow::Error::msg("Committer not provided"))?,
+            message: value.into(),
+        })
+    }
+}
+
+impl From<CommitData<'_>> for Commit {
+    fn from(data: CommitData<'_>) -> Commit {
+        let mut buf = String::new();
+        buf.push_str("tree ");
+        buf.push_str(&data.tree_hash);
+        for parent in data.parent_hashes.iter() {
+            buf.push_str("\nparent ");
+            buf.push_str(parent);
+        }
+        buf.push_str(&format!(
+            "\nauthor {} <{}> {} {:+05}",
+            data.author.name, data.author.email, data.author.timestamp, data.author.timezone
+        ));
+        buf.push_str(&format!(
+            "\ncommitter {} <{}> {} {:+05}",
+            data.committer.name,
+            data.committer.email,
+            data.committer.timestamp,
+            data.committer.timezone
+        ));
+        buf.push('\n');
+        buf.push('\n');
+        buf.push_str(&data.message);
+        Commit(buf)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Commit(pub(crate) String);
+
+impl TryFrom<&[u8]> for Commit {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let string = std::str::from_utf8(value)?;
+        CommitData::try_from(string)?;
+        Ok(Self(string.to_owned()))
+    }
+}
+
+impl Commit {
+    /// The raw text of the commit object.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn data(&self) -> CommitData<'_> {
+        CommitData::try_from(self.0.as_str())
+            .expect("It is invariant that commit contains correct data")
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+
+    /// First line of the commit message
+    pub fn subject(&self) -> &str {
+        let message = self
+            .0
+            .split_once("\n\n")
+            .map(|(_, m)| m)
+            .unwrap_or_default();
+        message.lines().next().unwrap_or_default()
+    }
+}
+
+/// Reads the `<type> <size>` header of a loose object, inflating only the first few bytes.
+pub(crate) fn read_loose_header(path: &Path) -> anyhow::Result<(String, usize)> {
+    let stream = stream::open_loose(path)?;
+    Ok((stream.kind, stream.size))
+}
+
+/// Reads a loose object file, returns its type and contents.
+pub(crate) fn read_loose_object(path: &Path) -> anyhow::Result<(String, Vec<u8>)> {
+    let file = fs::File::open(path)?;
+    let mut decoder = flate2::read::ZlibDecoder::new(&file);
+    let mut contents = Vec::new();
+    decoder.read_to_end(&mut contents)?;
+
+    // The data in contents is structured like this:
+    // <type> <size>\0<payload>
+    //       ^- a space character
+    // Thus we need to split at first zero byte
+    let index_first_zero = contents.iter().position(|b| *b == 0u8).ok_or_else(|| {
+        anyhow::Error::msg("Invalid object file data, could not find header terminator.")
+    })?;
+    let header = std::str::from_utf8(&contents[..index_first_zero])?;
+    let (object_type, object_size) = header.split_once(' ').ok_or_else(|| {
+        anyhow::Error::msg(format!(
+            "Invalid object file header format, expected '<type> <size>', found '{}'",
+            header
+        ))
+    })?;
+    let object_data = &contents[index_first_zero + 1..];
+    let object_size = object_size.parse::<usize>()?;
+    anyhow::ensure!(object_size == object_data.len());
+
+    Ok((object_type.to_owned(), object_data.to_owned()))
+}
+
+#[derive(Debug, Clone)]
+pub enum Object {
+    Unknown { kind: String, data: Vec<u8> },
+    Blob(Vec<u8>),
+    Commit(Commit),
+    Tree(TreeData),
+}
+
+impl Object {
+    /// Builds an object from its type name and contents, like they are found in packs.
+    /// Trees are parsed with hashes of `format`.
+    pub fn from_parts(kind: &str, data: Vec<u8>, format: ObjectFormat) -> anyhow::Result<Object> {
+        Ok(match kind {
+            "blob" => Object::Blob(data),
+            "commit" => {
+                Object::Commit(Commit::try_from(&data[..]).context("Trying to parse commit")?)
+            }
+            "tree" => Object::Tree(TreeData { data, format }),
+            _ => Object::Unknown {
+                kind: kind.to_owned(),
+                data,
+            },
+        })
+    }
+
+    pub fn kind(&self) -> &str {
+        match self {
+            Object::Blob(_) => "blob",
+            Object::Commit(_) => "commit",
+            Object::Tree(_) => "tree",
+            Object::Unknown { kind, .. } => kind,
+        }
+    }
+
+    pub fn contents_bytes(&self) -> &[u8] {
+        match self {
+            Object::Blob(data) => data,
+            Object::Commit(data) => data.bytes(),
+            Object::Tree(data) => &data.data,
+            Object::Unknown { data, .. } => data,
+        }
+    }
+
+    pub fn hash(&self, format: ObjectFormat) -> Result<RawHash> {
+        let mut hasher = format.hasher();
+        hasher.update(self.kind().as_bytes());
+        hasher.update(b" ");
+        hasher.update(self.contents_bytes().len().to_string().as_bytes());
+        hasher.update(b"\0");
+        hasher.update(self.contents_bytes());
+        hasher.finalize()
+    }
+
+    pub fn hash_string(&self, format: ObjectFormat) -> Result<String> {
+        Ok(hex::encode(self.hash(format)?))
+    }
+}
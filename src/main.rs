@@ -1,24 +1,39 @@
 use anyhow::Context;
-use bytes::BufMut;
-use clap::{
-    builder::{ValueParser, ValueParserFactory},
-    Args, Parser,
+use clap::{Args, Parser};
+use git_starter_rust::{
+    batch, blame, checkout, clone, config, diff, fsck, gc, hash::ObjectFormat, init, merge, pack,
+    pathspec, reflog, refs, stream, worktree, CommitData, Error, Object, ObjectRef, PersonLine,
+    Repository, TreeData,
 };
-use sha1::{Digest, Sha1};
 use std::{
-    borrow::Cow,
     fs,
     io::Read,
     io::Write,
-    os::unix::fs::MetadataExt,
-    path::{Path, PathBuf},
+    path::PathBuf,
     time::{SystemTime, UNIX_EPOCH},
 };
 
 #[derive(Parser)]
+struct Cli {
+    /// Run as if started in this directory, relative to the previous one if given repeatedly
+    #[arg(short = 'C', value_name("path"))]
+    directories: Vec<PathBuf>,
+    /// Path to the repository, like setting GIT_DIR
+    #[arg(long, value_name("path"))]
+    git_dir: Option<PathBuf>,
+    /// Path to the working tree, like setting GIT_WORK_TREE
+    #[arg(long, value_name("path"))]
+    work_tree: Option<PathBuf>,
+    #[command(subcommand)]
+    subcommand: Subcommand,
+}
+
+#[derive(clap::Subcommand)]
 enum Subcommand {
     /// Initializes an empty repository
-    Init,
+    Init(InitArgs),
+    /// Clone a local repository into a new directory
+    Clone(CloneArgs),
     /// Reads a specific object from repository
     CatFile(CatFileArgs),
     /// Calculates hash for an object, optionally saves it to repository
@@ -29,16 +44,122 @@ enum Subcommand {
     WriteTree,
     /// Create a commit for given tree
     CommitTree(CommitTreeArgs),
+    /// Compare the content and mode of blobs found via two tree objects
+    DiffTree(DiffTreeArgs),
+    /// Switch branches or check out a commit, updating the working tree
+    Checkout(CheckoutArgs),
+    /// Switch branches
+    Switch(SwitchArgs),
+    /// Restore working tree files or index entries
+    Restore(RestoreArgs),
+    /// List, create, rename or delete branches
+    Branch(BranchArgs),
+    /// Join another branch into the current one
+    Merge(MergeArgs),
+    /// Run a three-way merge on a single file
+    MergeFile(MergeFileArgs),
+    /// Merge two commits into a new tree without touching the index or working tree
+    MergeTree(MergeTreeArgs),
+    /// Show what revision last modified each line of a file
+    Blame(BlameArgs),
+    /// Verify the integrity and connectivity of the objects in the repository
+    Fsck(FsckArgs),
+    /// Pack objects into a single pack file
+    Repack(RepackArgs),
+    /// Remove unreachable loose objects
+    Prune(PruneArgs),
+    /// Move references into the packed-refs file
+    PackRefs(PackRefsArgs),
+    /// Clean up and optimize the repository: pack refs and objects, expire reflogs, prune
+    Gc(GcArgs),
+    /// Manage multiple working trees sharing one repository
+    Worktree(WorktreeArgs),
+    /// Show or prune the logs of reference updates
+    Reflog(ReflogArgs),
 }
 
 #[derive(Debug, Clone, Args)]
+#[command(group(clap::ArgGroup::new("batch_mode").args(["batch", "batch_check", "batch_command"])))]
 struct CatFileArgs {
-    /// The object hash to read out
-    #[arg(required(true), index(1))]
-    object: ObjectRef,
+    /// The object to read out, a revision like `HEAD~2` or an abbreviated hash. In the
+    /// `<type> <object>` form, the type it is expected to have or be peeled to
+    #[arg(index(1), required_unless_present_any(["batch", "batch_check", "batch_command"]))]
+    object: Option<String>,
+    /// The object to read out when a type is given first
+    #[arg(index(2), value_name("OBJECT"), conflicts_with("mode"))]
+    typed_object: Option<String>,
     /// Automatically pretty-print based on object type
-    #[arg(short)]
+    #[arg(short, group("mode"))]
     pretty_print: bool,
+    /// Show the object type
+    #[arg(short('t'), group("mode"))]
+    show_type: bool,
+    /// Show the object size
+    #[arg(short('s'), group("mode"))]
+    show_size: bool,
+    /// Exit with zero status if the object exists and is valid, non-zero otherwise
+    #[arg(short('e'), group("mode"))]
+    exists: bool,
+    /// Print the header and contents of each object named on stdin
+    #[arg(long, value_name("format"), num_args(0..=1), require_equals(true),
+          default_missing_value(batch::DEFAULT_FORMAT), conflicts_with("object"))]
+    batch: Option<String>,
+    /// Print only the header of each object named on stdin
+    #[arg(long, value_name("format"), num_args(0..=1), require_equals(true),
+          default_missing_value(batch::DEFAULT_FORMAT), conflicts_with_all(["object", "batch"]))]
+    batch_check: Option<String>,
+    /// Read `contents <object>`, `info <object>` and `flush` commands from stdin
+    #[arg(long, value_name("format"), num_args(0..=1), require_equals(true),
+          default_missing_value(batch::DEFAULT_FORMAT),
+          conflicts_with_all(["object", "batch", "batch_check"]))]
+    batch_command: Option<String>,
+    /// Show all objects in the repository instead of reading names from stdin
+    #[arg(long, requires("batch_mode"))]
+    batch_all_objects: bool,
+    /// Buffer the output instead of flushing it after every object
+    #[arg(long, requires("batch_mode"))]
+    buffer: bool,
+    /// Accepted for compatibility, objects are always listed in hash order
+    #[arg(long, requires("batch_all_objects"))]
+    unordered: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+struct InitArgs {
+    /// Directory to create the repository in, the current one by default
+    directory: Option<PathBuf>,
+    /// Create a repository without a working tree
+    #[arg(long)]
+    bare: bool,
+    /// Name of the branch HEAD points to (default from init.defaultBranch, master)
+    #[arg(short = 'b', long, value_name("branch-name"))]
+    initial_branch: Option<String>,
+    /// Directory to copy templates from
+    #[arg(long, value_name("template-directory"))]
+    template: Option<String>,
+    /// Share the repository with the group, everybody or given octal permissions
+    #[arg(long, value_name("permissions"), num_args = 0..=1, require_equals = true, default_missing_value = "group")]
+    shared: Option<String>,
+    /// Hash function naming the objects, sha1 or sha256
+    #[arg(long, value_name("format"))]
+    object_format: Option<String>,
+    /// Only print errors and warnings
+    #[arg(short = 'q', long)]
+    quiet: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+struct CloneArgs {
+    /// The repository to clone from
+    repository: PathBuf,
+    /// Where to clone to, by default named after the repository
+    directory: Option<PathBuf>,
+    /// Borrow the objects of the source repository instead of copying them
+    #[arg(short = 's', long)]
+    shared: bool,
+    /// Borrow objects from this repository too, copying only those it is missing
+    #[arg(long, value_name("repository"))]
+    reference: Vec<PathBuf>,
 }
 
 #[derive(Debug, Clone, Args)]
@@ -53,12 +174,30 @@ struct LsTreeArgs {
 
 #[derive(Debug, Clone, Args)]
 struct HashObjectArgs {
-    /// The file to read data from
-    #[arg(required(true), index(1))]
-    file: String,
-    /// Automatically pretty-print based on object type
+    /// The files to read data from
+    #[arg(index(1))]
+    files: Vec<String>,
+    /// Write the object into the object database
     #[arg(short)]
     write: bool,
+    /// Type of object to create
+    #[arg(short('t'), value_name("type"), default_value("blob"))]
+    object_type: String,
+    /// Allow any object type and skip validating the contents
+    #[arg(long)]
+    literally: bool,
+    /// Read the object from standard input
+    #[arg(long)]
+    stdin: bool,
+    /// Read file names from standard input, one per line
+    #[arg(long, conflicts_with_all(["stdin", "files", "path"]))]
+    stdin_paths: bool,
+    /// Hash the object as if it were located at the given path, for choosing filters
+    #[arg(long, value_name("file"))]
+    path: Option<String>,
+    /// Hash the contents as is, without applying filters like line ending conversion
+    #[arg(long, conflicts_with("path"))]
+    no_filters: bool,
 }
 
 #[derive(Debug, Clone, Args)]
@@ -76,531 +215,830 @@ struct CommitTreeArgs {
     message: String,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
-struct ObjectRef(String);
-
-impl ObjectRef {
-    pub fn from_sha1(hash: &str) -> anyhow::Result<ObjectRef> {
-        anyhow::ensure!(hash.len() == 40);
-        anyhow::ensure!(hash.chars().all(|c| c.is_ascii_hexdigit()));
-        Ok(ObjectRef(hash.to_owned()))
-    }
-
-    fn hash_prefix(&self) -> &str {
-        &self.0[..2]
-    }
+#[derive(Debug, Clone, Args)]
+struct DiffTreeArgs {
+    /// Tree-ish objects to compare, a single commit is compared with its first parent
+    #[arg(required(true), num_args(1..=2))]
+    objects: Vec<String>,
+    /// Recurse into sub-trees
+    #[arg(short)]
+    recursive: bool,
+    /// Generate a patch, implies -r
+    #[arg(short, long)]
+    patch: bool,
+    /// Show only names of changed files
+    #[arg(long)]
+    name_only: bool,
+    /// Show only names and status of changed files
+    #[arg(long)]
+    name_status: bool,
+    /// Detect renames, optionally with a similarity threshold (e.g. -M90%)
+    #[arg(
+        short = 'M',
+        long = "find-renames",
+        value_name = "n",
+        num_args(0..=1),
+        require_equals(true),
+        default_missing_value("")
+    )]
+    find_renames: Option<String>,
+    /// Detect copies as well as renames, optionally with a similarity threshold
+    #[arg(
+        short = 'C',
+        long = "find-copies",
+        value_name = "n",
+        num_args(0..=1),
+        require_equals(true),
+        default_missing_value("")
+    )]
+    find_copies: Option<String>,
+    /// Consider unmodified files as copy sources too, implies -C
+    #[arg(long)]
+    find_copies_harder: bool,
+    /// Skip inexact rename detection if there are more files than this, defaults to
+    /// diff.renameLimit
+    #[arg(short = 'l')]
+    rename_limit: Option<usize>,
+    /// Show the root commit as a big creation event
+    #[arg(long)]
+    root: bool,
+}
 
-    fn matches_remainder(&self, remainder: &str) -> bool {
-        remainder.eq_ignore_ascii_case(&self.0[2..])
-    }
+#[derive(Debug, Clone, Args)]
+struct CheckoutArgs {
+    /// Branch to switch to or commit to detach at, the start point with -b
+    #[arg(index(1))]
+    target: Option<String>,
+    /// Create a new branch and switch to it
+    #[arg(short = 'b', value_name = "new_branch")]
+    new_branch: Option<String>,
+    /// Detach HEAD at the given commit, even if it names a branch
+    #[arg(long)]
+    detach: bool,
+    /// Throw away local modifications
+    #[arg(short, long)]
+    force: bool,
+}
 
-    fn matches(&self, hash: &str) -> bool {
-        self.0.eq_ignore_ascii_case(hash)
-    }
+#[derive(Debug, Clone, Args)]
+struct SwitchArgs {
+    /// Branch to switch to, the start point with -c
+    #[arg(index(1))]
+    branch: Option<String>,
+    /// Create a new branch and switch to it
+    #[arg(short = 'c', long = "create", value_name = "new_branch")]
+    create: Option<String>,
+    /// Switch to a commit in detached HEAD state
+    #[arg(short, long)]
+    detach: bool,
+    /// Throw away local modifications
+    #[arg(short, long, alias = "discard-changes")]
+    force: bool,
 }
 
-impl ValueParserFactory for ObjectRef {
-    type Parser = ValueParser;
+#[derive(Debug, Clone, Args)]
+struct RestoreArgs {
+    /// Restore from this tree-ish, defaults to the index (or HEAD with --staged)
+    #[arg(short, long)]
+    source: Option<String>,
+    /// Restore the index
+    #[arg(short = 'S', long)]
+    staged: bool,
+    /// Restore the working tree, the default if --staged is not given
+    #[arg(short = 'W', long)]
+    worktree: bool,
+    /// Restore conflicted files from our side of the merge
+    #[arg(long, conflicts_with = "theirs")]
+    ours: bool,
+    /// Restore conflicted files from their side of the merge
+    #[arg(long)]
+    theirs: bool,
+    /// Files to restore
+    #[arg(required(true))]
+    pathspec: Vec<String>,
+}
 
-    fn value_parser() -> Self::Parser {
-        ValueParser::from(ObjectRef::from_sha1)
-    }
+#[derive(Debug, Clone, Args)]
+struct BranchArgs {
+    /// Branch names, followed by the start point when creating a branch
+    args: Vec<String>,
+    /// List both local and remote-tracking branches
+    #[arg(short, long)]
+    all: bool,
+    /// List remote-tracking branches
+    #[arg(short, long)]
+    remotes: bool,
+    /// Show the commit and subject line of each branch tip
+    #[arg(short, long)]
+    verbose: bool,
+    /// Only list branches merged into the commit (HEAD by default)
+    #[arg(long, value_name = "commit", num_args(0..=1), default_missing_value("HEAD"))]
+    merged: Option<String>,
+    /// Only list branches not merged into the commit (HEAD by default)
+    #[arg(long, value_name = "commit", num_args(0..=1), default_missing_value("HEAD"))]
+    no_merged: Option<String>,
+    /// Rename a branch, the current one if only the new name is given
+    #[arg(short = 'm', long = "move")]
+    rename: bool,
+    /// Rename a branch even if the new name already exists
+    #[arg(short = 'M')]
+    force_rename: bool,
+    /// Delete fully merged branches
+    #[arg(short, long)]
+    delete: bool,
+    /// Delete branches regardless of their merge status
+    #[arg(short = 'D')]
+    force_delete: bool,
+    /// Set upstream of a branch, the current one by default
+    #[arg(short = 'u', long, value_name = "upstream")]
+    set_upstream_to: Option<String>,
+    /// Remove upstream information of a branch, the current one by default
+    #[arg(long)]
+    unset_upstream: bool,
+    /// Reset the branch to the start point if it already exists
+    #[arg(short, long)]
+    force: bool,
 }
 
-struct Repository {
-    path: PathBuf,
+#[derive(Debug, Clone, Args)]
+struct MergeArgs {
+    /// Branch or commit to merge into HEAD
+    #[arg(required_unless_present("abort"))]
+    commit: Option<String>,
+    /// Message for the merge commit
+    #[arg(short, long)]
+    message: Option<String>,
+    /// Always create a merge commit, even when fast-forwarding is possible
+    #[arg(long, conflicts_with = "ff_only")]
+    no_ff: bool,
+    /// Refuse to merge unless HEAD can be fast-forwarded
+    #[arg(long)]
+    ff_only: bool,
+    /// Stop before creating the merge commit
+    #[arg(long)]
+    no_commit: bool,
+    /// Strategy option, `ours` or `theirs` resolves conflicting hunks in favor of a side
+    #[arg(short = 'X', long = "strategy-option", value_name = "option")]
+    strategy_option: Option<String>,
+    /// Allow merging histories without a common ancestor
+    #[arg(long)]
+    allow_unrelated_histories: bool,
+    /// Abort the merge in progress, restoring the pre-merge state
+    #[arg(long)]
+    abort: bool,
 }
 
-impl Repository {
-    pub fn from_current_dir() -> anyhow::Result<Repository> {
-        Ok(Repository {
-            path: std::env::current_dir()?.join(".git"),
-        })
-    }
+#[derive(Debug, Clone, Args)]
+struct MergeFileArgs {
+    /// File to merge changes into, overwritten with the result unless -p is given
+    current: PathBuf,
+    /// Common ancestor of both files
+    base: PathBuf,
+    /// File with the changes to merge
+    other: PathBuf,
+    /// Labels for the current, base and other file in conflict markers
+    #[arg(short = 'L', value_name = "label")]
+    labels: Vec<String>,
+    /// Write the result to standard output instead of overwriting the current file
+    #[arg(short = 'p', long)]
+    stdout: bool,
+    /// Resolve conflicts in favor of the current file
+    #[arg(long, conflicts_with_all = ["theirs", "union"])]
+    ours: bool,
+    /// Resolve conflicts in favor of the other file
+    #[arg(long, conflicts_with = "union")]
+    theirs: bool,
+    /// Resolve conflicts by keeping lines from both files
+    #[arg(long)]
+    union: bool,
+    /// Show the base version in conflicts
+    #[arg(long, conflicts_with = "zdiff3")]
+    diff3: bool,
+    /// Show the base version in conflicts, without lines both sides agree on
+    #[arg(long)]
+    zdiff3: bool,
+    /// Length of conflict markers
+    #[arg(long, default_value_t = merge::DEFAULT_MARKER_SIZE)]
+    marker_size: usize,
+    /// Do not warn about conflicts
+    #[arg(short, long)]
+    quiet: bool,
+}
 
-    pub fn find_from_current_dir() -> anyhow::Result<Repository> {
-        let mut current_dir = std::env::current_dir()?;
-        // TODO: Arbitrary depth limit of 50, make it configurable
-        for _ in 0..50 {
-            let maybe_path = fs::read_dir(&current_dir)?.find_map(|d| {
-                let dir = d.ok()?;
-                if dir.file_type().ok()?.is_dir() && dir.file_name() == ".git" {
-                    Some(dir.path())
-                } else {
-                    None
-                }
-            });
+#[derive(Debug, Clone, Args)]
+struct MergeTreeArgs {
+    /// Write the merged tree, the only supported mode
+    #[arg(long)]
+    write_tree: bool,
+    /// Only list names of conflicted files
+    #[arg(long)]
+    name_only: bool,
+    /// Show informational messages, by default only shown when there are conflicts
+    #[arg(long, overrides_with = "no_messages")]
+    messages: bool,
+    #[arg(long, hide = true)]
+    no_messages: bool,
+    /// Use this commit or tree as merge base instead of computing it
+    #[arg(long, value_name = "tree-ish")]
+    merge_base: Option<String>,
+    /// Allow merging histories without a common ancestor
+    #[arg(long)]
+    allow_unrelated_histories: bool,
+    /// Strategy option, `ours` or `theirs` resolves conflicting hunks in favor of a side
+    #[arg(short = 'X', long = "strategy-option", value_name = "option")]
+    strategy_option: Option<String>,
+    branch1: String,
+    branch2: String,
+}
 
-            if let Some(path) = maybe_path {
-                return Ok(Repository { path });
-            }
-            if !current_dir.pop() {
-                return Err(anyhow::Error::msg(
-                    "Git repository not found (reached root)",
-                ));
-            }
-        }
-        Err(anyhow::Error::msg(format!(
-            "Git repository not found (depth limit reached at {:?})",
-            current_dir
-        )))
-    }
+#[derive(Debug, Clone, Args)]
+struct BlameArgs {
+    /// Optional revision to start from, followed by the file to blame
+    #[arg(required = true, num_args = 1..=2, value_name = "rev> <file")]
+    args: Vec<String>,
+    /// Only blame lines in range `start,end`, `start,+count` or `/regex/,end`
+    #[arg(short = 'L', value_name = "range")]
+    ranges: Vec<String>,
+    /// Show machine readable output, with commit details once per commit
+    #[arg(long)]
+    porcelain: bool,
+    /// Like --porcelain, but with commit details for every line
+    #[arg(long)]
+    line_porcelain: bool,
+    /// Look through this revision, attributing its changes to earlier commits
+    #[arg(long, value_name = "rev")]
+    ignore_rev: Vec<String>,
+    /// Ignore revisions listed in this file, one per line
+    #[arg(long, value_name = "file")]
+    ignore_revs_file: Vec<PathBuf>,
+    /// Detect lines moved or copied within the file
+    #[arg(short = 'M')]
+    detect_moves: bool,
+    /// Do not treat root commits as boundaries
+    #[arg(long)]
+    root: bool,
+    /// Show full commit hashes
+    #[arg(short = 'l')]
+    long: bool,
+    /// Suppress author name and date
+    #[arg(short = 's')]
+    suppress: bool,
+    /// Show author email instead of name
+    #[arg(short = 'e', long = "show-email")]
+    show_email: bool,
+    /// Show the file name of the original commit
+    #[arg(short = 'f', long = "show-name")]
+    show_name: bool,
+}
 
-    pub fn init(&self) -> anyhow::Result<()> {
-        fs::create_dir(&self.path)?;
-        fs::create_dir(self.path.join("objects"))?;
-        fs::create_dir(self.path.join("refs"))?;
-        fs::write(self.path.join("HEAD"), "ref: refs/heads/master\n")?;
-        Ok(())
-    }
+#[derive(Debug, Clone, Args)]
+struct FsckArgs {
+    /// Print all unreachable objects instead of only dangling ones
+    #[arg(long)]
+    unreachable: bool,
+    /// Do not print dangling objects
+    #[arg(long = "no-dangling")]
+    no_dangling: bool,
+    /// Report root commits
+    #[arg(long)]
+    root: bool,
+    /// Do not consider objects referenced only by reflogs reachable
+    #[arg(long = "no-reflogs")]
+    no_reflogs: bool,
+    /// Only check connectivity, not the contents of objects
+    #[arg(long = "connectivity-only")]
+    connectivity_only: bool,
+    /// Write dangling objects into .git/lost-found
+    #[arg(long = "lost-found")]
+    lost_found: bool,
+}
 
-    pub fn find_object(&self, object_ref: &ObjectRef) -> anyhow::Result<Object> {
-        let maybe_container_path = fs::read_dir(self.path.join("objects/"))?.find_map(|f| {
-            let dir = f.ok()?;
-            if dir.file_type().ok()?.is_dir()
-                && dir
-                    .file_name()
-                    .eq_ignore_ascii_case(object_ref.hash_prefix())
-            {
-                Some(dir.path())
-            } else {
-                None
-            }
-        });
-        eprintln!("Container path: {maybe_container_path:?}");
-
-        if let Some(container_path) = maybe_container_path {
-            let maybe_object_path = fs::read_dir(container_path)?.find_map(|f| {
-                let file = f.ok()?;
-                if file.file_type().ok()?.is_file()
-                    && object_ref.matches_remainder(file.file_name().to_str()?)
-                {
-                    Some(file.path())
-                } else {
-                    None
-                }
-            });
-            eprintln!("{maybe_object_path:?}");
-            if let Some(object_path) = maybe_object_path {
-                let object = Object::from_path(&object_path).context("Trying to read object")?;
-                anyhow::ensure!(object_ref.matches(&object.hash_string()));
-                Ok(object)
-            } else {
-                Err(anyhow::Error::msg("Could not find requested object"))
-            }
-        } else {
-            Err(anyhow::Error::msg("Could not find requested object"))
-        }
-    }
+#[derive(Debug, Clone, Args)]
+struct RepackArgs {
+    /// Pack all reachable objects into a single pack, not only loose ones
+    #[arg(short = 'a')]
+    all: bool,
+    /// Like -a, but unreachable objects of removed packs are kept as loose objects
+    #[arg(short = 'A')]
+    all_keep_unreachable: bool,
+    /// Remove redundant packs and loose objects after packing
+    #[arg(short = 'd')]
+    delete: bool,
+    /// Do not report what was packed
+    #[arg(short = 'q', long)]
+    quiet: bool,
+    /// Number of objects considered as delta base for each object
+    #[arg(long)]
+    window: Option<usize>,
+    /// Maximum length of delta chains
+    #[arg(long)]
+    depth: Option<usize>,
+}
 
-    pub fn save_object(&self, object: &Object) -> anyhow::Result<()> {
-        let hash = object.hash_string();
-        let (prefix, remainder) = hash.as_str().split_at(2);
-        let container_path = self.path.join("objects/").join(prefix);
-        let file_path = container_path.join(remainder);
-        fs::create_dir_all(container_path)?;
-        object.write_to(&file_path)
-    }
+#[derive(Debug, Clone, Args)]
+struct PruneArgs {
+    /// Do not remove anything, only report what would be removed
+    #[arg(short = 'n', long = "dry-run")]
+    dry_run: bool,
+    /// Report removed objects
+    #[arg(short = 'v', long)]
+    verbose: bool,
+    /// Only prune objects older than this, like "2.weeks.ago"
+    #[arg(long)]
+    expire: Option<String>,
 }
 
-#[derive(Debug)]
-struct TreeItem<'a> {
-    mode: u32,
-    name: Cow<'a, str>,
-    hash: Cow<'a, [u8; 20]>,
+#[derive(Debug, Clone, Args)]
+struct PackRefsArgs {
+    /// Pack all references, not only tags and already packed ones
+    #[arg(long)]
+    all: bool,
 }
 
-impl TreeItem<'_> {
-    pub fn parse<'s>(data: &'s [u8]) -> anyhow::Result<(&'s [u8], TreeItem<'s>)> {
-        let index_first_zero = data.iter().position(|b| *b == 0u8).ok_or_else(|| {
-            anyhow::Error::msg("Invalid tree item data, could not find filename terminator")
-        })?;
-        let (header, rest) = data.split_at(index_first_zero);
-        let (hash, rest) = rest[1..].split_at(20);
-        let (mode, name) = std::str::from_utf8(header)?
-            .split_once(' ')
-            .ok_or_else(|| {
-                anyhow::Error::msg(
-                    "Invalid tree item data, could not find split between mode and filename.",
-                )
-            })?;
+#[derive(Debug, Clone, Args)]
+struct WorktreeArgs {
+    #[command(subcommand)]
+    command: WorktreeCommand,
+}
 
-        Ok((
-            rest,
-            TreeItem {
-                mode: u32::from_str_radix(mode, 8)?,
-                name: Cow::Borrowed(name),
-                hash: Cow::Borrowed(hash.try_into()?),
-            },
-        ))
-    }
+#[derive(Debug, Clone, clap::Subcommand)]
+enum WorktreeCommand {
+    /// Create a working tree at <path> and check out <commit-ish> in it
+    Add(WorktreeAddArgs),
+    /// List the working trees
+    List(WorktreeListArgs),
+    /// Keep a working tree from being pruned or removed
+    Lock(WorktreeLockArgs),
+    /// Remove administrative files of working trees that no longer exist
+    Prune(WorktreePruneArgs),
+    /// Remove a working tree
+    Remove(WorktreeRemoveArgs),
+    /// Unlock a working tree
+    Unlock(WorktreeUnlockArgs),
+}
 
-    pub fn is_file(&self) -> bool {
-        0 != (self.mode & (1 << 15))
-    }
+#[derive(Debug, Clone, Args)]
+struct WorktreeAddArgs {
+    /// Where to create the working tree
+    path: PathBuf,
+    /// Branch to check out or commit to detach HEAD at, by default a new branch named
+    /// after <path>
+    commit_ish: Option<String>,
+    /// Create a new branch starting at <commit-ish>
+    #[arg(short = 'b', value_name("new-branch"), conflicts_with_all(["reset_branch", "detach"]))]
+    new_branch: Option<String>,
+    /// Create a new branch, or reset it if it exists
+    #[arg(short = 'B', value_name("new-branch"), conflicts_with("detach"))]
+    reset_branch: Option<String>,
+    /// Detach HEAD in the new working tree
+    #[arg(short = 'd', long)]
+    detach: bool,
+    /// Check out a branch even if another working tree has it checked out
+    #[arg(short = 'f', long)]
+    force: bool,
+    /// Do not populate the working tree and index
+    #[arg(long = "no-checkout")]
+    no_checkout: bool,
+    /// Lock the new working tree
+    #[arg(long)]
+    lock: bool,
+    /// Why the new working tree is locked
+    #[arg(long, requires("lock"))]
+    reason: Option<String>,
+    /// Do not report what was done
+    #[arg(short = 'q', long)]
+    quiet: bool,
 }
 
-#[derive(Debug, Clone)]
-struct TreeDataIterator<'a> {
-    data: &'a [u8],
+#[derive(Debug, Clone, Args)]
+struct WorktreeListArgs {
+    /// Output in a format easy to parse for scripts
+    #[arg(long)]
+    porcelain: bool,
+    /// Show lock and prune reasons
+    #[arg(short = 'v', long)]
+    verbose: bool,
 }
 
-impl<'a> Iterator for TreeDataIterator<'a> {
-    type Item = TreeItem<'a>;
+#[derive(Debug, Clone, Args)]
+struct WorktreeLockArgs {
+    /// Path or name of the working tree
+    worktree: String,
+    /// Why the working tree is locked
+    #[arg(long)]
+    reason: Option<String>,
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let (rest, item) = TreeItem::parse(self.data).ok()?;
-        self.data = rest;
-        Some(item)
-    }
+#[derive(Debug, Clone, Args)]
+struct WorktreePruneArgs {
+    /// Only report what would be removed
+    #[arg(short = 'n', long = "dry-run")]
+    dry_run: bool,
+    /// Report what is removed
+    #[arg(short = 'v', long)]
+    verbose: bool,
+    /// Only prune working trees that went missing before this date
+    #[arg(long)]
+    expire: Option<String>,
 }
 
-#[derive(Debug, Clone)]
-struct TreeData {
-    data: Vec<u8>,
+#[derive(Debug, Clone, Args)]
+struct WorktreeRemoveArgs {
+    /// Path or name of the working tree
+    worktree: String,
+    /// Remove it even with changes or untracked files, twice to remove a locked one
+    #[arg(short = 'f', long, action = clap::ArgAction::Count)]
+    force: u8,
 }
 
-impl TreeData {
-    pub fn empty() -> TreeData {
-        TreeData { data: vec![] }
-    }
+#[derive(Debug, Clone, Args)]
+struct WorktreeUnlockArgs {
+    /// Path or name of the working tree
+    worktree: String,
+}
 
-    pub fn iter(&self) -> TreeDataIterator<'_> {
-        TreeDataIterator { data: &self.data }
-    }
+#[derive(Debug, Clone, Args)]
+#[command(args_conflicts_with_subcommands = true)]
+struct ReflogArgs {
+    #[command(subcommand)]
+    command: Option<ReflogCommand>,
+    /// Reference whose log is shown, HEAD by default
+    #[arg(value_name("ref"))]
+    reference: Option<String>,
+}
 
-    pub fn add_object(&mut self, object: &Object, name: &str, mut mode: u32) -> anyhow::Result<()> {
-        if let Object::Tree(_) = object {
-            mode &= !(1 << 15);
-        } else {
-            mode |= 1 << 15;
-        }
+#[derive(Debug, Clone, clap::Subcommand)]
+enum ReflogCommand {
+    /// Show the log of a reference, the default
+    Show(ReflogShowArgs),
+    /// Remove old entries from reflogs
+    Expire(ReflogExpireArgs),
+    /// Remove single entries like master@{2} from reflogs
+    Delete(ReflogDeleteArgs),
+}
 
-        let mode = format!("{mode:o}");
-        self.data.put(mode.as_bytes());
-        self.data.put_u8(b' ');
-        self.data.put(name.as_bytes());
-        self.data.put_u8(0);
-        self.data.put(&object.hash()[..]);
-        Ok(())
-    }
+#[derive(Debug, Clone, Args)]
+struct ReflogShowArgs {
+    /// Reference whose log is shown, HEAD by default
+    #[arg(value_name("ref"))]
+    reference: Option<String>,
+}
 
-    pub fn add_item(&mut self, item: &TreeItem) {
-        let mode = format!("{:o}", item.mode);
-        self.data.put(mode.as_bytes());
-        self.data.put_u8(b' ');
-        self.data.put(item.name.as_bytes());
-        self.data.put_u8(0);
-        self.data.put(&item.hash[..]);
-    }
-
-    pub fn sort(&mut self) {
-        let mut items: Vec<_> = self
-            .iter()
-            .map(|i| TreeItem {
-                mode: i.mode,
-                name: Cow::Owned(i.name.into_owned()),
-                hash: Cow::Owned(i.hash.into_owned()),
-            })
-            .collect();
-        items.sort_by(|a, b| a.name.cmp(&b.name));
-
-        self.data.clear();
-        for item in items {
-            self.add_item(&item);
-        }
-    }
-}
-
-#[derive(Debug, PartialEq)]
-struct PersonLine<'a> {
-    name: Cow<'a, str>,
-    email: Cow<'a, str>,
-    timestamp: u64,
-    timezone: i32,
-}
-
-impl<'a> TryFrom<&'a str> for PersonLine<'a> {
-    type Error = anyhow::Error;
-
-    fn try_from(value: &'a str) -> Result<Self, Self::Error> {
-        let (name, rest) = value
-            .split_once('<')
-            .ok_or_else(|| anyhow::Error::msg("Could not find beginning of email"))?;
-        let (email, rest) = rest
-            .split_once('>')
-            .ok_or_else(|| anyhow::Error::msg("Could not find end of email"))?;
-        let (timestamp, timezone) = rest
-            .trim()
-            .split_once(' ')
-            .ok_or_else(|| anyhow::Error::msg("Could not find timezone"))?;
-
-        let name = name.trim();
-        let timestamp = timestamp.parse().context("Trying to parse timestamp")?;
-
-        let timezone = timezone
-            // .strip_prefix("+")
-            // .unwrap_or(timezone)
-            .parse()
-            .with_context(|| format!("Trying to parse timezone: {}", timezone))?;
-        Ok(PersonLine {
-            name: name.into(),
-            email: email.into(),
-            timestamp: timestamp,
-            timezone: timezone,
-        })
-    }
-}
-
-#[derive(Debug)]
-struct CommitData<'a> {
-    tree_hash: Cow<'a, str>,
-    parent_hashes: Vec<Cow<'a, str>>,
-    author: PersonLine<'a>,
-    committer: PersonLine<'a>,
-    message: Cow<'a, str>,
-}
-
-impl<'a> TryFrom<&'a str> for CommitData<'a> {
-    type Error = anyhow::Error;
-
-    fn try_from(mut value: &'a str) -> Result<Self, Self::Error> {
-        let mut tree_hash = None;
-        let mut parent_hashes = Vec::new();
-        let mut author = None;
-        let mut committer = None;
-        loop {
-            let (line, rest) = value
-                .split_once('\n')
-                .ok_or_else(|| anyhow::Error::msg("Could not find next commit line"))?;
-            value = rest;
-
-            // Header is separated from message by one empty line
-            if line.is_empty() {
-                break;
-            }
-            let (tag, value) = line
-                .split_once(' ')
-                .ok_or_else(|| anyhow::Error::msg("Failed to parse header item"))?;
-
-            match tag {
-                "tree" => {
-                    anyhow::ensure!(tree_hash == None);
-                    anyhow::ensure!(value.chars().all(|c| c.is_ascii_hexdigit()));
-                    anyhow::ensure!(value.len() == 40);
-                    tree_hash = Some(value.into());
-                }
-                "author" => {
-                    anyhow::ensure!(author == None);
-                    author = Some(PersonLine::try_from(value).context("Trying to parse author")?);
-                }
-                "committer" => {
-                    anyhow::ensure!(committer == None);
-                    committer =
-                        Some(PersonLine::try_from(value).context("Trying to parse commiter")?);
-                }
-                "parent" => {
-                    anyhow::ensure!(value.chars().all(|c| c.is_ascii_hexdigit()));
-                    anyhow::ensure!(value.len() == 40);
-                    parent_hashes.push(value.into());
-                }
-                _ => {
-                    anyhow::bail!("unexpected tag in commit '{}'", tag);
-                }
-            }
-        }
+#[derive(Debug, Clone, Args)]
+struct ReflogExpireArgs {
+    /// Remove entries older than this date (default from gc.reflogExpire, 90.days.ago)
+    #[arg(long)]
+    expire: Option<String>,
+    /// Remove entries older than this date that are not reachable from the reference
+    /// (default from gc.reflogExpireUnreachable, 30.days.ago)
+    #[arg(long = "expire-unreachable")]
+    expire_unreachable: Option<String>,
+    /// Expire the reflogs of all references
+    #[arg(long)]
+    all: bool,
+    /// Only report what would be removed
+    #[arg(short = 'n', long = "dry-run")]
+    dry_run: bool,
+    /// References whose reflogs are expired
+    #[arg(value_name("ref"))]
+    refs: Vec<String>,
+}
 
-        Ok(CommitData {
-            tree_hash: tree_hash.ok_or_else(|| anyhow::Error::msg("Tree hash not found"))?,
-            parent_hashes,
-            author: author.ok_or_else(|| anyhow::Error::msg("Author not proviced"))?,
-            committer: committer.ok_or_else(|| anyhow::Error::msg("Committer not provided"))?,
-            message: value.into(),
-        })
-    }
+#[derive(Debug, Clone, Args)]
+struct ReflogDeleteArgs {
+    /// Make each remaining entry start at the value the entry before it ended at
+    #[arg(long)]
+    rewrite: bool,
+    /// Point the reference at the newest remaining entry if the newest one was deleted
+    #[arg(long)]
+    updateref: bool,
+    /// Entries to delete, like HEAD@{1}
+    #[arg(value_name("ref@{n}"), required = true)]
+    entries: Vec<String>,
 }
 
-impl Into<Commit> for CommitData<'_> {
-    fn into(self) -> Commit {
-        let mut buf = String::new();
-        buf.push_str("tree ");
-        buf.push_str(&self.tree_hash);
-        for parent in self.parent_hashes.iter() {
-            buf.push_str("\nparent ");
-            buf.push_str(parent);
-        }
-        buf.push_str(&format!(
-            "\nauthor {} <{}> {} {:+04}",
-            self.author.name, self.author.email, self.author.timestamp, self.author.timezone
-        ));
-        buf.push_str(&format!(
-            "\ncommitter {} <{}> {} {:+04}",
-            self.committer.name,
-            self.committer.email,
-            self.committer.timestamp,
-            self.committer.timezone
-        ));
-        buf.push('\n');
-        buf.push('\n');
-        buf.push_str(&self.message);
-        Commit(buf)
-    }
+#[derive(Debug, Clone, Args)]
+struct GcArgs {
+    /// Prune loose objects older than this date (default from gc.pruneExpire, 2.weeks.ago)
+    #[arg(long, num_args = 0..=1, default_missing_value = "now")]
+    prune: Option<String>,
+    /// Do not prune any loose objects
+    #[arg(long = "no-prune")]
+    no_prune: bool,
+    /// Search harder for deltas, taking more time
+    #[arg(long)]
+    aggressive: bool,
+    /// Do not report what was packed
+    #[arg(short = 'q', long)]
+    quiet: bool,
 }
 
-#[derive(Debug, Clone)]
-struct Commit(String);
+/// The repository a command works on. Warnings collected while using it are printed once the
+/// command is done with it.
+struct CommandRepository(Repository);
 
-impl TryFrom<&[u8]> for Commit {
-    type Error = anyhow::Error;
+impl std::ops::Deref for CommandRepository {
+    type Target = Repository;
 
-    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        let string = std::str::from_utf8(value)?;
-        CommitData::try_from(string)?;
-        Ok(Self(string.to_owned()))
+    fn deref(&self) -> &Repository {
+        &self.0
     }
 }
 
-impl Commit {
-    pub fn data(&self) -> CommitData<'_> {
-        CommitData::try_from(self.0.as_str())
-            .expect("It is invariant that commit contains correct data")
-    }
-
-    pub fn bytes(&self) -> &[u8] {
-        self.0.as_bytes()
+impl Drop for CommandRepository {
+    fn drop(&mut self) {
+        for warning in self.0.take_warnings() {
+            eprintln!("{warning}");
+        }
     }
 }
 
-#[derive(Debug, Clone)]
-enum Object {
-    Unknown { kind: String, data: Vec<u8> },
-    Blob(Vec<u8>),
-    Commit(Commit),
-    Tree(TreeData),
+fn find_repository() -> anyhow::Result<CommandRepository> {
+    Ok(CommandRepository(Repository::find_from_current_dir()?))
 }
 
-impl Object {
-    fn from_path(path: &PathBuf) -> anyhow::Result<Object> {
-        let file = fs::File::open(path)?;
-        let mut decoder = flate2::read::ZlibDecoder::new(&file);
-        let mut contents = Vec::new();
-        decoder.read_to_end(&mut contents)?;
-
-        // The data in contents is structured like this:
-        // <type> <size>\0<payload>
-        //       ^- a space character
-        // Thus we need to split at first zero byte
-        let index_first_zero = contents.iter().position(|b| *b == 0u8).ok_or_else(|| {
-            anyhow::Error::msg("Invalid object file data, could not find header terminator.")
-        })?;
-        let header = std::str::from_utf8(&contents[..index_first_zero])?;
-        let (object_type, object_size) = header.split_once(' ').ok_or_else(|| {
-            anyhow::Error::msg(format!(
-                "Invalid object file header format, expected '<type> <size>', found '{}'",
-                header
-            ))
-        })?;
-        let object_data = &contents[index_first_zero + 1..];
-        let object_size = object_size.parse::<usize>()?;
-        anyhow::ensure!(object_size == object_data.len());
-
-        Ok(match object_type {
-            "blob" => Object::Blob(object_data.to_owned()),
-            "commit" => {
-                Object::Commit(Commit::try_from(object_data).context("Trying to parse commit")?)
-            }
-            "tree" => Object::Tree(TreeData {
-                data: object_data.to_owned(),
-            }),
-            _ => Object::Unknown {
-                kind: object_type.to_owned(),
-                data: object_data.to_owned(),
-            },
-        })
+fn cmd_init(args: InitArgs) -> anyhow::Result<()> {
+    let format = match &args.object_format {
+        Some(name) => Some(
+            ObjectFormat::from_name(name)
+                .ok_or_else(|| anyhow::Error::msg(format!("unknown hash algorithm '{name}'")))?,
+        ),
+        None => None,
+    };
+    let shared = match &args.shared {
+        Some(value) => Some(init::SharedRepository::parse(value)?),
+        None => None,
+    };
+    // An empty template directory means no templates, others are relative to where we started
+    let template = match args.template {
+        Some(template) if template.is_empty() => Some(PathBuf::new()),
+        Some(template) => Some(std::env::current_dir()?.join(template)),
+        None => None,
+    };
+    // Like git, work from inside the new directory, so a relative GIT_DIR is below it
+    if let Some(directory) = &args.directory {
+        fs::create_dir_all(directory)
+            .with_context(|| format!("cannot mkdir {}", directory.display()))?;
+        std::env::set_current_dir(directory)?;
     }
-
-    pub fn kind(&self) -> &str {
-        match self {
-            Object::Blob(_) => "blob",
-            Object::Commit(_) => "commit",
-            Object::Tree(_) => "tree",
-            Object::Unknown { kind, .. } => kind,
+    let current_dir = std::env::current_dir()?;
+    let (git_dir, work_tree) = match std::env::var_os("GIT_DIR") {
+        Some(git_dir) => (current_dir.join(git_dir), Some(current_dir)),
+        None if args.bare => (current_dir, None),
+        None => (current_dir.join(".git"), Some(current_dir)),
+    };
+    let work_tree = match std::env::var_os("GIT_WORK_TREE") {
+        Some(work_tree) if !args.bare => Some(PathBuf::from(work_tree)),
+        _ if args.bare => None,
+        _ => work_tree,
+    };
+    let repo = CommandRepository(Repository::new(git_dir).with_work_tree(work_tree));
+    let options = init::InitOptions {
+        format,
+        bare: args.bare,
+        initial_branch: args.initial_branch,
+        template,
+        shared,
+    };
+    let existed = repo.init(&options)?;
+    if !args.quiet {
+        let git_dir = fs::canonicalize(repo.git_dir())?;
+        let shared = match shared {
+            Some(shared) if shared != init::SharedRepository::Umask => "shared ",
+            _ => "",
+        };
+        match existed {
+            true => println!(
+                "Reinitialized existing {shared}Git repository in {}/",
+                git_dir.display()
+            ),
+            false => println!(
+                "Initialized empty {shared}Git repository in {}/",
+                git_dir.display()
+            ),
         }
     }
+    Ok(())
+}
 
-    pub fn contents_bytes(&self) -> &[u8] {
-        match self {
-            Object::Blob(data) => data,
-            Object::Commit(data) => data.bytes(),
-            Object::Tree(data) => &data.data,
-            Object::Unknown { data, .. } => data,
+fn cmd_clone(args: CloneArgs) -> anyhow::Result<()> {
+    let directory = match args.directory {
+        Some(directory) => directory,
+        None => {
+            // Named like the source, without a trailing /.git or .git
+            let source = args.repository.components().collect::<PathBuf>();
+            let source = match source.file_name() {
+                Some(name) if name == ".git" => source.parent().unwrap_or(&source).to_owned(),
+                _ => source,
+            };
+            let name = source
+                .file_name()
+                .and_then(|name| name.to_str())
+                .context("could not guess directory name, please specify a directory")?;
+            PathBuf::from(name.strip_suffix(".git").unwrap_or(name))
         }
-    }
+    };
+    eprintln!("Cloning into '{}'...", directory.display());
+    let options = clone::CloneOptions {
+        shared: args.shared,
+        reference: args.reference,
+    };
+    CommandRepository(clone::clone_local(&args.repository, &directory, &options)?);
+    Ok(())
+}
 
-    pub fn hash(&self) -> [u8; 20] {
-        let mut hasher = Sha1::new();
-        hasher.update(self.kind().as_bytes());
-        hasher.update(b" ");
-        hasher.update(self.contents_bytes().len().to_string().as_bytes());
-        hasher.update(b"\0");
-        hasher.update(&self.contents_bytes());
-        hasher.finalize().into()
+/// Writes the header and optionally the contents of the object named `name` in batch mode.
+fn write_batch_object(
+    repo: &Repository,
+    reader: &batch::ObjectReader,
+    format: &batch::BatchFormat,
+    name: &str,
+    rest: &str,
+    contents: bool,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let object_ref = match repo.resolve_revision(name) {
+        Ok(object_ref) => object_ref,
+        Err(Error::Ambiguous(_)) => {
+            writeln!(out, "{name} ambiguous")?;
+            return Ok(());
+        }
+        Err(_) => {
+            writeln!(out, "{name} missing")?;
+            return Ok(());
+        }
+    };
+    let Some(info) = reader.read(&object_ref)? else {
+        writeln!(out, "{name} missing")?;
+        return Ok(());
+    };
+    writeln!(out, "{}", format.expand(&info, rest))?;
+    if contents {
+        let mut stream = reader
+            .open(&object_ref)?
+            .ok_or_else(|| anyhow::Error::msg(format!("Could not read object {name}")))?;
+        std::io::copy(&mut stream, out)?;
+        writeln!(out)?;
     }
+    Ok(())
+}
 
-    pub fn hash_string(&self) -> String {
-        self.hash().iter().map(|b| format!("{b:02x}")).collect()
+fn cmd_cat_file_batch(args: CatFileArgs) -> anyhow::Result<()> {
+    let repo = find_repository()?;
+    let (format, contents) = match (&args.batch, &args.batch_check, &args.batch_command) {
+        (Some(format), _, _) => (format, true),
+        (_, Some(format), _) => (format, false),
+        (_, _, Some(format)) => (format, false),
+        _ => unreachable!("Called in batch mode only"),
+    };
+    let format = batch::BatchFormat::parse(format)?;
+    let reader = batch::ObjectReader::new(&repo, &format)?;
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+
+    if args.batch_all_objects {
+        for object_ref in repo.all_objects()? {
+            write_batch_object(
+                &repo,
+                &reader,
+                &format,
+                object_ref.as_str(),
+                "",
+                contents,
+                &mut out,
+            )?;
+            if !args.buffer {
+                out.flush()?;
+            }
+        }
+        out.flush()?;
+        return Ok(());
     }
 
-    pub fn write_to(&self, path: &PathBuf) -> anyhow::Result<()> {
-        let file = fs::File::create(path)?;
-        let mut encoder = flate2::write::ZlibEncoder::new(file, flate2::Compression::fast());
-        encoder.write_all(self.kind().as_bytes())?;
-        encoder.write_all(b" ")?;
-        encoder.write_all(self.contents_bytes().len().to_string().as_bytes())?;
-        encoder.write_all(b"\0")?;
-        encoder.write_all(&self.contents_bytes())?;
-        encoder.flush()?;
-        Ok(())
+    for line in std::io::stdin().lines() {
+        let line = line?;
+        if args.batch_command.is_some() {
+            if line.is_empty() {
+                anyhow::bail!("empty command in input");
+            }
+            if line.starts_with(char::is_whitespace) {
+                anyhow::bail!("whitespace before command: '{line}'");
+            }
+            let (command, argument) = match line.split_once(' ') {
+                Some((command, argument)) => (command, Some(argument)),
+                None => (line.as_str(), None),
+            };
+            match (command, argument) {
+                ("contents" | "info", None) => anyhow::bail!("{command} requires arguments"),
+                ("contents" | "info", Some(argument)) => {
+                    let (name, rest) = split_batch_input(&format, argument);
+                    let contents = command == "contents";
+                    write_batch_object(&repo, &reader, &format, name, rest, contents, &mut out)?;
+                }
+                ("flush", Some(_)) => anyhow::bail!("flush takes no arguments"),
+                ("flush", None) if !args.buffer => {
+                    anyhow::bail!("flush is only for --buffer mode")
+                }
+                ("flush", None) => {}
+                _ => anyhow::bail!("unknown command: '{line}'"),
+            }
+            if !args.buffer || command == "flush" {
+                out.flush()?;
+            }
+            continue;
+        }
+        let (name, rest) = split_batch_input(&format, &line);
+        write_batch_object(&repo, &reader, &format, name, rest, contents, &mut out)?;
+        if !args.buffer {
+            out.flush()?;
+        }
     }
+    out.flush()?;
+    Ok(())
 }
 
-fn cmd_init() -> anyhow::Result<()> {
-    let repo = Repository::from_current_dir()?;
-    println!("Creating git repository in {:?}", repo.path);
-    repo.init()
+/// Splits an input line into the object name and `%(rest)` if the format asks for it.
+fn split_batch_input<'a>(format: &batch::BatchFormat, line: &'a str) -> (&'a str, &'a str) {
+    if !format.splits_input() {
+        return (line, "");
+    }
+    match line.split_once([' ', '\t']) {
+        Some((name, rest)) => (name, rest.trim_start_matches([' ', '\t'])),
+        None => (line, ""),
+    }
 }
 
 fn cmd_cat_file(args: CatFileArgs) -> anyhow::Result<()> {
-    let repo = Repository::find_from_current_dir()?;
-    eprintln!("Git repository found in {:?}", repo.path);
-    let obj = repo.find_object(&args.object)?;
-    eprintln!("Found object: {:?}", obj);
-    if args.pretty_print {
-        match &obj {
-            Object::Blob(data) => std::io::stdout().lock().write_all(&data)?,
-            Object::Commit(data) => print!("{}", data.0),
-            Object::Tree(data) => {
-                for item in data.iter() {
-                    let mode = item.mode;
-                    let kind = if item.is_file() { "blob" } else { "tree" };
-                    let name = item.name;
-                    let hash: String = item.hash.iter().map(|b| format!("{b:02x}")).collect();
-                    println!("{mode:06o} {kind} {hash}    {name}");
-                }
+    if args.batch.is_some() || args.batch_check.is_some() || args.batch_command.is_some() {
+        return cmd_cat_file_batch(args);
+    }
+    let repo = find_repository()?;
+    eprintln!("Git repository found in {:?}", repo.git_dir());
+    let name = args.object.expect("Required unless in batch mode");
+    if let Some(typed_object) = &args.typed_object {
+        // The object is peeled to the type, like a commit to its tree, and output raw
+        let object = repo.resolve_revision(typed_object)?;
+        let object = repo
+            .peel(&object, &name)
+            .map_err(|_| anyhow::Error::msg(format!("git cat-file {typed_object}: bad file")))?;
+        let mut stream = repo
+            .open_object(&object)?
+            .ok_or_else(|| Error::NotFound(object.clone()))?;
+        std::io::copy(&mut stream, &mut std::io::stdout().lock())?;
+        return Ok(());
+    }
+    if !(args.pretty_print || args.show_type || args.show_size || args.exists) {
+        // A usage error, like those reported by the argument parser
+        eprintln!("fatal: only two arguments allowed in <type> <object> mode, not 1");
+        std::process::exit(129);
+    }
+    let object = repo.resolve_revision(&name)?;
+    if args.show_type || args.show_size || args.exists {
+        let header = repo.read_object_header(&object);
+        if args.exists {
+            // Like git, a missing or unreadable object only shows in the exit status
+            let exists = matches!(header, Ok(Some(_)));
+            drop(repo);
+            std::process::exit(if exists { 0 } else { 1 });
+        }
+        let (kind, size) =
+            header?.ok_or_else(|| anyhow::Error::msg("git cat-file: could not get object info"))?;
+        if args.show_type {
+            println!("{kind}");
+        } else {
+            println!("{size}");
+        }
+        return Ok(());
+    }
+    let mut stream = repo
+        .open_object(&object)?
+        .ok_or_else(|| Error::NotFound(object.clone()))?;
+    // Blobs and raw contents are copied in chunks, only other objects are parsed for printing
+    if !args.pretty_print || stream.kind == "blob" || stream.kind == "tag" {
+        std::io::copy(&mut stream, &mut std::io::stdout().lock())?;
+        return Ok(());
+    }
+    let kind = stream.kind.clone();
+    let obj = Object::from_parts(&kind, stream.into_bytes()?, repo.object_format()?)?;
+    match &obj {
+        Object::Commit(data) => print!("{}", data.as_str()),
+        Object::Tree(data) => {
+            for item in data.iter() {
+                let mode = item.mode;
+                let kind = if item.is_file() { "blob" } else { "tree" };
+                let name = item.name;
+                let hash: String = item.hash.iter().map(|b| format!("{b:02x}")).collect();
+                println!("{mode:06o} {kind} {hash}    {name}");
             }
-            _ => anyhow::bail!("Don't know how to pretty-print {obj:?}."),
         }
-    } else {
-        std::io::stdout().lock().write_all(obj.contents_bytes())?;
+        _ => anyhow::bail!("Don't know how to pretty-print a {}.", obj.kind()),
     }
     Ok(())
 }
 
 fn cmd_ls_tree(args: LsTreeArgs) -> anyhow::Result<()> {
-    let repo = Repository::find_from_current_dir()?;
-    eprintln!("Git repository found in {:?}", repo.path);
+    let repo = find_repository()?;
+    eprintln!("Git repository found in {:?}", repo.git_dir());
     let obj = repo.find_object(&args.object)?;
-    eprintln!("Found object: {:?}", obj);
     match obj {
         Object::Tree(data) => {
             for item in data.iter() {
@@ -617,7 +1055,7 @@ fn cmd_ls_tree(args: LsTreeArgs) -> anyhow::Result<()> {
         }
         _ => anyhow::bail!(
             "Not a tree object. {} is {}.",
-            obj.hash_string(),
+            obj.hash_string(repo.object_format()?)?,
             obj.kind()
         ),
     }
@@ -625,81 +1063,109 @@ fn cmd_ls_tree(args: LsTreeArgs) -> anyhow::Result<()> {
 }
 
 fn cmd_hash_object(args: HashObjectArgs) -> anyhow::Result<()> {
-    let mut data = Vec::new();
-    let mut file = fs::File::open(&args.file)?;
-    file.read_to_end(&mut data)?;
-    drop(file);
-
-    let object = Object::Blob(data);
-    println!("{}", object.hash_string());
-    if args.write {
-        let repo = Repository::find_from_current_dir()?;
-        eprintln!("Git repository found in {:?}", repo.path);
-        repo.save_object(&object)?;
-    }
-    Ok(())
-}
-
-fn build_tree_for_directory(objects: &mut Vec<Object>, path: &Path) -> anyhow::Result<TreeData> {
-    let mut tree = TreeData::empty();
+    let repo = if args.write {
+        Some(find_repository()?)
+    } else {
+        find_repository().ok()
+    };
+    let kind = args.object_type.as_str();
+    let format = match &repo {
+        Some(repo) => repo.object_format()?,
+        None => ObjectFormat::default(),
+    };
 
-    for file in fs::read_dir(path)? {
-        let file = file?;
-        let metadata = file.metadata()?;
-        let file_name = file
-            .file_name()
-            .to_str()
-            .ok_or_else(|| anyhow::Error::msg("Failed to parse filename"))?
-            .to_owned();
-        let (object, mode) = if metadata.is_dir() {
-            if file_name.starts_with('.') {
-                eprintln!("Skipping directory {:?}.", file.path());
-                continue;
+    // Filters only apply to blobs, and need a path inside the repository to find attributes
+    let filter_path = |path: &str| -> anyhow::Result<Option<String>> {
+        match &repo {
+            Some(repo) if kind == "blob" && !args.literally && !args.no_filters => {
+                match pathspec::repo_path(repo, path) {
+                    Ok(path) if repo.may_filter(&path)? => Ok(Some(path)),
+                    _ => Ok(None),
+                }
             }
-            (
-                Object::Tree(build_tree_for_directory(objects, &file.path())?),
-                0o040000,
-            )
-        } else if metadata.is_file() {
-            let object = Object::Blob(fs::read(file.path())?);
-            let is_executable = 0 != (metadata.mode() & 0o111);
-            let mode = if is_executable { 0o100755 } else { 0o100644 };
-            (object, mode)
+            _ => Ok(None),
+        }
+    };
+    let hash = |data: Vec<u8>, filter_path: Option<String>| -> anyhow::Result<()> {
+        let data = match (&repo, filter_path) {
+            (Some(repo), Some(path)) => repo.clean_filter(&path, data)?,
+            _ => data,
+        };
+        let object = if kind == "blob" {
+            Object::Blob(data)
         } else {
-            todo!("Only directories and normal files are supported!")
+            if !args.literally {
+                fsck::validate_object(kind, &data, format)?;
+            }
+            // Only the type and contents matter for hashing and writing
+            Object::Unknown {
+                kind: kind.to_owned(),
+                data,
+            }
+        };
+        println!("{}", object.hash_string(format)?);
+        if let (true, Some(repo)) = (args.write, &repo) {
+            repo.save_object(&object)?;
+        }
+        Ok(())
+    };
+    // Blobs that are not filtered are hashed and written in chunks
+    let hash_stream = |reader: &mut fs::File, size: u64| -> anyhow::Result<()> {
+        let object_ref = match (args.write, &repo) {
+            (true, Some(repo)) => repo.write_object_stream(kind, size, reader)?,
+            _ => ObjectRef::from_hash(&stream::hash_stream(format, kind, size, reader)?),
         };
+        println!("{}", object_ref.as_str());
+        Ok(())
+    };
+    let hash_file = |file: &str, path: &str| -> anyhow::Result<()> {
+        let filter_path = filter_path(path)?;
+        if kind != "blob" || filter_path.is_some() {
+            let data = fs::read(file).with_context(|| format!("Cannot open '{file}'"))?;
+            return hash(data, filter_path);
+        }
+        let mut reader = fs::File::open(file).with_context(|| format!("Cannot open '{file}'"))?;
+        let size = reader.metadata()?.len();
+        hash_stream(&mut reader, size)
+    };
 
-        tree.add_object(&object, &file_name, mode)?;
-        objects.push(object);
+    if args.stdin {
+        let filter_path = args.path.as_deref().map(filter_path).transpose()?.flatten();
+        if kind != "blob" || filter_path.is_some() {
+            let mut data = Vec::new();
+            std::io::stdin().lock().read_to_end(&mut data)?;
+            hash(data, filter_path)?;
+        } else {
+            let (mut reader, size) = stream::stdin_with_size(&std::env::temp_dir())?;
+            hash_stream(&mut reader, size)?;
+        }
     }
-
-    tree.sort();
-    Ok(tree)
+    for file in &args.files {
+        hash_file(file, args.path.as_deref().unwrap_or(file))?;
+    }
+    if args.stdin_paths {
+        for file in std::io::stdin().lines() {
+            let file = file?;
+            hash_file(&file, &file)?;
+        }
+    }
+    Ok(())
 }
 
 fn cmd_write_tree() -> anyhow::Result<()> {
-    let repo = Repository::find_from_current_dir()?;
-    eprintln!("Git repository found in {:?}", repo.path);
-    let mut objects = Vec::new();
-    let tree = build_tree_for_directory(&mut objects, &std::env::current_dir()?)?;
-    let tree = Object::Tree(tree);
-    let tree_hash = tree.hash_string();
-    objects.push(tree);
-
-    for obj in objects.iter() {
-        repo.save_object(obj)?;
-    }
-
-    println!("{tree_hash}");
+    let repo = find_repository()?;
+    eprintln!("Git repository found in {:?}", repo.git_dir());
+    let tree = repo.write_tree_from_dir(&std::env::current_dir()?)?;
+    println!("{tree}");
     Ok(())
 }
 
 fn cmd_commit_tree(args: CommitTreeArgs) -> anyhow::Result<()> {
     eprintln!("commit-tree {:?}", args);
-    let repo = Repository::find_from_current_dir()?;
-    eprintln!("Git repository found in {:?}", repo.path);
+    let repo = find_repository()?;
+    eprintln!("Git repository found in {:?}", repo.git_dir());
     // First ensure that the provided tree exists
-    let tree = ObjectRef::from_sha1(&args.tree_sha).with_context(|| "Searching for tree object")?;
+    let tree = ObjectRef::from_hex(&args.tree_sha).with_context(|| "Searching for tree object")?;
     repo.find_object(&tree)?;
 
     let now = SystemTime::now();
@@ -729,7 +1195,7 @@ fn cmd_commit_tree(args: CommitTreeArgs) -> anyhow::Result<()> {
     for parent in args.parent_hashes {
         eprintln!("parent {}", parent);
         // Ensure this is a valid object ref that exists
-        let parent_ref = ObjectRef::from_sha1(&parent).with_context(|| "Checking parent ref")?;
+        let parent_ref = ObjectRef::from_hex(&parent).with_context(|| "Checking parent ref")?;
         repo.find_object(&parent_ref)
             .context("Looking for parent commit")?;
 
@@ -738,25 +1204,1161 @@ fn cmd_commit_tree(args: CommitTreeArgs) -> anyhow::Result<()> {
     eprintln!("{:?}", commit);
     let object = Object::Commit(commit.into());
     repo.save_object(&object)?;
-    println!("{}", object.hash_string());
+    println!("{}", object.hash_string(repo.object_format()?)?);
+    Ok(())
+}
+
+fn cmd_diff_tree(args: DiffTreeArgs) -> anyhow::Result<()> {
+    let repo = find_repository()?;
+    let config = repo.config()?;
+
+    let mut opts = diff::DiffOptions {
+        recursive: args.recursive || args.patch,
+        ..Default::default()
+    };
+    if let Some(score) = args.find_renames.as_deref() {
+        opts.detection = diff::Detection::Renames;
+        if !score.is_empty() {
+            opts.min_score = diff::parse_score(score)?;
+        }
+    }
+    if let Some(score) = args.find_copies.as_deref() {
+        opts.detection = diff::Detection::Copies;
+        if !score.is_empty() {
+            opts.min_score = diff::parse_score(score)?;
+        }
+    }
+    if args.find_copies_harder {
+        opts.detection = diff::Detection::CopiesHarder;
+    }
+    opts.rename_limit = match args.rename_limit {
+        Some(limit) => limit,
+        None => config
+            .get_int("diff.renameLimit")?
+            .map(|limit| limit.max(0) as usize)
+            .unwrap_or(diff::DEFAULT_RENAME_LIMIT),
+    };
+
+    let mut out = std::io::stdout().lock();
+    let (old, new) = match args.objects.as_slice() {
+        [old, new] => (
+            Some(repo.peel_to_tree(&repo.resolve_revision(old)?)?),
+            repo.peel_to_tree(&repo.resolve_revision(new)?)?,
+        ),
+        [commit] => {
+            let commit = repo.resolve_revision(commit)?;
+            let Object::Commit(commit_data) = repo.find_object(&commit)? else {
+                anyhow::bail!("{} is not a commit", commit.as_str());
+            };
+            let data = commit_data.data();
+            let new = repo.read_tree(&ObjectRef::from_hex(&data.tree_hash)?)?;
+            let old = match data.parent_hashes.first() {
+                Some(parent) => Some(repo.peel_to_tree(&ObjectRef::from_hex(parent)?)?),
+                None if args.root => None,
+                None => return Ok(()),
+            };
+            writeln!(out, "{}", commit.as_str())?;
+            (old, new)
+        }
+        _ => unreachable!("Clap ensures one or two objects"),
+    };
+
+    let entries = diff::diff_trees(&repo, old.as_ref(), Some(&new), &opts)?;
+    for entry in entries.iter() {
+        if args.name_only {
+            writeln!(out, "{}", entry.path())?;
+        } else if args.name_status {
+            diff::write_name_status(&mut out, entry)?;
+        } else if args.patch {
+            diff::write_patch(&mut out, &repo, entry)?;
+        } else {
+            diff::write_raw(&mut out, entry)?;
+        }
+    }
+    Ok(())
+}
+
+fn describe_commit(repo: &Repository, commit: &ObjectRef) -> anyhow::Result<String> {
+    let subject = repo.read_commit(commit)?.subject().to_owned();
+    Ok(format!("{} {}", &commit.as_str()[..7], subject))
+}
+
+/// Moves HEAD to `target`, updating the working tree, and reports it like git does.
+fn switch_to(
+    repo: &Repository,
+    target: &str,
+    create_branch: Option<&str>,
+    detach: bool,
+    force: bool,
+) -> anyhow::Result<()> {
+    let old_head = repo.head()?;
+    let commit = repo.resolve_revision(target)?;
+    let commit = repo.peel(&commit, "commit")?;
+
+    let new_head = match create_branch {
+        Some(name) => {
+            refs::check_branch_name(name)?;
+            let ref_name = format!("refs/heads/{name}");
+            anyhow::ensure!(
+                !repo.ref_exists(&ref_name)?,
+                "a branch named '{}' already exists",
+                name
+            );
+            refs::Head::Branch(ref_name)
+        }
+        None if !detach && repo.ref_exists(&format!("refs/heads/{target}"))? => {
+            refs::Head::Branch(format!("refs/heads/{target}"))
+        }
+        None => refs::Head::Detached(commit.clone()),
+    };
+
+    if let (refs::Head::Branch(name), true) = (&new_head, new_head != old_head) {
+        repo.ensure_branch_not_checked_out(name)?;
+    }
+    let from = match &old_head {
+        refs::Head::Branch(_) => old_head.branch_name().unwrap_or_default().to_owned(),
+        refs::Head::Detached(old_commit) => old_commit.as_str().to_owned(),
+    };
+    let message = format!(
+        "checkout: moving from {from} to {}",
+        create_branch.unwrap_or(target)
+    );
+    // The branch is created before HEAD moves to it, so the creation is only in its own reflog
+    let new_branch = match (&new_head, create_branch) {
+        (refs::Head::Branch(name), Some(_)) => Some(name),
+        _ => None,
+    };
+    if let Some(name) = new_branch {
+        repo.update_ref(name, &commit, &format!("branch: Created from {target}"))?;
+    }
+    if let Err(e) = repo.checkout_commit(&commit, &new_head, force, &message) {
+        if let Some(name) = new_branch {
+            repo.delete_ref(name)?;
+        }
+        return Err(e);
+    }
+
+    if let refs::Head::Detached(old_commit) = &old_head {
+        if new_head != old_head {
+            eprintln!(
+                "Previous HEAD position was {}",
+                describe_commit(repo, old_commit)?
+            );
+        }
+    }
+    match (&new_head, create_branch) {
+        (refs::Head::Branch(_), Some(name)) => eprintln!("Switched to a new branch '{name}'"),
+        (refs::Head::Branch(_), None) if new_head == old_head => {
+            eprintln!(
+                "Already on '{}'",
+                new_head.branch_name().unwrap_or_default()
+            )
+        }
+        (refs::Head::Branch(_), None) => eprintln!(
+            "Switched to branch '{}'",
+            new_head.branch_name().unwrap_or_default()
+        ),
+        (refs::Head::Detached(commit), _) => {
+            if old_head.branch_name().is_some() {
+                eprintln!("Note: switching to '{target}'.\n");
+                eprintln!("You are in 'detached HEAD' state. Commits you make here are not kept");
+                eprintln!(
+                    "by any branch unless you create one, e.g. with 'switch -c <new-branch>'.\n"
+                );
+            }
+            eprintln!("HEAD is now at {}", describe_commit(repo, commit)?);
+        }
+    }
     Ok(())
 }
 
+fn cmd_checkout(args: CheckoutArgs) -> anyhow::Result<()> {
+    let repo = find_repository()?;
+    let target = match &args.target {
+        Some(target) => target.as_str(),
+        None if args.new_branch.is_some() || args.detach => "HEAD",
+        None => anyhow::bail!("You must specify a branch or commit to check out"),
+    };
+    switch_to(
+        &repo,
+        target,
+        args.new_branch.as_deref(),
+        args.detach,
+        args.force,
+    )
+}
+
+fn cmd_switch(args: SwitchArgs) -> anyhow::Result<()> {
+    let repo = find_repository()?;
+    let target = match &args.branch {
+        Some(branch) => branch.as_str(),
+        None if args.create.is_some() || args.detach => "HEAD",
+        None => anyhow::bail!("missing branch or commit argument"),
+    };
+    if args.create.is_none() && !args.detach && !repo.ref_exists(&format!("refs/heads/{target}"))? {
+        // Unlike checkout, switch only detaches HEAD when explicitly asked to
+        let object = repo.resolve_revision(target)?;
+        anyhow::bail!(
+            "a branch is expected, got {} '{}'\nhint: use --detach to switch to a commit",
+            repo.find_object(&object)?.kind(),
+            target
+        );
+    }
+    switch_to(
+        &repo,
+        target,
+        args.create.as_deref(),
+        args.detach,
+        args.force,
+    )
+}
+
+fn cmd_restore(args: RestoreArgs) -> anyhow::Result<()> {
+    let repo = find_repository()?;
+    let pathspec = pathspec::Pathspec::new(&repo, &args.pathspec)?;
+    let options = checkout::RestoreOptions {
+        staged: args.staged,
+        worktree: args.worktree || !args.staged,
+        conflict_side: match (args.ours, args.theirs) {
+            (true, _) => Some(2),
+            (_, true) => Some(3),
+            _ => None,
+        },
+    };
+    anyhow::ensure!(
+        options.conflict_side.is_none() || (args.source.is_none() && !args.staged),
+        "--ours and --theirs only work when restoring the working tree from the index"
+    );
+
+    // With --staged the default source is HEAD rather than the index
+    let source = match (&args.source, args.staged) {
+        (Some(rev), _) => Some(repo.resolve_revision(rev)?),
+        (None, true) => repo.head_commit()?,
+        (None, false) => None,
+    };
+    let source_tree = match &source {
+        Some(source) => Some(repo.peel_to_tree(source)?),
+        // Unborn branch, restoring the index from an empty tree
+        None if args.staged => Some(TreeData::empty(repo.object_format()?)),
+        None => None,
+    };
+    repo.restore(&pathspec, source_tree.as_ref(), &options)
+}
+
+fn branch_or_current(repo: &Repository, name: Option<&String>) -> anyhow::Result<String> {
+    match name {
+        Some(name) => Ok(name.clone()),
+        None => repo
+            .current_branch()?
+            .ok_or_else(|| anyhow::Error::msg("HEAD is detached, no branch given")),
+    }
+}
+
+fn cmd_branch(args: BranchArgs) -> anyhow::Result<()> {
+    let repo = find_repository()?;
+
+    if args.delete || args.force_delete {
+        anyhow::ensure!(!args.args.is_empty(), "branch name required");
+        let mut failed = false;
+        for name in args.args.iter() {
+            match repo.delete_branch(name, args.force_delete || args.force) {
+                Ok(tip) => println!("Deleted branch {name} (was {}).", &tip.as_str()[..7]),
+                Err(error) => {
+                    eprintln!("error: {error:#}");
+                    failed = true;
+                }
+            }
+        }
+        if failed {
+            // Like git, the other branches are still deleted
+            drop(repo);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if args.rename || args.force_rename {
+        let (old, new) = match args.args.as_slice() {
+            [new] => (branch_or_current(&repo, None)?, new.clone()),
+            [old, new] => (old.clone(), new.clone()),
+            _ => anyhow::bail!("branch rename takes one or two branch names"),
+        };
+        return repo.rename_branch(&old, &new, args.force_rename || args.force);
+    }
+    if let Some(upstream) = &args.set_upstream_to {
+        anyhow::ensure!(
+            args.args.len() <= 1,
+            "too many arguments to set new upstream"
+        );
+        let branch = branch_or_current(&repo, args.args.first())?;
+        anyhow::ensure!(
+            repo.ref_exists(&Repository::branch_ref(&branch))?,
+            "branch '{}' does not exist",
+            branch
+        );
+        let upstream = repo.resolve_upstream(upstream)?;
+        repo.set_upstream(&branch, &upstream)?;
+        println!(
+            "branch '{branch}' set up to track '{}'.",
+            upstream.short_name()
+        );
+        return Ok(());
+    }
+    if args.unset_upstream {
+        anyhow::ensure!(args.args.len() <= 1, "too many arguments to unset upstream");
+        let branch = branch_or_current(&repo, args.args.first())?;
+        anyhow::ensure!(
+            repo.unset_upstream(&branch)?,
+            "branch '{}' has no upstream information",
+            branch
+        );
+        return Ok(());
+    }
+    if !args.args.is_empty() && args.merged.is_none() && args.no_merged.is_none() {
+        let (name, start) = match args.args.as_slice() {
+            [name] => (name, "HEAD"),
+            [name, start] => (name, start.as_str()),
+            _ => anyhow::bail!("too many arguments for creating a branch"),
+        };
+        let start_ref = repo.resolve_revision(start)?;
+        repo.create_branch(name, &start_ref, start, args.force)?;
+        // Like branch.autoSetupMerge, branching off a remote-tracking branch tracks it
+        if let Some((full_name, _)) = repo.dwim_ref(start)? {
+            if let Some(remote_branch) = full_name.strip_prefix("refs/remotes/") {
+                let upstream = repo.resolve_upstream(remote_branch)?;
+                repo.set_upstream(name, &upstream)?;
+                println!(
+                    "branch '{name}' set up to track '{}'.",
+                    upstream.short_name()
+                );
+            }
+        }
+        return Ok(());
+    }
+
+    // Listing
+    let mut prefixes = Vec::new();
+    if !args.remotes || args.all {
+        prefixes.push("refs/heads/");
+    }
+    if args.remotes || args.all {
+        prefixes.push("refs/remotes/");
+    }
+    let merged_filter = match (&args.merged, &args.no_merged) {
+        (Some(commit), _) => Some((repo.resolve_revision(commit)?, true)),
+        (_, Some(commit)) => Some((repo.resolve_revision(commit)?, false)),
+        _ => None,
+    };
+
+    let head = repo.head()?;
+    // (name as displayed, is current, target, symbolic ref target)
+    let mut rows: Vec<(String, bool, ObjectRef, Option<String>)> = Vec::new();
+    if let refs::Head::Detached(commit) = &head {
+        rows.push((
+            format!("(HEAD detached at {})", &commit.as_str()[..7]),
+            true,
+            commit.clone(),
+            None,
+        ));
+    }
+    for prefix in prefixes {
+        for (name, target) in repo.list_refs(prefix)? {
+            let display = if args.all && prefix == "refs/remotes/" {
+                name["refs/".len()..].to_owned()
+            } else {
+                name[prefix.len()..].to_owned()
+            };
+            let symbolic = repo
+                .read_symbolic_ref(&name)?
+                .map(|t| t.strip_prefix("refs/remotes/").unwrap_or(&t).to_owned());
+            let current = head == refs::Head::Branch(name.clone());
+            rows.push((display, current, target, symbolic));
+        }
+    }
+    if let Some((commit, merged)) = &merged_filter {
+        let mut filtered = Vec::with_capacity(rows.len());
+        for row in rows {
+            if repo.is_ancestor(&row.2, commit)? == *merged {
+                filtered.push(row);
+            }
+        }
+        rows = filtered;
+    }
+
+    let width = rows.iter().map(|r| r.0.len()).max().unwrap_or(0);
+    let mut out = std::io::stdout().lock();
+    for (name, current, target, symbolic) in rows {
+        let marker = if current { '*' } else { ' ' };
+        match symbolic {
+            Some(symbolic) => writeln!(out, "{marker} {name} -> {symbolic}")?,
+            None if args.verbose => writeln!(
+                out,
+                "{marker} {name:width$} {}",
+                describe_commit(&repo, &target)?
+            )?,
+            None => writeln!(out, "{marker} {name}")?,
+        }
+    }
+    Ok(())
+}
+
+/// Shows the files changed between two commits and how much.
+fn print_diffstat(repo: &Repository, old: &ObjectRef, new: &ObjectRef) -> anyhow::Result<()> {
+    let options = diff::DiffOptions {
+        recursive: true,
+        detection: diff::Detection::Renames,
+        ..diff::DiffOptions::default()
+    };
+    let (old, new) = (repo.peel_to_tree(old)?, repo.peel_to_tree(new)?);
+    let entries = diff::diff_trees(repo, Some(&old), Some(&new), &options)?;
+    if !entries.is_empty() {
+        diff::write_stat(&mut std::io::stdout().lock(), repo, &entries)?;
+    }
+    Ok(())
+}
+
+fn cmd_merge(args: MergeArgs) -> anyhow::Result<()> {
+    let repo = find_repository()?;
+    if args.abort {
+        return repo.merge_abort();
+    }
+    let name = args
+        .commit
+        .ok_or_else(|| anyhow::Error::msg("No commit specified"))?;
+    let options = merge::MergeCommitOptions {
+        no_ff: args.no_ff,
+        ff_only: args.ff_only,
+        no_commit: args.no_commit,
+        allow_unrelated_histories: args.allow_unrelated_histories,
+        message: args.message,
+        favor: args
+            .strategy_option
+            .as_deref()
+            .map(merge::Favor::parse)
+            .transpose()?,
+    };
+    match repo.merge(&name, &options)? {
+        merge::MergeOutcome::UpToDate => println!("Already up to date."),
+        merge::MergeOutcome::Unborn => {}
+        merge::MergeOutcome::FastForward { from, to } => {
+            println!("Updating {}..{}", &from.as_str()[..7], &to.as_str()[..7]);
+            println!("Fast-forward");
+            print_diffstat(&repo, &from, &to)?;
+        }
+        merge::MergeOutcome::Committed {
+            parent,
+            commit,
+            messages,
+        } => {
+            for message in messages.iter() {
+                println!("{message}");
+            }
+            println!("{}", merge::MERGE_SUMMARY);
+            print_diffstat(&repo, &parent, &commit)?;
+        }
+        merge::MergeOutcome::Stopped {
+            conflicts,
+            messages,
+        } => {
+            for message in messages.iter() {
+                println!("{message}");
+            }
+            if conflicts.is_empty() {
+                println!("Automatic merge went well; stopped before committing as requested");
+            } else {
+                // A conflict is not an error, git reports it with exit code 1
+                println!("Automatic merge failed; fix conflicts and then commit the result.");
+                drop(repo);
+                std::process::exit(1);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn cmd_merge_file(args: MergeFileArgs) -> anyhow::Result<()> {
+    let read = |path: &PathBuf| {
+        fs::read(path).with_context(|| format!("could not open '{}'", path.display()))
+    };
+    let current = read(&args.current)?;
+    let base = read(&args.base)?;
+    let other = read(&args.other)?;
+    for (path, data) in [
+        (&args.current, &current),
+        (&args.base, &base),
+        (&args.other, &other),
+    ] {
+        anyhow::ensure!(
+            !diff::is_binary(data),
+            "Cannot merge binary files: {}",
+            path.display()
+        );
+    }
+
+    let mut labels = args.labels.iter();
+    let mut label = |path: &PathBuf| {
+        labels
+            .next()
+            .cloned()
+            .unwrap_or_else(|| path.display().to_string())
+    };
+    let options = merge::MergeOptions {
+        style: if args.zdiff3 {
+            merge::ConflictStyle::ZDiff3
+        } else if args.diff3 {
+            merge::ConflictStyle::Diff3
+        } else {
+            merge::ConflictStyle::Merge
+        },
+        marker_size: args.marker_size,
+        favor: if args.ours {
+            Some(merge::Favor::Ours)
+        } else if args.theirs {
+            Some(merge::Favor::Theirs)
+        } else if args.union {
+            Some(merge::Favor::Union)
+        } else {
+            None
+        },
+        join_if_no_alnum: true,
+        ours_label: label(&args.current),
+        base_label: label(&args.base),
+        theirs_label: label(&args.other),
+    };
+    let merged = merge::merge_file(&base, &current, &other, &options);
+    if args.stdout {
+        std::io::stdout().write_all(&merged.data)?;
+    } else {
+        fs::write(&args.current, &merged.data)?;
+    }
+    if merged.conflicts > 0 {
+        if !args.quiet && !args.stdout {
+            eprintln!("warning: conflicts during merge");
+        }
+        // Like git, the exit status is the number of conflicts
+        std::io::stdout().flush()?;
+        std::process::exit(merged.conflicts.min(127) as i32);
+    }
+    Ok(())
+}
+
+fn cmd_merge_tree(args: MergeTreeArgs) -> anyhow::Result<()> {
+    let repo = find_repository()?;
+    let ours = repo.peel(&repo.resolve_revision(&args.branch1)?, "commit")?;
+    let theirs = repo.peel(&repo.resolve_revision(&args.branch2)?, "commit")?;
+    let (base_tree, base_label) = match &args.merge_base {
+        Some(base) => (
+            Some(repo.peel_to_tree(&repo.resolve_revision(base)?)?),
+            base.clone(),
+        ),
+        None => {
+            let bases = repo.merge_bases(&ours, &theirs)?;
+            anyhow::ensure!(
+                !bases.is_empty() || args.allow_unrelated_histories,
+                "refusing to merge unrelated histories"
+            );
+            let label = match bases.as_slice() {
+                [base] => base.as_str()[..7].to_owned(),
+                _ => "merged common ancestors".to_owned(),
+            };
+            (repo.merge_base_tree(&bases)?, label)
+        }
+    };
+    let options = merge::MergeOptions {
+        style: repo
+            .config()?
+            .get("merge.conflictStyle")
+            .map(merge::ConflictStyle::parse)
+            .transpose()?
+            .unwrap_or_default(),
+        favor: args
+            .strategy_option
+            .as_deref()
+            .map(merge::Favor::parse)
+            .transpose()?,
+        ours_label: args.branch1.clone(),
+        base_label,
+        theirs_label: args.branch2.clone(),
+        ..merge::MergeOptions::default()
+    };
+    let merged = repo.merge_trees(
+        base_tree.as_ref(),
+        &repo.peel_to_tree(&ours)?,
+        &repo.peel_to_tree(&theirs)?,
+        &options,
+    )?;
+
+    let mut out = std::io::stdout().lock();
+    writeln!(
+        out,
+        "{}",
+        Object::Tree(merged.tree.clone()).hash_string(repo.object_format()?)?
+    )?;
+    let clean = merged.conflicts.is_empty();
+    if !clean {
+        let mut conflicts: Vec<&merge::Conflict> = merged.conflicts.iter().collect();
+        conflicts.sort_by(|a, b| a.path.cmp(&b.path));
+        for conflict in conflicts {
+            if args.name_only {
+                writeln!(out, "{}", conflict.path)?;
+                continue;
+            }
+            for (stage, entry) in conflict.stages.iter().enumerate() {
+                if let Some((mode, hash)) = entry {
+                    writeln!(
+                        out,
+                        "{mode:06o} {} {}\t{}",
+                        hex::encode(hash),
+                        stage + 1,
+                        conflict.path
+                    )?;
+                }
+            }
+        }
+    }
+    if args.messages || (!clean && !args.no_messages) {
+        writeln!(out)?;
+        for message in merged.messages.iter() {
+            writeln!(out, "{message}")?;
+        }
+    }
+    out.flush()?;
+    if !clean {
+        drop(repo);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn cmd_blame(args: BlameArgs) -> anyhow::Result<()> {
+    let repo = find_repository()?;
+    let (start, file) = match args.args.as_slice() {
+        [file] => (None, file),
+        [rev, file] => (
+            Some(repo.peel(&repo.resolve_revision(rev)?, "commit")?),
+            file,
+        ),
+        _ => unreachable!("clap limits the number of arguments"),
+    };
+    let path = pathspec::repo_path(&repo, file)?;
+    let options = blame::BlameOptions {
+        ranges: repo.blame_line_ranges(&path, start.as_ref(), &args.ranges)?,
+        ignore_revs: repo.blame_ignore_revs(&args.ignore_rev, &args.ignore_revs_file)?,
+        detect_moves: args.detect_moves,
+    };
+    let result = repo.blame(&path, start.as_ref(), &options)?;
+    let format = blame::BlameFormat {
+        porcelain: args.porcelain,
+        line_porcelain: args.line_porcelain,
+        show_root: args.root,
+        long: args.long,
+        suppress: args.suppress,
+        show_email: args.show_email,
+        show_name: args.show_name,
+    };
+    blame::write_blame(
+        &mut std::io::stdout().lock(),
+        &repo,
+        &path,
+        &result,
+        &format,
+    )
+}
+
+fn cmd_fsck(args: FsckArgs) -> anyhow::Result<()> {
+    let repo = find_repository()?;
+    let opts = fsck::FsckOptions {
+        connectivity_only: args.connectivity_only,
+        reflogs: !args.no_reflogs,
+        unreachable: args.unreachable,
+        dangling: !args.no_dangling,
+        root: args.root,
+        lost_found: args.lost_found,
+    };
+    let report = repo.fsck(&opts)?;
+    for line in report.lines {
+        match line {
+            fsck::FsckLine::Diagnostic(line) => eprintln!("{line}"),
+            fsck::FsckLine::Object(line) => println!("{line}"),
+        }
+    }
+    if report.errors != 0 {
+        drop(repo);
+        std::process::exit(report.errors);
+    }
+    Ok(())
+}
+
+/// Delta search and compression settings, from arguments or the `pack.*` configuration.
+fn pack_options(
+    config: &config::Config,
+    window: Option<usize>,
+    depth: Option<usize>,
+) -> anyhow::Result<pack::PackOptions> {
+    let defaults = pack::PackOptions::default();
+    let compression = match config.get_int("pack.compression")? {
+        Some(level) => Some(level),
+        None => config.get_int("core.compression")?,
+    };
+    Ok(pack::PackOptions {
+        window: match window {
+            Some(window) => window,
+            None => config
+                .get_int("pack.window")?
+                .map_or(defaults.window, |w| w as usize),
+        },
+        depth: match depth {
+            Some(depth) => depth,
+            None => config
+                .get_int("pack.depth")?
+                .map_or(defaults.depth, |d| d as usize),
+        },
+        compression: match compression {
+            Some(level @ 0..=9) => level as u32,
+            _ => defaults.compression,
+        },
+    })
+}
+
+fn report_repack(repacked: Option<gc::Repacked>, quiet: bool) {
+    match repacked {
+        Some(repacked) if !quiet => eprintln!(
+            "Total {} (delta {}), written to pack-{}",
+            repacked.objects, repacked.deltas, repacked.name
+        ),
+        Some(_) => {}
+        None => println!("Nothing new to pack."),
+    }
+}
+
+fn cmd_repack(args: RepackArgs) -> anyhow::Result<()> {
+    let repo = find_repository()?;
+    let config = repo.config()?;
+    let opts = gc::RepackOptions {
+        all: args.all || args.all_keep_unreachable,
+        delete: args.delete,
+        unpack_unreachable: args.all_keep_unreachable.then_some(0),
+        pack: pack_options(&config, args.window, args.depth)?,
+    };
+    report_repack(repo.repack(&opts)?, args.quiet);
+    Ok(())
+}
+
+fn cmd_prune(args: PruneArgs) -> anyhow::Result<()> {
+    let repo = find_repository()?;
+    let now = gc::now();
+    let expire = match &args.expire {
+        Some(expire) => gc::parse_expiry(expire, now)?,
+        None => Some(now),
+    };
+    let Some(expire) = expire else {
+        return Ok(());
+    };
+    for (object, kind) in repo.prune(expire, args.dry_run)? {
+        if args.verbose || args.dry_run {
+            println!("{} {kind}", object.as_str());
+        }
+    }
+    Ok(())
+}
+
+fn cmd_pack_refs(args: PackRefsArgs) -> anyhow::Result<()> {
+    find_repository()?.pack_refs(args.all)
+}
+
+fn cmd_gc(args: GcArgs) -> anyhow::Result<()> {
+    let repo = find_repository()?;
+    let config = repo.config()?;
+    let now = gc::now();
+    let expiry = |name: &str, default: &str| {
+        gc::parse_expiry(config.get(name).unwrap_or(default), now)
+            .with_context(|| format!("Invalid value for {name}"))
+    };
+
+    repo.pack_refs(true)?;
+    repo.expire_reflogs(
+        expiry("gc.reflogExpire", "90.days.ago")?,
+        expiry("gc.reflogExpireUnreachable", "30.days.ago")?,
+    )?;
+
+    let prune = match &args.prune {
+        _ if args.no_prune => None,
+        Some(date) => gc::parse_expiry(date, now)?,
+        None => expiry("gc.pruneExpire", "2.weeks.ago")?,
+    };
+    let (window, depth) = if args.aggressive {
+        (
+            Some(config.get_int("gc.aggressiveWindow")?.unwrap_or(250) as usize),
+            Some(config.get_int("gc.aggressiveDepth")?.unwrap_or(50) as usize),
+        )
+    } else {
+        (None, None)
+    };
+    let opts = gc::RepackOptions {
+        all: true,
+        delete: true,
+        // Unreachable packed objects become loose, so that they expire like loose ones
+        unpack_unreachable: match prune {
+            Some(expire) if expire >= now => None,
+            Some(expire) => Some(expire),
+            None => Some(0),
+        },
+        pack: pack_options(&config, window, depth)?,
+    };
+    let repacked = repo.repack(&opts)?;
+    if repacked.is_some() {
+        report_repack(repacked, args.quiet);
+    }
+    if let Some(expire) = prune {
+        repo.prune(expire, false)?;
+    }
+    Ok(())
+}
+
+fn cmd_worktree_add(args: WorktreeAddArgs) -> anyhow::Result<()> {
+    let repo = find_repository()?;
+    let start = || -> anyhow::Result<ObjectRef> {
+        let start = args.commit_ish.as_deref().unwrap_or("HEAD");
+        Ok(repo.peel(&repo.resolve_revision(start)?, "commit")?)
+    };
+    let branch_exists = |name: &str| repo.ref_exists(&Repository::branch_ref(name));
+    // Without a commit-ish, a branch named like the directory is checked out or created
+    let guessed = match (
+        &args.commit_ish,
+        &args.new_branch,
+        &args.reset_branch,
+        args.detach,
+    ) {
+        (None, None, None, false) => args
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(str::to_owned),
+        _ => None,
+    };
+    let new_branch = match (&args.new_branch, &args.reset_branch, &guessed) {
+        (Some(name), _, _) | (None, Some(name), _) => Some(name.clone()),
+        (None, None, Some(name)) if !branch_exists(name)? => Some(name.clone()),
+        _ => None,
+    };
+    let checkout_branch = match (&guessed, &args.commit_ish) {
+        _ if new_branch.is_some() || args.detach => None,
+        (Some(name), _) | (None, Some(name)) if branch_exists(name)? => Some(name.clone()),
+        _ => None,
+    };
+
+    let (commit, head, preparing) = if let Some(name) = &new_branch {
+        let commit = start()?;
+        let ref_name = Repository::branch_ref(name);
+        let preparing = match repo.read_ref(&ref_name)? {
+            Some(old) if args.reset_branch.is_some() => {
+                format!("resetting branch '{name}'; was at {}", &old.as_str()[..7])
+            }
+            _ => format!("new branch '{name}'"),
+        };
+        repo.create_branch(
+            name,
+            &commit,
+            args.commit_ish.as_deref().unwrap_or("HEAD"),
+            args.reset_branch.is_some(),
+        )?;
+        (commit, refs::Head::Branch(ref_name), preparing)
+    } else if let Some(name) = &checkout_branch {
+        let ref_name = Repository::branch_ref(name);
+        // Checked before announcing the worktree, like git
+        if !args.force {
+            repo.ensure_branch_not_checked_out(&ref_name)?;
+        }
+        let commit = repo.peel(&repo.resolve_revision(&ref_name)?, "commit")?;
+        (
+            commit,
+            refs::Head::Branch(ref_name),
+            format!("checking out '{name}'"),
+        )
+    } else {
+        let commit = start()?;
+        let preparing = format!("detached HEAD {}", &commit.as_str()[..7]);
+        (commit.clone(), refs::Head::Detached(commit), preparing)
+    };
+
+    if !args.quiet {
+        eprintln!("Preparing worktree ({preparing})");
+    }
+    let options = worktree::AddWorktreeOptions {
+        force: args.force,
+        no_checkout: args.no_checkout,
+        lock: args.lock.then(|| args.reason.clone().unwrap_or_default()),
+    };
+    repo.add_worktree(&args.path, &commit, &head, &options)?;
+    if !args.quiet && !args.no_checkout {
+        println!("HEAD is now at {}", describe_commit(&repo, &commit)?);
+    }
+    Ok(())
+}
+
+fn cmd_worktree_list(args: WorktreeListArgs) -> anyhow::Result<()> {
+    let repo = find_repository()?;
+    let worktrees = repo.worktrees()?;
+    let mut out = std::io::stdout().lock();
+    if args.porcelain {
+        for worktree in &worktrees {
+            writeln!(out, "worktree {}", worktree.path.display())?;
+            if worktree.bare {
+                writeln!(out, "bare")?;
+            } else {
+                let commit = worktree.repository().head_commit().ok().flatten();
+                let format = repo.object_format()?;
+                let commit = commit.unwrap_or_else(|| ObjectRef::null(format));
+                writeln!(out, "HEAD {commit}")?;
+                match &worktree.head {
+                    Some(refs::Head::Branch(name)) => writeln!(out, "branch {name}")?,
+                    _ => writeln!(out, "detached")?,
+                }
+            }
+            match worktree.locked.as_deref() {
+                Some("") => writeln!(out, "locked")?,
+                Some(reason) => writeln!(out, "locked {reason}")?,
+                None => {}
+            }
+            if let Some(reason) = &worktree.prunable {
+                writeln!(out, "prunable {reason}")?;
+            }
+            writeln!(out)?;
+        }
+        return Ok(());
+    }
+
+    let width = worktrees
+        .iter()
+        .map(|worktree| worktree.path.display().to_string().len() + 1)
+        .max()
+        .unwrap_or(0);
+    for worktree in &worktrees {
+        let mut line = format!("{:<width$} ", worktree.path.display());
+        if worktree.bare {
+            line.push_str("(bare)");
+        } else {
+            let commit = worktree.repository().head_commit().ok().flatten();
+            let commit =
+                commit.map_or_else(|| "0".repeat(7), |commit| commit.as_str()[..7].to_owned());
+            line.push_str(&commit);
+            match &worktree.head {
+                Some(refs::Head::Branch(name)) => {
+                    let name = name.strip_prefix("refs/heads/").unwrap_or(name);
+                    line.push_str(&format!(" [{name}]"));
+                }
+                _ => line.push_str(" (detached HEAD)"),
+            }
+        }
+        match (&worktree.locked, args.verbose) {
+            (Some(reason), true) if !reason.is_empty() => {
+                line.push_str(&format!("\n\tlocked: {reason}"))
+            }
+            (Some(_), _) => line.push_str(" locked"),
+            (None, _) => {}
+        }
+        match (&worktree.prunable, args.verbose) {
+            (Some(reason), true) => line.push_str(&format!("\n\tprunable: {reason}")),
+            (Some(_), false) => line.push_str(" prunable"),
+            (None, _) => {}
+        }
+        writeln!(out, "{line}")?;
+    }
+    Ok(())
+}
+
+fn cmd_worktree(args: WorktreeArgs) -> anyhow::Result<()> {
+    match args.command {
+        WorktreeCommand::Add(args) => cmd_worktree_add(args),
+        WorktreeCommand::List(args) => cmd_worktree_list(args),
+        WorktreeCommand::Lock(args) => {
+            let repo = find_repository()?;
+            let worktree = repo.find_worktree(&args.worktree)?;
+            repo.lock_worktree(&worktree, args.reason.as_deref().unwrap_or_default())
+        }
+        WorktreeCommand::Unlock(args) => {
+            let repo = find_repository()?;
+            let worktree = repo.find_worktree(&args.worktree)?;
+            repo.unlock_worktree(&worktree)
+        }
+        WorktreeCommand::Remove(args) => {
+            let repo = find_repository()?;
+            let worktree = repo.find_worktree(&args.worktree)?;
+            repo.remove_worktree(&worktree, args.force)
+        }
+        WorktreeCommand::Prune(args) => {
+            let repo = find_repository()?;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let expire = match &args.expire {
+                Some(expire) => match gc::parse_expiry(expire, now)? {
+                    Some(expire) => Some(expire),
+                    // Nothing expires
+                    None => return Ok(()),
+                },
+                None => None,
+            };
+            for (name, reason) in repo.prune_worktrees(expire, args.dry_run)? {
+                if args.verbose || args.dry_run {
+                    println!("Removing worktrees/{name}: {reason}");
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+fn cmd_reflog(args: ReflogArgs) -> anyhow::Result<()> {
+    let repo = find_repository()?;
+    match args.command {
+        None => reflog_show(&repo, args.reference.as_deref()),
+        Some(ReflogCommand::Show(args)) => reflog_show(&repo, args.reference.as_deref()),
+        Some(ReflogCommand::Expire(args)) => {
+            let config = repo.config()?;
+            let now = gc::now();
+            let expiry = |value: &Option<String>, name: &str, default: &str| {
+                gc::parse_expiry(
+                    value
+                        .as_deref()
+                        .unwrap_or_else(|| config.get(name).unwrap_or(default)),
+                    now,
+                )
+                .with_context(|| format!("Invalid value for {name}"))
+            };
+            let expire = expiry(&args.expire, "gc.reflogExpire", "90.days.ago")?;
+            let expire_unreachable = expiry(
+                &args.expire_unreachable,
+                "gc.reflogExpireUnreachable",
+                "30.days.ago",
+            )?;
+            let names = if args.all {
+                repo.reflog_files()?
+                    .into_iter()
+                    .map(|(name, _)| name)
+                    .collect()
+            } else {
+                anyhow::ensure!(!args.refs.is_empty(), "no reflog specified to expire");
+                args.refs
+                    .iter()
+                    .map(|name| repo.reflog_ref_name(name))
+                    .collect::<anyhow::Result<Vec<_>>>()?
+            };
+            for name in names {
+                let removed =
+                    repo.expire_reflog(&name, expire, expire_unreachable, args.dry_run)?;
+                if args.dry_run && removed > 0 {
+                    println!("would prune {removed} entries of {name}");
+                }
+            }
+            Ok(())
+        }
+        Some(ReflogCommand::Delete(args)) => {
+            // Like git, each entry is counted after the ones before it were deleted
+            for entry in &args.entries {
+                let (name, n) = reflog::split_reflog_revision(entry)
+                    .and_then(|(name, spec)| Some((name, spec.parse::<usize>().ok()?)))
+                    .ok_or_else(|| anyhow::Error::msg(format!("not a reflog: {entry}")))?;
+                let name = repo.reflog_ref_name(name)?;
+                repo.delete_reflog_entry(&name, n, args.rewrite, args.updateref)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Prints the reflog of `reference`, newest entry first, as `<hash> <reference>@{<n>}: <message>`.
+fn reflog_show(repo: &Repository, reference: Option<&str>) -> anyhow::Result<()> {
+    let reference = reference.unwrap_or("HEAD");
+    let entries = repo.reflog(&repo.reflog_ref_name(reference)?)?;
+    for (n, entry) in entries.iter().rev().enumerate() {
+        // Deletions have no commit to show
+        let Some(new) = &entry.new else {
+            continue;
+        };
+        println!(
+            "{} {reference}@{{{n}}}: {}",
+            &new.as_str()[..7],
+            entry.message
+        );
+    }
+    Ok(())
+}
+
+/// Git attaches the optional thresholds of `diff-tree -M50%` and `-C75%` directly to the
+/// flag, clap only takes optional values after an equals sign.
+fn attach_short_values(args: impl Iterator<Item = std::ffi::OsString>) -> Vec<std::ffi::OsString> {
+    let mut in_diff_tree = false;
+    args.map(|arg| {
+        let text = arg.to_str().unwrap_or_default();
+        in_diff_tree |= text == "diff-tree";
+        match (text.get(..2), text.get(2..)) {
+            (Some("-M" | "-C"), Some(value))
+                if in_diff_tree && !value.is_empty() && !value.starts_with('=') =>
+            {
+                format!("{}={value}", &text[..2]).into()
+            }
+            _ => arg,
+        }
+    })
+    .collect()
+}
+
 fn main() {
-    let res = match Subcommand::parse() {
-        Subcommand::Init => cmd_init(),
+    let cli = match Cli::try_parse_from(attach_short_values(std::env::args_os())) {
+        Ok(cli) => cli,
+        Err(error) => {
+            let _ = error.print();
+            // Git exits with 129 on usage errors
+            std::process::exit(if error.use_stderr() { 129 } else { 0 });
+        }
+    };
+    for dir in cli
+        .directories
+        .iter()
+        .filter(|dir| !dir.as_os_str().is_empty())
+    {
+        if let Err(error) = std::env::set_current_dir(dir) {
+            eprintln!("fatal: cannot change to '{}': {error}", dir.display());
+            std::process::exit(128);
+        }
+    }
+    // Like git, the options are passed on through the environment
+    if let Some(git_dir) = &cli.git_dir {
+        std::env::set_var("GIT_DIR", git_dir);
+    }
+    if let Some(work_tree) = &cli.work_tree {
+        std::env::set_var("GIT_WORK_TREE", work_tree);
+    }
+    let res = match cli.subcommand {
+        Subcommand::Init(args) => cmd_init(args),
+        Subcommand::Clone(args) => cmd_clone(args),
         Subcommand::CatFile(args) => cmd_cat_file(args),
         Subcommand::LsTree(args) => cmd_ls_tree(args),
         Subcommand::HashObject(args) => cmd_hash_object(args),
         Subcommand::WriteTree => cmd_write_tree(),
         Subcommand::CommitTree(args) => cmd_commit_tree(args),
+        Subcommand::DiffTree(args) => cmd_diff_tree(args),
+        Subcommand::Checkout(args) => cmd_checkout(args),
+        Subcommand::Switch(args) => cmd_switch(args),
+        Subcommand::Restore(args) => cmd_restore(args),
+        Subcommand::Branch(args) => cmd_branch(args),
+        Subcommand::Merge(args) => cmd_merge(args),
+        Subcommand::MergeFile(args) => cmd_merge_file(args),
+        Subcommand::MergeTree(args) => cmd_merge_tree(args),
+        Subcommand::Blame(args) => cmd_blame(args),
+        Subcommand::Fsck(args) => cmd_fsck(args),
+        Subcommand::Repack(args) => cmd_repack(args),
+        Subcommand::Prune(args) => cmd_prune(args),
+        Subcommand::PackRefs(args) => cmd_pack_refs(args),
+        Subcommand::Gc(args) => cmd_gc(args),
+        Subcommand::Worktree(args) => cmd_worktree(args),
+        Subcommand::Reflog(args) => cmd_reflog(args),
     };
 
     if let Err(error) = res {
-        eprintln!("Error during command execution: {:?}", error);
-        for err in error.chain() {
-            eprintln!(" Caused by: {:?}", err);
+        if let Some(Error::Refused(message)) = error.downcast_ref::<Error>() {
+            eprintln!("error: {message}");
+            std::process::exit(1);
         }
-        panic!("Failed!");
+        eprintln!("fatal: {error:#}");
+        std::process::exit(128);
     }
 }
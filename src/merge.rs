@@ -0,0 +1,1188 @@
+//! Three-way merging of files and trees.
+
+use crate::{
+    checkout::tree_snapshot,
+    diff::{is_binary, is_tree_mode},
+    hash::RawHash,
+    index::IndexEntry,
+    myers::{self, Edit},
+    CommitData, Error, Object, ObjectRef, Repository, TreeData, TreeItem,
+};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    fs,
+};
+
+pub const DEFAULT_MARKER_SIZE: usize = 7;
+
+/// How conflicting hunks are presented, see `merge.conflictStyle`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictStyle {
+    /// Only our and their side, conflicts are split at lines both sides agree on
+    #[default]
+    Merge,
+    /// Both sides and the common ancestor, conflicts are never split
+    Diff3,
+    /// Like `Diff3`, but lines common to both sides at the edges of a conflict are
+    /// moved out of it
+    ZDiff3,
+}
+
+impl ConflictStyle {
+    pub fn parse(name: &str) -> anyhow::Result<ConflictStyle> {
+        match name {
+            "merge" => Ok(ConflictStyle::Merge),
+            "diff3" => Ok(ConflictStyle::Diff3),
+            "zdiff3" => Ok(ConflictStyle::ZDiff3),
+            _ => anyhow::bail!("unknown conflict style '{name}'"),
+        }
+    }
+}
+
+/// Side which wins conflicting hunks instead of leaving conflict markers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Favor {
+    Ours,
+    Theirs,
+    /// Keep lines from both sides, ours first
+    Union,
+}
+
+impl Favor {
+    /// Parses a strategy option as given to `merge -X`.
+    pub fn parse(option: &str) -> anyhow::Result<Favor> {
+        match option {
+            "ours" => Ok(Favor::Ours),
+            "theirs" => Ok(Favor::Theirs),
+            _ => anyhow::bail!("unknown strategy option: -X{option}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MergeOptions {
+    pub style: ConflictStyle,
+    pub marker_size: usize,
+    pub favor: Option<Favor>,
+    /// Also join conflicts separated only by lines without letters or digits, like
+    /// `git merge-file` does. Conflicts at most three lines apart are always joined.
+    pub join_if_no_alnum: bool,
+    /// Labels written after conflict markers
+    pub ours_label: String,
+    pub base_label: String,
+    pub theirs_label: String,
+}
+
+impl Default for MergeOptions {
+    fn default() -> MergeOptions {
+        MergeOptions {
+            style: ConflictStyle::default(),
+            marker_size: DEFAULT_MARKER_SIZE,
+            favor: None,
+            join_if_no_alnum: false,
+            ours_label: String::new(),
+            base_label: String::new(),
+            theirs_label: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MergedFile {
+    pub data: Vec<u8>,
+    /// Number of conflicting hunks left in `data`
+    pub conflicts: usize,
+}
+
+/// For every line of `old`, the line of `new` it was matched with.
+fn matched_lines(old: &[&[u8]], new: &[&[u8]]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; old.len()];
+    for edit in myers::diff(old, new) {
+        if let Edit::Equal { old, new } = edit {
+            matches[old] = Some(new);
+        }
+    }
+    matches
+}
+
+/// A conflict in `Merge` style that is only written out once it is clear it can not be
+/// joined with the next one, and the lines both sides agree on after it.
+struct PendingConflict<'l> {
+    ours: Vec<&'l [u8]>,
+    theirs: Vec<&'l [u8]>,
+    after: Vec<&'l [u8]>,
+}
+
+struct FileMerger<'a, 'l> {
+    options: &'a MergeOptions,
+    out: Vec<u8>,
+    conflicts: usize,
+    pending: Option<PendingConflict<'l>>,
+}
+
+impl<'l> FileMerger<'_, 'l> {
+    fn lines(&mut self, lines: &[&[u8]]) {
+        for line in lines {
+            self.out.extend_from_slice(line);
+        }
+    }
+
+    /// Emits lines both sides agree on, unchanged or changed the same way on both sides.
+    fn common(&mut self, lines: &[&'l [u8]]) {
+        match &mut self.pending {
+            Some(pending) => pending.after.extend_from_slice(lines),
+            None => self.lines(lines),
+        }
+    }
+
+    /// Emits a hunk only one side changed, or both the same way. Like in git, conflicts
+    /// are never joined across such a hunk.
+    fn resolved(&mut self, lines: &[&[u8]]) {
+        self.flush();
+        self.lines(lines);
+    }
+
+    fn marker(&mut self, c: u8, label: &str) {
+        // A side without a final newline must not swallow the marker
+        if self.out.last().is_some_and(|b| *b != b'\n') {
+            self.out.push(b'\n');
+        }
+        self.out
+            .resize(self.out.len() + self.options.marker_size, c);
+        if !label.is_empty() {
+            self.out.push(b' ');
+            self.out.extend_from_slice(label.as_bytes());
+        }
+        self.out.push(b'\n');
+    }
+
+    /// Writes a conflict, or the favored side of it.
+    fn conflict(&mut self, ours: &[&[u8]], base: Option<&[&[u8]]>, theirs: &[&[u8]]) {
+        let options = self.options;
+        match options.favor {
+            Some(Favor::Ours) => return self.lines(ours),
+            Some(Favor::Theirs) => return self.lines(theirs),
+            Some(Favor::Union) => {
+                self.lines(ours);
+                if ours.last().is_some_and(|line| !line.ends_with(b"\n")) {
+                    self.out.push(b'\n');
+                }
+                return self.lines(theirs);
+            }
+            None => {}
+        }
+        self.conflicts += 1;
+        self.marker(b'<', &options.ours_label);
+        self.lines(ours);
+        if let Some(base) = base {
+            self.marker(b'|', &options.base_label);
+            self.lines(base);
+        }
+        self.marker(b'=', "");
+        self.lines(theirs);
+        self.marker(b'>', &options.theirs_label);
+    }
+
+    /// Adds a conflict in `Merge` style, joining it with the previous one if only a few
+    /// lines separate them.
+    fn join_conflict(&mut self, ours: &[&'l [u8]], theirs: &[&'l [u8]]) {
+        let join_if_no_alnum = self.options.join_if_no_alnum;
+        if let Some(pending) = &mut self.pending {
+            let no_alnum = || {
+                pending
+                    .after
+                    .iter()
+                    .all(|line| !line.iter().any(u8::is_ascii_alphanumeric))
+            };
+            if pending.after.len() <= 3 || (join_if_no_alnum && no_alnum()) {
+                let after = std::mem::take(&mut pending.after);
+                pending.ours.extend_from_slice(&after);
+                pending.ours.extend_from_slice(ours);
+                pending.theirs.extend_from_slice(&after);
+                pending.theirs.extend_from_slice(theirs);
+                return;
+            }
+        }
+        self.flush();
+        self.pending = Some(PendingConflict {
+            ours: ours.to_vec(),
+            theirs: theirs.to_vec(),
+            after: Vec::new(),
+        });
+    }
+
+    /// Writes out the pending conflict, if any.
+    fn flush(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            self.conflict(&pending.ours, None, &pending.theirs);
+            self.lines(&pending.after);
+        }
+    }
+
+    /// Emits a hunk both sides changed in a different way.
+    fn unresolved(&mut self, ours: &[&'l [u8]], base: &[&[u8]], theirs: &[&'l [u8]]) {
+        match self.options.style {
+            ConflictStyle::Diff3 => {
+                self.flush();
+                self.conflict(ours, Some(base), theirs);
+            }
+            ConflictStyle::ZDiff3 => {
+                self.flush();
+                let prefix = ours.iter().zip(theirs).take_while(|(a, b)| a == b).count();
+                let suffix = ours[prefix..]
+                    .iter()
+                    .rev()
+                    .zip(theirs[prefix..].iter().rev())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                self.lines(&ours[..prefix]);
+                self.conflict(
+                    &ours[prefix..ours.len() - suffix],
+                    Some(base),
+                    &theirs[prefix..theirs.len() - suffix],
+                );
+                self.lines(&ours[ours.len() - suffix..]);
+            }
+            ConflictStyle::Merge => {
+                // Split the conflict at lines both sides agree on
+                let (mut o, mut t) = (0, 0);
+                for edit in myers::diff(ours, theirs) {
+                    if let Edit::Equal { old, new } = edit {
+                        if old > o || new > t {
+                            self.join_conflict(&ours[o..old], &theirs[t..new]);
+                        }
+                        self.common(&ours[old..old + 1]);
+                        (o, t) = (old + 1, new + 1);
+                    }
+                }
+                if o < ours.len() || t < theirs.len() {
+                    self.join_conflict(&ours[o..], &theirs[t..]);
+                }
+            }
+        }
+    }
+
+    fn hunk(&mut self, ours: &[&'l [u8]], base: &[&[u8]], theirs: &[&'l [u8]]) {
+        if ours == base {
+            self.resolved(theirs);
+        } else if theirs == base || ours == theirs {
+            self.resolved(ours);
+        } else {
+            self.unresolved(ours, base, theirs);
+        }
+    }
+}
+
+/// Merges changes from `base` to `theirs` into `ours` line by line, leaving conflict
+/// markers where both sides changed the same lines differently.
+pub fn merge_file(base: &[u8], ours: &[u8], theirs: &[u8], options: &MergeOptions) -> MergedFile {
+    let base = myers::split_lines(base);
+    let ours = myers::split_lines(ours);
+    let theirs = myers::split_lines(theirs);
+    let ours_matches = matched_lines(&base, &ours);
+    let theirs_matches = matched_lines(&base, &theirs);
+
+    let mut merger = FileMerger {
+        options,
+        out: Vec::new(),
+        conflicts: 0,
+        pending: None,
+    };
+    let (mut b, mut o, mut t) = (0, 0, 0);
+    loop {
+        // Lines unchanged on both sides are copied over
+        let mut stable = 0;
+        while b + stable < base.len()
+            && ours_matches[b + stable] == Some(o + stable)
+            && theirs_matches[b + stable] == Some(t + stable)
+        {
+            stable += 1;
+        }
+        if stable > 0 {
+            merger.common(&base[b..b + stable]);
+            (b, o, t) = (b + stable, o + stable, t + stable);
+            continue;
+        }
+        // Otherwise everything up to the next line both sides kept is a changed hunk
+        let next = (b..base.len()).find_map(|i| match (ours_matches[i], theirs_matches[i]) {
+            (Some(oi), Some(ti)) if oi >= o && ti >= t => Some((i, oi, ti)),
+            _ => None,
+        });
+        match next {
+            Some((nb, no, nt)) => {
+                merger.hunk(&ours[o..no], &base[b..nb], &theirs[t..nt]);
+                (b, o, t) = (nb, no, nt);
+            }
+            None => {
+                merger.hunk(&ours[o..], &base[b..], &theirs[t..]);
+                break;
+            }
+        }
+    }
+    merger.flush();
+    MergedFile {
+        data: merger.out,
+        conflicts: merger.conflicts,
+    }
+}
+
+type Entry = (u32, RawHash);
+
+/// A path left unmerged, with the versions to record in index stages 1 to 3
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub path: String,
+    /// Base, ours and theirs
+    pub stages: [Option<Entry>; 3],
+}
+
+#[derive(Debug, Clone)]
+pub struct TreeMerge {
+    /// Result of the merge, conflicted files contain conflict markers
+    pub tree: TreeData,
+    pub conflicts: Vec<Conflict>,
+    /// Progress and conflict messages like `CONFLICT (content): Merge conflict in <path>`
+    pub messages: Vec<String>,
+}
+
+/// How [`Repository::merge`] joins another commit into HEAD
+#[derive(Debug, Clone, Default)]
+pub struct MergeCommitOptions {
+    /// Create a merge commit even when HEAD could be fast-forwarded
+    pub no_ff: bool,
+    /// Only fast-forward, refuse to merge when a merge commit would be needed
+    pub ff_only: bool,
+    /// Stop before committing, as if there were conflicts
+    pub no_commit: bool,
+    pub allow_unrelated_histories: bool,
+    /// Message of the merge commit, like `Merge branch 'topic'` if not given
+    pub message: Option<String>,
+    pub favor: Option<Favor>,
+}
+
+/// What [`Repository::merge`] did
+#[derive(Debug, Clone)]
+pub enum MergeOutcome {
+    /// The commit was already merged, nothing changed
+    UpToDate,
+    /// HEAD was an unborn branch, which now starts at the merged commit
+    Unborn,
+    /// HEAD moved forward from `from` to the merged commit `to`
+    FastForward { from: ObjectRef, to: ObjectRef },
+    /// HEAD moved from `parent` to the merge commit `commit`
+    Committed {
+        parent: ObjectRef,
+        commit: ObjectRef,
+        /// See [`TreeMerge::messages`]
+        messages: Vec<String>,
+    },
+    /// The merge is in the working tree and index, but not committed. `MERGE_HEAD` and
+    /// `MERGE_MSG` are written for the commit concluding it.
+    Stopped {
+        /// Paths left unmerged, empty when the merge was stopped on request
+        conflicts: Vec<String>,
+        messages: Vec<String>,
+    },
+}
+
+/// What git reports after making a merge commit
+pub const MERGE_SUMMARY: &str = "Merge made by the 'recursive' strategy.";
+
+/// Files recording a merge in progress, in the git directory
+const MERGE_STATE_FILES: [&str; 3] = ["MERGE_HEAD", "MERGE_MSG", "MERGE_MODE"];
+
+fn is_regular_mode(mode: u32) -> bool {
+    mode & 0o170000 == 0o100000
+}
+
+impl Repository {
+    /// Merges the changes between `base` and `theirs` into `ours`. All resulting blobs and
+    /// trees are written to the object database, but nothing else is touched.
+    pub fn merge_trees(
+        &self,
+        base: Option<&TreeData>,
+        ours: &TreeData,
+        theirs: &TreeData,
+        options: &MergeOptions,
+    ) -> anyhow::Result<TreeMerge> {
+        let mut result = TreeMerge {
+            tree: TreeData::empty(self.object_format()?),
+            conflicts: Vec::new(),
+            messages: Vec::new(),
+        };
+        result.tree =
+            self.merge_tree_level("", [base, Some(ours), Some(theirs)], options, &mut result)?;
+        self.save_object(&Object::Tree(result.tree.clone()))?;
+        Ok(result)
+    }
+
+    /// Merges the commit `name` resolves to into HEAD, like `git merge`: fast-forwarding if
+    /// possible, creating a merge commit otherwise. On conflicts, the merge is left in the
+    /// working tree and the index to be resolved. The `merge.ff` and `merge.conflictStyle`
+    /// settings are honored.
+    pub fn merge(&self, name: &str, options: &MergeCommitOptions) -> anyhow::Result<MergeOutcome> {
+        let merge_head_path = self.git_dir().join("MERGE_HEAD");
+        anyhow::ensure!(
+            !merge_head_path.exists(),
+            "You have not concluded your merge (MERGE_HEAD exists).\nPlease, commit your changes before you merge."
+        );
+        let theirs = self.peel(&self.resolve_revision(name)?, "commit")?;
+        let theirs_tree = self.peel_to_tree(&theirs)?;
+        let config = self.config()?;
+        let no_ff = options.no_ff || config.get("merge.ff") == Some("false");
+        let ff_only = options.ff_only || (!options.no_ff && config.get("merge.ff") == Some("only"));
+
+        let Some(ours) = self.head_commit()? else {
+            // Nothing to merge into, the unborn branch just starts at the merged commit
+            self.update_worktree(None, &theirs_tree, false, "merge")?;
+            self.update_ref("HEAD", &theirs, &format!("merge {name}: Fast-forward"))?;
+            return Ok(MergeOutcome::Unborn);
+        };
+        if self.is_ancestor(&theirs, &ours)? {
+            return Ok(MergeOutcome::UpToDate);
+        }
+        let ours_tree = self.peel_to_tree(&ours)?;
+        self.write_file_atomically(
+            &self.git_dir().join("ORIG_HEAD"),
+            format!("{}\n", ours.as_str()).as_bytes(),
+        )?;
+        if !no_ff && self.is_ancestor(&ours, &theirs)? {
+            self.update_worktree(Some(&ours_tree), &theirs_tree, false, "merge")?;
+            self.update_ref("HEAD", &theirs, &format!("merge {name}: Fast-forward"))?;
+            return Ok(MergeOutcome::FastForward {
+                from: ours,
+                to: theirs,
+            });
+        }
+        anyhow::ensure!(!ff_only, "Not possible to fast-forward, aborting.");
+        self.check_index_matches(&ours_tree)?;
+
+        let bases = self.merge_bases(&ours, &theirs)?;
+        anyhow::ensure!(
+            !bases.is_empty() || options.allow_unrelated_histories,
+            "refusing to merge unrelated histories"
+        );
+        let base_tree = self.merge_base_tree(&bases)?;
+        let tree_options = MergeOptions {
+            style: config
+                .get("merge.conflictStyle")
+                .map(ConflictStyle::parse)
+                .transpose()?
+                .unwrap_or_default(),
+            favor: options.favor,
+            ours_label: "HEAD".to_owned(),
+            base_label: match bases.as_slice() {
+                [base] => base.as_str()[..7].to_owned(),
+                _ => "merged common ancestors".to_owned(),
+            },
+            theirs_label: name.to_owned(),
+            ..MergeOptions::default()
+        };
+        let merged =
+            self.merge_trees(base_tree.as_ref(), &ours_tree, &theirs_tree, &tree_options)?;
+        self.update_worktree(Some(&ours_tree), &merged.tree, false, "merge")?;
+
+        let message = match &options.message {
+            Some(message) => message.clone(),
+            None => self.merge_message(name)?,
+        };
+        if !merged.conflicts.is_empty() || options.no_commit {
+            let mut index = self.read_index()?.unwrap_or_default();
+            let mut merge_msg = format!("{message}\n");
+            if !merged.conflicts.is_empty() {
+                merge_msg.push_str("\n# Conflicts:\n");
+            }
+            for conflict in merged.conflicts.iter() {
+                index.remove_path(&conflict.path);
+                for (stage, entry) in conflict.stages.iter().enumerate() {
+                    if let Some((mode, hash)) = entry {
+                        index.add(IndexEntry::new(
+                            &conflict.path,
+                            *mode,
+                            *hash,
+                            stage as u8 + 1,
+                        ));
+                    }
+                }
+                merge_msg.push_str(&format!("#\t{}\n", conflict.path));
+            }
+            self.write_index(&index)?;
+            self.write_file_atomically(
+                &merge_head_path,
+                format!("{}\n", theirs.as_str()).as_bytes(),
+            )?;
+            self.write_file_atomically(&self.git_dir().join("MERGE_MSG"), merge_msg.as_bytes())?;
+            self.write_file_atomically(&self.git_dir().join("MERGE_MODE"), b"")?;
+            return Ok(MergeOutcome::Stopped {
+                conflicts: merged.conflicts.into_iter().map(|c| c.path).collect(),
+                messages: merged.messages,
+            });
+        }
+
+        let format = self.object_format()?;
+        let commit = CommitData {
+            tree_hash: Object::Tree(merged.tree.clone())
+                .hash_string(format)?
+                .into(),
+            parent_hashes: vec![ours.as_str().into(), theirs.as_str().into()],
+            author: self.identity("AUTHOR")?,
+            committer: self.identity("COMMITTER")?,
+            message: format!("{message}\n").into(),
+        };
+        let object = Object::Commit(commit.into());
+        self.save_object(&object)?;
+        let commit = ObjectRef::from_hash(&object.hash(format)?);
+        self.update_ref("HEAD", &commit, &format!("merge {name}: {MERGE_SUMMARY}"))?;
+        Ok(MergeOutcome::Committed {
+            parent: ours,
+            commit,
+            messages: merged.messages,
+        })
+    }
+
+    /// Gives up on a merge stopped for conflicts, going back to the HEAD commit.
+    pub fn merge_abort(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.git_dir().join("MERGE_HEAD").exists(),
+            "There is no merge to abort (MERGE_HEAD missing)."
+        );
+        let head = self
+            .head_commit()?
+            .ok_or_else(|| anyhow::Error::msg("HEAD does not point to a commit"))?;
+        let tree = self.peel_to_tree(&head)?;
+        self.checkout_tree(Some(&tree), &tree, true)?;
+        for name in MERGE_STATE_FILES {
+            let _ = fs::remove_file(self.git_dir().join(name));
+        }
+        Ok(())
+    }
+
+    /// Merge results are written over the index, so it may not have changes of its own.
+    fn check_index_matches(&self, head: &TreeData) -> anyhow::Result<()> {
+        let Some(index) = self.read_index()? else {
+            return Ok(());
+        };
+        let head = tree_snapshot(self, Some(head))?;
+        let mut staged: BTreeSet<&str> = index
+            .entries()
+            .iter()
+            .filter(|e| e.stage != 0 || head.get(&e.path) != Some(&(e.mode, e.hash)))
+            .map(|e| e.path.as_str())
+            .collect();
+        staged.extend(
+            head.keys()
+                .filter(|path| index.get(path, 0).is_none())
+                .map(String::as_str),
+        );
+        if staged.is_empty() {
+            return Ok(());
+        }
+        let paths: Vec<&str> = staged.into_iter().collect();
+        Err(Error::Refused(format!(
+            "Your local changes to the following files would be overwritten by merge:\n\t{}\nPlease commit your changes or stash them before you merge.\nAborting",
+            paths.join("\n\t")
+        ))
+        .into())
+    }
+
+    /// Default merge commit message, like `Merge branch 'topic' into next`.
+    fn merge_message(&self, name: &str) -> anyhow::Result<String> {
+        let message = match self.dwim_ref(name)? {
+            Some((full_name, _)) if full_name.starts_with("refs/heads/") => {
+                format!("Merge branch '{}'", &full_name["refs/heads/".len()..])
+            }
+            Some((full_name, _)) if full_name.starts_with("refs/remotes/") => {
+                format!(
+                    "Merge remote-tracking branch '{}'",
+                    &full_name["refs/remotes/".len()..]
+                )
+            }
+            Some((full_name, _)) if full_name.starts_with("refs/tags/") => {
+                format!("Merge tag '{}'", &full_name["refs/tags/".len()..])
+            }
+            _ => format!("Merge commit '{name}'"),
+        };
+        Ok(match self.current_branch()? {
+            Some(branch) if branch != "master" && branch != "main" => {
+                format!("{message} into {branch}")
+            }
+            _ => message,
+        })
+    }
+
+    /// Tree to use as merge base given the merge bases of two commits. Several merge bases
+    /// are merged into a virtual one first, `None` means the histories are unrelated.
+    pub fn merge_base_tree(&self, bases: &[ObjectRef]) -> anyhow::Result<Option<TreeData>> {
+        let Some((first, rest)) = bases.split_first() else {
+            return Ok(None);
+        };
+        let mut tree = self.peel_to_tree(first)?;
+        for (i, next) in rest.iter().enumerate() {
+            let inner = self.merge_base_tree(&self.merge_bases(first, next)?)?;
+            let options = MergeOptions {
+                ours_label: format!("Temporary merge branch {}", i + 1),
+                theirs_label: format!("Temporary merge branch {}", i + 2),
+                ..MergeOptions::default()
+            };
+            tree = self
+                .merge_trees(inner.as_ref(), &tree, &self.peel_to_tree(next)?, &options)?
+                .tree;
+        }
+        Ok(Some(tree))
+    }
+
+    fn merge_tree_level(
+        &self,
+        prefix: &str,
+        trees: [Option<&TreeData>; 3],
+        options: &MergeOptions,
+        result: &mut TreeMerge,
+    ) -> anyhow::Result<TreeData> {
+        let mut entries: BTreeMap<String, [Option<Entry>; 3]> = BTreeMap::new();
+        for (side, tree) in trees.iter().enumerate() {
+            for item in tree.iter().flat_map(|tree| tree.iter()) {
+                entries.entry(item.name.into_owned()).or_default()[side] =
+                    Some((item.mode, item.hash));
+            }
+        }
+
+        let mut merged: Vec<(String, Entry)> = Vec::new();
+        for (name, [base, ours, theirs]) in entries {
+            let path = format!("{prefix}{name}");
+            if ours == theirs || base == theirs {
+                merged.extend(ours.map(|entry| (name, entry)));
+                continue;
+            }
+            if base == ours {
+                merged.extend(theirs.map(|entry| (name, entry)));
+                continue;
+            }
+
+            let is_tree = |entry: Option<Entry>| entry.is_some_and(|(mode, _)| is_tree_mode(mode));
+            let tree_or_none = |entry: Option<Entry>| entry.is_none() || is_tree(entry);
+            if tree_or_none(base) && tree_or_none(ours) && tree_or_none(theirs) {
+                let tree = self.merge_subtrees(&path, [base, ours, theirs], options, result)?;
+                merged.extend(tree.map(|entry| (name, entry)));
+                continue;
+            }
+            if is_tree(ours) || is_tree(theirs) {
+                // A directory on one side and a file on the other, the file is moved aside
+                let (dir_side, file_side, label) = if is_tree(ours) {
+                    (1, theirs, &options.theirs_label)
+                } else {
+                    (2, ours, &options.ours_label)
+                };
+                let mut sides = [base.filter(|_| is_tree(base)), None, None];
+                sides[dir_side] = [ours, theirs][dir_side - 1];
+                let tree = self.merge_subtrees(&path, sides, options, result)?;
+                merged.extend(tree.map(|entry| (name.clone(), entry)));
+                if let Some(file) = file_side {
+                    let moved = format!("{name}~{label}");
+                    result.messages.push(format!(
+                        "CONFLICT (file/directory): directory in the way of {path} from {label}; moving it to {prefix}{moved} instead."
+                    ));
+                    merged.push((moved, file));
+                    let mut stages = [base.filter(|_| !is_tree(base)), None, None];
+                    stages[3 - dir_side] = Some(file);
+                    result.conflicts.push(Conflict { path, stages });
+                }
+                continue;
+            }
+
+            let base = base.filter(|_| !is_tree(base));
+            let entry = match (ours, theirs) {
+                (Some(ours), Some(theirs)) => {
+                    self.merge_entries(&path, base, ours, theirs, options, result)?
+                }
+                (Some(entry), None) | (None, Some(entry)) => {
+                    let (deleted, modified) = if ours.is_some() {
+                        (&options.theirs_label, &options.ours_label)
+                    } else {
+                        (&options.ours_label, &options.theirs_label)
+                    };
+                    result.messages.push(format!(
+                        "CONFLICT (modify/delete): {path} deleted in {deleted} and modified in {modified}.  Version {modified} of {path} left in tree."
+                    ));
+                    result.conflicts.push(Conflict {
+                        path,
+                        stages: [base, ours, theirs],
+                    });
+                    entry
+                }
+                (None, None) => continue,
+            };
+            merged.push((name, entry));
+        }
+
+        let mut tree = TreeData::empty(self.object_format()?);
+        for (name, (mode, hash)) in merged.iter() {
+            tree.add_item(&TreeItem {
+                mode: *mode,
+                name: Cow::Borrowed(name),
+                hash: *hash,
+            });
+        }
+        tree.sort();
+        Ok(tree)
+    }
+
+    /// Merges the trees at `path`, returns `None` if the result is empty.
+    fn merge_subtrees(
+        &self,
+        path: &str,
+        entries: [Option<Entry>; 3],
+        options: &MergeOptions,
+        result: &mut TreeMerge,
+    ) -> anyhow::Result<Option<Entry>> {
+        let mut trees = Vec::new();
+        for entry in entries {
+            trees.push(match entry {
+                Some((_, hash)) => Some(self.read_tree(&ObjectRef::from_hash(&hash))?),
+                None => None,
+            });
+        }
+        let tree = self.merge_tree_level(
+            &format!("{path}/"),
+            [trees[0].as_ref(), trees[1].as_ref(), trees[2].as_ref()],
+            options,
+            result,
+        )?;
+        if tree.iter().next().is_none() {
+            return Ok(None);
+        }
+        let object = Object::Tree(tree);
+        self.save_object(&object)?;
+        Ok(Some((0o40000, object.hash(self.object_format()?)?)))
+    }
+
+    /// Merges two different non-tree entries for the same path.
+    fn merge_entries(
+        &self,
+        path: &str,
+        base: Option<Entry>,
+        ours: Entry,
+        theirs: Entry,
+        options: &MergeOptions,
+        result: &mut TreeMerge,
+    ) -> anyhow::Result<Entry> {
+        let conflict = |result: &mut TreeMerge| {
+            result.conflicts.push(Conflict {
+                path: path.to_owned(),
+                stages: [base, Some(ours), Some(theirs)],
+            })
+        };
+        let kind = if base.is_some() { "content" } else { "add/add" };
+        if !is_regular_mode(ours.0) || !is_regular_mode(theirs.0) {
+            // Symbolic links and submodules cannot be merged by content
+            let entry = match options.favor {
+                Some(Favor::Theirs) => theirs,
+                Some(Favor::Ours | Favor::Union) => ours,
+                None => {
+                    result
+                        .messages
+                        .push(format!("CONFLICT ({kind}): Merge conflict in {path}"));
+                    conflict(result);
+                    ours
+                }
+            };
+            return Ok(entry);
+        }
+
+        let base_mode = base.map(|(mode, _)| mode);
+        let mode = if base_mode == Some(ours.0) {
+            theirs.0
+        } else {
+            ours.0
+        };
+        if ours.1 == theirs.1 || base.map(|(_, hash)| hash) == Some(ours.1) {
+            return Ok((mode, theirs.1));
+        }
+        if base.map(|(_, hash)| hash) == Some(theirs.1) {
+            return Ok((mode, ours.1));
+        }
+
+        result.messages.push(format!("Auto-merging {path}"));
+        let base_data = match base {
+            Some((_, hash)) => self.read_blob(&ObjectRef::from_hash(&hash))?,
+            None => Vec::new(),
+        };
+        let ours_data = self.read_blob(&ObjectRef::from_hash(&ours.1))?;
+        let theirs_data = self.read_blob(&ObjectRef::from_hash(&theirs.1))?;
+        if is_binary(&base_data) || is_binary(&ours_data) || is_binary(&theirs_data) {
+            let entry = match options.favor {
+                Some(Favor::Theirs) => theirs,
+                Some(Favor::Ours | Favor::Union) => ours,
+                None => {
+                    result.messages.push(format!(
+                        "warning: Cannot merge binary files: {path} ({} vs. {})",
+                        options.ours_label, options.theirs_label
+                    ));
+                    result
+                        .messages
+                        .push(format!("CONFLICT ({kind}): Merge conflict in {path}"));
+                    conflict(result);
+                    ours
+                }
+            };
+            return Ok((mode, entry.1));
+        }
+
+        let merged = merge_file(&base_data, &ours_data, &theirs_data, options);
+        let object = Object::Blob(merged.data);
+        self.save_object(&object)?;
+        if merged.conflicts > 0 {
+            result
+                .messages
+                .push(format!("CONFLICT ({kind}): Merge conflict in {path}"));
+            conflict(result);
+        }
+        Ok((mode, object.hash(self.object_format()?)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::odb::MemoryDatabase;
+
+    fn merge(base: &str, ours: &str, theirs: &str, options: &MergeOptions) -> (String, usize) {
+        let merged = merge_file(base.as_bytes(), ours.as_bytes(), theirs.as_bytes(), options);
+        (String::from_utf8(merged.data).unwrap(), merged.conflicts)
+    }
+
+    fn labeled(style: ConflictStyle, favor: Option<Favor>) -> MergeOptions {
+        MergeOptions {
+            style,
+            favor,
+            ours_label: "ours".to_owned(),
+            base_label: "base".to_owned(),
+            theirs_label: "theirs".to_owned(),
+            ..MergeOptions::default()
+        }
+    }
+
+    const BASE: &str = "a\nb\nc\nd\ne\n";
+    const OURS: &str = "a\nx\ny\nz\ne\n";
+    const THEIRS: &str = "a\nx\nq\nz\ne\n";
+
+    #[test]
+    fn clean_merges() {
+        let options = MergeOptions::default();
+        assert_eq!(
+            merge(BASE, "a\nB\nc\nd\ne\n", "a\nb\nc\nD\ne\n", &options),
+            ("a\nB\nc\nD\ne\n".to_owned(), 0)
+        );
+        // The same change on both sides, and a change on one side only
+        assert_eq!(merge(BASE, OURS, OURS, &options), (OURS.to_owned(), 0));
+        assert_eq!(merge(BASE, BASE, THEIRS, &options), (THEIRS.to_owned(), 0));
+        assert_eq!(merge(BASE, OURS, BASE, &options), (OURS.to_owned(), 0));
+        assert_eq!(merge("", "a\n", "a\n", &options), ("a\n".to_owned(), 0));
+    }
+
+    #[test]
+    fn conflict_styles() {
+        // The expected results are those of `git merge-file`
+        let options = labeled(ConflictStyle::Merge, None);
+        assert_eq!(
+            merge(BASE, OURS, THEIRS, &options),
+            (
+                "a\nx\n<<<<<<< ours\ny\n=======\nq\n>>>>>>> theirs\nz\ne\n".to_owned(),
+                1
+            )
+        );
+        let options = labeled(ConflictStyle::Diff3, None);
+        assert_eq!(
+            merge(BASE, OURS, THEIRS, &options),
+            (
+                "a\n<<<<<<< ours\nx\ny\nz\n||||||| base\nb\nc\nd\n=======\nx\nq\nz\n>>>>>>> theirs\ne\n"
+                    .to_owned(),
+                1
+            )
+        );
+        let options = labeled(ConflictStyle::ZDiff3, None);
+        assert_eq!(
+            merge(BASE, OURS, THEIRS, &options),
+            (
+                "a\nx\n<<<<<<< ours\ny\n||||||| base\nb\nc\nd\n=======\nq\n>>>>>>> theirs\nz\ne\n"
+                    .to_owned(),
+                1
+            )
+        );
+        let options = MergeOptions {
+            marker_size: 3,
+            ..MergeOptions::default()
+        };
+        assert_eq!(
+            merge("a\n", "a\n1\n2\n", "a\n3\n", &options),
+            ("a\n<<<\n1\n2\n===\n3\n>>>\n".to_owned(), 1)
+        );
+    }
+
+    #[test]
+    fn close_conflicts_are_joined() {
+        let options = MergeOptions::default();
+        let base = "a\nb\nc\nd\ne\nf\ng\nh\n";
+        // Three lines apart
+        assert_eq!(
+            merge(
+                base,
+                "a\nB\nc\nd\ne\nF\ng\nh\n",
+                "a\nX\nc\nd\ne\nY\ng\nh\n",
+                &options
+            ),
+            (
+                "a\n<<<<<<<\nB\nc\nd\ne\nF\n=======\nX\nc\nd\ne\nY\n>>>>>>>\ng\nh\n".to_owned(),
+                1
+            )
+        );
+        // Four lines apart
+        assert_eq!(
+            merge(base, "a\nB\nc\nd\ne\nf\nG\nh\n", "a\nX\nc\nd\ne\nf\nY\nh\n", &options),
+            (
+                "a\n<<<<<<<\nB\n=======\nX\n>>>>>>>\nc\nd\ne\nf\n<<<<<<<\nG\n=======\nY\n>>>>>>>\nh\n"
+                    .to_owned(),
+                2
+            )
+        );
+        // A change only one side made keeps them apart
+        assert_eq!(
+            merge(
+                base,
+                "a\nB\nc\nD\ne\nF\ng\nh\n",
+                "a\nX\nc\nd\ne\nY\ng\nh\n",
+                &options
+            )
+            .1,
+            2
+        );
+        // Diff3 conflicts are never joined
+        let options = labeled(ConflictStyle::Diff3, None);
+        assert_eq!(
+            merge(
+                base,
+                "a\nB\nc\nd\ne\nF\ng\nh\n",
+                "a\nX\nc\nd\ne\nY\ng\nh\n",
+                &options
+            )
+            .1,
+            2
+        );
+
+        // Like `git merge-file`, also join conflicts separated by punctuation only
+        let base = "1\n2\n3\n4\n5\n6\n7\n8\n";
+        let ours = "1\nA\n}\n\n{\n)\nB\n8\n";
+        let theirs = "1\nC\n}\n\n{\n)\nD\n8\n";
+        assert_eq!(merge(base, ours, theirs, &MergeOptions::default()).1, 2);
+        let options = MergeOptions {
+            join_if_no_alnum: true,
+            ..MergeOptions::default()
+        };
+        assert_eq!(
+            merge(base, ours, theirs, &options),
+            (
+                "1\n<<<<<<<\nA\n}\n\n{\n)\nB\n=======\nC\n}\n\n{\n)\nD\n>>>>>>>\n8\n".to_owned(),
+                1
+            )
+        );
+    }
+
+    #[test]
+    fn conflicts_without_final_newline() {
+        let (merged, conflicts) = merge("a\n", "a\nb", "a\nc", &MergeOptions::default());
+        assert_eq!(merged, "a\n<<<<<<<\nb\n=======\nc\n>>>>>>>\n");
+        assert_eq!(conflicts, 1);
+    }
+
+    #[test]
+    fn favored_sides() {
+        for (favor, expected) in [
+            (Favor::Ours, OURS),
+            (Favor::Theirs, THEIRS),
+            (Favor::Union, "a\nx\ny\nq\nz\ne\n"),
+        ] {
+            let options = labeled(ConflictStyle::Merge, Some(favor));
+            assert_eq!(
+                merge(BASE, OURS, THEIRS, &options),
+                (expected.to_owned(), 0)
+            );
+        }
+        // Favored sides are chosen after the conflict was split, and joined again
+        let options = labeled(ConflictStyle::Merge, Some(Favor::Union));
+        assert_eq!(
+            merge(BASE, "a\nx\ny\nz\nw\ne\n", "a\nx\nq\nz\nv\ne\n", &options),
+            ("a\nx\ny\nz\nw\nq\nz\nv\ne\n".to_owned(), 0)
+        );
+        let options = labeled(ConflictStyle::Diff3, Some(Favor::Union));
+        assert_eq!(
+            merge(BASE, OURS, THEIRS, &options),
+            ("a\nx\ny\nz\nx\nq\nz\ne\n".to_owned(), 0)
+        );
+        assert!(Favor::parse("ours").is_ok());
+        assert!(Favor::parse("patience").is_err());
+    }
+
+    fn memory_repo() -> Repository {
+        Repository::with_object_database(".git".into(), Box::new(MemoryDatabase::default()))
+    }
+
+    fn blob(repo: &Repository, data: &str) -> RawHash {
+        let object = Object::Blob(data.as_bytes().to_vec());
+        repo.save_object(&object).unwrap();
+        object.hash(repo.object_format().unwrap()).unwrap()
+    }
+
+    fn tree(repo: &Repository, entries: &[(&str, u32, RawHash)]) -> TreeData {
+        let mut tree = TreeData::empty(repo.object_format().unwrap());
+        for (name, mode, hash) in entries {
+            tree.add_item(&TreeItem {
+                mode: *mode,
+                name: Cow::Borrowed(name),
+                hash: *hash,
+            });
+        }
+        tree.sort();
+        repo.save_object(&Object::Tree(tree.clone())).unwrap();
+        tree
+    }
+
+    fn entries(tree: &TreeData) -> Vec<(String, u32, RawHash)> {
+        tree.iter()
+            .map(|item| (item.name.into_owned(), item.mode, item.hash))
+            .collect()
+    }
+
+    fn read(repo: &Repository, hash: &RawHash) -> String {
+        String::from_utf8(repo.read_blob(&ObjectRef::from_hash(hash)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn clean_tree_merges() {
+        let repo = memory_repo();
+        let options = labeled(ConflictStyle::Merge, None);
+        let (a, b) = (blob(&repo, BASE), blob(&repo, "b\n"));
+        let sub = tree(&repo, &[("b", 0o100644, b)]);
+        let sub_hash = Object::Tree(sub)
+            .hash(repo.object_format().unwrap())
+            .unwrap();
+        let base = tree(&repo, &[("a", 0o100644, a), ("dir", 0o40000, sub_hash)]);
+
+        // Ours changes the content of `a` and adds a file, theirs changes its mode and
+        // another part of it, and removes the directory
+        let ours_a = blob(&repo, "a\nB\nc\nd\ne\n");
+        let ours = tree(
+            &repo,
+            &[
+                ("a", 0o100644, ours_a),
+                ("dir", 0o40000, sub_hash),
+                ("new", 0o100644, b),
+            ],
+        );
+        let theirs_a = blob(&repo, "a\nb\nc\nD\ne\n");
+        let theirs = tree(&repo, &[("a", 0o100755, theirs_a)]);
+
+        let merged = repo
+            .merge_trees(Some(&base), &ours, &theirs, &options)
+            .unwrap();
+        assert!(merged.conflicts.is_empty());
+        assert_eq!(merged.messages, ["Auto-merging a"]);
+        let merged_entries = entries(&merged.tree);
+        assert_eq!(merged_entries.len(), 2);
+        assert_eq!(merged_entries[0].0, "a");
+        assert_eq!(merged_entries[0].1, 0o100755);
+        assert_eq!(read(&repo, &merged_entries[0].2), "a\nB\nc\nD\ne\n");
+        assert_eq!(merged_entries[1], ("new".to_owned(), 0o100644, b));
+        // The merged tree is stored
+        let tree_hash = Object::Tree(merged.tree)
+            .hash(repo.object_format().unwrap())
+            .unwrap();
+        assert!(repo.has_object(&ObjectRef::from_hash(&tree_hash)).unwrap());
+    }
+
+    #[test]
+    fn add_add_conflicts() {
+        let repo = memory_repo();
+        let options = labeled(ConflictStyle::Merge, None);
+        let base = tree(&repo, &[]);
+        let (ours_f, theirs_f) = (blob(&repo, "ours\n"), blob(&repo, "theirs\n"));
+        let ours = tree(&repo, &[("f", 0o100644, ours_f)]);
+        let theirs = tree(&repo, &[("f", 0o100644, theirs_f)]);
+
+        let merged = repo
+            .merge_trees(Some(&base), &ours, &theirs, &options)
+            .unwrap();
+        assert_eq!(
+            merged.messages,
+            ["Auto-merging f", "CONFLICT (add/add): Merge conflict in f"]
+        );
+        assert_eq!(merged.conflicts.len(), 1);
+        assert_eq!(merged.conflicts[0].path, "f");
+        assert_eq!(
+            merged.conflicts[0].stages,
+            [None, Some((0o100644, ours_f)), Some((0o100644, theirs_f))]
+        );
+        let merged_entries = entries(&merged.tree);
+        assert_eq!(
+            read(&repo, &merged_entries[0].2),
+            "<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\n"
+        );
+    }
+
+    #[test]
+    fn modify_delete_conflicts() {
+        let repo = memory_repo();
+        let options = labeled(ConflictStyle::Merge, None);
+        let (base_f, theirs_f) = (blob(&repo, BASE), blob(&repo, THEIRS));
+        let base = tree(&repo, &[("f", 0o100644, base_f)]);
+        let ours = tree(&repo, &[]);
+        let theirs = tree(&repo, &[("f", 0o100644, theirs_f)]);
+
+        let merged = repo
+            .merge_trees(Some(&base), &ours, &theirs, &options)
+            .unwrap();
+        assert_eq!(
+            merged.messages,
+            ["CONFLICT (modify/delete): f deleted in ours and modified in theirs.  Version theirs of f left in tree."]
+        );
+        assert_eq!(
+            merged.conflicts[0].stages,
+            [Some((0o100644, base_f)), None, Some((0o100644, theirs_f))]
+        );
+        assert_eq!(
+            entries(&merged.tree),
+            [("f".to_owned(), 0o100644, theirs_f)]
+        );
+    }
+
+    #[test]
+    fn mode_conflicts() {
+        let repo = memory_repo();
+        let (base_f, link, theirs_f) = (
+            blob(&repo, BASE),
+            blob(&repo, "target"),
+            blob(&repo, THEIRS),
+        );
+        let base = tree(&repo, &[("f", 0o100644, base_f)]);
+        let ours = tree(&repo, &[("f", 0o120000, link)]);
+        let theirs = tree(&repo, &[("f", 0o100644, theirs_f)]);
+
+        // A symbolic link can not be merged with a file by content
+        let options = labeled(ConflictStyle::Merge, None);
+        let merged = repo
+            .merge_trees(Some(&base), &ours, &theirs, &options)
+            .unwrap();
+        assert_eq!(merged.messages, ["CONFLICT (content): Merge conflict in f"]);
+        assert_eq!(merged.conflicts.len(), 1);
+        assert_eq!(entries(&merged.tree), [("f".to_owned(), 0o120000, link)]);
+
+        let options = labeled(ConflictStyle::Merge, Some(Favor::Theirs));
+        let merged = repo
+            .merge_trees(Some(&base), &ours, &theirs, &options)
+            .unwrap();
+        assert!(merged.conflicts.is_empty());
+        assert_eq!(
+            entries(&merged.tree),
+            [("f".to_owned(), 0o100644, theirs_f)]
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Line based diffing using Myers' O(ND) algorithm.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edit {
+    /// Line `old` of the old sequence is the same as line `new` of the new one
+    Equal { old: usize, new: usize },
+    /// Line `old` of the old sequence was removed
+    Delete { old: usize },
+    /// Line `new` of the new sequence was added
+    Insert { new: usize },
+}
+
+/// Splits data into lines, each line keeps its terminating `\n` (if there is one).
+pub fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    data.split_inclusive(|b| *b == b'\n').collect()
+}
+
+/// Calculates the shortest edit script transforming `old` into `new`.
+pub fn diff<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Edit> {
+    // Common prefix and suffix do not need to go through the (quadratic in
+    // the worst case) search, this makes typical small edits of big files cheap.
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    let mut edits = Vec::with_capacity(old.len().max(new.len()));
+    edits.extend((0..prefix).map(|i| Edit::Equal { old: i, new: i }));
+    edits.extend(
+        shortest_edit(old_mid, new_mid)
+            .into_iter()
+            .map(|e| match e {
+                Edit::Equal { old, new } => Edit::Equal {
+                    old: old + prefix,
+                    new: new + prefix,
+                },
+                Edit::Delete { old } => Edit::Delete { old: old + prefix },
+                Edit::Insert { new } => Edit::Insert { new: new + prefix },
+            }),
+    );
+    edits.extend((0..suffix).map(|i| Edit::Equal {
+        old: old.len() - suffix + i,
+        new: new.len() - suffix + i,
+    }));
+    edits
+}
+
+fn shortest_edit<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Edit> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let max = n + m;
+    if max == 0 {
+        return Vec::new();
+    }
+
+    // `v[k + offset]` holds the furthest x reached on diagonal k.
+    // For backtracking we keep the part of `v` that was reachable at the
+    // beginning of every round, that is diagonals -d..=d.
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    'search: for d in 0..=max {
+        trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let idx = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| v[(k + d) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let (prev_x, prev_y) = if d == 0 {
+            (0, 0)
+        } else {
+            (at(prev_k), at(prev_k) - prev_k)
+        };
+
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            edits.push(Edit::Equal {
+                old: x as usize,
+                new: y as usize,
+            });
+        }
+        if d > 0 {
+            if x == prev_x {
+                edits.push(Edit::Insert {
+                    new: prev_y as usize,
+                });
+            } else {
+                edits.push(Edit::Delete {
+                    old: prev_x as usize,
+                });
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    edits.reverse();
+    edits
+}
@@ -0,0 +1,957 @@
+//! Where objects are stored. A repository reads and writes objects through an
+//! [`ObjectDatabase`], normally its loose objects and packs, but it can be replaced by an
+//! in-memory one or any other storage.
+//!
+//! Objects are also looked up in alternate object directories, listed in
+//! `objects/info/alternates` or `GIT_ALTERNATE_OBJECT_DIRECTORIES`, so that repositories can
+//! share a common store. New objects are only ever written to the repository itself.
+//!
+//! ```
+//! use git_starter_rust::{odb::MemoryDatabase, Object, ObjectRef, Repository};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let repo = Repository::with_object_database(".git".into(), Box::new(MemoryDatabase::default()));
+//! let blob = Object::Blob(b"hello\n".to_vec());
+//! repo.save_object(&blob)?;
+//! assert_eq!(repo.read_blob(&ObjectRef::from_hash(&blob.hash(repo.object_format()?)?))?, b"hello\n");
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    cache::PackCache,
+    hash::ObjectFormat,
+    pack::Pack,
+    read_loose_header, read_loose_object,
+    stream::{self, ObjectStream},
+    Error, ObjectRef, Repository, Result,
+};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs,
+    io::{BufWriter, Read},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Storage for objects, addressed by their hash.
+pub trait ObjectDatabase {
+    /// The hash function naming the objects
+    fn format(&self) -> ObjectFormat;
+
+    /// Reads the type and contents of an object, `None` if it is not stored here.
+    fn read(&self, object_ref: &ObjectRef) -> Result<Option<(String, Vec<u8>)>>;
+
+    /// Reads only the type and size of an object.
+    fn read_header(&self, object_ref: &ObjectRef) -> Result<Option<(String, usize)>> {
+        Ok(self
+            .read(object_ref)?
+            .map(|(kind, data)| (kind, data.len())))
+    }
+
+    /// Opens an object to read its contents in chunks.
+    fn open(&self, object_ref: &ObjectRef) -> Result<Option<ObjectStream>> {
+        Ok(self.read(object_ref)?.map(|(kind, data)| {
+            ObjectStream::new(kind, data.len(), Box::new(std::io::Cursor::new(data)))
+        }))
+    }
+
+    /// Stores an object unless it is stored already, returns its name.
+    fn write(&self, kind: &str, data: &[u8]) -> Result<ObjectRef>;
+
+    /// Stores an object of `size` bytes read from `reader`, like `write`.
+    fn write_stream(&self, kind: &str, size: u64, reader: &mut dyn Read) -> Result<ObjectRef> {
+        self.write_stream_unless(kind, size, reader, &|_| Ok(false))
+    }
+
+    /// Like `write_stream`, but drops the object if `exists` returns true for it once it is
+    /// hashed. This lets databases that are searched together avoid storing it twice.
+    fn write_stream_unless(
+        &self,
+        kind: &str,
+        size: u64,
+        reader: &mut dyn Read,
+        exists: &dyn Fn(&ObjectRef) -> Result<bool>,
+    ) -> Result<ObjectRef> {
+        let mut data = Vec::new();
+        reader.take(size).read_to_end(&mut data)?;
+        let hash = stream::hash_stream(self.format(), kind, data.len() as u64, &mut &data[..])?;
+        let object_ref = ObjectRef::from_hash(&hash);
+        if exists(&object_ref)? {
+            return Ok(object_ref);
+        }
+        self.write(kind, &data)
+    }
+
+    fn exists(&self, object_ref: &ObjectRef) -> Result<bool> {
+        Ok(self.read_header(object_ref)?.is_some())
+    }
+
+    /// Lists all objects stored here, sorted.
+    fn iterate(&self) -> Result<Vec<ObjectRef>>;
+
+    /// Lists the objects whose hex name starts with `prefix`, sorted. The prefix is at
+    /// least two characters long and in lower case.
+    fn find_prefix(&self, prefix: &str) -> Result<Vec<ObjectRef>> {
+        let mut objects = self.iterate()?;
+        objects.retain(|object_ref| object_ref.as_str().starts_with(prefix));
+        Ok(objects)
+    }
+}
+
+/// Shared databases, like the packs of a repository that are also used for repacking.
+impl<T: ObjectDatabase + ?Sized> ObjectDatabase for Rc<T> {
+    fn format(&self) -> ObjectFormat {
+        (**self).format()
+    }
+
+    fn read(&self, object_ref: &ObjectRef) -> Result<Option<(String, Vec<u8>)>> {
+        (**self).read(object_ref)
+    }
+
+    fn read_header(&self, object_ref: &ObjectRef) -> Result<Option<(String, usize)>> {
+        (**self).read_header(object_ref)
+    }
+
+    fn open(&self, object_ref: &ObjectRef) -> Result<Option<ObjectStream>> {
+        (**self).open(object_ref)
+    }
+
+    fn write(&self, kind: &str, data: &[u8]) -> Result<ObjectRef> {
+        (**self).write(kind, data)
+    }
+
+    fn write_stream(&self, kind: &str, size: u64, reader: &mut dyn Read) -> Result<ObjectRef> {
+        (**self).write_stream(kind, size, reader)
+    }
+
+    fn write_stream_unless(
+        &self,
+        kind: &str,
+        size: u64,
+        reader: &mut dyn Read,
+        exists: &dyn Fn(&ObjectRef) -> Result<bool>,
+    ) -> Result<ObjectRef> {
+        (**self).write_stream_unless(kind, size, reader, exists)
+    }
+
+    fn exists(&self, object_ref: &ObjectRef) -> Result<bool> {
+        (**self).exists(object_ref)
+    }
+
+    fn iterate(&self) -> Result<Vec<ObjectRef>> {
+        (**self).iterate()
+    }
+
+    fn find_prefix(&self, prefix: &str) -> Result<Vec<ObjectRef>> {
+        (**self).find_prefix(prefix)
+    }
+}
+
+fn corrupt(object_ref: &ObjectRef, error: anyhow::Error) -> Error {
+    Error::Corrupt {
+        object: object_ref.clone(),
+        reason: format!("{error:#}"),
+    }
+}
+
+/// Zlib compressed files in `objects/<xx>/<rest of the hex name>`, one per object.
+#[derive(Debug, Clone)]
+pub struct LooseDatabase {
+    dir: PathBuf,
+    format: ObjectFormat,
+    compression: u32,
+    fsync: bool,
+}
+
+impl LooseDatabase {
+    /// Opens the objects directory `dir`, writing with fast compression and without fsync.
+    pub fn new(dir: PathBuf, format: ObjectFormat) -> LooseDatabase {
+        LooseDatabase {
+            dir,
+            format,
+            compression: flate2::Compression::fast().level(),
+            fsync: false,
+        }
+    }
+
+    /// Sets the zlib level for new objects and whether they are synced to disk.
+    pub fn with_write_options(mut self, compression: u32, fsync: bool) -> LooseDatabase {
+        self.compression = compression;
+        self.fsync = fsync;
+        self
+    }
+
+    /// Path of the object file, whether it exists or not.
+    pub fn object_path(&self, object_ref: &ObjectRef) -> PathBuf {
+        let (prefix, remainder) = object_ref.0.split_at(2);
+        self.dir.join(prefix).join(remainder)
+    }
+}
+
+impl ObjectDatabase for LooseDatabase {
+    fn format(&self) -> ObjectFormat {
+        self.format
+    }
+
+    fn read(&self, object_ref: &ObjectRef) -> Result<Option<(String, Vec<u8>)>> {
+        let path = self.object_path(object_ref);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        read_loose_object(&path)
+            .map(Some)
+            .map_err(|e| corrupt(object_ref, e))
+    }
+
+    fn read_header(&self, object_ref: &ObjectRef) -> Result<Option<(String, usize)>> {
+        let path = self.object_path(object_ref);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        read_loose_header(&path)
+            .map(Some)
+            .map_err(|e| corrupt(object_ref, e))
+    }
+
+    fn open(&self, object_ref: &ObjectRef) -> Result<Option<ObjectStream>> {
+        let path = self.object_path(object_ref);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        stream::open_loose(&path)
+            .map(Some)
+            .map_err(|e| corrupt(object_ref, e))
+    }
+
+    fn write(&self, kind: &str, data: &[u8]) -> Result<ObjectRef> {
+        // Skip deflating when the object is known
+        let object_ref = ObjectRef::from_hash(&stream::hash_stream(
+            self.format,
+            kind,
+            data.len() as u64,
+            &mut &data[..],
+        )?);
+        if self.exists(&object_ref)? {
+            return Ok(object_ref);
+        }
+        self.write_stream(kind, data.len() as u64, &mut &data[..])
+    }
+
+    /// Writes the object to a temporary file first and renames it into place, so readers
+    /// never see a partial object. The file is made read-only like git does.
+    fn write_stream_unless(
+        &self,
+        kind: &str,
+        size: u64,
+        mut reader: &mut dyn Read,
+        exists: &dyn Fn(&ObjectRef) -> Result<bool>,
+    ) -> Result<ObjectRef> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let temp_path = self.dir.join(format!(
+            "tmp_obj_{}_{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = fs::File::create(&temp_path)?;
+        let mut encoder = flate2::write::ZlibEncoder::new(
+            BufWriter::new(file),
+            flate2::Compression::new(self.compression),
+        );
+        let copied = stream::copy_object(self.format, kind, size, &mut reader, &mut encoder);
+        let result = copied.map_err(Error::from).and_then(|hash| {
+            let file = encoder.finish()?.into_inner().map_err(|e| e.into_error())?;
+            let object_ref = ObjectRef::from_hash(&hash);
+            let final_path = self.object_path(&object_ref);
+            if final_path.is_file() || exists(&object_ref)? {
+                fs::remove_file(&temp_path)?;
+                return Ok(object_ref);
+            }
+            if self.fsync {
+                file.sync_all()?;
+            }
+            file.set_permissions(fs::Permissions::from_mode(0o444))?;
+            drop(file);
+            if let Some(dir) = final_path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::rename(&temp_path, &final_path)?;
+            Ok(object_ref)
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    fn exists(&self, object_ref: &ObjectRef) -> Result<bool> {
+        Ok(self.object_path(object_ref).is_file())
+    }
+
+    fn iterate(&self) -> Result<Vec<ObjectRef>> {
+        let mut objects = Vec::new();
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(objects),
+            Err(e) => return Err(e.into()),
+        };
+        for dir in entries {
+            let dir = dir?;
+            let prefix = dir.file_name();
+            let Some(prefix) = prefix.to_str() else {
+                continue;
+            };
+            if prefix.len() != 2 || !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(dir.path())? {
+                let name = file?.file_name();
+                if let Ok(object_ref) =
+                    ObjectRef::from_hex(&format!("{prefix}{}", name.to_string_lossy()))
+                {
+                    objects.push(object_ref);
+                }
+            }
+        }
+        objects.sort();
+        Ok(objects)
+    }
+
+    /// Only reads the directory the objects would be in.
+    fn find_prefix(&self, prefix: &str) -> Result<Vec<ObjectRef>> {
+        let mut objects = Vec::new();
+        let entries = match fs::read_dir(self.dir.join(&prefix[..2])) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(objects),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let name = entry?.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if name.len() == self.format.hex_len() - 2 && name.starts_with(&prefix[2..]) {
+                if let Ok(object_ref) = ObjectRef::from_hex(&format!("{}{name}", &prefix[..2])) {
+                    objects.push(object_ref);
+                }
+            }
+        }
+        objects.sort();
+        Ok(objects)
+    }
+}
+
+/// The packs in `objects/pack`, read-only.
+#[derive(Debug)]
+pub struct PackDatabase {
+    dir: PathBuf,
+    format: ObjectFormat,
+    cache: RefCell<PackCache>,
+}
+
+impl PackDatabase {
+    pub fn new(dir: PathBuf, format: ObjectFormat) -> PackDatabase {
+        PackDatabase {
+            dir,
+            format,
+            cache: RefCell::default(),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Opens all packs. They are kept open until the pack directory changes.
+    pub fn packs(&self) -> anyhow::Result<Rc<[Pack]>> {
+        let modified = match fs::metadata(&self.dir) {
+            Ok(metadata) => Some(metadata.modified()?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let mut cache = self.cache.borrow_mut();
+        if let (Some(packs), true) = (&cache.packs, cache.modified == modified) {
+            return Ok(packs.clone());
+        }
+        let mut packs = Vec::new();
+        if modified.is_some() {
+            for entry in fs::read_dir(&self.dir)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "idx")
+                    && path.with_extension("pack").exists()
+                {
+                    packs.push(Pack::open(&path, self.format)?);
+                }
+            }
+        }
+        packs.sort_by(|a, b| a.pack_path.cmp(&b.pack_path));
+        let packs: Rc<[Pack]> = packs.into();
+        cache.modified = modified;
+        cache.packs = Some(packs.clone());
+        Ok(packs)
+    }
+
+    /// Calls `f` with the pack containing an object and the offset of its entry.
+    fn with_entry<T>(
+        &self,
+        object_ref: &ObjectRef,
+        f: impl FnOnce(&Pack, u64) -> anyhow::Result<T>,
+    ) -> Result<Option<T>> {
+        let hash = object_ref.to_bytes();
+        for pack in self.packs()?.iter() {
+            if let Some(position) = pack.index.position(&hash) {
+                return pack
+                    .index
+                    .offset(position)
+                    .and_then(|offset| f(pack, offset))
+                    .map(Some)
+                    .map_err(|e| corrupt(object_ref, e));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl ObjectDatabase for PackDatabase {
+    fn format(&self) -> ObjectFormat {
+        self.format
+    }
+
+    fn read(&self, object_ref: &ObjectRef) -> Result<Option<(String, Vec<u8>)>> {
+        self.with_entry(object_ref, |pack, offset| {
+            let (kind, data) = pack.read_at(offset)?;
+            Ok((kind.to_owned(), data))
+        })
+    }
+
+    fn read_header(&self, object_ref: &ObjectRef) -> Result<Option<(String, usize)>> {
+        self.with_entry(object_ref, |pack, offset| {
+            let (kind, size) = pack.read_header_at(offset)?;
+            Ok((kind.to_owned(), size))
+        })
+    }
+
+    fn open(&self, object_ref: &ObjectRef) -> Result<Option<ObjectStream>> {
+        self.with_entry(object_ref, |pack, offset| pack.open_at(offset))
+    }
+
+    fn write(&self, _kind: &str, _data: &[u8]) -> Result<ObjectRef> {
+        Err(Error::Other(anyhow::Error::msg(
+            "objects can not be added to existing packs",
+        )))
+    }
+
+    fn exists(&self, object_ref: &ObjectRef) -> Result<bool> {
+        let hash = object_ref.to_bytes();
+        Ok(self
+            .packs()?
+            .iter()
+            .any(|pack| pack.index.position(&hash).is_some()))
+    }
+
+    fn iterate(&self) -> Result<Vec<ObjectRef>> {
+        let mut objects = Vec::new();
+        for pack in self.packs()?.iter() {
+            for i in 0..pack.index.len() {
+                objects.push(ObjectRef::from_hash(&pack.index.hash(i)));
+            }
+        }
+        objects.sort();
+        objects.dedup();
+        Ok(objects)
+    }
+
+    /// Only searches the part of each index with the first byte of `prefix`.
+    fn find_prefix(&self, prefix: &str) -> Result<Vec<ObjectRef>> {
+        let mut objects = Vec::new();
+        for pack in self.packs()?.iter() {
+            objects.extend(
+                pack.index
+                    .find_prefix(prefix)
+                    .iter()
+                    .map(|hash| ObjectRef::from_hash(hash)),
+            );
+        }
+        objects.sort();
+        objects.dedup();
+        Ok(objects)
+    }
+}
+
+/// Objects kept in memory only, for tests and scratch work.
+#[derive(Debug, Default)]
+pub struct MemoryDatabase {
+    format: ObjectFormat,
+    objects: RefCell<BTreeMap<ObjectRef, (String, Vec<u8>)>>,
+}
+
+impl MemoryDatabase {
+    pub fn new(format: ObjectFormat) -> MemoryDatabase {
+        MemoryDatabase {
+            format,
+            objects: RefCell::default(),
+        }
+    }
+}
+
+impl ObjectDatabase for MemoryDatabase {
+    fn format(&self) -> ObjectFormat {
+        self.format
+    }
+
+    fn read(&self, object_ref: &ObjectRef) -> Result<Option<(String, Vec<u8>)>> {
+        Ok(self.objects.borrow().get(object_ref).cloned())
+    }
+
+    fn read_header(&self, object_ref: &ObjectRef) -> Result<Option<(String, usize)>> {
+        Ok(self
+            .objects
+            .borrow()
+            .get(object_ref)
+            .map(|(kind, data)| (kind.clone(), data.len())))
+    }
+
+    fn write(&self, kind: &str, data: &[u8]) -> Result<ObjectRef> {
+        let hash = stream::hash_stream(self.format(), kind, data.len() as u64, &mut &data[..])?;
+        let object_ref = ObjectRef::from_hash(&hash);
+        self.objects
+            .borrow_mut()
+            .entry(object_ref.clone())
+            .or_insert_with(|| (kind.to_owned(), data.to_vec()));
+        Ok(object_ref)
+    }
+
+    fn exists(&self, object_ref: &ObjectRef) -> Result<bool> {
+        Ok(self.objects.borrow().contains_key(object_ref))
+    }
+
+    fn iterate(&self) -> Result<Vec<ObjectRef>> {
+        Ok(self.objects.borrow().keys().cloned().collect())
+    }
+}
+
+/// Several databases searched in order. New objects go to the first one, unless any of
+/// them has the object already.
+pub struct CompositeDatabase {
+    databases: Vec<Box<dyn ObjectDatabase>>,
+}
+
+impl CompositeDatabase {
+    pub fn new(databases: Vec<Box<dyn ObjectDatabase>>) -> CompositeDatabase {
+        CompositeDatabase { databases }
+    }
+
+    fn first(&self) -> Result<&dyn ObjectDatabase> {
+        self.databases
+            .first()
+            .map(|database| &**database)
+            .ok_or_else(|| Error::Other(anyhow::Error::msg("no object database to write to")))
+    }
+}
+
+impl ObjectDatabase for CompositeDatabase {
+    fn format(&self) -> ObjectFormat {
+        self.databases
+            .first()
+            .map(|database| database.format())
+            .unwrap_or_default()
+    }
+
+    fn read(&self, object_ref: &ObjectRef) -> Result<Option<(String, Vec<u8>)>> {
+        for database in &self.databases {
+            if let Some(object) = database.read(object_ref)? {
+                return Ok(Some(object));
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_header(&self, object_ref: &ObjectRef) -> Result<Option<(String, usize)>> {
+        for database in &self.databases {
+            if let Some(header) = database.read_header(object_ref)? {
+                return Ok(Some(header));
+            }
+        }
+        Ok(None)
+    }
+
+    fn open(&self, object_ref: &ObjectRef) -> Result<Option<ObjectStream>> {
+        for database in &self.databases {
+            if let Some(stream) = database.open(object_ref)? {
+                return Ok(Some(stream));
+            }
+        }
+        Ok(None)
+    }
+
+    fn write(&self, kind: &str, data: &[u8]) -> Result<ObjectRef> {
+        let hash = stream::hash_stream(self.format(), kind, data.len() as u64, &mut &data[..])?;
+        let object_ref = ObjectRef::from_hash(&hash);
+        if self.exists(&object_ref)? {
+            return Ok(object_ref);
+        }
+        self.first()?.write(kind, data)
+    }
+
+    fn write_stream_unless(
+        &self,
+        kind: &str,
+        size: u64,
+        reader: &mut dyn Read,
+        exists: &dyn Fn(&ObjectRef) -> Result<bool>,
+    ) -> Result<ObjectRef> {
+        self.first()?
+            .write_stream_unless(kind, size, reader, &|object_ref| {
+                Ok(exists(object_ref)? || self.exists(object_ref)?)
+            })
+    }
+
+    fn exists(&self, object_ref: &ObjectRef) -> Result<bool> {
+        for database in &self.databases {
+            if database.exists(object_ref)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn iterate(&self) -> Result<Vec<ObjectRef>> {
+        let mut objects = Vec::new();
+        for database in &self.databases {
+            objects.extend(database.iterate()?);
+        }
+        objects.sort();
+        objects.dedup();
+        Ok(objects)
+    }
+
+    fn find_prefix(&self, prefix: &str) -> Result<Vec<ObjectRef>> {
+        let mut objects = Vec::new();
+        for database in &self.databases {
+            objects.extend(database.find_prefix(prefix)?);
+        }
+        objects.sort();
+        objects.dedup();
+        Ok(objects)
+    }
+}
+
+/// Alternates may list object directories with alternates of their own, up to this depth
+const MAX_ALTERNATE_DEPTH: usize = 5;
+
+/// Object directories of alternates, in the order they were found, with the problems met on
+/// the way
+#[derive(Debug)]
+struct Alternates {
+    dirs: Vec<PathBuf>,
+    problems: Vec<String>,
+}
+
+/// Adds the object directories listed in `<objects_dir>/info/alternates`, followed by their
+/// own alternates. Relative paths are relative to `objects_dir`.
+fn read_alternates(objects_dir: &Path, depth: usize, alternates: &mut Alternates) -> Result<()> {
+    let contents = match fs::read_to_string(objects_dir.join("info").join("alternates")) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let paths = contents
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| objects_dir.join(line));
+    add_alternates(paths, objects_dir, depth, alternates)
+}
+
+fn add_alternates(
+    paths: impl Iterator<Item = PathBuf>,
+    base: &Path,
+    depth: usize,
+    alternates: &mut Alternates,
+) -> Result<()> {
+    if depth > MAX_ALTERNATE_DEPTH {
+        alternates.problems.push(format!(
+            "error: {}: ignoring alternate object stores, nesting too deep",
+            base.display()
+        ));
+        return Ok(());
+    }
+    for path in paths {
+        let Ok(dir) = fs::canonicalize(&path) else {
+            alternates.problems.push(format!(
+                "error: object directory {} does not exist; check .git/objects/info/alternates",
+                path.display()
+            ));
+            continue;
+        };
+        // The repository's own directory comes first, so cycles end there
+        if !alternates.dirs.contains(&dir) {
+            alternates.dirs.push(dir.clone());
+            read_alternates(&dir, depth + 1, alternates)?;
+        }
+    }
+    Ok(())
+}
+
+impl Repository {
+    /// Where objects are read from and written to: the loose objects and then the packs,
+    /// followed by those of the alternates, unless the repository was opened with another
+    /// database.
+    pub fn object_database(&self) -> Result<&dyn ObjectDatabase> {
+        if let Some(database) = self.odb.get() {
+            return Ok(&**database);
+        }
+        let format = self.object_format()?;
+        let mut databases: Vec<Box<dyn ObjectDatabase>> = vec![
+            Box::new(self.loose_database()?),
+            Box::new(self.pack_database()?),
+        ];
+        let alternates = self.alternates()?;
+        for problem in alternates.problems {
+            self.warn(problem);
+        }
+        for dir in alternates.dirs {
+            databases.push(Box::new(PackDatabase::new(dir.join("pack"), format)));
+            databases.push(Box::new(LooseDatabase::new(dir, format)));
+        }
+        let database = CompositeDatabase::new(databases);
+        Ok(&**self.odb.get_or_init(|| Box::new(database)))
+    }
+
+    /// Object directories of other repositories objects are also read from: those in
+    /// `GIT_ALTERNATE_OBJECT_DIRECTORIES` and `objects/info/alternates`, and their
+    /// alternates in turn.
+    pub fn alternate_object_dirs(&self) -> Result<Vec<PathBuf>> {
+        Ok(self.alternates()?.dirs)
+    }
+
+    fn alternates(&self) -> Result<Alternates> {
+        let objects_dir = self.common_dir.join("objects");
+        let mut alternates = Alternates {
+            dirs: vec![fs::canonicalize(&objects_dir).unwrap_or(objects_dir.clone())],
+            problems: Vec::new(),
+        };
+        if let Some(paths) = std::env::var_os("GIT_ALTERNATE_OBJECT_DIRECTORIES") {
+            let paths = std::env::split_paths(&paths).filter(|path| !path.as_os_str().is_empty());
+            add_alternates(paths, &objects_dir, 0, &mut alternates)?;
+        }
+        read_alternates(&objects_dir, 0, &mut alternates)?;
+        alternates.dirs.remove(0);
+        Ok(alternates)
+    }
+
+    /// The loose objects, written with the compression and fsync settings from
+    /// `core.looseCompression`, `core.compression` and `core.fsync`.
+    pub fn loose_database(&self) -> Result<LooseDatabase> {
+        let config = self.config()?;
+        let compression = match config.get_int("core.looseCompression")? {
+            Some(level) => Some(level),
+            None => config.get_int("core.compression")?,
+        };
+        let compression = match compression {
+            Some(level @ 0..=9) => level as u32,
+            Some(-1) => flate2::Compression::default().level(),
+            Some(level) => {
+                return Err(Error::Other(anyhow::Error::msg(format!(
+                    "bad zlib compression level {level}"
+                ))))
+            }
+            None => flate2::Compression::fast().level(),
+        };
+        let mut fsync = config.get_bool("core.fsyncObjectFiles")?.unwrap_or(false);
+        if let Some(components) = config.get("core.fsync") {
+            for component in components.split(',').map(str::trim) {
+                let (enable, component) = match component.strip_prefix('-') {
+                    Some(component) => (false, component),
+                    None => (true, component),
+                };
+                match component {
+                    "none" => fsync = false,
+                    "loose-object" | "objects" | "committed" | "added" | "all" => fsync = enable,
+                    _ => {}
+                }
+            }
+        }
+        let objects_dir = self.common_dir.join("objects");
+        Ok(LooseDatabase::new(objects_dir, self.object_format()?)
+            .with_write_options(compression, fsync))
+    }
+
+    /// The packs, shared by all users of the repository so they are opened only once.
+    pub fn pack_database(&self) -> Result<Rc<PackDatabase>> {
+        if let Some(packs) = self.packs.get() {
+            return Ok(packs.clone());
+        }
+        let packs = PackDatabase::new(self.pack_dir(), self.object_format()?);
+        Ok(self.packs.get_or_init(|| Rc::new(packs)).clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fails every lookup, to tell which databases a composite consults.
+    struct FailingDatabase;
+
+    impl ObjectDatabase for FailingDatabase {
+        fn format(&self) -> ObjectFormat {
+            ObjectFormat::Sha1
+        }
+
+        fn read(&self, _object_ref: &ObjectRef) -> Result<Option<(String, Vec<u8>)>> {
+            Err(Error::Other(anyhow::Error::msg(
+                "read from a failing database",
+            )))
+        }
+
+        fn write(&self, _kind: &str, _data: &[u8]) -> Result<ObjectRef> {
+            Err(Error::Other(anyhow::Error::msg(
+                "write to a failing database",
+            )))
+        }
+
+        fn iterate(&self) -> Result<Vec<ObjectRef>> {
+            Err(Error::Other(anyhow::Error::msg(
+                "iterate a failing database",
+            )))
+        }
+    }
+
+    fn blob_ref(data: &[u8]) -> ObjectRef {
+        let hash = stream::hash_stream(
+            ObjectFormat::Sha1,
+            "blob",
+            data.len() as u64,
+            &mut &data[..],
+        )
+        .unwrap();
+        ObjectRef::from_hash(&hash)
+    }
+
+    #[test]
+    fn memory_round_trip() {
+        let database = MemoryDatabase::default();
+        let object_ref = database.write("blob", b"hello\n").unwrap();
+        assert_eq!(
+            object_ref.as_str(),
+            "ce013625030ba8dba906f756967f9e9ca394464a"
+        );
+        assert_eq!(
+            database.read(&object_ref).unwrap(),
+            Some(("blob".to_owned(), b"hello\n".to_vec()))
+        );
+        assert_eq!(
+            database.read_header(&object_ref).unwrap(),
+            Some(("blob".to_owned(), 6))
+        );
+        let mut stream = database.open(&object_ref).unwrap().unwrap();
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello\n");
+        assert!(database.exists(&object_ref).unwrap());
+
+        // Writing again stores nothing new
+        let streamed = database
+            .write_stream("blob", 6, &mut &b"hello\n"[..])
+            .unwrap();
+        assert_eq!(streamed, object_ref);
+        assert_eq!(database.iterate().unwrap(), [object_ref]);
+    }
+
+    #[test]
+    fn missing_objects() {
+        let database = MemoryDatabase::default();
+        let missing = blob_ref(b"missing\n");
+        assert_eq!(database.read(&missing).unwrap(), None);
+        assert_eq!(database.read_header(&missing).unwrap(), None);
+        assert!(database.open(&missing).unwrap().is_none());
+        assert!(!database.exists(&missing).unwrap());
+
+        let repo = Repository::with_object_database(".git".into(), Box::new(database));
+        assert!(!repo.has_object(&missing).unwrap());
+        assert!(matches!(
+            repo.read_raw_object(&missing),
+            Err(Error::NotFound(object_ref)) if object_ref == missing
+        ));
+    }
+
+    #[test]
+    fn composite_lookup_order() {
+        let first = Rc::new(MemoryDatabase::default());
+        let second = Rc::new(MemoryDatabase::default());
+        let in_first = first.write("blob", b"first\n").unwrap();
+        let in_second = second.write("blob", b"second\n").unwrap();
+
+        let composite = CompositeDatabase::new(vec![
+            Box::new(first.clone()),
+            Box::new(second.clone()),
+            Box::new(FailingDatabase),
+        ]);
+        // Found objects end the search, missing ones fall through to the next database
+        assert_eq!(
+            composite.read(&in_first).unwrap(),
+            Some(("blob".to_owned(), b"first\n".to_vec()))
+        );
+        assert_eq!(
+            composite.read(&in_second).unwrap(),
+            Some(("blob".to_owned(), b"second\n".to_vec()))
+        );
+        assert!(composite.read(&blob_ref(b"missing\n")).is_err());
+
+        // New objects go to the first database, known ones are not copied there
+        let composite = CompositeDatabase::new(vec![Box::new(first.clone()), Box::new(second)]);
+        let written = composite.write("blob", b"new\n").unwrap();
+        assert!(first.exists(&written).unwrap());
+        assert_eq!(first.iterate().unwrap().len(), 2);
+        assert_eq!(composite.write("blob", b"second\n").unwrap(), in_second);
+        assert!(!first.exists(&in_second).unwrap());
+        let streamed = composite
+            .write_stream("blob", 7, &mut &b"second\n"[..])
+            .unwrap();
+        assert_eq!(streamed, in_second);
+        assert!(!first.exists(&in_second).unwrap());
+
+        let empty = CompositeDatabase::new(Vec::new());
+        assert_eq!(empty.read(&in_first).unwrap(), None);
+        assert!(empty.write("blob", b"first\n").is_err());
+    }
+
+    #[test]
+    fn prefix_lookup_across_databases() {
+        // Two blobs whose names share their first four characters
+        let mut seen = BTreeMap::new();
+        let (a, b) = (0..)
+            .find_map(|i| {
+                let data = format!("{i}\n").into_bytes();
+                let prefix = blob_ref(&data).as_str()[..4].to_owned();
+                seen.insert(prefix, data.clone()).map(|other| (other, data))
+            })
+            .unwrap();
+        let (a_ref, b_ref) = (blob_ref(&a), blob_ref(&b));
+        let prefix = &a_ref.as_str()[..4];
+
+        let first = MemoryDatabase::default();
+        let second = MemoryDatabase::default();
+        first.write("blob", &a).unwrap();
+        second.write("blob", &b).unwrap();
+        second.write("blob", &a).unwrap();
+        let composite = CompositeDatabase::new(vec![Box::new(first), Box::new(second)]);
+        let mut both = vec![a_ref.clone(), b_ref.clone()];
+        both.sort();
+        assert_eq!(composite.find_prefix(prefix).unwrap(), both);
+        assert_eq!(
+            composite.find_prefix(&a_ref.as_str()[..12]).unwrap(),
+            std::slice::from_ref(&a_ref)
+        );
+
+        let repo = Repository::with_object_database(".git".into(), Box::new(composite));
+        assert!(matches!(
+            repo.resolve_revision(prefix),
+            Err(Error::Ambiguous(_))
+        ));
+        assert_eq!(repo.resolve_revision(&b_ref.as_str()[..12]).unwrap(), b_ref);
+    }
+}
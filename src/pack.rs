@@ -0,0 +1,807 @@
+//! Reading pack files and their `.idx` indexes from `objects/pack`.
+
+use crate::{
+    delta::{self, apply_delta, DeltaIndex},
+    hash::{ObjectFormat, RawHash},
+    stream::ObjectStream,
+    Repository,
+};
+use anyhow::Context;
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+const IDX_V2_SIGNATURE: &[u8; 4] = b"\xfftOc";
+const PACK_SIGNATURE: &[u8; 4] = b"PACK";
+
+const OBJ_COMMIT: u8 = 1;
+const OBJ_TREE: u8 = 2;
+const OBJ_BLOB: u8 = 3;
+const OBJ_TAG: u8 = 4;
+const OBJ_OFS_DELTA: u8 = 6;
+const OBJ_REF_DELTA: u8 = 7;
+
+/// Deltas referring to deltas referring to ... give up after this many steps
+const MAX_DELTA_DEPTH: usize = 10000;
+
+fn kind_name(kind: u8) -> anyhow::Result<&'static str> {
+    Ok(match kind {
+        OBJ_COMMIT => "commit",
+        OBJ_TREE => "tree",
+        OBJ_BLOB => "blob",
+        OBJ_TAG => "tag",
+        _ => anyhow::bail!("Invalid object type {kind} in pack"),
+    })
+}
+
+fn kind_number(kind: &str) -> anyhow::Result<u8> {
+    Ok(match kind {
+        "commit" => OBJ_COMMIT,
+        "tree" => OBJ_TREE,
+        "blob" => OBJ_BLOB,
+        "tag" => OBJ_TAG,
+        _ => anyhow::bail!("Object type {kind} cannot be packed"),
+    })
+}
+
+/// A pack index, mapping object hashes to offsets in the pack
+#[derive(Debug)]
+pub struct PackIndex {
+    version: u32,
+    format: ObjectFormat,
+    data: Vec<u8>,
+    count: usize,
+}
+
+impl PackIndex {
+    /// Parses an index of a pack with objects of `format`, which decides the length of the
+    /// hashes in it.
+    pub fn parse(data: Vec<u8>, format: ObjectFormat) -> anyhow::Result<PackIndex> {
+        let version = if data.starts_with(IDX_V2_SIGNATURE) {
+            anyhow::ensure!(data.len() >= 8, "Pack index is too short");
+            u32::from_be_bytes(data[4..8].try_into().unwrap())
+        } else {
+            1
+        };
+        anyhow::ensure!(
+            version == 1 || version == 2,
+            "Unsupported pack index version {}",
+            version
+        );
+        let hash_len = format.raw_len();
+        let fanout_start = if version == 1 { 0 } else { 8 };
+        anyhow::ensure!(
+            data.len() >= fanout_start + 256 * 4 + 2 * hash_len,
+            "Pack index is too short"
+        );
+        let count = u32::from_be_bytes(
+            data[fanout_start + 255 * 4..fanout_start + 256 * 4]
+                .try_into()
+                .unwrap(),
+        ) as usize;
+        let index = PackIndex {
+            version,
+            format,
+            data,
+            count,
+        };
+        let expected = match version {
+            1 => 256 * 4 + count * (hash_len + 4) + 2 * hash_len,
+            _ => 8 + 256 * 4 + count * (hash_len + 8) + 2 * hash_len,
+        };
+        // Version 2 may have a table of 64-bit offsets before the trailer
+        anyhow::ensure!(
+            index.data.len() == expected
+                || (version == 2
+                    && index.data.len() > expected
+                    && (index.data.len() - expected) & 7 == 0),
+            "Pack index has wrong size"
+        );
+        Ok(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn fanout(&self, byte: usize) -> usize {
+        let start = if self.version == 1 { 0 } else { 8 } + byte * 4;
+        u32::from_be_bytes(self.data[start..start + 4].try_into().unwrap()) as usize
+    }
+
+    pub fn hash(&self, i: usize) -> RawHash {
+        let hash_len = self.format.raw_len();
+        let start = match self.version {
+            1 => 256 * 4 + i * (hash_len + 4) + 4,
+            _ => 8 + 256 * 4 + i * hash_len,
+        };
+        RawHash::from_bytes(&self.data[start..start + hash_len])
+            .expect("Hashes have a valid length")
+    }
+
+    /// Offset of the entry at position `i` in the pack. Fails if it points past the table of
+    /// large offsets, whose size is not recorded in the index.
+    pub fn offset(&self, i: usize) -> anyhow::Result<u64> {
+        let hash_len = self.format.raw_len();
+        if self.version == 1 {
+            let start = 256 * 4 + i * (hash_len + 4);
+            return Ok(u32::from_be_bytes(self.data[start..start + 4].try_into().unwrap()) as u64);
+        }
+        let start = 8 + 256 * 4 + self.count * (hash_len + 4) + i * 4;
+        let offset = u32::from_be_bytes(self.data[start..start + 4].try_into().unwrap());
+        if offset & 0x8000_0000 == 0 {
+            return Ok(offset as u64);
+        }
+        let large = 8 + 256 * 4 + self.count * (hash_len + 8) + (offset & 0x7fff_ffff) as usize * 8;
+        // The large offsets end where the trailing checksums start
+        anyhow::ensure!(
+            large + 8 <= self.data.len() - 2 * hash_len,
+            "Pack index has a bad large offset"
+        );
+        Ok(u64::from_be_bytes(
+            self.data[large..large + 8].try_into().unwrap(),
+        ))
+    }
+
+    /// Position of `hash` in the sorted hash table.
+    pub fn position(&self, hash: &[u8]) -> Option<usize> {
+        let first = hash[0] as usize;
+        let start = if first == 0 {
+            0
+        } else {
+            self.fanout(first - 1)
+        };
+        let end = self.fanout(first);
+        let (mut low, mut high) = (start, end);
+        while low < high {
+            let mid = (low + high) / 2;
+            match (*self.hash(mid)).cmp(hash) {
+                std::cmp::Ordering::Equal => return Some(mid),
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+            }
+        }
+        None
+    }
+
+    /// Hashes starting with a hex prefix (of at least two characters).
+    pub fn find_prefix(&self, prefix: &str) -> Vec<RawHash> {
+        let Ok(first) = u8::from_str_radix(&prefix[..2], 16) else {
+            return Vec::new();
+        };
+        let start = if first == 0 {
+            0
+        } else {
+            self.fanout(first as usize - 1)
+        };
+        (start..self.fanout(first as usize))
+            .map(|i| self.hash(i))
+            .filter(|hash| hex::encode(hash).starts_with(prefix))
+            .collect()
+    }
+
+    /// Checksum of the pack this index belongs to, stored in the trailer.
+    pub fn pack_checksum(&self) -> RawHash {
+        let hash_len = self.format.raw_len();
+        let start = self.data.len() - 2 * hash_len;
+        RawHash::from_bytes(&self.data[start..start + hash_len])
+            .expect("Hashes have a valid length")
+    }
+
+    /// Checks the trailing checksum of the index itself.
+    pub fn verify(&self) -> anyhow::Result<()> {
+        let (content, checksum) = self.data.split_at(self.data.len() - self.format.raw_len());
+        anyhow::ensure!(
+            self.format.digest(content)?[..] == checksum[..],
+            "Pack index checksum mismatch"
+        );
+        Ok(())
+    }
+}
+
+/// Type and size from the header of a pack entry, with the base of deltas
+enum EntryHeader {
+    Object { kind: u8 },
+    OfsDelta { base_offset: u64 },
+    RefDelta { base: RawHash },
+}
+
+#[derive(Debug)]
+pub struct Pack {
+    pub pack_path: PathBuf,
+    pub index: PackIndex,
+}
+
+impl Pack {
+    /// Opens a pack of objects named by `format` given the path of its `.idx` file.
+    pub fn open(idx_path: &Path, format: ObjectFormat) -> anyhow::Result<Pack> {
+        let data = fs::read(idx_path).with_context(|| format!("Reading {idx_path:?}"))?;
+        Ok(Pack {
+            pack_path: idx_path.with_extension("pack"),
+            index: PackIndex::parse(data, format)
+                .with_context(|| format!("Parsing {idx_path:?}"))?,
+        })
+    }
+
+    fn read_entry_header(
+        &self,
+        reader: &mut impl Read,
+        offset: u64,
+    ) -> anyhow::Result<(EntryHeader, usize)> {
+        let mut byte = [0u8];
+        reader.read_exact(&mut byte)?;
+        let kind = (byte[0] >> 4) & 7;
+        let mut size = (byte[0] & 0x0f) as usize;
+        let mut shift = 4;
+        while byte[0] & 0x80 != 0 {
+            reader.read_exact(&mut byte)?;
+            size |= ((byte[0] & 0x7f) as usize) << shift;
+            shift += 7;
+        }
+        let header = match kind {
+            OBJ_OFS_DELTA => {
+                reader.read_exact(&mut byte)?;
+                let mut distance = (byte[0] & 0x7f) as u64;
+                while byte[0] & 0x80 != 0 {
+                    reader.read_exact(&mut byte)?;
+                    distance = ((distance + 1) << 7) | (byte[0] & 0x7f) as u64;
+                }
+                let base_offset = offset
+                    .checked_sub(distance)
+                    .ok_or_else(|| anyhow::Error::msg("Delta base offset out of bounds"))?;
+                EntryHeader::OfsDelta { base_offset }
+            }
+            OBJ_REF_DELTA => {
+                let mut base = vec![0u8; self.index.format.raw_len()];
+                reader.read_exact(&mut base)?;
+                let base = RawHash::from_bytes(&base).expect("Read a hash of valid length");
+                EntryHeader::RefDelta { base }
+            }
+            kind => {
+                kind_name(kind)?;
+                EntryHeader::Object { kind }
+            }
+        };
+        Ok((header, size))
+    }
+
+    /// Reads and inflates the entry at `offset`, resolving deltas.
+    pub fn read_at(&self, offset: u64) -> anyhow::Result<(&'static str, Vec<u8>)> {
+        let mut file = fs::File::open(&self.pack_path)
+            .with_context(|| format!("Opening {:?}", self.pack_path))?;
+        let mut deltas = Vec::new();
+        let mut offset = offset;
+        let (kind, mut data) = loop {
+            anyhow::ensure!(deltas.len() < MAX_DELTA_DEPTH, "Delta chain is too long");
+            file.seek(SeekFrom::Start(offset))?;
+            let mut reader = BufReader::new(&mut file);
+            let (header, size) = self.read_entry_header(&mut reader, offset)?;
+            let mut data = Vec::with_capacity(size);
+            flate2::read::ZlibDecoder::new(reader).read_to_end(&mut data)?;
+            anyhow::ensure!(data.len() == size, "Pack entry has wrong size");
+            match header {
+                EntryHeader::Object { kind, .. } => break (kind_name(kind)?, data),
+                EntryHeader::OfsDelta { base_offset } => {
+                    deltas.push(data);
+                    offset = base_offset;
+                }
+                EntryHeader::RefDelta { base } => {
+                    deltas.push(data);
+                    let position = self.index.position(&base).ok_or_else(|| {
+                        anyhow::Error::msg(format!("Delta base {base:?} not in pack"))
+                    })?;
+                    offset = self.index.offset(position)?;
+                }
+            }
+        };
+        for delta in deltas.iter().rev() {
+            data = apply_delta(&data, delta)?;
+        }
+        Ok((kind, data))
+    }
+
+    /// Opens the entry at `offset` for reading. Whole objects are inflated while they are
+    /// read, deltas have to be resolved in memory.
+    pub fn open_at(&self, offset: u64) -> anyhow::Result<ObjectStream> {
+        let mut file = fs::File::open(&self.pack_path)
+            .with_context(|| format!("Opening {:?}", self.pack_path))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(file);
+        let (header, size) = self.read_entry_header(&mut reader, offset)?;
+        if let EntryHeader::Object { kind } = header {
+            let decoder = flate2::read::ZlibDecoder::new(reader);
+            return Ok(ObjectStream::new(
+                kind_name(kind)?.to_owned(),
+                size,
+                Box::new(decoder),
+            ));
+        }
+        let (kind, data) = self.read_at(offset)?;
+        Ok(ObjectStream::new(
+            kind.to_owned(),
+            data.len(),
+            Box::new(std::io::Cursor::new(data)),
+        ))
+    }
+
+    /// Reads the type and size of the entry at `offset`. Only the start of deltas is inflated
+    /// to find their size, their type comes from the headers along the delta chain.
+    pub fn read_header_at(&self, offset: u64) -> anyhow::Result<(&'static str, usize)> {
+        let mut file = fs::File::open(&self.pack_path)
+            .with_context(|| format!("Opening {:?}", self.pack_path))?;
+        let mut result_size = None;
+        let mut offset = offset;
+        for _ in 0..MAX_DELTA_DEPTH {
+            file.seek(SeekFrom::Start(offset))?;
+            let mut reader = BufReader::new(&mut file);
+            let (header, size) = self.read_entry_header(&mut reader, offset)?;
+            if result_size.is_none() && !matches!(header, EntryHeader::Object { .. }) {
+                let mut delta = flate2::read::ZlibDecoder::new(reader);
+                result_size = Some(delta::read_result_size(&mut delta)?);
+            }
+            match header {
+                EntryHeader::Object { kind } => {
+                    return Ok((kind_name(kind)?, result_size.unwrap_or(size)))
+                }
+                EntryHeader::OfsDelta { base_offset } => offset = base_offset,
+                EntryHeader::RefDelta { base } => {
+                    let position = self.index.position(&base).ok_or_else(|| {
+                        anyhow::Error::msg(format!("Delta base {base:?} not in pack"))
+                    })?;
+                    offset = self.index.offset(position)?;
+                }
+            }
+        }
+        anyhow::bail!("Delta chain is too long")
+    }
+
+    /// Returns the size an entry takes up in the pack and the hash of its delta base, if any.
+    pub fn entry_info(&self, position: usize) -> anyhow::Result<(u64, Option<RawHash>)> {
+        let offset = self.index.offset(position)?;
+        let pack_len = fs::metadata(&self.pack_path)?.len();
+        let offsets = (0..self.index.len())
+            .map(|i| self.index.offset(i))
+            .collect::<anyhow::Result<Vec<u64>>>()?;
+        // Entries end where the next one starts, the last one before the trailing checksum
+        let end = offsets
+            .iter()
+            .copied()
+            .filter(|&o| o > offset)
+            .min()
+            .unwrap_or(pack_len.saturating_sub(self.index.format.raw_len() as u64));
+        let mut file = fs::File::open(&self.pack_path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let (header, _) = self.read_entry_header(&mut BufReader::new(file), offset)?;
+        let base = match header {
+            EntryHeader::Object { .. } => None,
+            EntryHeader::RefDelta { base } => Some(base),
+            EntryHeader::OfsDelta { base_offset } => Some(
+                (0..self.index.len())
+                    .find(|&i| offsets[i] == base_offset)
+                    .map(|i| self.index.hash(i))
+                    .ok_or_else(|| anyhow::Error::msg("Delta base not in pack index"))?,
+            ),
+        };
+        Ok((end - offset, base))
+    }
+
+    pub fn read(&self, hash: &[u8]) -> anyhow::Result<Option<(&'static str, Vec<u8>)>> {
+        match self.index.position(hash) {
+            Some(i) => self.read_at(self.index.offset(i)?).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Checks the pack header and trailing checksum against the index.
+    pub fn verify_checksum(&self) -> anyhow::Result<()> {
+        let mut file = fs::File::open(&self.pack_path)?;
+        let mut header = [0u8; 12];
+        file.read_exact(&mut header)?;
+        anyhow::ensure!(&header[..4] == PACK_SIGNATURE, "Invalid pack signature");
+        let count = u32::from_be_bytes(header[8..12].try_into().unwrap()) as usize;
+        anyhow::ensure!(
+            count == self.index.len(),
+            "Pack has {} objects but its index lists {}",
+            count,
+            self.index.len()
+        );
+
+        let hash_len = self.index.format.raw_len();
+        let length = file.metadata()?.len();
+        anyhow::ensure!(length >= 12 + hash_len as u64, "Pack is too short");
+        file.seek(SeekFrom::Start(0))?;
+        let mut hasher = self.index.format.hasher();
+        let mut remaining = length - hash_len as u64;
+        let mut buf = vec![0u8; 64 * 1024];
+        while remaining > 0 {
+            let chunk = remaining.min(buf.len() as u64) as usize;
+            file.read_exact(&mut buf[..chunk])?;
+            hasher.update(&buf[..chunk]);
+            remaining -= chunk as u64;
+        }
+        let mut checksum = vec![0u8; hash_len];
+        file.read_exact(&mut checksum)?;
+        anyhow::ensure!(
+            hasher.finalize()?[..] == checksum[..],
+            "Pack checksum mismatch"
+        );
+        anyhow::ensure!(
+            checksum[..] == self.index.pack_checksum()[..],
+            "Pack checksum does not match its index"
+        );
+        Ok(())
+    }
+}
+
+/// Git's hash of the path an object was found at. It sorts by the last characters, so that
+/// similar files (like those with the same extension) end up close to each other.
+pub fn name_hash(name: &str) -> u32 {
+    name.bytes()
+        .filter(|c| !c.is_ascii_whitespace())
+        .fold(0u32, |hash, c| (hash >> 2).wrapping_add((c as u32) << 24))
+}
+
+/// An object to be written into a pack. Its contents are only read while the pack is
+/// written, so that just the objects tried as delta bases are kept in memory.
+pub struct PackObject {
+    pub hash: RawHash,
+    pub kind: &'static str,
+    /// Size of the contents, which objects are ordered by before they are read
+    pub size: usize,
+    /// See [`name_hash`], zero for objects without a path like commits
+    pub name_hash: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct PackOptions {
+    /// Number of preceding objects to try as delta base
+    pub window: usize,
+    /// Maximum length of delta chains
+    pub depth: usize,
+    /// zlib compression level
+    pub compression: u32,
+}
+
+impl Default for PackOptions {
+    fn default() -> Self {
+        PackOptions {
+            window: 10,
+            depth: 50,
+            compression: 6,
+        }
+    }
+}
+
+/// Chooses the best delta base for `object` with contents `data` among the preceding objects
+/// in `window`, where `depths` holds the delta chain length of each preceding object.
+/// Returns the index of the base and the delta if the object should be a delta.
+fn find_delta(
+    objects: &[PackObject],
+    window: &VecDeque<(usize, DeltaIndex)>,
+    depths: &[usize],
+    object: &PackObject,
+    data: &[u8],
+    opts: &PackOptions,
+) -> Option<(usize, Vec<u8>)> {
+    let mut best: Option<(usize, Vec<u8>)> = None;
+    // A delta has to save at least half of the object to be worth it
+    let mut max_size = (data.len() / 2).saturating_sub(20);
+    for (base, index) in window.iter().rev() {
+        if max_size == 0
+            || objects[*base].kind != object.kind
+            || depths[*base] >= opts.depth
+            || index.base().len().abs_diff(data.len()) >= max_size
+        {
+            continue;
+        }
+        if let Some(delta) = index.create_delta(data, max_size) {
+            max_size = delta.len().saturating_sub(1);
+            best = Some((*base, delta));
+        }
+    }
+    best
+}
+
+fn write_entry_header(out: &mut Vec<u8>, kind: u8, size: usize) {
+    let mut byte = (kind << 4) | (size & 0x0f) as u8;
+    let mut size = size >> 4;
+    while size > 0 {
+        out.push(byte | 0x80);
+        byte = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    out.push(byte);
+}
+
+/// Writes the base of an offset delta as its distance, in git's variable length encoding.
+fn write_base_distance(out: &mut Vec<u8>, mut distance: u64) {
+    let mut bytes = vec![(distance & 0x7f) as u8];
+    distance >>= 7;
+    while distance > 0 {
+        distance -= 1;
+        bytes.push(0x80 | (distance & 0x7f) as u8);
+        distance >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+/// Builds a version 2 index for a pack from `(hash, crc32, offset)` of its entries.
+fn index_data(
+    format: ObjectFormat,
+    mut entries: Vec<(RawHash, u32, u64)>,
+    pack_checksum: &[u8],
+) -> crate::Result<Vec<u8>> {
+    entries.sort_by_key(|entry| entry.0);
+    let hash_len = format.raw_len();
+    let mut data = Vec::with_capacity(8 + 256 * 4 + entries.len() * (hash_len + 8) + 2 * hash_len);
+    data.extend_from_slice(IDX_V2_SIGNATURE);
+    data.extend_from_slice(&2u32.to_be_bytes());
+    let mut count = 0;
+    for byte in 0..256 {
+        while count < entries.len() && entries[count].0[0] as usize <= byte {
+            count += 1;
+        }
+        data.extend_from_slice(&(count as u32).to_be_bytes());
+    }
+    for (hash, ..) in &entries {
+        data.extend_from_slice(hash);
+    }
+    for (_, crc, _) in &entries {
+        data.extend_from_slice(&crc.to_be_bytes());
+    }
+    // Offsets which do not fit into 31 bits go into a separate table
+    let mut large = Vec::new();
+    for (.., offset) in &entries {
+        if *offset < 0x8000_0000 {
+            data.extend_from_slice(&(*offset as u32).to_be_bytes());
+        } else {
+            data.extend_from_slice(&(0x8000_0000 | large.len() as u32).to_be_bytes());
+            large.push(*offset);
+        }
+    }
+    for offset in large {
+        data.extend_from_slice(&offset.to_be_bytes());
+    }
+    data.extend_from_slice(pack_checksum);
+    let checksum = format.digest(&data)?;
+    data.extend_from_slice(&checksum);
+    Ok(data)
+}
+
+/// Writes objects named by `format` into a new pack with its index in `dir`, using offset
+/// deltas between similar objects. The contents of each object are read with `read` when
+/// it is written. Returns the checksum naming the pack and the number of deltas.
+pub fn write_pack(
+    format: ObjectFormat,
+    dir: &Path,
+    mut objects: Vec<PackObject>,
+    opts: &PackOptions,
+    mut read: impl FnMut(&PackObject) -> anyhow::Result<Vec<u8>>,
+) -> anyhow::Result<(String, usize)> {
+    // Similar objects next to each other, larger ones first as deleting data makes
+    // smaller deltas than adding it. Bases thus always precede their deltas. Ties are
+    // broken by the hash, so the same objects always make the same pack.
+    objects.sort_by(|a, b| {
+        (a.kind, b.name_hash, b.size, a.hash).cmp(&(b.kind, a.name_hash, a.size, b.hash))
+    });
+
+    fs::create_dir_all(dir)?;
+    // Unique within the process too, packs may be written more than once
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let unique = format!(
+        "{}_{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let tmp_pack = dir.join(format!("tmp_pack_{unique}"));
+    let mut file = BufWriter::new(fs::File::create(&tmp_pack)?);
+    let mut hasher = format.hasher();
+    let mut header = Vec::with_capacity(12);
+    header.extend_from_slice(PACK_SIGNATURE);
+    header.extend_from_slice(&2u32.to_be_bytes());
+    header.extend_from_slice(&(objects.len() as u32).to_be_bytes());
+    file.write_all(&header)?;
+    hasher.update(&header);
+
+    let mut offset = header.len() as u64;
+    let mut offsets = Vec::with_capacity(objects.len());
+    let mut entries = Vec::with_capacity(objects.len());
+    let mut depths = Vec::with_capacity(objects.len());
+    let mut window: VecDeque<(usize, DeltaIndex)> = VecDeque::new();
+    let mut deltas = 0;
+    for (i, object) in objects.iter().enumerate() {
+        let data = read(object)?;
+        let delta = find_delta(&objects, &window, &depths, object, &data, opts);
+        let mut entry = Vec::new();
+        let contents = match &delta {
+            Some((base, delta)) => {
+                write_entry_header(&mut entry, OBJ_OFS_DELTA, delta.len());
+                write_base_distance(&mut entry, offset - offsets[*base]);
+                depths.push(depths[*base] + 1);
+                deltas += 1;
+                delta
+            }
+            None => {
+                write_entry_header(&mut entry, kind_number(object.kind)?, data.len());
+                depths.push(0);
+                &data
+            }
+        };
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(entry, flate2::Compression::new(opts.compression));
+        encoder.write_all(contents)?;
+        let entry = encoder.finish()?;
+
+        let mut crc = flate2::Crc::new();
+        crc.update(&entry);
+        file.write_all(&entry)?;
+        hasher.update(&entry);
+        offsets.push(offset);
+        entries.push((object.hash, crc.sum(), offset));
+        offset += entry.len() as u64;
+
+        if opts.window > 0 {
+            if window.len() == opts.window {
+                window.pop_front();
+            }
+            window.push_back((i, DeltaIndex::new(data)));
+        }
+    }
+    let checksum = hasher.finalize()?;
+    file.write_all(&checksum)?;
+    file.into_inner()?.sync_all()?;
+
+    let name = hex::encode(checksum);
+    let tmp_idx = dir.join(format!("tmp_idx_{unique}"));
+    fs::write(&tmp_idx, index_data(format, entries, &checksum)?)?;
+    // The index makes the pack visible, so it has to come last
+    fs::rename(&tmp_pack, dir.join(format!("pack-{name}.pack")))?;
+    fs::rename(&tmp_idx, dir.join(format!("pack-{name}.idx")))?;
+    Ok((name, deltas))
+}
+
+impl Repository {
+    pub fn pack_dir(&self) -> PathBuf {
+        self.common_dir.join("objects").join("pack")
+    }
+
+    /// Opens all packs of the repository. They are kept open until the pack directory
+    /// changes.
+    pub fn packs(&self) -> anyhow::Result<Rc<[Pack]>> {
+        self.pack_database()?.packs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(format: ObjectFormat, kind: &'static str, data: &[u8]) -> PackObject {
+        let mut hasher = format.hasher();
+        hasher.update(format!("{kind} {}\0", data.len()).as_bytes());
+        hasher.update(data);
+        PackObject {
+            hash: hasher.finalize().unwrap(),
+            kind,
+            size: data.len(),
+            name_hash: name_hash("file.txt"),
+        }
+    }
+
+    /// Removed when the test ends, also when it fails
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn temp_dir(name: &str) -> TempDir {
+        TempDir(std::env::temp_dir().join(format!("{name}-{}", std::process::id())))
+    }
+
+    #[test]
+    fn write_and_read_pack() {
+        let format = ObjectFormat::Sha1;
+        let dir = temp_dir("write-pack-test");
+        let dir = &dir.0;
+        // Versions of a file which make good deltas, and a commit that does not
+        let mut contents: Vec<(&'static str, Vec<u8>)> = (10..15)
+            .map(|n| {
+                let lines: String = (0..n * 100).map(|i| format!("line {i}\n")).collect();
+                ("blob", lines.into_bytes())
+            })
+            .collect();
+        contents.push((
+            "commit",
+            b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n".to_vec(),
+        ));
+        let objects = contents
+            .iter()
+            .map(|(kind, data)| object(format, kind, data))
+            .collect();
+        let find = |object: &PackObject| {
+            let (_, data) = contents
+                .iter()
+                .find(|(kind, data)| self::object(format, kind, data).hash == object.hash)
+                .unwrap();
+            Ok(data.clone())
+        };
+        let (name, deltas) =
+            write_pack(format, dir, objects, &PackOptions::default(), find).unwrap();
+        assert_eq!(deltas, 4);
+
+        let pack = Pack::open(&dir.join(format!("pack-{name}.idx")), format).unwrap();
+        pack.index.verify().unwrap();
+        pack.verify_checksum().unwrap();
+        assert_eq!(pack.index.len(), contents.len());
+        assert_eq!(hex::encode(pack.index.pack_checksum()), name);
+        for (kind, data) in &contents {
+            let hash = object(format, kind, data).hash;
+            assert_eq!(pack.read(&hash).unwrap(), Some((*kind, data.clone())));
+        }
+        // No temporary files are left behind
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn packs_do_not_depend_on_object_order() {
+        let format = ObjectFormat::Sha1;
+        let dir = temp_dir("pack-order-test");
+        // Same kind, name and size, so only their hashes order them
+        let contents: Vec<Vec<u8>> = (0..4).map(|n| format!("blob {n}\n").into_bytes()).collect();
+        let objects = || contents.iter().map(|data| object(format, "blob", data));
+        let find = |object: &PackObject| {
+            Ok(contents
+                .iter()
+                .find(|data| self::object(format, "blob", data).hash == object.hash)
+                .unwrap()
+                .clone())
+        };
+        let opts = PackOptions::default();
+        let (name, _) = write_pack(format, &dir.0, objects().collect(), &opts, find).unwrap();
+        let reversed = objects().rev().collect();
+        let (reversed_name, _) = write_pack(format, &dir.0, reversed, &opts, find).unwrap();
+        assert_eq!(name, reversed_name);
+    }
+
+    #[test]
+    fn index_with_large_offsets() {
+        let format = ObjectFormat::Sha1;
+        let hash = |byte: u8| RawHash::from_bytes(&[byte; 20]).unwrap();
+        let entries = vec![
+            (hash(0xee), 3, 0x1_0000_0000),
+            (hash(0x01), 1, 12),
+            (hash(0x80), 2, 0x8000_0000),
+        ];
+        let checksum = [0x42; 20];
+        let data = index_data(format, entries, &checksum).unwrap();
+        let index = PackIndex::parse(data.clone(), format).unwrap();
+        index.verify().unwrap();
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.pack_checksum()[..], checksum);
+        assert_eq!(index.position(&[0x01; 20]), Some(0));
+        assert_eq!(index.position(&[0x02; 20]), None);
+        let offsets: Vec<u64> = (0..3).map(|i| index.offset(i).unwrap()).collect();
+        assert_eq!(offsets, [12, 0x8000_0000, 0x1_0000_0000]);
+        assert_eq!(index.find_prefix("ee"), [hash(0xee)]);
+
+        // An entry pointing past the two large offsets is an error, not a panic
+        let mut data = data;
+        let entry = 8 + 256 * 4 + 3 * 24 + 2 * 4;
+        data[entry..entry + 4].copy_from_slice(&0x8000_0002u32.to_be_bytes());
+        let index = PackIndex::parse(data, format).unwrap();
+        assert_eq!(index.offset(1).unwrap(), 0x8000_0000);
+        assert!(index.offset(2).is_err());
+    }
+}
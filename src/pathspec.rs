@@ -0,0 +1,140 @@
+//! Matching of paths given on the command line against repository paths.
+
+use crate::Repository;
+use std::path::{Component, Path};
+
+/// Matches `text` against a shell glob, `*` and `?` match slashes too like in git pathspecs.
+pub fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|i| glob_matches(rest, &text[i..])),
+        Some((b'?', rest)) => !text.is_empty() && glob_matches(rest, &text[1..]),
+        Some((b'[', rest)) => {
+            let Some(end) = rest.iter().skip(1).position(|c| *c == b']').map(|p| p + 1) else {
+                return text.first() == Some(&b'[') && glob_matches(rest, &text[1..]);
+            };
+            let Some((c, text_rest)) = text.split_first() else {
+                return false;
+            };
+            let (negated, class) = match rest[..end].split_first() {
+                Some((b'!' | b'^', class)) => (true, class),
+                _ => (false, &rest[..end]),
+            };
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    matched |= class[i] <= *c && *c <= class[i + 2];
+                    i += 3;
+                } else {
+                    matched |= class[i] == *c;
+                    i += 1;
+                }
+            }
+            matched != negated && glob_matches(&rest[end + 1..], text_rest)
+        }
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            text.first() == Some(&rest[0]) && glob_matches(&rest[1..], &text[1..])
+        }
+        Some((c, rest)) => text.first() == Some(c) && glob_matches(rest, &text[1..]),
+    }
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// Converts a path given relative to the current directory into a path relative to the
+/// top of the working tree, without resolving symbolic links.
+pub fn repo_path(repo: &Repository, arg: &str) -> anyhow::Result<String> {
+    let current_dir = std::env::current_dir()?;
+    let work_dir = repo.work_dir()?;
+    let prefix = current_dir.strip_prefix(work_dir).unwrap_or(Path::new(""));
+
+    let mut parts: Vec<String> = Vec::new();
+    let joined = if Path::new(arg).is_absolute() {
+        Path::new(arg)
+            .strip_prefix(work_dir)
+            .map_err(|_| {
+                anyhow::Error::msg(format!("'{arg}' is outside repository at {work_dir:?}"))
+            })?
+            .to_path_buf()
+    } else {
+        prefix.join(arg)
+    };
+    for component in joined.components() {
+        match component {
+            Component::Normal(part) => parts.push(
+                part.to_str()
+                    .ok_or_else(|| anyhow::Error::msg("Path is not valid UTF-8"))?
+                    .to_owned(),
+            ),
+            Component::ParentDir => {
+                anyhow::ensure!(
+                    parts.pop().is_some(),
+                    "'{}' is outside repository at {:?}",
+                    arg,
+                    work_dir
+                );
+            }
+            _ => {}
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+#[derive(Debug, Clone)]
+struct Pattern {
+    /// As given by the user, for error messages
+    raw: String,
+    /// Relative to the top of the working tree, empty pattern matches everything
+    path: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Pathspec {
+    patterns: Vec<Pattern>,
+}
+
+impl Pathspec {
+    /// Creates a pathspec from command line arguments, which are relative to the
+    /// current directory.
+    pub fn new(repo: &Repository, args: &[String]) -> anyhow::Result<Pathspec> {
+        let mut patterns = Vec::new();
+        for arg in args {
+            patterns.push(Pattern {
+                raw: arg.clone(),
+                path: repo_path(repo, arg)?,
+            });
+        }
+        Ok(Pathspec { patterns })
+    }
+
+    fn pattern_matches(pattern: &str, path: &str) -> bool {
+        pattern.is_empty()
+            || path == pattern
+            || path
+                .strip_prefix(pattern)
+                .is_some_and(|rest| rest.starts_with('/'))
+            || (is_glob(pattern) && glob_matches(pattern.as_bytes(), path.as_bytes()))
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| Self::pattern_matches(&pattern.path, path))
+    }
+
+    /// Returns patterns that do not match any of given paths, for error reporting.
+    pub fn unmatched<'p>(&'p self, paths: &[&str]) -> Vec<&'p str> {
+        self.patterns
+            .iter()
+            .filter(|pattern| {
+                !paths
+                    .iter()
+                    .any(|path| Self::pattern_matches(&pattern.path, path))
+            })
+            .map(|pattern| pattern.raw.as_str())
+            .collect()
+    }
+}
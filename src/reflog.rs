@@ -0,0 +1,433 @@
+//! Reference logs: the values a reference had over time, and `<ref>@{<n>}` lookups in them.
+
+use crate::{config, gc, refs::Head, Error, ObjectRef, PersonLine, Repository};
+use anyhow::Context;
+use std::{fs, io::Write};
+
+/// One update of a reference, a line of `logs/<ref>`
+#[derive(Debug)]
+pub struct ReflogEntry {
+    /// Value before the update, `None` if the reference was created
+    pub old: Option<ObjectRef>,
+    /// Value after the update, `None` if the reference was deleted
+    pub new: Option<ObjectRef>,
+    /// Who updated the reference and when
+    pub committer: PersonLine<'static>,
+    pub message: String,
+}
+
+impl ReflogEntry {
+    /// Parses a line like `<old> <new> Name <email> 1700000000 +0100\t<message>`.
+    pub fn parse(line: &str) -> anyhow::Result<ReflogEntry> {
+        let (header, message) = line.split_once('\t').unwrap_or((line, ""));
+        let mut parts = header.splitn(3, ' ');
+        let mut hash = || -> anyhow::Result<Option<ObjectRef>> {
+            let hex = parts.next().context("Truncated reflog entry")?;
+            let object = ObjectRef::from_hex(hex)?;
+            Ok(hex.bytes().any(|b| b != b'0').then_some(object))
+        };
+        let old = hash()?;
+        let new = hash()?;
+        let person = PersonLine::try_from(parts.next().context("Truncated reflog entry")?)?;
+        Ok(ReflogEntry {
+            old,
+            new,
+            committer: PersonLine {
+                name: person.name.into_owned().into(),
+                email: person.email.into_owned().into(),
+                timestamp: person.timestamp,
+                timezone: person.timezone,
+            },
+            message: message.to_owned(),
+        })
+    }
+}
+
+/// Splits `master@{2}` into the reference and what is in the braces.
+pub fn split_reflog_revision(rev: &str) -> Option<(&str, &str)> {
+    let (name, spec) = rev.strip_suffix('}')?.split_once("@{")?;
+    Some((name, spec))
+}
+
+impl Repository {
+    /// Whether updating `name` starts a reflog for it, following `core.logAllRefUpdates`.
+    /// Existing reflogs are always appended to.
+    fn should_autocreate_reflog(&self, name: &str) -> anyhow::Result<bool> {
+        let enabled = match self.config()?.get("core.logAllRefUpdates") {
+            Some(value) if value.eq_ignore_ascii_case("always") => return Ok(true),
+            Some(value) => config::parse_bool(value).ok_or_else(|| {
+                anyhow::Error::msg(format!(
+                    "Bad config value '{value}' for 'core.logAllRefUpdates'"
+                ))
+            })?,
+            None => !self.is_bare(),
+        };
+        Ok(enabled
+            && (name == "HEAD"
+                || ["refs/heads/", "refs/remotes/", "refs/notes/"]
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))))
+    }
+
+    /// Records that `name` changed from `old` to `new`, by the committer identity.
+    pub(crate) fn append_reflog(
+        &self,
+        name: &str,
+        old: Option<&ObjectRef>,
+        new: &ObjectRef,
+        message: &str,
+    ) -> anyhow::Result<()> {
+        let path = self.reflog_path(name);
+        if !path.exists() && !self.should_autocreate_reflog(name)? {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let null = ObjectRef::null(self.object_format()?);
+        let committer = self.identity("COMMITTER")?;
+        let mut line = format!(
+            "{} {} {} <{}> {} {:+05}",
+            old.unwrap_or(&null).0,
+            new.0,
+            committer.name,
+            committer.email,
+            committer.timestamp,
+            committer.timezone
+        );
+        // Messages are kept on a single line
+        let message = message.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !message.is_empty() {
+            line.push('\t');
+            line.push_str(&message);
+        }
+        line.push('\n');
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("Trying to write reflog of {name}"))
+    }
+
+    /// The reflog of a reference, oldest entry first. Empty if there is none.
+    pub fn reflog(&self, name: &str) -> anyhow::Result<Vec<ReflogEntry>> {
+        let log = match fs::read_to_string(self.reflog_path(name)) {
+            Ok(log) => log,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        log.lines()
+            .map(|line| {
+                ReflogEntry::parse(line).with_context(|| format!("Invalid reflog entry of {name}"))
+            })
+            .collect()
+    }
+
+    /// Full name of the reference whose reflog `<name>@{...}` refers to. An empty name is
+    /// the current branch, or `HEAD` when detached.
+    pub fn reflog_ref_name(&self, name: &str) -> anyhow::Result<String> {
+        if name.is_empty() {
+            return Ok(match self.head()? {
+                Head::Branch(branch) => branch,
+                Head::Detached(_) => "HEAD".to_owned(),
+            });
+        }
+        if let Some((full_name, _)) = self.dwim_ref(name)? {
+            return Ok(full_name);
+        }
+        // Deleted references may still have a reflog
+        for candidate in [name.to_owned(), Self::branch_ref(name)] {
+            if self.reflog_path(&candidate).exists() {
+                return Ok(candidate);
+            }
+        }
+        anyhow::bail!("unknown reference '{name}'")
+    }
+
+    /// Resolves `<name>@{<spec>}`, where `spec` is either the number of updates to go back
+    /// or a date like `yesterday` at which the value the reference had is looked up.
+    pub(crate) fn resolve_reflog_revision(
+        &self,
+        name: &str,
+        spec: &str,
+        rev: &str,
+    ) -> crate::Result<ObjectRef> {
+        let full_name = self
+            .reflog_ref_name(name)
+            .map_err(|_| Error::InvalidRef(rev.to_owned()))?;
+        let shown = if name.is_empty() {
+            full_name.strip_prefix("refs/heads/").unwrap_or(&full_name)
+        } else {
+            name
+        };
+        let entries = self.reflog(&full_name)?;
+        let found = if let Ok(n) = spec.parse::<usize>() {
+            match entries.len().checked_sub(n + 1) {
+                Some(i) => entries[i].new.clone(),
+                // One past the oldest entry is the value before it
+                None if n == entries.len() => entries.first().and_then(|entry| entry.old.clone()),
+                None => None,
+            }
+            .ok_or_else(|| {
+                anyhow::Error::msg(format!(
+                    "log for '{shown}' only has {} entries",
+                    entries.len()
+                ))
+            })?
+        } else {
+            let date = gc::parse_expiry(spec, gc::now())
+                .ok()
+                .flatten()
+                .ok_or_else(|| Error::InvalidRef(rev.to_owned()))?;
+            let oldest = entries
+                .first()
+                .ok_or_else(|| anyhow::Error::msg(format!("log for '{shown}' is empty")))?;
+            match entries
+                .iter()
+                .rev()
+                .find(|entry| entry.committer.timestamp <= date)
+            {
+                Some(entry) => entry.new.clone(),
+                None => {
+                    self.warn(format!(
+                        "warning: log for '{shown}' only goes back to {}",
+                        oldest.committer.date_string()
+                    ));
+                    oldest.old.clone().or_else(|| oldest.new.clone())
+                }
+            }
+            .ok_or_else(|| Error::InvalidRef(rev.to_owned()))?
+        };
+        Ok(found)
+    }
+
+    /// Removes the `n`th newest entry from the reflog of `name`. With `rewrite` the old values
+    /// of all entries are made the new values of the entries before them, and with `update_ref`
+    /// the reference is set to the new newest value if the newest entry was removed.
+    pub fn delete_reflog_entry(
+        &self,
+        name: &str,
+        n: usize,
+        rewrite: bool,
+        update_ref: bool,
+    ) -> anyhow::Result<()> {
+        let path = self.reflog_path(name);
+        let log = fs::read_to_string(&path)
+            .with_context(|| format!("reflog of '{name}' does not exist"))?;
+        let mut lines: Vec<String> = log.lines().map(str::to_owned).collect();
+        let index = lines
+            .len()
+            .checked_sub(n + 1)
+            .ok_or_else(|| anyhow::Error::msg(format!("no reflog entry {name}@{{{n}}}")))?;
+        lines.remove(index);
+        if rewrite {
+            // Each entry then starts where the one before it ended
+            let mut previous = ObjectRef::null(self.object_format()?);
+            for line in &mut lines {
+                let old_len = line.find(' ').unwrap_or(0);
+                line.replace_range(..old_len, previous.as_str());
+                if let Some(new) = ReflogEntry::parse(line)?.new {
+                    previous = new;
+                }
+            }
+        }
+        let mut kept = lines.join("\n");
+        if !kept.is_empty() {
+            kept.push('\n');
+        }
+        self.write_file_atomically(&path, kept.as_bytes())?;
+
+        if update_ref && n == 0 {
+            if let Some(newest) = lines.last() {
+                if let Some(target) = ReflogEntry::parse(newest)?.new {
+                    self.write_ref(name, &target)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::odb::MemoryDatabase;
+    use std::path::PathBuf;
+
+    /// A git directory with only `HEAD` on `main`, removed when the test ends
+    struct TempRepo(PathBuf);
+
+    impl TempRepo {
+        fn new(name: &str) -> TempRepo {
+            let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join(".git/logs/refs/heads")).unwrap();
+            fs::create_dir_all(dir.join(".git/refs/heads")).unwrap();
+            fs::write(dir.join(".git/HEAD"), "ref: refs/heads/main\n").unwrap();
+            TempRepo(dir)
+        }
+
+        fn repo(&self) -> Repository {
+            Repository::with_object_database(
+                self.0.join(".git"),
+                Box::new(MemoryDatabase::default()),
+            )
+        }
+    }
+
+    impl Drop for TempRepo {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn hash(digit: char) -> ObjectRef {
+        ObjectRef::from_hex(&digit.to_string().repeat(40)).unwrap()
+    }
+
+    fn line(old: char, new: char, timestamp: u64, message: &str) -> String {
+        format!(
+            "{} {} A U Thor <author@example.com> {timestamp} +0100\t{message}\n",
+            hash(old),
+            hash(new)
+        )
+    }
+
+    /// `main` created at 1, then moved to 2 and 3, a thousand seconds apart
+    fn repo_with_reflog(name: &str) -> TempRepo {
+        let temp = TempRepo::new(name);
+        let log = [
+            line('0', '1', 1_700_000_000, "branch: Created from HEAD"),
+            line('1', '2', 1_700_001_000, "commit: second"),
+            line('2', '3', 1_700_002_000, "commit: third"),
+        ]
+        .concat();
+        fs::write(temp.0.join(".git/logs/refs/heads/main"), log).unwrap();
+        fs::write(
+            temp.0.join(".git/refs/heads/main"),
+            format!("{}\n", hash('3')),
+        )
+        .unwrap();
+        temp
+    }
+
+    #[test]
+    fn parse_entries() {
+        let entry =
+            ReflogEntry::parse(line('0', '1', 1_700_000_000, "commit (initial): x").trim_end())
+                .unwrap();
+        assert_eq!(entry.old, None);
+        assert_eq!(entry.new, Some(hash('1')));
+        assert_eq!(entry.committer.name, "A U Thor");
+        assert_eq!(entry.committer.email, "author@example.com");
+        assert_eq!(entry.committer.timestamp, 1_700_000_000);
+        assert_eq!(entry.committer.timezone, 100);
+        assert_eq!(entry.message, "commit (initial): x");
+
+        // Deletions have a null new value, and messages are optional
+        let deleted = format!("{} {} A <a@b> 1700000000 +0000", hash('1'), hash('0'));
+        let entry = ReflogEntry::parse(&deleted).unwrap();
+        assert_eq!(entry.old, Some(hash('1')));
+        assert_eq!(entry.new, None);
+        assert_eq!(entry.message, "");
+
+        assert!(ReflogEntry::parse(&hash('1').to_string()).is_err());
+        assert!(ReflogEntry::parse("not-a-hash 1111 A <a@b> 1700000000 +0000").is_err());
+    }
+
+    #[test]
+    fn split_revisions() {
+        assert_eq!(split_reflog_revision("main@{2}"), Some(("main", "2")));
+        assert_eq!(split_reflog_revision("@{1}"), Some(("", "1")));
+        assert_eq!(
+            split_reflog_revision("HEAD@{2 days ago}"),
+            Some(("HEAD", "2 days ago"))
+        );
+        assert_eq!(split_reflog_revision("main"), None);
+        assert_eq!(split_reflog_revision("main@{1"), None);
+    }
+
+    #[test]
+    fn resolve_by_count() {
+        let temp = repo_with_reflog("reflog-count-test");
+        let repo = temp.repo();
+        assert_eq!(repo.resolve_revision("main@{0}").unwrap(), hash('3'));
+        assert_eq!(repo.resolve_revision("main@{1}").unwrap(), hash('2'));
+        assert_eq!(repo.resolve_revision("main@{2}").unwrap(), hash('1'));
+        // An empty name is the current branch
+        assert_eq!(repo.resolve_revision("@{1}").unwrap(), hash('2'));
+        let error = repo.resolve_revision("main@{3}").unwrap_err();
+        assert_eq!(error.to_string(), "log for 'main' only has 3 entries");
+        assert!(matches!(
+            repo.resolve_revision("other@{0}"),
+            Err(Error::InvalidRef(_))
+        ));
+    }
+
+    #[test]
+    fn resolve_by_date() {
+        let temp = repo_with_reflog("reflog-date-test");
+        let repo = temp.repo();
+        // The value the reference had at that time
+        assert_eq!(
+            repo.resolve_revision("main@{@1700001500}").unwrap(),
+            hash('2')
+        );
+        assert_eq!(
+            repo.resolve_revision("main@{@1700002000}").unwrap(),
+            hash('3')
+        );
+        assert_eq!(repo.resolve_revision("main@{now}").unwrap(), hash('3'));
+        // Before the reflog starts, the oldest known value
+        assert_eq!(
+            repo.resolve_revision("main@{@1600000000}").unwrap(),
+            hash('1')
+        );
+        assert!(matches!(
+            repo.resolve_revision("main@{someday}"),
+            Err(Error::InvalidRef(_))
+        ));
+    }
+
+    #[test]
+    fn delete_entries() {
+        let temp = repo_with_reflog("reflog-delete-test");
+        let repo = temp.repo();
+        let news = |repo: &Repository| -> Vec<Option<ObjectRef>> {
+            repo.reflog("refs/heads/main")
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.new)
+                .collect()
+        };
+        let olds = |repo: &Repository| -> Vec<Option<ObjectRef>> {
+            repo.reflog("refs/heads/main")
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.old)
+                .collect()
+        };
+
+        repo.delete_reflog_entry("refs/heads/main", 1, false, false)
+            .unwrap();
+        assert_eq!(news(&repo), [Some(hash('1')), Some(hash('3'))]);
+        assert_eq!(olds(&repo), [None, Some(hash('2'))]);
+        assert!(repo
+            .delete_reflog_entry("refs/heads/main", 2, false, false)
+            .is_err());
+
+        // Rewriting makes each entry start where the one before it ended
+        let temp = repo_with_reflog("reflog-rewrite-test");
+        let repo = temp.repo();
+        repo.delete_reflog_entry("refs/heads/main", 1, true, false)
+            .unwrap();
+        assert_eq!(olds(&repo), [None, Some(hash('1'))]);
+
+        // Deleting the newest entry can move the reference back
+        repo.delete_reflog_entry("refs/heads/main", 0, false, true)
+            .unwrap();
+        assert_eq!(news(&repo), [Some(hash('1'))]);
+        assert_eq!(repo.resolve_revision("main").unwrap(), hash('1'));
+    }
+}
@@ -0,0 +1,573 @@
+//! References, `HEAD` and revision parsing.
+
+use crate::{Error, Object, ObjectRef, Repository};
+use anyhow::Context;
+use std::{fs, path::PathBuf};
+
+/// What `HEAD` points to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Head {
+    /// On a branch, contains the full reference name like `refs/heads/master`.
+    /// The branch does not need to exist yet (unborn branch).
+    Branch(String),
+    /// Detached at a specific commit
+    Detached(ObjectRef),
+}
+
+impl Head {
+    /// Short branch name, if on a branch
+    pub fn branch_name(&self) -> Option<&str> {
+        match self {
+            Head::Branch(name) => Some(name.strip_prefix("refs/heads/").unwrap_or(name)),
+            Head::Detached(_) => None,
+        }
+    }
+}
+
+/// Validates a reference name using the rules of `git check-ref-format`.
+pub fn check_ref_format(name: &str) -> anyhow::Result<()> {
+    let valid = !name.is_empty()
+        && name != "@"
+        && !name.starts_with('/')
+        && !name.ends_with('/')
+        && !name.ends_with('.')
+        && !name.contains("..")
+        && !name.contains("//")
+        && !name.contains("@{")
+        && !name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+        && name
+            .split('/')
+            .all(|part| !part.starts_with('.') && !part.ends_with(".lock"));
+    anyhow::ensure!(valid, "'{}' is not a valid reference name", name);
+    Ok(())
+}
+
+/// Validates a branch name, which additionally may not start with a dash.
+pub fn check_branch_name(name: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !name.starts_with('-') && name != "HEAD",
+        "'{}' is not a valid branch name",
+        name
+    );
+    check_ref_format(&format!("refs/heads/{name}"))
+        .with_context(|| format!("'{name}' is not a valid branch name"))
+}
+
+/// Whether a reference belongs to a single worktree: `HEAD` and other pseudo-refs, and
+/// those below `refs/worktree`, `refs/bisect` and `refs/rewritten`.
+pub fn is_per_worktree_ref(name: &str) -> bool {
+    !name.starts_with("refs/")
+        || ["refs/worktree/", "refs/bisect/", "refs/rewritten/"]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+}
+
+impl Repository {
+    /// The directory a reference is stored in: the git directory of the worktree or the
+    /// common one. Names like `main-worktree/HEAD` and `worktrees/<name>/HEAD` refer to
+    /// the per-worktree references of other worktrees.
+    fn ref_base(&self, name: &str) -> (PathBuf, String) {
+        if let Some(name) = name.strip_prefix("main-worktree/") {
+            return (self.common_dir.clone(), name.to_owned());
+        }
+        if let Some((worktree, name)) = name
+            .strip_prefix("worktrees/")
+            .and_then(|rest| rest.split_once('/'))
+        {
+            let git_dir = self.common_dir.join("worktrees").join(worktree);
+            return (git_dir, name.to_owned());
+        }
+        match is_per_worktree_ref(name) {
+            true => (self.path.clone(), name.to_owned()),
+            false => (self.common_dir.clone(), name.to_owned()),
+        }
+    }
+
+    fn ref_path(&self, name: &str) -> PathBuf {
+        let (base, name) = self.ref_base(name);
+        base.join(name)
+    }
+
+    /// Where the reflog of a reference is kept
+    pub fn reflog_path(&self, name: &str) -> PathBuf {
+        let (base, name) = self.ref_base(name);
+        base.join("logs").join(name)
+    }
+
+    /// Files below `dir` (`refs` or `logs`) of the worktree's git directory and the common
+    /// one, each only with the references stored there, named with `prefix` in front.
+    pub(crate) fn ref_files(
+        &self,
+        dir: &str,
+        prefix: &str,
+    ) -> anyhow::Result<Vec<(String, PathBuf)>> {
+        let linked = self.path != self.common_dir;
+        let mut files = Vec::new();
+        for (base, per_worktree) in [(&self.common_dir, false), (&self.path, true)] {
+            if per_worktree && !linked {
+                continue;
+            }
+            let root = base.join(dir);
+            let mut pending = vec![root.clone()];
+            while let Some(current) = pending.pop() {
+                let entries = match fs::read_dir(&current) {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                for entry in entries {
+                    let entry = entry?;
+                    if entry.file_type()?.is_dir() {
+                        pending.push(entry.path());
+                        continue;
+                    }
+                    let path = entry.path();
+                    let Some(name) = path.strip_prefix(&root).ok().and_then(|p| p.to_str()) else {
+                        continue;
+                    };
+                    let name = format!("{prefix}{name}");
+                    if !linked || is_per_worktree_ref(&name) == per_worktree {
+                        files.push((name, path));
+                    }
+                }
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Reads `packed-refs` into `(name, hash)` pairs, peeled tag lines are skipped.
+    pub fn packed_refs(&self) -> anyhow::Result<Vec<(String, ObjectRef)>> {
+        let text = match fs::read_to_string(self.common_dir.join("packed-refs")) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut refs = Vec::new();
+        for line in text.lines() {
+            if line.starts_with('#') || line.starts_with('^') || line.is_empty() {
+                continue;
+            }
+            let (hash, name) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow::Error::msg(format!("Invalid packed-refs line '{line}'")))?;
+            refs.push((name.to_owned(), ObjectRef::from_hex(hash)?));
+        }
+        Ok(refs)
+    }
+
+    /// Reads the raw contents of a reference without following symbolic references.
+    /// Returns `Ok(None)` if the reference does not exist.
+    fn read_raw_ref(&self, name: &str) -> anyhow::Result<Option<String>> {
+        let path = self.ref_path(name);
+        // A directory like `refs/heads/feature` for `feature/x` is no reference either
+        match fs::read_to_string(&path) {
+            Ok(contents) => return Ok(Some(contents.trim_end().to_owned())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound || path.is_dir() => {}
+            Err(e) => return Err(e).with_context(|| format!("Trying to read ref {name}")),
+        }
+        Ok(self
+            .packed_refs()?
+            .into_iter()
+            .find(|(packed_name, _)| packed_name == name)
+            .map(|(_, hash)| hash.0))
+    }
+
+    /// Resolves a reference name like `HEAD` or `refs/heads/master` to an object,
+    /// following symbolic references. Returns `Ok(None)` for missing (or unborn) refs.
+    pub fn read_ref(&self, name: &str) -> anyhow::Result<Option<ObjectRef>> {
+        let mut name = name.to_owned();
+        // Same limit of nested symbolic refs as git uses
+        for _ in 0..5 {
+            match self.read_raw_ref(&name)? {
+                None => return Ok(None),
+                Some(contents) => match contents.strip_prefix("ref: ") {
+                    Some(target) => name = target.trim().to_owned(),
+                    None => {
+                        return ObjectRef::from_hex(&contents)
+                            .map(Some)
+                            .with_context(|| format!("Invalid contents of ref {name}"))
+                    }
+                },
+            }
+        }
+        anyhow::bail!("Symbolic reference nesting too deep at {name}")
+    }
+
+    /// Returns the target of a symbolic reference, `None` for direct or missing refs.
+    pub fn read_symbolic_ref(&self, name: &str) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .read_raw_ref(name)?
+            .and_then(|contents| contents.strip_prefix("ref: ").map(|t| t.trim().to_owned())))
+    }
+
+    /// Lists references starting with `prefix` (e.g. `refs/heads/`), sorted by name.
+    /// Symbolic references are included, resolved to the object they point to.
+    pub fn list_refs(&self, prefix: &str) -> anyhow::Result<Vec<(String, ObjectRef)>> {
+        let mut names: Vec<String> = self
+            .packed_refs()?
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| name.starts_with(prefix))
+            .collect();
+
+        for (name, _) in self.ref_files("refs", "refs/")? {
+            if name.starts_with(prefix) && check_ref_format(&name).is_ok() {
+                names.push(name);
+            }
+        }
+        names.sort();
+        names.dedup();
+
+        let mut refs = Vec::with_capacity(names.len());
+        for name in names {
+            // Dangling symbolic refs are skipped
+            if let Some(target) = self.read_ref(&name)? {
+                refs.push((name, target));
+            }
+        }
+        Ok(refs)
+    }
+
+    /// Deletes a reference, both its loose file and its `packed-refs` entry.
+    pub fn delete_ref(&self, name: &str) -> anyhow::Result<()> {
+        check_ref_format(name)?;
+        match fs::remove_file(self.ref_path(name)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Trying to delete ref {name}")),
+        }
+        match fs::remove_file(self.reflog_path(name)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Trying to delete reflog of {name}")),
+        }
+        // Prune directories left empty, but never `refs/heads` and friends themselves
+        let refs_dir = self.ref_base(name).0.join("refs");
+        let mut dir = self.ref_path(name).parent().map(PathBuf::from);
+        while let Some(current) = dir {
+            if current.parent() == Some(&refs_dir) || fs::remove_dir(&current).is_err() {
+                break;
+            }
+            dir = current.parent().map(PathBuf::from);
+        }
+
+        let packed_path = self.common_dir.join("packed-refs");
+        let text = match fs::read_to_string(&packed_path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut kept = String::with_capacity(text.len());
+        let mut skipping = false;
+        for line in text.lines() {
+            if line.starts_with('^') {
+                // Peeled value belongs to the ref above it
+                if !skipping {
+                    kept.push_str(line);
+                    kept.push('\n');
+                }
+                continue;
+            }
+            skipping = line.split_once(' ').map(|(_, n)| n) == Some(name);
+            if !skipping {
+                kept.push_str(line);
+                kept.push('\n');
+            }
+        }
+        if kept != text {
+            self.write_file_atomically(&packed_path, kept.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Renames a reference, moving its reflog along with it and recording the rename there.
+    /// Renaming a reference to its own name leaves it alone.
+    pub fn rename_ref(&self, old: &str, new: &str, message: &str) -> anyhow::Result<()> {
+        if old == new {
+            return Ok(());
+        }
+        let target = self
+            .read_ref(old)?
+            .ok_or_else(|| anyhow::Error::msg(format!("No such ref: {old}")))?;
+        let log = match fs::read(self.reflog_path(old)) {
+            Ok(log) => Some(log),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        // Deleted first, so `a` can become `a/b`
+        self.delete_ref(old)?;
+        self.write_ref(new, &target)?;
+        if let Some(log) = log {
+            let new_log = self.reflog_path(new);
+            if let Some(parent) = new_log.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            self.write_file_atomically(&new_log, &log)?;
+        }
+        self.append_reflog(new, Some(&target), &target, message)
+    }
+
+    pub fn ref_exists(&self, name: &str) -> anyhow::Result<bool> {
+        Ok(self.read_ref(name)?.is_some())
+    }
+
+    pub fn head(&self) -> anyhow::Result<Head> {
+        let contents = self
+            .read_raw_ref("HEAD")?
+            .ok_or_else(|| anyhow::Error::msg("HEAD not found"))?;
+        match contents.strip_prefix("ref: ") {
+            Some(target) => Ok(Head::Branch(target.trim().to_owned())),
+            None => Ok(Head::Detached(
+                ObjectRef::from_hex(&contents).context("Invalid contents of HEAD")?,
+            )),
+        }
+    }
+
+    /// The commit `HEAD` points to, `None` on an unborn branch.
+    pub fn head_commit(&self) -> anyhow::Result<Option<ObjectRef>> {
+        self.read_ref("HEAD")
+    }
+
+    /// Points `HEAD` at a branch or commit, recording the move in the reflog of `HEAD`
+    /// unless it ends up on an unborn branch.
+    pub fn set_head(&self, head: &Head, message: &str) -> anyhow::Result<()> {
+        let old = self.head_commit().ok().flatten();
+        let (contents, new) = match head {
+            Head::Branch(name) => (format!("ref: {name}\n"), self.read_ref(name)?),
+            Head::Detached(hash) => (format!("{}\n", hash.0), Some(hash.clone())),
+        };
+        self.write_file_atomically(&self.path.join("HEAD"), contents.as_bytes())?;
+        match new {
+            Some(new) => self.append_reflog("HEAD", old.as_ref(), &new, message),
+            None => Ok(()),
+        }
+    }
+
+    /// The reference symbolic references starting at `name` end at.
+    fn resolve_symbolic_ref(&self, name: &str) -> anyhow::Result<String> {
+        let mut name = name.to_owned();
+        while let Some(contents) = self.read_raw_ref(&name)? {
+            match contents.strip_prefix("ref: ") {
+                Some(next) => name = next.trim().to_owned(),
+                None => break,
+            }
+        }
+        Ok(name)
+    }
+
+    /// Points a reference at given object without touching reflogs, symbolic references
+    /// are followed.
+    pub(crate) fn write_ref(&self, name: &str, target: &ObjectRef) -> anyhow::Result<()> {
+        let name = self.resolve_symbolic_ref(name)?;
+        if name != "HEAD" {
+            check_ref_format(&name)?;
+        }
+        let path = self.ref_path(&name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        self.write_file_atomically(&path, format!("{}\n", target.0).as_bytes())
+    }
+
+    /// Points a reference at given object, symbolic references are followed. A change is
+    /// recorded with `message` in the reflog of the reference, and in that of `HEAD` when
+    /// it is on the updated branch.
+    pub fn update_ref(&self, name: &str, target: &ObjectRef, message: &str) -> anyhow::Result<()> {
+        let name = self.resolve_symbolic_ref(name)?;
+        let old = self.read_ref(&name)?;
+        self.write_ref(&name, target)?;
+        if old.as_ref() == Some(target) {
+            return Ok(());
+        }
+        self.append_reflog(&name, old.as_ref(), target, message)?;
+        if name != "HEAD" && self.head().ok() == Some(Head::Branch(name.clone())) {
+            self.append_reflog("HEAD", old.as_ref(), target, message)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `<path>.lock` and renames it over `path`, like git does for refs and the index.
+    pub fn write_file_atomically(&self, path: &std::path::Path, data: &[u8]) -> anyhow::Result<()> {
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+            .with_context(|| {
+                format!("Unable to create {lock_path:?}, is another git process running?")
+            })?;
+        let result =
+            std::io::Write::write_all(&mut file, data).and_then(|_| fs::rename(&lock_path, path));
+        if result.is_err() {
+            let _ = fs::remove_file(&lock_path);
+        }
+        result.with_context(|| format!("Trying to write {path:?}"))
+    }
+
+    /// Finds objects whose hash starts with given (at least 4 characters long) prefix, in
+    /// the repository and its alternates.
+    fn find_objects_by_prefix(&self, prefix: &str) -> anyhow::Result<Vec<ObjectRef>> {
+        Ok(self
+            .object_database()?
+            .find_prefix(&prefix.to_ascii_lowercase())?)
+    }
+
+    /// Resolves a name of a reference the way git does, trying `<name>`, `refs/<name>`,
+    /// `refs/tags/<name>`, `refs/heads/<name>`, `refs/remotes/<name>` and
+    /// `refs/remotes/<name>/HEAD` in this order. Returns the full name of the matching ref.
+    pub fn dwim_ref(&self, name: &str) -> anyhow::Result<Option<(String, ObjectRef)>> {
+        if name.is_empty() || check_ref_format(name).is_err() && name != "HEAD" {
+            return Ok(None);
+        }
+        let candidates = [
+            name.to_owned(),
+            format!("refs/{name}"),
+            format!("refs/tags/{name}"),
+            format!("refs/heads/{name}"),
+            format!("refs/remotes/{name}"),
+            format!("refs/remotes/{name}/HEAD"),
+        ];
+        for candidate in candidates {
+            // Only pseudo-refs like HEAD may live directly in the git directory
+            if !candidate.starts_with("refs/")
+                && !candidate
+                    .chars()
+                    .all(|c| c.is_ascii_uppercase() || c == '_')
+            {
+                continue;
+            }
+            if let Some(target) = self.read_ref(&candidate)? {
+                return Ok(Some((candidate, target)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Resolves a revision like `HEAD~2`, `master^2`, `v1.0^{tree}`, `master@{1}`,
+    /// `@{yesterday}` or an abbreviated hash.
+    pub fn resolve_revision(&self, rev: &str) -> crate::Result<ObjectRef> {
+        // Split off the trailing `~<n>`, `^<n>` and `^{<type>}` operators
+        let base_end = rev.find(['~', '^']).unwrap_or(rev.len());
+        let (base, mut operators) = rev.split_at(base_end);
+
+        let mut current = match base {
+            "" => return Err(Error::InvalidRef(rev.to_owned())),
+            "@" => self
+                .head_commit()?
+                .ok_or_else(|| Error::InvalidRef(rev.to_owned()))?,
+            _ => match crate::reflog::split_reflog_revision(base) {
+                Some((name, spec)) => self.resolve_reflog_revision(name, spec, rev)?,
+                None => self.resolve_name(base)?,
+            },
+        };
+
+        while !operators.is_empty() {
+            if !operators.starts_with(['~', '^']) {
+                return Err(Error::InvalidRef(rev.to_owned()));
+            }
+            let op = operators.as_bytes()[0];
+            operators = &operators[1..];
+            if op == b'^' && operators.starts_with('{') {
+                let end = operators
+                    .find('}')
+                    .ok_or_else(|| Error::InvalidRef(rev.to_owned()))?;
+                let kind = &operators[1..end];
+                operators = &operators[end + 1..];
+                current = self.peel(&current, kind)?;
+                continue;
+            }
+            let digits_end = operators
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(operators.len());
+            let (digits, rest) = operators.split_at(digits_end);
+            operators = rest;
+            let n: usize = if digits.is_empty() {
+                1
+            } else {
+                digits
+                    .parse()
+                    .map_err(|_| Error::InvalidRef(rev.to_owned()))?
+            };
+            match op {
+                b'~' => {
+                    for _ in 0..n {
+                        current = self.nth_parent(&current, 1, rev)?;
+                    }
+                }
+                b'^' if n == 0 => current = self.peel(&current, "commit")?,
+                b'^' => current = self.nth_parent(&current, n, rev)?,
+                _ => unreachable!("Operators start with '~' or '^'"),
+            }
+        }
+        Ok(current)
+    }
+
+    fn resolve_name(&self, name: &str) -> crate::Result<ObjectRef> {
+        let hex_len = self.object_format()?.hex_len();
+        if name.len() == hex_len && name.chars().all(|c| c.is_ascii_hexdigit()) {
+            return ObjectRef::from_hex(&name.to_ascii_lowercase());
+        }
+        if let Some((_, target)) = self.dwim_ref(name)? {
+            return Ok(target);
+        }
+        if name.len() >= 4 && name.len() < hex_len && name.chars().all(|c| c.is_ascii_hexdigit()) {
+            let mut found = self.find_objects_by_prefix(name)?;
+            if found.len() > 1 {
+                return Err(Error::Ambiguous(name.to_owned()));
+            }
+            if let Some(found) = found.pop() {
+                return Ok(found);
+            }
+        }
+        Err(Error::InvalidRef(name.to_owned()))
+    }
+
+    fn nth_parent(&self, object: &ObjectRef, n: usize, rev: &str) -> crate::Result<ObjectRef> {
+        let commit = self.peel(object, "commit")?;
+        let Object::Commit(commit) = self.find_object(&commit)? else {
+            unreachable!("Peeled to a commit");
+        };
+        let parent = commit
+            .data()
+            .parent_hashes
+            .get(n - 1)
+            .map(|p| p.to_string())
+            .ok_or_else(|| Error::InvalidRef(rev.to_owned()))?;
+        ObjectRef::from_hex(&parent)
+    }
+
+    /// Dereferences an object until it has given type, `^{<type>}` style. An empty type
+    /// follows tags until an object that is not a tag is reached, like `^{}`.
+    pub fn peel(&self, object: &ObjectRef, kind: &str) -> crate::Result<ObjectRef> {
+        let obj = self.find_object(object)?;
+        // `^{}` follows tags until something else is reached
+        let done = match kind {
+            "" => obj.kind() != "tag",
+            "object" => true,
+            kind => obj.kind() == kind,
+        };
+        if done {
+            return Ok(object.clone());
+        }
+        match (obj, kind) {
+            (Object::Commit(commit), "tree") => ObjectRef::from_hex(&commit.data().tree_hash),
+            (Object::Unknown { kind: tag, data }, _) if tag == "tag" => {
+                let target = std::str::from_utf8(&data)
+                    .ok()
+                    .and_then(|data| data.lines().next())
+                    .and_then(|line| line.strip_prefix("object "))
+                    .ok_or_else(|| Error::Corrupt {
+                        object: object.clone(),
+                        reason: "malformed tag".to_owned(),
+                    })?;
+                self.peel(&ObjectRef::from_hex(target)?, kind)
+            }
+            (obj, _) => Err(object.wrong_type(obj.kind(), kind)),
+        }
+    }
+}
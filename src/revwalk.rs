@@ -0,0 +1,76 @@
+//! Walking the commit graph.
+
+use crate::{ObjectRef, Repository};
+use std::collections::{HashSet, VecDeque};
+
+impl Repository {
+    pub fn commit_parents(&self, commit: &ObjectRef) -> crate::Result<Vec<ObjectRef>> {
+        self.read_commit(commit)?
+            .data()
+            .parent_hashes
+            .iter()
+            .map(|parent| ObjectRef::from_hex(parent))
+            .collect()
+    }
+
+    /// Checks whether `ancestor` is reachable from `descendant` (a commit is its own ancestor).
+    pub fn is_ancestor(
+        &self,
+        ancestor: &ObjectRef,
+        descendant: &ObjectRef,
+    ) -> anyhow::Result<bool> {
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::from([descendant.clone()]);
+        while let Some(commit) = queue.pop_front() {
+            if commit == *ancestor {
+                return Ok(true);
+            }
+            if seen.insert(commit.clone()) {
+                queue.extend(self.commit_parents(&commit)?);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Finds the best common ancestors of two commits, that is common ancestors which are
+    /// not reachable from any other common ancestor. Criss-cross histories have several.
+    pub fn merge_bases(&self, one: &ObjectRef, two: &ObjectRef) -> anyhow::Result<Vec<ObjectRef>> {
+        let mut ancestors = HashSet::new();
+        let mut queue = VecDeque::from([one.clone()]);
+        while let Some(commit) = queue.pop_front() {
+            if ancestors.insert(commit.clone()) {
+                queue.extend(self.commit_parents(&commit)?);
+            }
+        }
+
+        // Walk down from the other side, stopping at the first common commits
+        let mut candidates = Vec::new();
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::from([two.clone()]);
+        while let Some(commit) = queue.pop_front() {
+            if !seen.insert(commit.clone()) {
+                continue;
+            }
+            if ancestors.contains(&commit) {
+                candidates.push(commit);
+            } else {
+                queue.extend(self.commit_parents(&commit)?);
+            }
+        }
+
+        let mut bases = Vec::new();
+        for candidate in candidates.iter() {
+            let mut redundant = false;
+            for other in candidates.iter().filter(|other| *other != candidate) {
+                if self.is_ancestor(candidate, other)? {
+                    redundant = true;
+                    break;
+                }
+            }
+            if !redundant {
+                bases.push(candidate.clone());
+            }
+        }
+        Ok(bases)
+    }
+}
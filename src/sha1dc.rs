@@ -0,0 +1,602 @@
+//! SHA-1 with collision detection, after Marc Stevens' counter-cryptanalysis as used by git.
+//!
+//! Every known practical collision attack on SHA-1 builds a near-collision from one of a
+//! few disturbance vectors. For each block the compression is redone with the message
+//! difference of every such vector applied, going back from an intermediate state to find
+//! the chaining value the other block of a pair would have needed. If that other block
+//! ends in the same chaining value, the block is one half of a collision.
+//!
+//! A near-collision block on a vector has to meet the vector's unavoidable bit conditions on
+//! the message, so like upstream only the vectors whose conditions hold are tried.
+
+const IV: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+/// Steps before which the state is kept for recompression
+const TEST_STEPS: [usize; 2] = [58, 65];
+
+struct DisturbanceVector {
+    /// Step the recompression starts from, one of `TEST_STEPS`
+    test_step: usize,
+    /// Message difference of a block built on this vector
+    message_difference: [u32; 80],
+}
+
+/// Message difference of disturbance vector I(K,b) or II(K,b). The vector is the message
+/// expansion of 16 words that are zero except for a few bits; each disturbance is
+/// corrected by a local collision over the next five steps.
+const fn message_difference(type_two: bool, k: usize, b: u32) -> [u32; 80] {
+    // Indices are shifted by 5, so that the corrections at the start can look back
+    let mut dv = [0u32; 85];
+    dv[k + 15 + 5] = 1 << b;
+    if type_two {
+        dv[k + 1 + 5] = 1 << ((b + 31) % 32);
+        dv[k + 3 + 5] = 1 << ((b + 31) % 32);
+    }
+    let mut t = k + 16;
+    while t < 80 {
+        dv[t + 5] = (dv[t + 2] ^ dv[t - 3] ^ dv[t - 9] ^ dv[t - 11]).rotate_left(1);
+        t += 1;
+    }
+    let mut t = k + 5;
+    while t > 0 {
+        t -= 1;
+        dv[t] = dv[t + 16].rotate_right(1) ^ dv[t + 13] ^ dv[t + 8] ^ dv[t + 2];
+    }
+    let mut dm = [0u32; 80];
+    let mut t = 0;
+    while t < 80 {
+        dm[t] = dv[t + 5]
+            ^ dv[t + 4].rotate_left(5)
+            ^ dv[t + 3]
+            ^ dv[t + 2].rotate_left(30)
+            ^ dv[t + 1].rotate_left(30)
+            ^ dv[t].rotate_left(30);
+        t += 1;
+    }
+    dm
+}
+
+const fn dv(type_two: bool, k: usize, b: u32, test_step: usize) -> DisturbanceVector {
+    DisturbanceVector {
+        test_step,
+        message_difference: message_difference(type_two, k, b),
+    }
+}
+
+/// The vectors checked by upstream sha1dc
+const DISTURBANCE_VECTORS: [DisturbanceVector; 32] = [
+    dv(false, 43, 0, 58),
+    dv(false, 44, 0, 58),
+    dv(false, 45, 0, 58),
+    dv(false, 46, 0, 58),
+    dv(false, 46, 2, 58),
+    dv(false, 47, 0, 58),
+    dv(false, 47, 2, 58),
+    dv(false, 48, 0, 58),
+    dv(false, 48, 2, 58),
+    dv(false, 49, 0, 58),
+    dv(false, 49, 2, 58),
+    dv(false, 50, 0, 65),
+    dv(false, 50, 2, 65),
+    dv(false, 51, 0, 65),
+    dv(false, 51, 2, 65),
+    dv(false, 52, 0, 65),
+    dv(true, 45, 0, 58),
+    dv(true, 46, 0, 58),
+    dv(true, 46, 2, 58),
+    dv(true, 47, 0, 58),
+    dv(true, 48, 0, 58),
+    dv(true, 49, 0, 58),
+    dv(true, 49, 2, 58),
+    dv(true, 50, 0, 65),
+    dv(true, 50, 2, 65),
+    dv(true, 51, 0, 65),
+    dv(true, 51, 2, 65),
+    dv(true, 52, 0, 65),
+    dv(true, 53, 0, 65),
+    dv(true, 54, 0, 65),
+    dv(true, 55, 0, 65),
+    dv(true, 56, 0, 65),
+];
+
+/// Two message bits whose xor has to be `xor` for a block built on one of `vectors`, which
+/// has a bit set for each index into `DISTURBANCE_VECTORS`
+struct BitCondition {
+    word: usize,
+    bit: u32,
+    other_word: usize,
+    other_bit: u32,
+    xor: u32,
+    vectors: u32,
+}
+
+const fn condition(
+    word: usize,
+    bit: u32,
+    other_word: usize,
+    other_bit: u32,
+    xor: u32,
+    vectors: u32,
+) -> BitCondition {
+    BitCondition {
+        word,
+        bit,
+        other_word,
+        other_bit,
+        xor,
+        vectors,
+    }
+}
+
+/// The unavoidable bit conditions checked by upstream sha1dc, on the expanded message
+const UNAVOIDABLE_CONDITIONS: [BitCondition; 157] = [
+    condition(35, 1, 36, 6, 1, 0x00000410),
+    condition(35, 3, 39, 28, 0, 0x00082000),
+    condition(35, 4, 39, 29, 0, 0x00080084),
+    condition(35, 5, 39, 30, 0, 0x00004000),
+    condition(35, 30, 36, 3, 1, 0x00100000),
+    condition(35, 30, 40, 28, 1, 0x00100000),
+    condition(36, 0, 37, 5, 1, 0x00400000),
+    condition(36, 0, 41, 30, 1, 0x00400000),
+    condition(36, 1, 37, 6, 1, 0x00041040),
+    condition(36, 4, 38, 4, 1, 0x28000000),
+    condition(36, 4, 40, 29, 0, 0x00110208),
+    condition(36, 4, 41, 29, 1, 0x20000800),
+    condition(36, 30, 37, 3, 1, 0x00200000),
+    condition(36, 30, 41, 28, 1, 0x00200000),
+    condition(37, 0, 38, 5, 1, 0x01000000),
+    condition(37, 0, 42, 30, 1, 0x01000000),
+    condition(37, 1, 37, 6, 0, 0x00004000),
+    condition(37, 1, 38, 6, 1, 0x00004100),
+    condition(37, 4, 39, 4, 1, 0x50000001),
+    condition(37, 4, 40, 29, 0, 0x50020021),
+    condition(37, 4, 41, 29, 0, 0x00220820),
+    condition(37, 4, 42, 29, 1, 0x40002001),
+    condition(37, 30, 38, 3, 1, 0x00800000),
+    condition(37, 30, 42, 28, 1, 0x00800000),
+    condition(38, 0, 39, 5, 1, 0x04000000),
+    condition(38, 0, 43, 30, 1, 0x04000000),
+    condition(38, 1, 39, 6, 1, 0x00000400),
+    condition(38, 1, 40, 1, 1, 0x00000400),
+    condition(38, 4, 40, 4, 1, 0xa0000002),
+    condition(38, 4, 41, 29, 0, 0xa0080082),
+    condition(38, 4, 42, 29, 0, 0x00882080),
+    condition(38, 4, 43, 29, 1, 0x80008002),
+    condition(38, 30, 39, 3, 1, 0x02000000),
+    condition(38, 30, 43, 28, 1, 0x02000000),
+    condition(39, 1, 40, 6, 1, 0x00401010),
+    condition(39, 1, 41, 1, 1, 0x00401000),
+    condition(39, 1, 42, 6, 1, 0x00000010),
+    condition(39, 4, 41, 4, 1, 0x40000005),
+    condition(39, 4, 42, 29, 0, 0x40100205),
+    condition(39, 4, 43, 29, 0, 0x02108200),
+    condition(39, 30, 40, 3, 1, 0x08000000),
+    condition(39, 30, 44, 28, 1, 0x08000000),
+    condition(40, 1, 41, 6, 1, 0x01004040),
+    condition(40, 1, 42, 1, 1, 0x01004000),
+    condition(40, 1, 43, 6, 1, 0x00000040),
+    condition(40, 4, 42, 4, 1, 0x8000000a),
+    condition(40, 4, 43, 29, 0, 0x8020080a),
+    condition(40, 4, 44, 29, 0, 0x08200800),
+    condition(40, 29, 41, 29, 0, 0x800a00a2),
+    condition(41, 1, 42, 6, 1, 0x04040100),
+    condition(41, 1, 43, 1, 1, 0x04040000),
+    condition(41, 1, 49, 1, 1, 0x00000100),
+    condition(41, 3, 45, 28, 0, 0x10000000),
+    condition(41, 4, 43, 4, 1, 0x00000025),
+    condition(41, 4, 44, 29, 0, 0x00812025),
+    condition(41, 4, 45, 29, 0, 0x10812000),
+    condition(41, 29, 42, 29, 0, 0x00180284),
+    condition(42, 1, 43, 6, 1, 0x00000400),
+    condition(42, 1, 50, 1, 1, 0x00000400),
+    condition(42, 3, 46, 28, 0, 0x20000000),
+    condition(42, 4, 44, 4, 1, 0x0000008a),
+    condition(42, 4, 45, 29, 0, 0x0202808a),
+    condition(42, 4, 46, 29, 0, 0x22028000),
+    condition(42, 6, 44, 6, 0, 0x00000110),
+    condition(42, 29, 43, 29, 0, 0x00300a08),
+    condition(43, 1, 44, 6, 1, 0x00001000),
+    condition(43, 3, 47, 28, 0, 0x40000000),
+    condition(43, 4, 45, 4, 1, 0x00000224),
+    condition(43, 4, 46, 29, 0, 0x08080225),
+    condition(43, 4, 47, 29, 0, 0x48080001),
+    condition(43, 6, 45, 6, 0, 0x00000440),
+    condition(43, 29, 44, 29, 0, 0x00a12820),
+    condition(44, 1, 45, 6, 1, 0x00404000),
+    condition(44, 1, 46, 1, 1, 0x00400000),
+    condition(44, 1, 51, 6, 1, 0x00004000),
+    condition(44, 1, 52, 1, 1, 0x00004000),
+    condition(44, 3, 48, 28, 0, 0x80000000),
+    condition(44, 4, 46, 4, 1, 0x00000888),
+    condition(44, 4, 47, 29, 0, 0x1010088a),
+    condition(44, 4, 48, 29, 0, 0x90100002),
+    condition(44, 6, 46, 6, 0, 0x00001110),
+    condition(44, 6, 48, 6, 0, 0x00001100),
+    condition(44, 29, 45, 29, 0, 0x0283a080),
+    condition(45, 1, 46, 6, 1, 0x01000000),
+    condition(45, 4, 47, 4, 1, 0x00002220),
+    condition(45, 4, 48, 29, 0, 0x20202224),
+    condition(45, 6, 47, 6, 0, 0x00004440),
+    condition(45, 6, 49, 6, 0, 0x00004400),
+    condition(45, 29, 46, 29, 0, 0x0a0a8200),
+    condition(46, 1, 47, 6, 1, 0x04000000),
+    condition(46, 4, 48, 4, 1, 0x00008880),
+    condition(46, 4, 49, 29, 0, 0x40808888),
+    condition(46, 6, 47, 1, 0, 0x01000010),
+    condition(46, 29, 47, 29, 0, 0x18180801),
+    condition(47, 1, 48, 6, 1, 0x00040000),
+    condition(47, 4, 49, 4, 1, 0x00012200),
+    condition(47, 4, 50, 29, 0, 0x82012220),
+    condition(47, 6, 48, 1, 0, 0x04000040),
+    condition(47, 29, 48, 29, 0, 0x30302002),
+    condition(48, 4, 50, 4, 1, 0x00028800),
+    condition(48, 4, 51, 29, 0, 0x08028880),
+    condition(48, 6, 50, 6, 0, 0x00041000),
+    condition(48, 6, 51, 1, 0, 0x00041000),
+    condition(48, 29, 49, 29, 0, 0x60a08004),
+    condition(49, 4, 52, 29, 0, 0x10092200),
+    condition(49, 29, 50, 29, 0, 0xc2810008),
+    condition(50, 1, 51, 6, 1, 0x00400000),
+    condition(50, 1, 53, 6, 1, 0x00400000),
+    condition(50, 1, 54, 1, 1, 0x00400000),
+    condition(50, 4, 53, 29, 0, 0x20128800),
+    condition(50, 29, 51, 29, 0, 0x8a020020),
+    condition(51, 1, 52, 6, 1, 0x01000000),
+    condition(51, 1, 54, 6, 1, 0x01000000),
+    condition(51, 1, 55, 1, 1, 0x01000000),
+    condition(51, 4, 54, 29, 0, 0x40282000),
+    condition(51, 29, 52, 29, 0, 0x18080080),
+    condition(52, 1, 53, 6, 1, 0x04000000),
+    condition(52, 1, 55, 6, 1, 0x04000000),
+    condition(52, 1, 56, 1, 1, 0x04000000),
+    condition(52, 4, 55, 29, 0, 0x80908000),
+    condition(52, 29, 53, 29, 0, 0x30110200),
+    condition(52, 29, 55, 29, 1, 0x00182000),
+    condition(53, 4, 56, 29, 0, 0x02200000),
+    condition(53, 29, 54, 29, 0, 0x60220800),
+    condition(53, 29, 56, 29, 1, 0x00308000),
+    condition(54, 4, 57, 29, 0, 0x08800000),
+    condition(54, 4, 60, 29, 1, 0x08000000),
+    condition(54, 29, 55, 29, 0, 0xc0882000),
+    condition(54, 29, 57, 29, 1, 0x00a00000),
+    condition(55, 4, 57, 4, 1, 0x10000000),
+    condition(55, 4, 58, 29, 0, 0x12000000),
+    condition(55, 4, 61, 29, 1, 0x10000000),
+    condition(55, 29, 56, 29, 0, 0x82108000),
+    condition(55, 29, 58, 29, 1, 0x02800000),
+    condition(56, 4, 59, 29, 0, 0x28000000),
+    condition(56, 29, 57, 29, 0, 0x08200000),
+    condition(56, 29, 59, 29, 1, 0x0a000000),
+    condition(57, 4, 59, 29, 0, 0x40000000),
+    condition(57, 29, 58, 29, 0, 0x10800000),
+    condition(58, 0, 59, 5, 1, 0x00000001),
+    condition(58, 0, 63, 30, 1, 0x00000001),
+    condition(58, 4, 62, 29, 0, 0x20000000),
+    condition(58, 29, 59, 29, 0, 0x22000000),
+    condition(59, 0, 60, 5, 1, 0x00000002),
+    condition(59, 0, 64, 30, 1, 0x00000002),
+    condition(59, 4, 63, 29, 0, 0x40000000),
+    condition(60, 0, 61, 5, 1, 0x00010004),
+    condition(60, 4, 64, 29, 0, 0x80000000),
+    condition(61, 0, 62, 5, 1, 0x00020008),
+    condition(61, 1, 62, 6, 1, 0x00000001),
+    condition(61, 2, 62, 7, 1, 0x00040010),
+    condition(62, 0, 63, 5, 1, 0x00080020),
+    condition(62, 1, 63, 6, 1, 0x00000002),
+    condition(62, 2, 63, 7, 1, 0x00000040),
+    condition(63, 0, 64, 5, 1, 0x00100080),
+    condition(63, 1, 64, 6, 1, 0x00010004),
+    condition(63, 2, 64, 7, 1, 0x00000100),
+];
+
+/// Bit mask of the disturbance vectors whose unavoidable bit conditions the block meets.
+fn possible_vectors(w: &[u32; 80]) -> u32 {
+    let mut mask = u32::MAX;
+    // Most blocks rule out every vector early
+    for conditions in UNAVOIDABLE_CONDITIONS.chunks(16) {
+        for condition in conditions {
+            let bits = (w[condition.word] >> condition.bit)
+                ^ (w[condition.other_word] >> condition.other_bit);
+            // All ones if the condition fails, without a branch that could not be predicted
+            let failed = ((bits ^ condition.xor) & 1).wrapping_neg();
+            mask &= !(condition.vectors & failed);
+        }
+        if mask == 0 {
+            break;
+        }
+    }
+    mask
+}
+
+/// Boolean function and constant of steps `20 * round..20 * round + 20`
+#[inline(always)]
+fn round_function(round: usize, b: u32, c: u32, d: u32) -> u32 {
+    match round {
+        0 => ((b & c) | (!b & d)).wrapping_add(0x5a827999),
+        1 => (b ^ c ^ d).wrapping_add(0x6ed9eba1),
+        2 => ((b & c) | (b & d) | (c & d)).wrapping_add(0x8f1bbcdc),
+        _ => (b ^ c ^ d).wrapping_add(0xca62c1d6),
+    }
+}
+
+type State = [u32; 5];
+
+/// Runs steps `from..to` forward.
+fn steps(mut state: State, w: &[u32; 80], from: usize, to: usize) -> State {
+    for round in 0..4 {
+        // Rounds are separate loops, so the round function is known in each
+        let words = w.get(from.max(round * 20)..to.min(round * 20 + 20));
+        for word in words.unwrap_or_default() {
+            let [a, b, c, d, e] = state;
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(round_function(round, b, c, d))
+                .wrapping_add(e)
+                .wrapping_add(*word);
+            state = [temp, a, b.rotate_left(30), c, d];
+        }
+    }
+    state
+}
+
+/// Undoes steps `0..to`, given the state after them.
+fn steps_back(mut state: State, w: &[u32; 80], to: usize) -> State {
+    for round in (0..4).rev() {
+        for t in (round * 20..to.min(round * 20 + 20)).rev() {
+            let [a, b, c, d, e] = state;
+            let (prev_a, prev_b) = (b, c.rotate_right(30));
+            let prev_e = a
+                .wrapping_sub(prev_a.rotate_left(5))
+                .wrapping_sub(round_function(round, prev_b, d, e))
+                .wrapping_sub(w[t]);
+            state = [prev_a, prev_b, d, e, prev_e];
+        }
+    }
+    state
+}
+
+fn add(ihv: State, state: State) -> State {
+    let mut out = ihv;
+    for (out, word) in out.iter_mut().zip(state) {
+        *out = out.wrapping_add(word);
+    }
+    out
+}
+
+fn expand(block: &[u8; 64]) -> [u32; 80] {
+    let mut w = [0u32; 80];
+    for (i, chunk) in block.chunks(4).enumerate() {
+        w[i] = u32::from_be_bytes(chunk.try_into().unwrap());
+    }
+    for t in 16..80 {
+        w[t] = (w[t - 3] ^ w[t - 8] ^ w[t - 14] ^ w[t - 16]).rotate_left(1);
+    }
+    w
+}
+
+fn compress(ihv: State, w: &[u32; 80]) -> State {
+    add(ihv, steps(ihv, w, 0, 80))
+}
+
+#[derive(Clone)]
+pub struct Sha1 {
+    ihv: State,
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+    collision: bool,
+}
+
+impl Sha1 {
+    pub fn new() -> Sha1 {
+        Sha1 {
+            ihv: IV,
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+            collision: false,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        while !data.is_empty() {
+            let take = (64 - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&data[..take]);
+            self.block_len += take;
+            data = &data[take..];
+            if self.block_len == 64 {
+                self.process();
+                self.block_len = 0;
+            }
+        }
+    }
+
+    /// Returns the digest and whether the data contains half of a collision. Like upstream,
+    /// the digest of such data is a different, safe one that its colliding twin does not
+    /// share.
+    pub fn finalize(mut self) -> ([u8; 20], bool) {
+        let bit_len = self.total_len.wrapping_mul(8);
+        self.block[self.block_len] = 0x80;
+        self.block[self.block_len + 1..].fill(0);
+        if self.block_len >= 56 {
+            self.process();
+            self.block.fill(0);
+        }
+        self.block[56..].copy_from_slice(&bit_len.to_be_bytes());
+        self.process();
+        let mut digest = [0u8; 20];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.ihv) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        (digest, self.collision)
+    }
+
+    fn process(&mut self) {
+        let w = expand(&self.block);
+        let ihv_in = self.ihv;
+        let mut states = [ihv_in; TEST_STEPS.len()];
+        let (mut state, mut from) = (ihv_in, 0);
+        for (saved, test_step) in states.iter_mut().zip(TEST_STEPS) {
+            state = steps(state, &w, from, test_step);
+            (*saved, from) = (state, test_step);
+        }
+        self.ihv = add(ihv_in, steps(state, &w, from, 80));
+
+        let possible = possible_vectors(&w);
+        for (i, dv) in DISTURBANCE_VECTORS.iter().enumerate() {
+            if possible & (1 << i) == 0 {
+                continue;
+            }
+            let mut other = w;
+            for (word, difference) in other.iter_mut().zip(dv.message_difference) {
+                *word ^= difference;
+            }
+            let saved = states[TEST_STEPS.iter().position(|s| *s == dv.test_step).unwrap()];
+            let other_ihv = steps_back(saved, &other, dv.test_step);
+            let end = steps(saved, &other, dv.test_step, 80);
+            if add(other_ihv, end) == self.ihv {
+                self.collision = true;
+                // Two more compressions make the digest differ from the colliding one
+                self.ihv = compress(compress(self.ihv, &w), &w);
+                break;
+            }
+        }
+    }
+}
+
+impl Default for Sha1 {
+    fn default() -> Sha1 {
+        Sha1::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hash::ObjectFormat, Error};
+    use sha1::Digest;
+
+    /// The first 320 bytes of `shattered-1.pdf`, up to the end of its near-collision blocks
+    const SHATTERED_PREFIX: &str = concat!(
+        "255044462d312e330a25e2e3cfd30a0a0a312030206f626a0a3c3c2f57696474",
+        "682032203020522f4865696768742033203020522f547970652034203020522f",
+        "537562747970652035203020522f46696c7465722036203020522f436f6c6f72",
+        "53706163652037203020522f4c656e6774682038203020522f42697473506572",
+        "436f6d706f6e656e7420383e3e0a73747265616d0affd8fffe00245348412d31",
+        "20697320646561642121212121852fec092339759c39b1a1c63c4c97e1fffe01",
+        "7f46dc93a6b67e013b029aaa1db2560b45ca67d688c7f84b8c4c791fe02b3df6",
+        "14f86db1690901c56b45c1530afedfb76038e972722fe7ad728f0e4904e046c2",
+        "30570fe9d41398abe12ef5bc942be33542a4802d98b5d70f2a332ec37fac3514",
+        "e74ddc0f2cc1a874cd0c78305a21566461309789606bd0bf3f98cda8044629a1",
+    );
+
+    fn sha1_hex(data: &[u8]) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+        let (digest, collision) = hasher.finalize();
+        assert!(!collision);
+        hex::encode(digest)
+    }
+
+    #[test]
+    fn known_answers() {
+        // FIPS 180-4 examples, the last one two blocks long after padding
+        assert_eq!(sha1_hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(
+            sha1_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+    }
+
+    #[test]
+    fn split_updates() {
+        let mut hasher = Sha1::new();
+        for _ in 0..1000 {
+            hasher.update(&[b'a'; 1000]);
+        }
+        let (digest, collision) = hasher.finalize();
+        assert!(!collision);
+        assert_eq!(
+            hex::encode(digest),
+            "34aa973cd4c4daa4f61eeb2bdbad27316534016f"
+        );
+    }
+
+    #[test]
+    fn message_differences_match_upstream() {
+        // Leading words of upstream's tables for I(43,0), I(46,2), II(45,0) and II(56,0)
+        let expected: [(usize, [u32; 4]); 4] = [
+            (0, [0x08000000, 0x9800000c, 0xd8000010, 0x08000010]),
+            (4, [0xb0000040, 0xd0000053, 0xd0000022, 0x20000000]),
+            (16, [0xec000014, 0x0c000002, 0xc0000010, 0xb400001c]),
+            (31, [0x2600001a, 0x00000010, 0x0400001c, 0xcc000014]),
+        ];
+        for (i, words) in expected {
+            assert_eq!(DISTURBANCE_VECTORS[i].message_difference[..4], words);
+        }
+        // The other block of a pair is an expanded message too
+        for dv in DISTURBANCE_VECTORS.iter() {
+            let dm = dv.message_difference;
+            for t in 16..80 {
+                assert_eq!(
+                    dm[t],
+                    (dm[t - 3] ^ dm[t - 8] ^ dm[t - 14] ^ dm[t - 16]).rotate_left(1)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn steps_back_undoes_steps() {
+        let mut block = [0u8; 64];
+        for (i, byte) in block.iter_mut().enumerate() {
+            *byte = (i * 37 + 11) as u8;
+        }
+        let w = expand(&block);
+        for test_step in TEST_STEPS {
+            let state = steps(IV, &w, 0, test_step);
+            assert_eq!(steps_back(state, &w, test_step), IV);
+        }
+    }
+
+    #[test]
+    fn detects_shattered() {
+        let prefix = hex::decode(SHATTERED_PREFIX).unwrap();
+        // The other PDF differs in the two near-collision blocks by the message difference
+        // of II(52,0)
+        let mut twin = prefix.clone();
+        let dm = DISTURBANCE_VECTORS[27].message_difference;
+        for block in twin[192..].chunks_mut(64) {
+            for (word, difference) in block.chunks_mut(4).zip(dm) {
+                let value = u32::from_be_bytes(word.try_into().unwrap()) ^ difference;
+                word.copy_from_slice(&value.to_be_bytes());
+            }
+        }
+        let colliding = "f92d74e3874587aaf443d1db961d4e26dde13e9c";
+        assert_eq!(hex::encode(sha1::Sha1::digest(&prefix)), colliding);
+        assert_eq!(hex::encode(sha1::Sha1::digest(&twin)), colliding);
+
+        for data in [&prefix, &twin] {
+            let mut hasher = Sha1::new();
+            hasher.update(data);
+            let (digest, collision) = hasher.finalize();
+            assert!(collision);
+            assert_ne!(hex::encode(digest), colliding);
+        }
+        // Only the second block completes the collision
+        for len in [192, 256] {
+            let mut hasher = Sha1::new();
+            hasher.update(&prefix[..len]);
+            assert!(!hasher.finalize().1);
+        }
+
+        let mut hasher = ObjectFormat::Sha1.hasher();
+        hasher.update(&prefix);
+        assert!(matches!(hasher.finalize(), Err(Error::Collision(_))));
+        let mut hasher = ObjectFormat::Sha1.hasher();
+        hasher.update(&prefix[..256]);
+        assert!(hasher.finalize().is_ok());
+    }
+}
@@ -0,0 +1,185 @@
+//! Reading and writing objects in chunks, so large blobs never have to fit in memory.
+
+use crate::{
+    hash::{ObjectFormat, RawHash},
+    odb::ObjectDatabase,
+    Object, ObjectRef, Repository, Result,
+};
+use std::{
+    fs,
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    os::fd::AsFd,
+    path::Path,
+};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// An object whose contents are inflated while they are read.
+pub struct ObjectStream {
+    pub kind: String,
+    pub size: usize,
+    reader: Box<dyn Read>,
+    remaining: usize,
+}
+
+impl ObjectStream {
+    pub fn new(kind: String, size: usize, reader: Box<dyn Read>) -> ObjectStream {
+        ObjectStream {
+            kind,
+            size,
+            reader,
+            remaining: size,
+        }
+    }
+
+    /// Reads the remaining contents into memory.
+    pub fn into_bytes(mut self) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.remaining);
+        self.read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl Read for ObjectStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let len = buf.len().min(self.remaining);
+        let read = self.reader.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Object is shorter than its header says",
+            ));
+        }
+        self.remaining -= read;
+        Ok(read)
+    }
+}
+
+/// Opens a loose object, inflating only its `<type> <size>` header up front.
+pub fn open_loose(path: &Path) -> anyhow::Result<ObjectStream> {
+    let file = fs::File::open(path)?;
+    let mut decoder = flate2::read::ZlibDecoder::new(BufReader::new(file));
+    let mut header = Vec::new();
+    let mut byte = [0u8];
+    loop {
+        decoder.read_exact(&mut byte).map_err(|_| {
+            anyhow::Error::msg("Invalid object file data, could not find header terminator.")
+        })?;
+        match byte[0] {
+            0 => break,
+            _ if header.len() >= 64 => anyhow::bail!("Object file header is too long"),
+            b => header.push(b),
+        }
+    }
+    let header = std::str::from_utf8(&header)?;
+    let (object_type, object_size) = header.split_once(' ').ok_or_else(|| {
+        anyhow::Error::msg(format!(
+            "Invalid object file header format, expected '<type> <size>', found '{}'",
+            header
+        ))
+    })?;
+    Ok(ObjectStream::new(
+        object_type.to_owned(),
+        object_size.parse()?,
+        Box::new(decoder),
+    ))
+}
+
+/// Copies `size` bytes from `reader` into the hasher and `out` in chunks, returns the hash
+/// of the object.
+pub(crate) fn copy_object(
+    format: ObjectFormat,
+    kind: &str,
+    size: u64,
+    reader: &mut impl Read,
+    out: &mut impl Write,
+) -> anyhow::Result<RawHash> {
+    let header = format!("{kind} {size}\0");
+    let mut hasher = format.hasher();
+    hasher.update(header.as_bytes());
+    out.write_all(header.as_bytes())?;
+
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut total = 0u64;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        total += read as u64;
+        anyhow::ensure!(total <= size, "Object data is longer than expected");
+        hasher.update(&buffer[..read]);
+        out.write_all(&buffer[..read])?;
+    }
+    anyhow::ensure!(total == size, "Object data is shorter than expected");
+    Ok(hasher.finalize()?)
+}
+
+/// Hashes an object of `size` bytes read from `reader`.
+pub fn hash_stream(
+    format: ObjectFormat,
+    kind: &str,
+    size: u64,
+    reader: &mut impl Read,
+) -> anyhow::Result<RawHash> {
+    copy_object(format, kind, size, reader, &mut std::io::sink())
+}
+
+/// Makes standard input readable as a stream of known size, which objects need for their
+/// header. A redirected file is read in place, anything else like a pipe is copied into an
+/// unlinked temporary file in `temp_dir` first. Returns the file to read and its size.
+pub fn stdin_with_size(temp_dir: &Path) -> anyhow::Result<(fs::File, u64)> {
+    let stdin = std::io::stdin();
+    let mut file = fs::File::from(stdin.as_fd().try_clone_to_owned()?);
+    let metadata = file.metadata()?;
+    if metadata.is_file() {
+        let position = file.stream_position()?;
+        return Ok((file, metadata.len().saturating_sub(position)));
+    }
+    let temp_path = temp_dir.join(format!("tmp_stdin_{}", std::process::id()));
+    let mut spool = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&temp_path)?;
+    // The open file stays readable, and nothing is left behind if anything goes wrong
+    fs::remove_file(&temp_path)?;
+    let size = std::io::copy(&mut stdin.lock(), &mut spool)?;
+    spool.seek(SeekFrom::Start(0))?;
+    Ok((spool, size))
+}
+
+impl Repository {
+    /// Opens an object for reading. Only deltified objects are inflated completely up front.
+    pub fn open_object(&self, object_ref: &ObjectRef) -> Result<Option<ObjectStream>> {
+        self.object_database()?.open(object_ref)
+    }
+
+    /// Writes an object of `size` bytes read from `reader`, hashing and deflating it in
+    /// chunks. Nothing is written if the object already exists.
+    pub fn write_object_stream(
+        &self,
+        kind: &str,
+        size: u64,
+        reader: &mut impl Read,
+    ) -> Result<ObjectRef> {
+        self.object_database()?.write_stream(kind, size, reader)
+    }
+
+    /// Writes an object unless it exists already, like `write_object_stream`.
+    pub fn save_object(&self, object: &Object) -> Result<()> {
+        self.object_database()?
+            .write(object.kind(), object.contents_bytes())?;
+        Ok(())
+    }
+
+    /// Writes an object as a loose object even if it is packed, for packs about to be deleted.
+    pub fn save_loose_object(&self, object: &Object) -> anyhow::Result<()> {
+        self.loose_database()?
+            .write(object.kind(), object.contents_bytes())?;
+        Ok(())
+    }
+}
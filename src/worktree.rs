@@ -0,0 +1,449 @@
+//! Linked worktrees: more working trees sharing the objects, branches and configuration of
+//! one repository, each with its own `HEAD`, index and per-worktree references.
+//!
+//! A linked worktree is administered in `worktrees/<name>` of the common git directory,
+//! which holds its `HEAD` and index, `commondir` pointing back at the common directory and
+//! `gitdir` naming the `.git` file in the working tree. That file in turn points at the
+//! administrative directory. A `locked` file keeps the worktree from being pruned.
+
+use crate::{
+    checkout::{tree_snapshot, Snapshot},
+    refs::Head,
+    ObjectRef, Repository,
+};
+use anyhow::Context;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// A working tree of the repository
+#[derive(Debug, Clone)]
+pub struct Worktree {
+    /// Top of the working tree, the git directory itself for a bare repository
+    pub path: PathBuf,
+    /// The common git directory for the main worktree, `worktrees/<name>` in it otherwise
+    pub git_dir: PathBuf,
+    pub is_main: bool,
+    pub bare: bool,
+    /// `None` if `HEAD` can not be read
+    pub head: Option<Head>,
+    /// Why the worktree is locked, possibly empty, `None` if it is not
+    pub locked: Option<String>,
+    /// Why the administrative files can be pruned, `None` if the worktree is still there
+    pub prunable: Option<String>,
+}
+
+impl Worktree {
+    /// Name of the administrative directory, `None` for the main worktree
+    pub fn name(&self) -> Option<&str> {
+        match self.is_main {
+            true => None,
+            false => self.git_dir.file_name().and_then(|name| name.to_str()),
+        }
+    }
+
+    /// Opens the repository as seen from this worktree.
+    pub fn repository(&self) -> Repository {
+        let work_tree = (!self.bare).then(|| self.path.clone());
+        Repository::new(self.git_dir.clone()).with_work_tree(work_tree)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AddWorktreeOptions {
+    /// Allow a branch that is already checked out elsewhere, or a path that is registered
+    /// for a missing worktree
+    pub force: bool,
+    /// Leave the working tree and index empty
+    pub no_checkout: bool,
+    /// Lock the new worktree with this reason
+    pub lock: Option<String>,
+}
+
+/// Reads the working tree path from the `gitdir` file of a linked worktree, or why it is
+/// prunable.
+fn linked_work_tree(git_dir: &Path) -> std::result::Result<PathBuf, String> {
+    if !git_dir.is_dir() {
+        return Err("not a valid directory".to_owned());
+    }
+    let dot_git = match fs::read_to_string(git_dir.join("gitdir")) {
+        Ok(dot_git) => dot_git,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err("gitdir file does not exist".to_owned())
+        }
+        Err(_) => return Err("unable to read gitdir file".to_owned()),
+    };
+    let dot_git = Path::new(dot_git.trim_end());
+    let Some(path) = dot_git.parent().filter(|_| dot_git.is_absolute()) else {
+        return Err("invalid gitdir file".to_owned());
+    };
+    if !dot_git.exists() {
+        return Err("gitdir file points to non-existent location".to_owned());
+    }
+    Ok(path.to_path_buf())
+}
+
+/// Where the `gitdir` file of a linked worktree says it is, whether it exists or not.
+fn recorded_work_tree(git_dir: &Path) -> Option<PathBuf> {
+    let dot_git = fs::read_to_string(git_dir.join("gitdir")).ok()?;
+    Path::new(dot_git.trim_end())
+        .parent()
+        .map(Path::to_path_buf)
+}
+
+/// Whether the working tree has changes in the index or files, or untracked files.
+fn is_dirty(repo: &Repository) -> anyhow::Result<bool> {
+    let index = repo.read_index()?.unwrap_or_default();
+    let head_tree = match repo.head_commit()? {
+        Some(head) => Some(repo.peel_to_tree(&head)?),
+        None => None,
+    };
+    let head = tree_snapshot(repo, head_tree.as_ref())?;
+    let staged: Snapshot = index
+        .entries()
+        .iter()
+        .map(|entry| (entry.path.clone(), (entry.mode, entry.hash)))
+        .collect();
+    if head != staged || index.entries().iter().any(|entry| entry.stage != 0) {
+        return Ok(true);
+    }
+    for entry in index.entries() {
+        if repo.is_worktree_modified(entry)? {
+            return Ok(true);
+        }
+    }
+    let work_dir = repo.work_dir()?;
+    let mut pending = vec![work_dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.file_name() == ".git" {
+                continue;
+            }
+            let path = entry.path();
+            let Some(relative) = path.strip_prefix(work_dir).ok().and_then(|p| p.to_str()) else {
+                return Ok(true);
+            };
+            if entry.file_type()?.is_dir() {
+                // Directories with tracked files are searched for untracked ones
+                let prefix = format!("{relative}/");
+                if !index.entries().iter().any(|e| e.path.starts_with(&prefix)) {
+                    return Ok(true);
+                }
+                pending.push(path);
+            } else if index.get(relative, 0).is_none() {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+impl Repository {
+    /// The main worktree followed by the linked ones, by name.
+    pub fn worktrees(&self) -> anyhow::Result<Vec<Worktree>> {
+        let main = Repository::new(self.common_dir.clone());
+        let bare = self.config()?.get_bool("core.bare")?.unwrap_or(false);
+        let mut worktrees = vec![Worktree {
+            path: match bare {
+                true => self.common_dir.clone(),
+                false => main.work_dir()?.to_path_buf(),
+            },
+            git_dir: self.common_dir.clone(),
+            is_main: true,
+            bare,
+            head: main.head().ok(),
+            locked: None,
+            prunable: None,
+        }];
+
+        let mut names = Vec::new();
+        match fs::read_dir(self.common_dir.join("worktrees")) {
+            Ok(entries) => {
+                for entry in entries {
+                    names.push(entry?.file_name());
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        names.sort();
+        for name in names {
+            let git_dir = self.common_dir.join("worktrees").join(name);
+            let locked = fs::read_to_string(git_dir.join("locked"))
+                .ok()
+                .map(|reason| reason.trim_end().to_owned());
+            let (path, prunable) = match linked_work_tree(&git_dir) {
+                Ok(path) => (path, None),
+                // Locked worktrees may be on removable media, they are kept anyway
+                Err(reason) => (
+                    recorded_work_tree(&git_dir).unwrap_or_else(|| git_dir.clone()),
+                    locked.is_none().then_some(reason),
+                ),
+            };
+            let head = Repository::new(git_dir.clone()).head().ok();
+            worktrees.push(Worktree {
+                path,
+                git_dir,
+                is_main: false,
+                bare: false,
+                head,
+                locked,
+                prunable,
+            });
+        }
+        Ok(worktrees)
+    }
+
+    /// The worktree this repository was opened in
+    pub fn current_worktree(&self) -> anyhow::Result<Worktree> {
+        self.worktrees()?
+            .into_iter()
+            .find(|worktree| worktree.git_dir == self.path)
+            .ok_or_else(|| anyhow::Error::msg("the current worktree is not registered"))
+    }
+
+    /// Finds a worktree by the path of its working tree or by its name.
+    pub fn find_worktree(&self, arg: &str) -> anyhow::Result<Worktree> {
+        // Worktrees which were deleted can still be named by the path they had
+        let path = fs::canonicalize(arg).ok().or_else(|| {
+            let arg = Path::new(arg);
+            let parent = match arg.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            Some(fs::canonicalize(parent).ok()?.join(arg.file_name()?))
+        });
+        let worktrees = self.worktrees()?;
+        let found = match worktrees
+            .iter()
+            .find(|worktree| path.as_ref() == Some(&worktree.path))
+        {
+            Some(worktree) => Some(worktree),
+            None => worktrees
+                .iter()
+                .find(|worktree| worktree.name() == Some(arg)),
+        };
+        found
+            .cloned()
+            .ok_or_else(|| anyhow::Error::msg(format!("'{arg}' is not a working tree")))
+    }
+
+    /// A worktree, possibly this one, which has `branch` (a full reference name) checked out.
+    pub fn worktree_with_branch(&self, branch: &str) -> anyhow::Result<Option<Worktree>> {
+        Ok(self.worktrees()?.into_iter().find(|worktree| {
+            !worktree.bare && worktree.head == Some(Head::Branch(branch.to_owned()))
+        }))
+    }
+
+    /// Fails if `branch` is checked out in any worktree.
+    pub fn ensure_branch_not_checked_out(&self, branch: &str) -> anyhow::Result<()> {
+        if let Some(worktree) = self.worktree_with_branch(branch)? {
+            anyhow::bail!(
+                "'{}' is already checked out at '{}'",
+                branch.strip_prefix("refs/heads/").unwrap_or(branch),
+                worktree.path.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Creates a linked worktree at `path` with `HEAD` set to `head`, checking out `commit`.
+    /// Returns the repository as seen from the new worktree.
+    pub fn add_worktree(
+        &self,
+        path: &Path,
+        commit: &ObjectRef,
+        head: &Head,
+        options: &AddWorktreeOptions,
+    ) -> anyhow::Result<Repository> {
+        if let (Head::Branch(branch), false) = (head, options.force) {
+            self.ensure_branch_not_checked_out(branch)?;
+        }
+        if path.exists() {
+            anyhow::ensure!(
+                fs::read_dir(path)?.next().is_none(),
+                "'{}' already exists",
+                path.display()
+            );
+        }
+        let absolute = std::env::current_dir()?.join(path);
+        for worktree in self.worktrees()? {
+            if worktree.path == absolute && worktree.prunable.is_some() {
+                anyhow::ensure!(
+                    options.force,
+                    "'{}' is a missing but already registered worktree;\nuse 'add -f' to override, or 'prune' or 'remove' to clear",
+                    path.display()
+                );
+                fs::remove_dir_all(&worktree.git_dir)?;
+            }
+        }
+
+        // Named like the directory, with a number added if that name is taken
+        let base_name: String = absolute
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("worktree")
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                    c
+                } else {
+                    '-'
+                }
+            })
+            .collect();
+        let worktrees_dir = self.common_dir.join("worktrees");
+        let mut name = base_name.clone();
+        let mut counter = 1;
+        while worktrees_dir.join(&name).exists() {
+            name = format!("{base_name}{counter}");
+            counter += 1;
+        }
+        let git_dir = worktrees_dir.join(&name);
+        fs::create_dir_all(&git_dir)?;
+        // Locked while it is being set up, so a concurrent prune leaves it alone
+        fs::write(git_dir.join("locked"), "initializing\n")?;
+
+        fs::create_dir_all(path)
+            .with_context(|| format!("could not create directory of '{}'", path.display()))?;
+        let work_tree = fs::canonicalize(path)?;
+        let dot_git = work_tree.join(".git");
+        fs::write(&dot_git, format!("gitdir: {}\n", git_dir.display()))?;
+        fs::write(git_dir.join("gitdir"), format!("{}\n", dot_git.display()))?;
+        fs::write(git_dir.join("commondir"), "../..\n")?;
+        // HEAD first points at the commit itself, so the git directory is valid from here on
+        fs::write(git_dir.join("HEAD"), format!("{}\n", commit.0))?;
+
+        let repo = Repository::new(git_dir.clone()).with_work_tree(Some(work_tree));
+        if !options.no_checkout {
+            let tree = repo.peel_to_tree(commit)?;
+            repo.checkout_tree(None, &tree, true)?;
+        }
+        repo.set_head(head, "reset: moving to HEAD")?;
+        match &options.lock {
+            Some(reason) => fs::write(git_dir.join("locked"), reason)?,
+            None => fs::remove_file(git_dir.join("locked"))?,
+        }
+        Ok(repo)
+    }
+
+    /// Deletes a linked worktree with its administrative files. Unless `force` is set, it
+    /// must not have changes or untracked files, and locked worktrees need `force` twice.
+    pub fn remove_worktree(&self, worktree: &Worktree, force: u8) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !worktree.is_main,
+            "'{}' is a main working tree",
+            worktree.path.display()
+        );
+        if let Some(reason) = &worktree.locked {
+            anyhow::ensure!(
+                force >= 2,
+                "cannot remove a locked working tree{};\nuse 'remove -f -f' to override or unlock first",
+                match reason.is_empty() {
+                    true => String::new(),
+                    false => format!(", lock reason: {reason}"),
+                }
+            );
+        }
+        if worktree.prunable.is_none() {
+            if force == 0 {
+                anyhow::ensure!(
+                    !is_dirty(&worktree.repository())?,
+                    "'{}' contains modified or untracked files, use --force to delete it",
+                    worktree.path.display()
+                );
+            }
+            fs::remove_dir_all(&worktree.path)
+                .with_context(|| format!("failed to delete '{}'", worktree.path.display()))?;
+        }
+        fs::remove_dir_all(&worktree.git_dir)?;
+        self.remove_empty_worktrees_dir();
+        Ok(())
+    }
+
+    /// Removes the administrative files of worktrees whose working tree is gone, if their
+    /// `gitdir` file is older than `expire`. Returns the names and reasons of those removed,
+    /// or to be removed with `dry_run`.
+    pub fn prune_worktrees(
+        &self,
+        expire: Option<u64>,
+        dry_run: bool,
+    ) -> anyhow::Result<Vec<(String, String)>> {
+        let mut pruned = Vec::new();
+        for worktree in self.worktrees()? {
+            let Some(reason) = &worktree.prunable else {
+                continue;
+            };
+            let modified = fs::metadata(worktree.git_dir.join("gitdir"))
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |duration| duration.as_secs());
+            if expire.is_some_and(|expire| modified > expire) {
+                continue;
+            }
+            if !dry_run {
+                match worktree.git_dir.is_dir() {
+                    true => fs::remove_dir_all(&worktree.git_dir)?,
+                    false => fs::remove_file(&worktree.git_dir)?,
+                }
+            }
+            let name = worktree.git_dir.file_name().unwrap_or_default();
+            pruned.push((name.to_string_lossy().into_owned(), reason.clone()));
+        }
+        if !dry_run {
+            self.remove_empty_worktrees_dir();
+        }
+        Ok(pruned)
+    }
+
+    fn remove_empty_worktrees_dir(&self) {
+        let _ = fs::remove_dir(self.common_dir.join("worktrees"));
+    }
+
+    /// Keeps a linked worktree from being pruned, moved or removed.
+    pub fn lock_worktree(&self, worktree: &Worktree, reason: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !worktree.is_main,
+            "The main working tree cannot be locked or unlocked"
+        );
+        if let Some(existing) = &worktree.locked {
+            match existing.is_empty() {
+                true => anyhow::bail!("'{}' is already locked", worktree.path.display()),
+                false => anyhow::bail!(
+                    "'{}' is already locked, reason: {existing}",
+                    worktree.path.display()
+                ),
+            }
+        }
+        fs::write(worktree.git_dir.join("locked"), reason)?;
+        Ok(())
+    }
+
+    pub fn unlock_worktree(&self, worktree: &Worktree) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !worktree.is_main,
+            "The main working tree cannot be locked or unlocked"
+        );
+        anyhow::ensure!(
+            worktree.locked.is_some(),
+            "'{}' is not locked",
+            worktree.path.display()
+        );
+        fs::remove_file(worktree.git_dir.join("locked"))?;
+        Ok(())
+    }
+
+    /// Opens the other worktrees, whose `HEAD` and index keep objects alive as well.
+    pub(crate) fn other_worktrees(&self) -> anyhow::Result<Vec<Repository>> {
+        Ok(self
+            .worktrees()?
+            .into_iter()
+            .filter(|worktree| worktree.git_dir != self.path && worktree.prunable.is_none())
+            .map(|worktree| worktree.repository())
+            .collect())
+    }
+}